@@ -1,5 +1,5 @@
 use anyhow::{anyhow, Result};
-use clap::{ArgAction, Parser};
+use clap::{ArgAction, Parser, ValueEnum};
 use humansize::{format_size, DECIMAL};
 use image::{self, DynamicImage, ImageFormat};
 use imagequant::{Attributes, Image as LiqImage};
@@ -7,16 +7,20 @@ use mozjpeg::{ColorSpace, Compress, ScanMode};
 use oxipng::{optimize_from_memory, Options as OxipngOptions};
 use rayon::prelude::*;
 use ravif::{Encoder as AvifEncoder};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::io::{Read, Write, Cursor};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use walkdir::WalkDir;
 use webp::Encoder as WebpEncoder;
 
 // Web server imports
 use axum::{
-    http::{header, StatusCode},
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
     response::{Html, Response},
     routing::{get, post},
     Router,
@@ -24,11 +28,14 @@ use axum::{
 use axum_extra::extract::Multipart;
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::CorsLayer;
-use reqwest;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
 
 /// CLI options
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about = "Rust-only image compressor (TinyPNG-like)")]
 struct Args {
     /// Launch web UI on localhost (default mode if no input provided)
@@ -39,9 +46,39 @@ struct Args {
     #[arg(long, default_value = "3030")]
     port: u16,
 
+    /// Don't launch a browser for the web UI, just print its URL (headless servers,
+    /// Docker, SSH)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_open: bool,
+
+    /// Origin allowed to call the web API cross-site, e.g. https://app.example.com
+    /// (repeatable); without any, CORS stays permissive
+    #[arg(long = "cors-origin", value_name = "ORIGIN", value_parser = parse_cors_origin)]
+    cors_origins: Vec<HeaderValue>,
+
+    /// Host /api/compress-url may fetch images from, e.g. cdn.example.com (repeatable);
+    /// without any, every public http(s) host is allowed
+    #[arg(long = "fetch-allow-host", value_name = "HOST")]
+    fetch_hosts: Vec<String>,
+
+    /// Let /api/compress-url fetch loopback, private and link-local addresses (for local
+    /// testing; refused by default)
+    #[arg(long, action = ArgAction::SetTrue)]
+    fetch_allow_private: bool,
+
+    /// Brotli/gzip web responses for clients that accept it (skips already-compressed images)
+    #[arg(long, action = ArgAction::SetTrue)]
+    http_compression: bool,
+
     /// Input file or directory (CLI mode)
     input: Option<PathBuf>,
 
+    /// Process exactly the files listed in this file (one path per line, `#` comments,
+    /// `-` reads stdin) instead of walking the input; relative paths are from the working
+    /// directory, and the input argument, if given, is only the root for archive names
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["stdout", "make_animation"])]
+    input_list: Option<PathBuf>,
+
     /// Output directory (defaults to same folder as each file)
     #[arg(short, long)]
     output: Option<PathBuf>,
@@ -50,43 +87,756 @@ struct Args {
     #[arg(long, action = ArgAction::SetTrue)]
     overwrite: bool,
 
+    /// With --overwrite: validate output before replacing, and on any failure keep
+    /// the original and write a `<file>.failed` marker with the reason
+    #[arg(long, action = ArgAction::SetTrue)]
+    keep_original_on_error: bool,
+
+    /// What to do when an output file already exists: overwrite it, skip the input, or
+    /// write next to it with a numeric suffix (image-1.webp)
+    #[arg(long, value_enum, default_value = "overwrite")]
+    on_conflict: OnConflict,
+
+    /// Stop at the first failed file: files not yet started are skipped and the run
+    /// exits with an error
+    #[arg(long, action = ArgAction::SetTrue)]
+    fail_fast: bool,
+
+    /// Retry a file this many times, with backoff, when reading or writing it fails
+    /// (flaky network mounts, heavy IO); decode and encode errors are not retried
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    retries: u32,
+
+    /// Print per-file details such as retries used
+    #[arg(short, long, action = ArgAction::SetTrue)]
+    verbose: bool,
+
+    /// Memory-map inputs larger than this many bytes instead of copying them onto the heap
+    #[arg(long, value_name = "BYTES")]
+    stream_threshold: Option<u64>,
+
     /// Number of concurrent workers (defaults to CPU count)
     #[arg(short, long)]
     jobs: Option<usize>,
 
+    /// Reproducible output: single-threaded processing and fixed archive timestamps
+    /// (overrides --jobs)
+    #[arg(long, action = ArgAction::SetTrue)]
+    deterministic: bool,
+
     /// Enable lossy PNG quantization (TinyPNG-like)
     #[arg(long = "png-lossy", action = ArgAction::SetTrue, default_value_t = true)]
     png_lossy: bool,
 
+    /// Metadata to remove, comma-separated: exif, icc, xmp, gps, thumbnail, all (default).
+    /// Anything not listed is carried over to JPEG, PNG and WebP outputs
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "all")]
+    strip: Vec<MetadataKind>,
+
+    /// Copyright/attribution text written into every output after --strip: a PNG text
+    /// chunk, a JPEG comment, or an XMP description in WebP (AVIF and others go without)
+    #[arg(long, value_name = "TEXT", value_parser = parse_comment)]
+    comment: Option<String>,
+
+    /// Rebuild the kept EXIF thumbnail from the output instead of dropping it when the image was resized
+    #[arg(long, action = ArgAction::SetTrue)]
+    regen_thumbnail: bool,
+
+    /// Re-encode the kept EXIF thumbnail at low JPEG quality (same size, fewer bytes)
+    #[arg(long, action = ArgAction::SetTrue)]
+    compress_embedded_thumb: bool,
+
+    /// Skip PNG quantization: lossless oxipng optimization of the original bytes only
+    #[arg(long, action = ArgAction::SetTrue)]
+    encode_only: bool,
+
+    /// Best lossless mode per format: PNG via oxipng only, lossless WebP and AVIF,
+    /// and JPEG re-encoded from its DCT coefficients (jpegtran-style)
+    #[arg(long, action = ArgAction::SetTrue)]
+    lossless: bool,
+
+    /// Keep 16-bit PNGs at 16 bits: they skip palette quantization (which is 8-bit only)
+    /// and are optimized losslessly with oxipng instead
+    #[arg(long, action = ArgAction::SetTrue)]
+    preserve_bit_depth: bool,
+
+    /// Warn when a high-bit-depth source (16-bit, float) is written with fewer bits per channel
+    #[arg(long, action = ArgAction::SetTrue)]
+    output_bit_depth_check: bool,
+
+    /// Turn --output-bit-depth-check warnings into errors
+    #[arg(long, action = ArgAction::SetTrue, requires = "output_bit_depth_check")]
+    strict: bool,
+
     /// Compression level: low (best quality), mid (balanced), or max (smallest file)
     /// Can also use granular format like "low-85" or "mid-75" for fine control
     #[arg(long, default_value = "mid")]
     compression_lvl: String,
 
+    /// Per-format quality defaults for a use case, instead of one range for every format
+    #[arg(long, value_enum, conflicts_with = "compression_lvl")]
+    preset: Option<QualityPreset>,
+
 
     /// Run oxipng after quantization (lossless structural optimization)
     #[arg(long = "oxipng", action = ArgAction::SetTrue, default_value_t = true)]
     oxipng: bool,
 
+    /// Pre-quantization noise (in 8-bit levels, e.g. 1.5) applied only to smooth gradients
+    /// to break up banding; 0 disables it
+    #[arg(long, default_value_t = 0.0, value_name = "STRENGTH")]
+    gradient_noise: f32,
+
+    /// Premultiply RGB by alpha before encoding, for renderers/engines that expect
+    /// premultiplied textures (output is no longer straight alpha)
+    #[arg(long, action = ArgAction::SetTrue)]
+    premultiply_alpha: bool,
+
+    /// Stretch the luminance histogram of dull scans/photos before compressing
+    #[arg(long, action = ArgAction::SetTrue)]
+    auto_contrast: bool,
+
+    /// Dither quantized PNGs only when they look photographic (gradients, many colors);
+    /// flat graphics are quantized without dithering
+    #[arg(long, action = ArgAction::SetTrue)]
+    auto_dither: bool,
+
+    /// Drop this many low bits per channel during PNG quantization (0 = off, up to 4)
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=4))]
+    png_posterize: u8,
+
+    /// Put the most transparent palette color at index 0 in quantized PNGs, for engines
+    /// that treat index 0 as the transparent color
+    #[arg(long, action = ArgAction::SetTrue)]
+    transparent_index_first: bool,
+
+    /// Largest bits per pixel for quantized PNGs (1, 2, 4 or 8): caps the palette at
+    /// 2^bits colors; the smallest depth that fits the palette is always written
+    #[arg(long, value_name = "BITS", value_parser = parse_png_bit_depth)]
+    png_bit_depth: Option<u8>,
+
+    /// Black-and-white 1-bit PNG output for document scans (transparency becomes white)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["to_webp", "to_avif", "formats", "map"])]
+    bilevel: bool,
+
+    /// Gray level (0-255) below which --bilevel pixels turn black
+    #[arg(long, default_value_t = 128, requires = "bilevel")]
+    bilevel_threshold: u8,
+
+    /// Floyd-Steinberg dither --bilevel output, for scans with photos or shading
+    #[arg(long, action = ArgAction::SetTrue, requires = "bilevel")]
+    bilevel_dither: bool,
+
+    /// Colors PNG quantization must keep exactly (brand/logo colors), e.g. "#1A73E8,#FFFFFF"
+    #[arg(long, value_name = "#RRGGBB,...", value_delimiter = ',', value_parser = parse_hex_color)]
+    keep_colors: Vec<[u8; 3]>,
+
+    /// Pick palette size and quality per image from its content (within --compression-lvl)
+    #[arg(long, action = ArgAction::SetTrue)]
+    adaptive: bool,
+
+    /// PNG interlacing: on (Adam7, progressive), off (smaller files), or keep the source's
+    #[arg(long, value_enum, default_value = "keep")]
+    png_interlace: PngInterlace,
+
+    /// Apply JPEG EXIF orientation to the pixels so the (metadata-free) output displays
+    /// upright; skipped with --lossless. `--autorotate false` keeps the stored pixel layout
+    #[arg(long, action = ArgAction::Set, default_value_t = true, num_args = 0..=1, default_missing_value = "true")]
+    autorotate: bool,
+
     /// Convert/generate WebP (overrides original format)
     #[arg(long, action = ArgAction::SetTrue)]
     to_webp: bool,
 
+    /// WebP compression effort: 0 (fastest) to 6 (smallest output)
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(0..=6))]
+    webp_method: u8,
+
+    /// Sharper, more accurate RGB->YUV conversion for lossy WebP (better saturated reds,
+    /// slower); the AVIF encoder has no sharp-YUV path
+    #[arg(long, action = ArgAction::SetTrue)]
+    sharp_yuv: bool,
+
+    /// Keep the RGB of fully transparent pixels in lossy WebP (texture masks, compositing);
+    /// off by default since libwebp flattens them for smaller files
+    #[arg(long, action = ArgAction::SetTrue)]
+    webp_exact: bool,
+
+    /// Spend WebP/AVIF bits where the eye sees them: smooth grain out of flat regions and
+    /// nudge quality up for detailed images, down for mostly flat ones
+    #[arg(long, action = ArgAction::SetTrue)]
+    perceptual: bool,
+
+    /// JPEG input smoothing (0-100) to remove noise before encoding; 0 disables it
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+    jpeg_smoothing: u8,
+
+    /// Insert a JPEG restart marker every N MCUs (8x8/16x16 blocks) for error resilience
+    /// in broadcast/streaming pipelines; 0 writes none
+    #[arg(long, value_name = "MCUS", default_value_t = 0)]
+    jpeg_restart: u16,
+
+    /// How mozjpeg groups color components into progressive scans
+    #[arg(long, value_enum, default_value = "all-components-together")]
+    jpeg_scan_mode: JpegScanMode,
+
+    /// How hard mozjpeg works at a given quality: "fast" is libjpeg-turbo's baseline
+    /// encoder, "best" adds scan-aware trellis quantization on top of the default
+    #[arg(long, value_enum, default_value = "balanced")]
+    jpeg_effort: JpegEffort,
+
+    /// Arithmetic-code JPEGs instead of Huffman (a few percent smaller). Many browsers and
+    /// viewers can't decode these, so only use it where you control the decoder
+    #[arg(long, action = ArgAction::SetTrue)]
+    jpeg_arithmetic: bool,
+
     /// Convert/generate AVIF (overrides original format)
     #[arg(long, action = ArgAction::SetTrue)]
     to_avif: bool,
+
+    /// Per-source-extension output formats, e.g. "png=webp,jpg=jpeg" (overrides --to-*)
+    #[arg(long, value_name = "EXT=FORMAT,...", value_parser = parse_format_map)]
+    map: Option<HashMap<String, OutputFormat>>,
+
+    /// Write every input in each of these formats, e.g. "webp,avif,original" for a
+    /// `<picture>` element; the encodes for one file run in parallel
+    #[arg(long, value_enum, value_delimiter = ',', value_name = "FORMATS", conflicts_with_all = ["map", "to_webp", "to_avif", "overwrite", "stdout"])]
+    formats: Option<Vec<OutputFormat>>,
+
+    /// Write one output per quality, e.g. "40,60,80" gives image-q40.webp, image-q60.webp
+    /// and image-q80.webp for side-by-side comparison
+    #[arg(long, value_delimiter = ',', value_name = "QUALITIES", value_parser = clap::value_parser!(u8).range(1..=100),
+          conflicts_with_all = ["formats", "overwrite", "stdout", "tar", "hash_names", "template", "data_uri"])]
+    quality_sweep: Option<Vec<u8>>,
+
+    /// Encode one image as lossy PNG, WebP, AVIF and JPEG at the current quality and print
+    /// each format's size and encode time; nothing is written
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["stdout", "overwrite", "tar", "formats"])]
+    benchmark: bool,
+
+    /// Also save the --benchmark encodes into this directory as <name>.<ext>
+    #[arg(long, value_name = "DIR", requires = "benchmark")]
+    benchmark_out: Option<PathBuf>,
+
+    /// Crop away uniform borders (the color of the top-left pixel, or transparency) before
+    /// anything else resizes the image
+    #[arg(long, action = ArgAction::SetTrue)]
+    trim: bool,
+
+    /// How far (0-255 per channel) a pixel may differ from the border color and still be
+    /// trimmed, for scans and JPEG noise
+    #[arg(long, value_name = "N", default_value_t = 0, requires = "trim")]
+    trim_tolerance: u8,
+
+    /// Letterbox to exactly WIDTHxHEIGHT: scale to fit (keeping aspect ratio), then pad
+    /// with --fit-background
+    #[arg(long, value_name = "WxH", value_parser = parse_fit, conflicts_with_all = ["max_dimension", "max_megapixels"])]
+    fit: Option<(u32, u32)>,
+
+    /// Padding color for --fit: #RRGGBB or "transparent"
+    #[arg(long, value_name = "COLOR", default_value = "#ffffff", value_parser = parse_fit_background, requires = "fit")]
+    fit_background: [u8; 4],
+
+    /// Downscale images so the capped side (see --cap-side) is at most this many pixels
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..))]
+    max_dimension: Option<u32>,
+
+    /// Which side --max-dimension applies to: the longest, or the shortest (minimum-resolution guarantee)
+    #[arg(long, value_enum, default_value = "longest", requires = "max_dimension")]
+    cap_side: CapSide,
+
+    /// Only apply --max-dimension to files larger than this (e.g. 500KB); smaller files
+    /// keep their native resolution
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size, requires = "max_dimension")]
+    shrink_if_over: Option<u64>,
+
+    /// Downscale images whose pixel count exceeds this many megapixels (e.g. 2 or 0.5),
+    /// keeping the aspect ratio
+    #[arg(long, value_name = "MP", value_parser = parse_megapixels)]
+    max_megapixels: Option<f64>,
+
+    /// AVIF bit depth: 8, or 10 to keep high-bit-depth (e.g. 16-bit PNG) sources smooth
+    #[arg(long, default_value_t = 8, value_parser = parse_avif_depth)]
+    avif_depth: u8,
+
+    /// AVIF chroma subsampling (default: 420); 444 keeps crisp colored edges (screenshots,
+    /// UI) at a larger size. 420/422 are 8-bit only, so 10-bit output defaults to 444
+    #[arg(long, value_enum)]
+    avif_subsampling: Option<AvifSubsampling>,
+
+    /// Split lossy AVIF output into COLSxROWS tiles (powers of two, e.g. "2x2") so large
+    /// images decode on several threads; by default the encoder decides
+    #[arg(long, value_name = "COLSxROWS", value_parser = parse_avif_tiles)]
+    avif_tiles: Option<(usize, usize)>,
+
+    /// Scale factor for rasterizing SVG inputs (1.0 = the SVG's intrinsic size)
+    #[arg(long, default_value_t = 1.0)]
+    svg_scale: f32,
+
+    /// Output width in pixels for SVG inputs (required when the SVG has no intrinsic size)
+    #[arg(long)]
+    svg_width: Option<u32>,
+
+    /// Keep SVG inputs as SVG, only stripping comments and redundant whitespace; they are
+    /// still rasterized when a raster output (--to-png, --to-webp, --map, ...) is requested
+    #[arg(long, action = ArgAction::SetTrue)]
+    svg_minify: bool,
+
+    /// Also write a center-cropped square thumbnail of this many pixels next to each
+    /// output (`<name>.thumb.<ext>`)
+    #[arg(long, value_name = "PX", value_parser = clap::value_parser!(u32).range(1..), conflicts_with_all = ["tar", "data_uri", "stdout"])]
+    thumbnail: Option<u32>,
+
+    /// Format for --thumbnail sidecars ("original" keeps PNG)
+    #[arg(long, value_enum, default_value = "webp", requires = "thumbnail")]
+    thumbnail_format: OutputFormat,
+
+    /// Also write a tiny blurred placeholder (LQIP) as a WebP data URI in a JSON sidecar
+    /// (`<name>.lqip.json`) for progressive image loading
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["tar", "data_uri", "stdout"])]
+    placeholder: bool,
+
+    /// After the run, tile a labeled thumbnail of every processed image into one grid image
+    /// at this path (format from its extension, PNG otherwise)
+    #[arg(long, value_name = "PATH", conflicts_with = "stdout")]
+    contact_sheet: Option<PathBuf>,
+
+    /// Thumbnails per row in --contact-sheet
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..), requires = "contact_sheet")]
+    sheet_columns: u32,
+
+    /// Instead of compressing files one by one, assemble the input directory's frames
+    /// (natural filename order: frame_2 before frame_10) into one animation at this frame rate
+    #[arg(long, value_name = "FPS", value_parser = parse_fps, conflicts_with_all = ["stdout", "tar", "overwrite", "contact_sheet"])]
+    make_animation: Option<f64>,
+
+    /// Container for --make-animation
+    #[arg(long, value_enum, default_value = "gif", requires = "make_animation")]
+    animation_format: AnimationFormat,
+
+    /// Keep the combined size of all outputs under this budget (e.g. "5MB", "800KB"),
+    /// lowering quality across the whole batch when needed
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    total_budget: Option<u64>,
+
+    /// Refuse to decode images that would need more than this much memory (e.g. "256MB"),
+    /// so hostile headers fail cleanly instead of exhausting RAM; applies to CLI and web
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    decode_memory_limit: Option<u64>,
+
+    /// Cap the estimated decoded size of images in flight at once (e.g. "2GB"); workers wait
+    /// for room instead of all decoding large files together. A file over the budget runs alone,
+    /// and a file's --formats/--quality-sweep outputs encode one after another
+    #[arg(long, value_name = "SIZE", value_parser = parse_byte_size)]
+    memory_budget: Option<u64>,
+
+    /// Write all outputs into a tar archive (gzip-compressed if it ends in .tar.gz/.tgz)
+    #[arg(long, value_name = "PATH", conflicts_with = "overwrite")]
+    tar: Option<PathBuf>,
+
+    /// Write each output as a `data:<mime>;base64,...` URI in `<output>.txt` instead of binary
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["overwrite", "tar"])]
+    data_uri: bool,
+
+    /// Fail animated inputs (GIF/WebP/APNG) instead of flattening them to one frame
+    #[arg(long, action = ArgAction::SetTrue)]
+    preserve_animation: bool,
+
+    /// Compress only the first frame of animated GIF/WebP/APNG inputs, as a static image
+    /// in the target format (poster images and thumbnails)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "preserve_animation")]
+    first_frame: bool,
+
+    /// Where --overwrite stages outputs before they replace the originals (defaults to the
+    /// system temp directory); staged files are removed whether or not the run succeeds
+    #[arg(long, value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Write the compressed bytes of a single input file to stdout (logging is silenced)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["output", "overwrite", "tar", "data_uri"])]
+    stdout: bool,
+
+    /// Copy the compressed result of a single input file to the system clipboard instead of
+    /// writing it (needs the `clipboard` feature and a desktop session)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["output", "overwrite", "tar", "data_uri", "stdout"])]
+    clipboard: bool,
+
+    /// Fingerprint output names with a content hash (image.a1b2c3d4.webp) and write
+    /// an original→hashed `hash-manifest.json` next to the outputs
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["overwrite", "tar", "data_uri"])]
+    hash_names: bool,
+
+    /// Write a JSON manifest mapping each written output to its size and SHA-256, with
+    /// paths relative to the output directory (or the input root), for integrity checks
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tar", "stdout"])]
+    manifest_out: Option<PathBuf>,
+
+    /// Output file name template, e.g. "{name}-{width}x{height}.{ext}"; placeholders:
+    /// {name} {ext} {width} {height} {quality} {hash}
+    #[arg(long, value_name = "TEMPLATE", value_parser = parse_template, conflicts_with_all = ["overwrite", "tar", "hash_names"])]
+    template: Option<String>,
+
+    /// Also compress the images inside ZIP archives found in the input, writing a
+    /// repackaged archive (other members are copied unchanged)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "formats")]
+    scan_archives: bool,
+
+    /// Only process these extensions, e.g. "png,jpg" (a subset of the supported ones)
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', value_parser = parse_supported_ext)]
+    only: Option<Vec<String>>,
+
+    /// Only process files modified within this window, e.g. "24h", "7d", "2w 3d"
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    since: Option<std::time::Duration>,
+
+    /// Compress in memory and report projected sizes without writing anything; with
+    /// --overwrite, previews each in-place replacement and warns about files that would grow
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["tar", "contact_sheet", "make_animation", "stdout"])]
+    dry_run: bool,
+
+    /// Compress images with identical pixels only once: the other copies get a hard link
+    /// (or a copy, across filesystems) of the first one's output
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["formats", "quality_sweep", "tar", "data_uri", "hash_names", "template", "stdout"])]
+    dedup: bool,
+
+    /// Report groups of visually similar images instead of compressing: files whose 64-bit
+    /// difference hashes are at most this many bits apart (0-64; ~5 catches re-encodes and
+    /// small edits) are listed together. Nothing is written or deleted
+    #[arg(long, value_name = "BITS", value_parser = clap::value_parser!(u32).range(0..=64), conflicts_with_all = ["stdout", "clipboard", "benchmark"])]
+    perceptual_dedup: Option<u32>,
+
+    /// List the files with their projected savings and pick which ones to compress before
+    /// anything is written (a safety net for --overwrite)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "stdout")]
+    interactive: bool,
+
+    /// Also print the results as a report after the summary: "markdown" gives a
+    /// GitHub-flavored table with a totals row, for pasting into PRs and docs
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    report: Option<ReportFormat>,
+
+    /// After the summary, estimate the monthly bandwidth (and with --cost-per-gb, money)
+    /// saved when every compressed file is served --monthly-views times
+    #[arg(long, action = ArgAction::SetTrue, requires = "monthly_views")]
+    bandwidth_report: bool,
+
+    /// Expected monthly views of each file, for --bandwidth-report
+    #[arg(long, value_name = "N", requires = "bandwidth_report")]
+    monthly_views: Option<u64>,
+
+    /// Egress price in dollars per GB (10^9 bytes), for --bandwidth-report
+    #[arg(long, value_name = "DOLLARS", requires = "bandwidth_report")]
+    cost_per_gb: Option<f64>,
+
+    /// Leave files untouched unless compression saves at least this percentage
+    #[arg(long, value_name = "PERCENT")]
+    min_savings: Option<f64>,
+
+    /// Skip inputs that look like our own output (a `c_` name, or the marker embedded in
+    /// PNG/JPEG output) to avoid generational loss on re-runs. On by default
+    #[arg(long, action = ArgAction::SetTrue, default_value_t = true, overrides_with = "no_skip_compressed")]
+    skip_compressed: bool,
+
+    /// Re-compress files that look like our own output, and leave new output unmarked
+    #[arg(long, action = ArgAction::SetTrue, overrides_with = "skip_compressed")]
+    no_skip_compressed: bool,
+
+    /// Fail a file when its magic bytes disagree with its extension
+    #[arg(long, action = ArgAction::SetTrue)]
+    strict_format: bool,
 }
 
-const SUPPORTED_EXTS: &[&str] = &["png", "jpg", "jpeg", "bmp", "tiff", "tif", "webp", "heic", "heif"];
+const SUPPORTED_EXTS: &[&str] = &[
+    "png",
+    "jpg",
+    "jpeg",
+    "bmp",
+    "tiff",
+    "tif",
+    "webp",
+    "heic",
+    "heif",
+    "ico",
+    #[cfg(feature = "pdf")]
+    "pdf",
+    #[cfg(feature = "avif-decode")]
+    "avif",
+    #[cfg(feature = "svg")]
+    "svg",
+];
+
+// Header carrying the per-request correlation ID
+const REQUEST_ID_HEADER: &str = "x-request-id";
 
 // Embedded HTML for web UI
 const INDEX_HTML: &str = include_str!("../assets/index.html");
 
+/// PNG interlacing policy applied during the oxipng pass
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum PngInterlace {
+    /// Force Adam7 interlacing
+    On,
+    /// Force non-interlaced output
+    Off,
+    /// Keep the interlacing of the source PNG
+    #[default]
+    Keep,
+}
+
+/// `--preset`: a use case with its own quality per output format
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum QualityPreset {
+    /// Balanced size and quality for websites
+    Web,
+    /// High quality for storage and reprocessing
+    Archive,
+    /// Smallest files
+    Max,
+    /// No quality loss; every format uses its lossless mode
+    Lossless,
+}
+
+/// Encoder quality for each output format
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct FormatQualities {
+    /// imagequant min-max range for lossy PNG
+    png: (u8, u8),
+    /// Images converted to JPEG (including PDF images)
+    jpeg: u8,
+    /// JPEGs re-encoded as JPEG
+    jpeg_reencode: u8,
+    webp: f32,
+    avif: f32,
+    /// HEIC/HEIF converted to JPEG
+    heic: u8,
+}
+
+impl FormatQualities {
+    /// Without a preset every lossy format sits at the middle of the range; JPEG re-encodes
+    /// and HEIC conversions keep fixed qualities
+    fn from_range(range: &str) -> Self {
+        let (min_q, max_q) = parse_quality_range(range);
+        let mid = (min_q + max_q) / 2;
+        FormatQualities { png: (min_q, max_q), jpeg: mid, jpeg_reencode: 75, webp: mid as f32, avif: mid as f32, heic: 85 }
+    }
+
+    /// The PNG range as a `--compression-lvl`-style "MIN-MAX" string
+    fn png_range(&self) -> String {
+        format!("{}-{}", self.png.0, self.png.1)
+    }
+}
+
+impl QualityPreset {
+    /// The preset a `--compression-lvl` stands for: low is best quality, max smallest files
+    fn from_level(level: &str) -> Self {
+        match level.trim().to_lowercase().as_str() {
+            "low" => QualityPreset::Archive,
+            "max" => QualityPreset::Max,
+            _ => QualityPreset::Web, // Default to mid
+        }
+    }
+
+    fn qualities(self) -> FormatQualities {
+        let (png, jpeg, webp, avif, heic) = match self {
+            QualityPreset::Web => ((50, 80), 75, 75.0, 60.0, 80),
+            QualityPreset::Archive => ((80, 95), 90, 90.0, 85.0, 92),
+            QualityPreset::Max => ((20, 60), 50, 40.0, 35.0, 60),
+            QualityPreset::Lossless => ((100, 100), 100, 100.0, 100.0, 100),
+        };
+        FormatQualities { png, jpeg, jpeg_reencode: jpeg, webp, avif, heic }
+    }
+}
+
+/// Run-wide quality range: the preset's PNG range, else `--compression-lvl`
+fn base_quality(args: &Args) -> String {
+    match args.preset {
+        Some(preset) => preset.qualities().png_range(),
+        None => compression_level_to_range(&args.compression_lvl),
+    }
+}
+
+/// Which image side `--max-dimension` caps
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CapSide {
+    /// Cap the longer side (fits inside a box)
+    #[default]
+    Longest,
+    /// Cap the shorter side
+    Shortest,
+}
+
+/// Chroma resolution of lossy AVIF output
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum AvifSubsampling {
+    #[value(name = "420")]
+    S420,
+    #[value(name = "422")]
+    S422,
+    #[value(name = "444")]
+    S444,
+}
+
+/// Progressive scan layout for mozjpeg (`ScanMode`)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum JpegScanMode {
+    AllComponentsTogether,
+    /// Smaller on some photos, but can flash gray or green while loading
+    ScanPerComponent,
+    /// Let mozjpeg try both per image
+    Auto,
+}
+
+impl JpegScanMode {
+    fn mozjpeg(self) -> ScanMode {
+        match self {
+            JpegScanMode::AllComponentsTogether => ScanMode::AllComponentsTogether,
+            JpegScanMode::ScanPerComponent => ScanMode::ScanPerComponent,
+            JpegScanMode::Auto => ScanMode::Auto,
+        }
+    }
+}
+
+/// mozjpeg encoder effort, independent of quality
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum JpegEffort {
+    /// No trellis quantization or scan optimization; baseline output
+    Fast,
+    /// Progressive, with optimized Huffman tables and scans
+    Balanced,
+    /// Balanced plus trellis quantization that considers each progressive scan
+    Best,
+}
+
+/// `--on-conflict`: handling of outputs left by an earlier run
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OnConflict {
+    Skip,
+    Overwrite,
+    Rename,
+}
+
+/// Layout of the `--report` table
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ReportFormat {
+    Markdown,
+}
+
+/// Animated output written by --make-animation
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum AnimationFormat {
+    Gif,
+    Webp,
+}
+
+/// Output format selectable per request (`output_format`) or per source extension (`--map`)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// Keep the source format
+    Original,
+    Webp,
+    Avif,
+    Jpeg,
+    Png,
+    Tiff,
+    Bmp,
+    Ico,
+}
+
+impl OutputFormat {
+    /// File extension for converted outputs (None keeps the source's)
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Original => None,
+            OutputFormat::Webp => Some("webp"),
+            OutputFormat::Avif => Some("avif"),
+            OutputFormat::Jpeg => Some("jpg"),
+            OutputFormat::Png => Some("png"),
+            OutputFormat::Tiff => Some("tiff"),
+            OutputFormat::Bmp => Some("bmp"),
+            OutputFormat::Ico => Some("ico"),
+        }
+    }
+
+    /// Replace the conversion flags in `opts` with this format
+    fn apply(self, opts: &mut CompressionOptions) {
+        opts.to_webp = self == OutputFormat::Webp;
+        opts.to_avif = self == OutputFormat::Avif;
+        opts.to_jpeg = self == OutputFormat::Jpeg;
+        opts.to_png = self == OutputFormat::Png;
+        opts.to_tiff = self == OutputFormat::Tiff;
+        opts.to_bmp = self == OutputFormat::Bmp;
+        opts.to_ico = self == OutputFormat::Ico;
+    }
+}
+
+/// Parse "png=webp,jpg=jpeg" into a map keyed by canonical source extension
+fn parse_format_map(s: &str) -> std::result::Result<HashMap<String, OutputFormat>, String> {
+    let mut map = HashMap::new();
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (src, dst) = pair
+            .split_once('=')
+            .ok_or_else(|| format!("expected EXT=FORMAT, got \"{}\"", pair))?;
+        let src = src.trim().trim_start_matches('.').to_lowercase();
+        if src.is_empty() {
+            return Err(format!("missing source extension in \"{}\"", pair));
+        }
+        let format = OutputFormat::from_str(dst.trim(), true)?;
+        map.insert(canonical_ext(&src).to_string(), format);
+    }
+    Ok(map)
+}
+
 #[derive(Debug, Clone)]
 struct CompressionOptions {
     png_lossy: bool,
     png_quality: String,
     oxipng: bool,
+    png_interlace: PngInterlace,
+    adaptive: bool,
+    svg_scale: f32,
+    svg_width: Option<u32>,
+    svg_minify: bool,
+    webp_method: u8,
+    format_map: HashMap<String, OutputFormat>,
+    jpeg_smoothing: u8,
+    max_dimension: Option<u32>,
+    cap_side: CapSide,
+    gradient_noise: f32,
+    avif_depth: u8,
+    lossless: bool,
+    max_megapixels: Option<f64>,
+    autorotate: bool,
+    max_colors: Option<u32>,
+    premultiply_alpha: bool,
+    auto_contrast: bool,
+    strip: Vec<MetadataKind>,
+    regen_thumbnail: bool,
+    png_posterize: u8,
+    sharp_yuv: bool,
+    webp_exact: bool,
+    preserve_bit_depth: bool,
+    jpeg_restart: u16,
+    jpeg_scan_mode: JpegScanMode,
+    avif_subsampling: AvifSubsampling,
+    avif_tiles: Option<(usize, usize)>,
+    perceptual: bool,
+    compress_embedded_thumb: bool,
+    keep_colors: Vec<[u8; 3]>,
+    shrink_if_over: Option<u64>,
+    png_bit_depth: Option<u8>,
+    jpeg_arithmetic: bool,
+    bit_depth_check: bool,
+    strict: bool,
+    auto_dither: bool,
+    bilevel: Option<(u8, bool)>,
+    preset: Option<QualityPreset>,
+    comment: Option<String>,
+    fit: Option<(u32, u32)>,
+    fit_background: [u8; 4],
+    transparent_index_first: bool,
+    jpeg_effort: JpegEffort,
+    first_frame: bool,
+    trim: Option<u8>,
     to_webp: bool,
     to_avif: bool,
     to_jpeg: bool,
@@ -96,6 +846,142 @@ struct CompressionOptions {
     to_ico: bool,
 }
 
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            png_lossy: true,
+            png_quality: "50-80".to_string(),
+            oxipng: true,
+            png_interlace: PngInterlace::Keep,
+            adaptive: false,
+            svg_scale: 1.0,
+            svg_width: None,
+            svg_minify: false,
+            webp_method: 4,
+            format_map: HashMap::new(),
+            jpeg_smoothing: 0,
+            max_dimension: None,
+            cap_side: CapSide::Longest,
+            gradient_noise: 0.0,
+            avif_depth: 8,
+            lossless: false,
+            max_megapixels: None,
+            autorotate: true,
+            max_colors: None,
+            premultiply_alpha: false,
+            auto_contrast: false,
+            strip: vec![MetadataKind::All],
+            regen_thumbnail: false,
+            png_posterize: 0,
+            sharp_yuv: false,
+            webp_exact: false,
+            preserve_bit_depth: false,
+            jpeg_restart: 0,
+            jpeg_scan_mode: JpegScanMode::AllComponentsTogether,
+            avif_subsampling: AvifSubsampling::S420,
+            avif_tiles: None,
+            perceptual: false,
+            compress_embedded_thumb: false,
+            keep_colors: Vec::new(),
+            shrink_if_over: None,
+            png_bit_depth: None,
+            jpeg_arithmetic: false,
+            bit_depth_check: false,
+            strict: false,
+            auto_dither: false,
+            bilevel: None,
+            preset: None,
+            comment: None,
+            fit: None,
+            fit_background: [255, 255, 255, 255],
+            transparent_index_first: false,
+            jpeg_effort: JpegEffort::Balanced,
+            first_frame: false,
+            trim: None,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+        }
+    }
+}
+
+impl CompressionOptions {
+    /// Per-format qualities: the preset's, unless the range was overridden since (by a
+    /// `.compressrc`, `--quality-sweep` or `--total-budget`)
+    fn qualities(&self) -> FormatQualities {
+        match self.preset.map(QualityPreset::qualities) {
+            Some(preset) if preset.png_range() == self.png_quality => preset,
+            _ => FormatQualities::from_range(&self.png_quality),
+        }
+    }
+
+    /// Build compression options from CLI args for a given quality range
+    fn from_args(args: &Args, quality: &str) -> Self {
+        Self {
+            png_lossy: args.png_lossy && !args.encode_only && !args.lossless && args.preset != Some(QualityPreset::Lossless),
+            png_quality: quality.to_string(),
+            oxipng: args.oxipng,
+            png_interlace: args.png_interlace,
+            adaptive: args.adaptive,
+            svg_scale: args.svg_scale,
+            svg_width: args.svg_width,
+            svg_minify: args.svg_minify,
+            webp_method: args.webp_method,
+            format_map: args.map.clone().unwrap_or_default(),
+            jpeg_smoothing: args.jpeg_smoothing,
+            max_dimension: args.max_dimension,
+            cap_side: args.cap_side,
+            gradient_noise: args.gradient_noise,
+            avif_depth: args.avif_depth,
+            lossless: args.lossless || args.preset == Some(QualityPreset::Lossless),
+            max_megapixels: args.max_megapixels,
+            autorotate: args.autorotate,
+            max_colors: None,
+            premultiply_alpha: args.premultiply_alpha,
+            auto_contrast: args.auto_contrast,
+            strip: args.strip.clone(),
+            regen_thumbnail: args.regen_thumbnail,
+            png_posterize: args.png_posterize,
+            sharp_yuv: args.sharp_yuv,
+            webp_exact: args.webp_exact,
+            preserve_bit_depth: args.preserve_bit_depth,
+            jpeg_restart: args.jpeg_restart,
+            jpeg_scan_mode: args.jpeg_scan_mode,
+            avif_subsampling: args.avif_subsampling.unwrap_or(if args.avif_depth == 8 { AvifSubsampling::S420 } else { AvifSubsampling::S444 }),
+            avif_tiles: args.avif_tiles,
+            perceptual: args.perceptual,
+            compress_embedded_thumb: args.compress_embedded_thumb,
+            keep_colors: args.keep_colors.clone(),
+            shrink_if_over: args.shrink_if_over,
+            png_bit_depth: args.png_bit_depth,
+            jpeg_arithmetic: args.jpeg_arithmetic,
+            bit_depth_check: args.output_bit_depth_check,
+            strict: args.strict,
+            auto_dither: args.auto_dither,
+            bilevel: args.bilevel.then_some((args.bilevel_threshold, args.bilevel_dither)),
+            preset: args.preset,
+            comment: args.comment.clone(),
+            fit: args.fit,
+            fit_background: args.fit_background,
+            transparent_index_first: args.transparent_index_first,
+            jpeg_effort: args.jpeg_effort,
+            first_frame: args.first_frame,
+            trim: args.trim.then_some(args.trim_tolerance),
+            to_webp: args.to_webp,
+            to_avif: args.to_avif,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+        }
+    }
+}
+
 fn human_size(nbytes: u64) -> String {
     format_size(nbytes, DECIMAL)
 }
@@ -103,31 +989,164 @@ fn human_size(nbytes: u64) -> String {
 /// Parse "50-80" into (min,max) u8
 fn parse_quality_range(s: &str) -> (u8, u8) {
     let parts: Vec<_> = s.split('-').collect();
-    let min = parts.get(0).and_then(|p| p.parse::<u8>().ok()).unwrap_or(50);
+    let min = parts.first().and_then(|p| p.parse::<u8>().ok()).unwrap_or(50);
     let max = parts.get(1).and_then(|p| p.parse::<u8>().ok()).unwrap_or(80);
     (min, max)
 }
 
-/// Map compression level (low/mid/max) to quality range
+/// Map compression level (low/mid/max) to the PNG range of the matching preset
 fn compression_level_to_range(level: &str) -> String {
-    match level.to_lowercase().as_str() {
-        "low" => "70-90".to_string(),
-        "mid" => "50-80".to_string(),
-        "max" => "20-60".to_string(),
-        _ => "50-80".to_string(), // Default to mid
+    QualityPreset::from_level(level).qualities().png_range()
+}
+
+/// Read the interlace method from a PNG's IHDR chunk (false if not a PNG)
+fn png_is_interlaced(bytes: &[u8]) -> bool {
+    bytes.len() > 28 && bytes.starts_with(b"\x89PNG\r\n\x1a\n") && &bytes[12..16] == b"IHDR" && bytes[28] == 1
+}
+
+/// Resolve the interlacing oxipng should apply for a given source
+fn resolve_interlace(source: &[u8], interlace: PngInterlace) -> oxipng::Interlacing {
+    match interlace {
+        PngInterlace::On => oxipng::Interlacing::Adam7,
+        PngInterlace::Off => oxipng::Interlacing::None,
+        PngInterlace::Keep if png_is_interlaced(source) => oxipng::Interlacing::Adam7,
+        PngInterlace::Keep => oxipng::Interlacing::None,
+    }
+}
+
+/// Shannon entropy (bits) of the luminance histogram, 0..=8
+fn luma_entropy(img: &DynamicImage) -> f64 {
+    let luma = img.to_luma8();
+    let mut hist = [0u64; 256];
+    for p in luma.as_raw() {
+        hist[*p as usize] += 1;
+    }
+    let total = luma.as_raw().len().max(1) as f64;
+    hist.iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / total;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Number of distinct RGBA colors, counting stops once `limit` is exceeded
+fn count_colors(img: &DynamicImage, limit: usize) -> usize {
+    let mut seen = std::collections::HashSet::new();
+    for px in img.to_rgba8().pixels() {
+        seen.insert(px.0);
+        if seen.len() > limit {
+            break;
+        }
+    }
+    seen.len()
+}
+
+/// Pick (min_q, max_q, palette size) for an image within the user's quality range.
+/// Simple graphics get a palette just big enough for their colors and the low end of
+/// the range; photos and gradients keep a full palette and the upper end.
+fn choose_adaptive_quality(img: &DynamicImage, min_q: u8, max_q: u8) -> (u8, u8, u16) {
+    let colors = count_colors(img, 256);
+    let entropy = luma_entropy(img);
+    let mid_q = min_q + (max_q.saturating_sub(min_q)) / 2;
+
+    if colors <= 256 {
+        // Already palette-sized: keep every color, quality barely matters
+        let palette = colors.max(2).next_power_of_two() as u16;
+        (min_q, mid_q.max(min_q), palette)
+    } else if entropy < 5.0 {
+        // Flat illustration with anti-aliasing: trim the palette
+        (min_q, mid_q, 128)
+    } else {
+        // Photographic content needs the full palette
+        (mid_q, max_q, 256)
+    }
+}
+
+/// Dithering level for `--auto-dither`: full for photographic content, none for flat
+/// graphics. Photos have more colors than a palette holds and mostly small, non-zero steps
+/// between neighbours; flat graphics are runs of one color broken by hard edges.
+fn auto_dither_level(rgba: &image::RgbaImage) -> f32 {
+    if count_colors(&DynamicImage::ImageRgba8(rgba.clone()), 256) <= 256 {
+        return 0.0;
+    }
+    let diff = |a: &image::Rgba<u8>, b: &image::Rgba<u8>| (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0);
+    let (mut pairs, mut smooth) = (0u64, 0u64);
+    for row in rgba.rows() {
+        let row: Vec<_> = row.collect();
+        for pair in row.windows(2) {
+            pairs += 1;
+            smooth += matches!(diff(pair[0], pair[1]), 1..=24) as u64;
+        }
+    }
+    if pairs > 0 && smooth as f64 / pairs as f64 > 0.25 { 1.0 } else { 0.0 }
+}
+
+/// Deterministic triangular noise in [-1, 1] for pixel (x, y), channel c
+fn gradient_noise_at(x: u32, y: u32, c: u32) -> f32 {
+    let mut h = (x as u64) << 32 | (y as u64) << 2 | c as u64;
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^= h >> 31;
+    let a = (h & 0xffff) as f32 / 65535.0;
+    let b = ((h >> 16) & 0xffff) as f32 / 65535.0;
+    a + b - 1.0
+}
+
+/// Perturb pixels that sit on a smooth gradient (small but non-zero neighbour
+/// differences) so quantization dithers instead of banding. Flat areas and hard
+/// edges are left alone, which keeps the cost in file size low.
+fn apply_gradient_noise(rgba: &mut image::RgbaImage, strength: f32) {
+    // Neighbourhood radius: plateaus narrower than this still count as gradient
+    const RADIUS: u32 = 8;
+    const MAX_STEP: i32 = 24;
+    let src = rgba.clone();
+    let (w, h) = src.dimensions();
+    let diff = |a: [u8; 4], b: [u8; 4]| (0..3).map(|c| (a[c] as i32 - b[c] as i32).abs()).max().unwrap_or(0);
+    for y in 0..h {
+        for x in 0..w {
+            let p = src.get_pixel(x, y).0;
+            let around = [
+                src.get_pixel(x.saturating_sub(RADIUS), y).0,
+                src.get_pixel((x + RADIUS).min(w - 1), y).0,
+                src.get_pixel(x, y.saturating_sub(RADIUS)).0,
+                src.get_pixel(x, (y + RADIUS).min(h - 1)).0,
+            ];
+            let spread = around.iter().map(|&q| diff(p, q)).max().unwrap_or(0);
+            let edge = diff(p, src.get_pixel((x + 1).min(w - 1), y).0).max(diff(p, src.get_pixel(x, (y + 1).min(h - 1)).0));
+            if spread == 0 || spread > MAX_STEP || edge > MAX_STEP / 2 {
+                continue;
+            }
+            let out = rgba.get_pixel_mut(x, y);
+            for (c, v) in out.0.iter_mut().take(3).enumerate() {
+                let noisy = p[c] as f32 + gradient_noise_at(x, y, c as u32) * strength;
+                *v = noisy.round().clamp(0.0, 255.0) as u8;
+            }
+        }
     }
 }
 
 /// PNG: quantize via libimagequant + optional oxipng (lossless)
-fn compress_png_bytes(input: &[u8], quality_range: &str, run_oxipng: bool) -> Result<Vec<u8>> {
+///
+/// Interlacing is applied by oxipng; if oxipng is disabled but Adam7 is wanted,
+/// a fast oxipng pass still runs so the requested interlacing is honored.
+fn compress_png_bytes(input: &[u8], opts: &CompressionOptions) -> Result<Vec<u8>> {
+    if opts.lossless || (opts.preserve_bit_depth && png_bit_depth(input) == Some(16)) {
+        return lossless_png_bytes(input, opts.png_interlace);
+    }
+
     // Decode to RGBA8
-    let img = image::load_from_memory(input)?;
-    let rgba = img.to_rgba8();
+    let img = load_image(input)?;
+    let mut rgba = img.to_rgba8();
     let (w_u32, h_u32) = (rgba.width(), rgba.height());
     let (w, h) = (w_u32 as usize, h_u32 as usize);
+    if opts.gradient_noise > 0.0 {
+        apply_gradient_noise(&mut rgba, opts.gradient_noise);
+    }
 
     // parse quality
-    let (min_q, max_q) = parse_quality_range(quality_range);
+    let (mut min_q, mut max_q) = parse_quality_range(&opts.png_quality);
     
     // For max compression (20-60 range), use aggressive settings
     let is_max_compression = max_q <= 60;
@@ -142,6 +1161,22 @@ fn compress_png_bytes(input: &[u8], quality_range: &str, run_oxipng: bool) -> Re
     } else {
         attr.set_speed(3)?; // Balanced speed
     }
+    if let Some(colors) = opts.max_colors {
+        attr.set_max_colors(colors.clamp(2, 256))?;
+    }
+    if opts.png_posterize > 0 {
+        attr.set_min_posterization(opts.png_posterize.min(4))?;
+    }
+
+    // Adaptive mode picks palette size and quality from the image content
+    if opts.adaptive {
+        let (a_min, a_max, colors) = choose_adaptive_quality(&img, min_q, max_q);
+        (min_q, max_q) = (a_min, a_max);
+        attr.set_max_colors(colors as u32)?;
+    }
+    if let Some(bits) = opts.png_bit_depth {
+        attr.set_max_colors(attr.max_colors().min(1 << bits))?;
+    }
     
     attr.set_quality(min_q, max_q)?;
     
@@ -150,58 +1185,495 @@ fn compress_png_bytes(input: &[u8], quality_range: &str, run_oxipng: bool) -> Re
         .map(|chunk| rgb::RGBA::new(chunk[0], chunk[1], chunk[2], chunk[3]))
         .collect();
     
+    // gamma 0.0 = libimagequant's sRGB default; alpha is taken as given (straight,
+    // or already premultiplied by --premultiply-alpha)
     let mut img_liq = LiqImage::new(&attr, rgba_pixels.as_slice(), w, h, 0.0)?;
+    for &[r, g, b] in &opts.keep_colors {
+        img_liq.add_fixed_color(rgb::RGBA::new(r, g, b, 255))?;
+    }
     let mut res = attr.quantize(&mut img_liq)?;
-    res.set_dithering_level(1.0)?;
+    res.set_dithering_level(if opts.auto_dither { auto_dither_level(&rgba) } else { 1.0 })?;
 
     let (palette, pixels) = res.remapped(&mut img_liq)?;
-
-    // Encode as RGBA PNG by expanding palette indices.
-    let mut expanded = Vec::with_capacity(w * h * 4);
-    for idx in pixels.iter() {
-        let p = palette[*idx as usize];
-        expanded.push(p.r);
-        expanded.push(p.g);
-        expanded.push(p.b);
-        expanded.push(p.a);
-    }
-
-    let dyn_img = DynamicImage::ImageRgba8(
-        image::RgbaImage::from_raw(w_u32, h_u32, expanded)
-            .ok_or_else(|| anyhow!("failed to build indexed->rgba image"))?,
-    );
-
-    let mut cursor = Cursor::new(Vec::new());
-    dyn_img.write_to(&mut cursor, ImageFormat::Png)?;
-    let png_buf = cursor.into_inner();
+    let png_buf = encode_indexed_png(w_u32, h_u32, &palette, &pixels, opts.transparent_index_first)?;
 
     // Optional oxipng optimization (lossless)
-    if run_oxipng {
-        let mut opts = OxipngOptions::from_preset(6);
-        opts.strip = oxipng::StripChunks::Safe;
-        let optimized = optimize_from_memory(&png_buf, &opts)?;
+    let interlacing = resolve_interlace(input, opts.png_interlace);
+    if opts.oxipng || interlacing == oxipng::Interlacing::Adam7 {
+        let mut ox_opts = OxipngOptions::from_preset(if opts.oxipng { 6 } else { 0 });
+        ox_opts.strip = oxipng::StripChunks::Safe;
+        ox_opts.interlace = Some(interlacing);
+        if opts.transparent_index_first {
+            // oxipng would otherwise re-sort (or drop) the palette
+            ox_opts.palette_reduction = false;
+            ox_opts.color_type_reduction = false;
+        }
+        let optimized = optimize_from_memory(&png_buf, &ox_opts)?;
         return Ok(optimized);
     }
 
     Ok(png_buf)
 }
 
-/// JPEG: re-encode with mozjpeg
-fn compress_jpeg_bytes(input: &[u8], quality: u8) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
-    let rgb = img.to_rgb8();
-    let (w, h) = (rgb.width() as usize, rgb.height() as usize);
+/// Palette PNG straight from imagequant's output, like pngquant writes: translucent
+/// entries are moved to the front so `tRNS` only lists those, and few-color images get
+/// 1/2/4-bit packed indices. `transparent_first` additionally puts the most transparent
+/// entry at index 0.
+fn encode_indexed_png(width: u32, height: u32, palette: &[rgb::RGBA<u8>], indices: &[u8], transparent_first: bool) -> Result<Vec<u8>> {
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by_key(|&i| palette[i].a == 255);
+    if transparent_first
+        && let Some(pos) = (0..order.len()).filter(|&p| palette[order[p]].a < 255).min_by_key(|&p| palette[order[p]].a)
+    {
+        order[..=pos].rotate_right(1);
+    }
+    let mut remap = vec![0u8; palette.len()];
+    for (new, &old) in order.iter().enumerate() {
+        remap[old] = new as u8;
+    }
+    let rgb: Vec<u8> = order.iter().flat_map(|&i| [palette[i].r, palette[i].g, palette[i].b]).collect();
+    let trns: Vec<u8> = order.iter().map(|&i| palette[i].a).take_while(|&a| a != 255).collect();
 
-    let mut comp = Compress::new(ColorSpace::JCS_RGB);
-    comp.set_size(w, h);
-    comp.set_quality(quality as f32);
-    comp.set_progressive_mode();
+    let (bits, depth) = match palette.len() {
+        0..=2 => (1, png::BitDepth::One),
+        3..=4 => (2, png::BitDepth::Two),
+        5..=16 => (4, png::BitDepth::Four),
+        _ => (8, png::BitDepth::Eight),
+    };
+    let per_byte = 8 / bits;
+    let row_bytes = (width as usize).div_ceil(per_byte);
+    let mut packed = vec![0u8; row_bytes * height as usize];
+    for (row, line) in indices.chunks(width as usize).enumerate() {
+        for (x, &idx) in line.iter().enumerate() {
+            let shift = 8 - bits * (x % per_byte + 1);
+            packed[row * row_bytes + x / per_byte] |= remap[idx as usize] << shift;
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(depth);
+    encoder.set_palette(rgb);
+    if !trns.is_empty() {
+        encoder.set_trns(trns);
+    }
+    encoder.set_compression(png::Compression::High);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&packed)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// 1-bit grayscale PNG: pixels darker than `threshold` are black, optionally with
+/// Floyd-Steinberg error diffusion; alpha is flattened onto white first
+fn to_bilevel_png(input: &[u8], threshold: u8, dither: bool) -> Result<Vec<u8>> {
+    let img = load_image(input)?.to_luma_alpha8();
+    let (w, h) = (img.width() as usize, img.height() as usize);
+    let mut gray: Vec<f32> = img
+        .pixels()
+        .map(|p| {
+            let a = p[1] as f32 / 255.0;
+            p[0] as f32 * a + 255.0 * (1.0 - a)
+        })
+        .collect();
+
+    let row_bytes = w.div_ceil(8);
+    let mut packed = vec![0u8; row_bytes * h];
+    for y in 0..h {
+        for x in 0..w {
+            let old = gray[y * w + x];
+            let white = old >= threshold as f32;
+            if white {
+                packed[y * row_bytes + x / 8] |= 0x80 >> (x % 8);
+            }
+            if dither {
+                let err = old - if white { 255.0 } else { 0.0 };
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    if nx >= 0 && (nx as usize) < w && y + dy < h {
+                        gray[(y + dy) * w + nx as usize] += err * weight;
+                    }
+                };
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, w as u32, h as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    encoder.set_compression(png::Compression::High);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&packed)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// PNG: lossless oxipng pass over the original bytes (no decode/re-encode)
+fn optimize_png_lossless(input: &[u8], interlace: PngInterlace) -> Result<Vec<u8>> {
+    let mut ox_opts = OxipngOptions::from_preset(6);
+    ox_opts.strip = oxipng::StripChunks::Safe;
+    ox_opts.interlace = Some(resolve_interlace(input, interlace));
+    Ok(optimize_from_memory(input, &ox_opts)?)
+}
+
+/// Bit depth per sample from a PNG's IHDR
+fn png_bit_depth(png: &[u8]) -> Option<u8> {
+    png_chunks(png).first().filter(|(kind, _)| kind == b"IHDR").and_then(|(_, ihdr)| ihdr.get(8).copied())
+}
+
+/// PNG output without quantization: PNG sources keep their stream, others are re-encoded first
+fn lossless_png_bytes(input: &[u8], interlace: PngInterlace) -> Result<Vec<u8>> {
+    if input.starts_with(b"\x89PNG") {
+        return optimize_png_lossless(input, interlace);
+    }
+    let img = load_image(input)?;
+    let mut cursor = Cursor::new(Vec::new());
+    img.write_to(&mut cursor, ImageFormat::Png)?;
+    optimize_png_lossless(&cursor.into_inner(), interlace)
+}
+
+/// JPEG: lossless jpegtran-style rewrite of the original DCT coefficients with
+/// optimized Huffman tables (or arithmetic coding), keeping a baseline source baseline and
+/// laying a progressive one's scans out per `scan_mode` (plus restart markers every
+/// `restart_interval` MCUs when non-zero); pixels are never decoded
+fn optimize_jpeg_lossless(input: &[u8], restart_interval: u16, scan_mode: JpegScanMode, arithmetic: bool) -> Result<Vec<u8>> {
+    use mozjpeg_sys::*;
+    use std::os::raw::{c_int, c_ulong};
+
+    extern "C-unwind" fn unwind_error_exit(cinfo: &mut jpeg_common_struct) {
+        let code = unsafe { cinfo.err.as_ref() }.map_or(0, |err| err.msg_code);
+        std::panic::resume_unwind(Box::new(format!("libjpeg error code {}", code)));
+    }
+    extern "C-unwind" fn silence_message(_cinfo: &mut jpeg_common_struct, _level: c_int) {}
+
+    // Progressive SOF markers (SOF2 Huffman, SOF6/10/14 arithmetic and hierarchical)
+    let progressive = jpeg_segments(input).iter().any(|&(marker, _)| matches!(marker, 0xC2 | 0xC6 | 0xCA | 0xCE));
+
+    unsafe {
+        let mut err: jpeg_error_mgr = std::mem::zeroed();
+        jpeg_std_error(&mut err);
+        err.error_exit = Some(unwind_error_exit);
+        err.emit_message = Some(silence_message);
+        let mut dinfo: jpeg_decompress_struct = std::mem::zeroed();
+        let mut cinfo: jpeg_compress_struct = std::mem::zeroed();
+        dinfo.common.err = &mut err;
+        cinfo.common.err = &mut err;
+        let mut outbuf: *mut u8 = std::ptr::null_mut();
+        let mut outsize: c_ulong = 0;
+
+        // libjpeg reports fatal errors by unwinding out of error_exit
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            jpeg_create_decompress(&mut dinfo);
+            jpeg_create_compress(&mut cinfo);
+            jpeg_mem_src(&mut dinfo, input.as_ptr(), input.len() as c_ulong);
+            jpeg_read_header(&mut dinfo, 1);
+            let coefficients = jpeg_read_coefficients(&mut dinfo);
+            jpeg_copy_critical_parameters(&dinfo, &mut cinfo);
+            cinfo.optimize_coding = 1;
+            if arithmetic {
+                // Arithmetic coding adapts as it goes; libjpeg rejects the statistics passes
+                // Huffman optimization and scan search need
+                cinfo.arith_code = 1;
+                cinfo.optimize_coding = 0;
+                jpeg_c_set_bool_param(&mut cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, 0);
+            }
+            if progressive {
+                jpeg_c_set_int_param(&mut cinfo, J_INT_PARAM::JINT_DC_SCAN_OPT_MODE, scan_mode.mozjpeg() as c_int);
+                jpeg_simple_progression(&mut cinfo);
+            } else {
+                // mozjpeg's defaults (applied by jpeg_copy_critical_parameters) are progressive
+                jpeg_c_set_bool_param(&mut cinfo, J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS, 0);
+                cinfo.scan_info = std::ptr::null();
+                cinfo.num_scans = 0;
+            }
+            cinfo.restart_interval = restart_interval.into();
+            jpeg_mem_dest(&mut cinfo, &mut outbuf, &mut outsize);
+            jpeg_write_coefficients(&mut cinfo, coefficients);
+            jpeg_finish_compress(&mut cinfo);
+            jpeg_finish_decompress(&mut dinfo);
+        }));
+        jpeg_destroy_compress(&mut cinfo);
+        jpeg_destroy_decompress(&mut dinfo);
+
+        let output = (!outbuf.is_null()).then(|| std::slice::from_raw_parts(outbuf, outsize as usize).to_vec());
+        if !outbuf.is_null() {
+            libc::free(outbuf.cast());
+        }
+        if let Err(panic) = result {
+            let msg = panic.downcast_ref::<String>().cloned().unwrap_or_else(|| "libjpeg failed".to_string());
+            return Err(anyhow!("lossless JPEG optimization failed: {}", msg));
+        }
+        output.ok_or_else(|| anyhow!("lossless JPEG optimization produced no output"))
+    }
+}
+
+/// Allocation cap for every image decode, set once from --decode-memory-limit at startup
+/// (the decoders are called from too many places to thread it through each one)
+static DECODE_MEMORY_LIMIT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(512 * 1024 * 1024);
+
+/// Install `--decode-memory-limit`, if given, as the process-wide decode cap
+fn apply_decode_memory_limit(args: &Args) {
+    if let Some(limit) = args.decode_memory_limit {
+        DECODE_MEMORY_LIMIT.store(limit, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+fn decode_limits() -> image::Limits {
+    let mut limits = image::Limits::default();
+    limits.max_alloc = Some(DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed));
+    limits
+}
+
+/// `image::load_from_memory` under the decode memory limit
+fn load_image(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return decode_webp(bytes);
+    }
+    if is_avif(bytes) {
+        return decode_avif(bytes);
+    }
+    if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        return decode_gif(bytes);
+    }
+    let mut reader = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(decode_limits());
+    reader.decode()
+}
+
+/// Still WebP via libwebp (the `image` crate is built without its WebP decoder). Animated
+/// WebPs are refused rather than flattened to their first frame.
+fn decode_webp(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::error::{DecodingError, ImageFormatHint, LimitError, LimitErrorKind, UnsupportedError, UnsupportedErrorKind};
+    let undecodable = || image::ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::WebP), "invalid WebP data"));
+    let features = webp::BitstreamFeatures::new(bytes).ok_or_else(undecodable)?;
+    if features.has_animation() {
+        return Err(image::ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormat::WebP.into(),
+            UnsupportedErrorKind::GenericFeature(format!("animation ({} frames)", webp_frame_count(bytes))),
+        )));
+    }
+    let (w, h) = (features.width(), features.height());
+    if w as u64 * h as u64 * 4 > DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(image::ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory)));
+    }
+    let decoded = webp::Decoder::new(bytes).decode().ok_or_else(undecodable)?;
+    let img = if decoded.is_alpha() {
+        image::RgbaImage::from_raw(w, h, decoded.to_vec()).map(DynamicImage::ImageRgba8)
+    } else {
+        image::RgbImage::from_raw(w, h, decoded.to_vec()).map(DynamicImage::ImageRgb8)
+    };
+    img.ok_or_else(undecodable)
+}
+
+/// Still GIF via our own frame decoder (the `image` crate is built without GIF). Animated
+/// GIFs are refused, as with WebP.
+fn decode_gif(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::error::{DecodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+    let frames = gif_frame_count(bytes);
+    if frames > 1 {
+        return Err(image::ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+            ImageFormat::Gif.into(),
+            UnsupportedErrorKind::GenericFeature(format!("animation ({} frames)", frames)),
+        )));
+    }
+    first_frame(bytes)
+        .map(DynamicImage::ImageRgba8)
+        .map_err(|e| image::ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Gif), e.to_string())))
+}
+
+/// ISOBMFF `ftyp` box with an AVIF major brand (still `avif` or sequence `avis`)
+fn is_avif(bytes: &[u8]) -> bool {
+    bytes.get(4..8) == Some(b"ftyp") && matches!(bytes.get(8..12), Some(b"avif") | Some(b"avis"))
+}
+
+/// Top-level ISOBMFF boxes as (type, payload), stopping at the first truncated one
+fn isobmff_boxes(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut boxes = Vec::new();
+    let mut i = 0;
+    while i + 8 <= data.len() {
+        let size = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as u64;
+        let kind = [data[i + 4], data[i + 5], data[i + 6], data[i + 7]];
+        let (header, size) = match size {
+            0 => (8, (data.len() - i) as u64),
+            1 => match data.get(i + 8..i + 16) {
+                Some(large) => (16, u64::from_be_bytes(large.try_into().unwrap())),
+                None => break,
+            },
+            n => (8, n),
+        };
+        let Some(payload) = usize::try_from(size).ok().filter(|&s| s >= header).and_then(|s| data.get(i + header..i + s)) else {
+            break;
+        };
+        boxes.push((kind, payload));
+        i += header + payload.len();
+    }
+    boxes
+}
+
+/// Largest `ispe` (image spatial extents) in a HEIF/AVIF `meta` box; for grid images that's
+/// the full canvas rather than a tile
+fn heif_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    fn child<'a>(payload: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+        isobmff_boxes(payload).into_iter().find(|(k, _)| k == kind).map(|(_, p)| p)
+    }
+    if data.get(4..8) != Some(b"ftyp") {
+        return None;
+    }
+    let meta = child(data, b"meta")?;
+    let ipco = child(child(meta.get(4..)?, b"iprp")?, b"ipco")?;
+    isobmff_boxes(ipco)
+        .into_iter()
+        .filter(|(kind, _)| kind == b"ispe")
+        .filter_map(|(_, p)| Some((tiff_u32(p, true, 4)?, tiff_u32(p, true, 8)?)))
+        .max_by_key(|&(w, h)| w as u64 * h as u64)
+}
+
+/// Canvas size from a WebP's first chunk (VP8X, VP8L or VP8 frame header), so the first
+/// few dozen bytes are enough
+fn webp_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if data.get(..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WEBP") {
+        return None;
+    }
+    let payload = data.get(20..30)?;
+    let le = |b: &[u8]| b.iter().rev().fold(0u32, |acc, &byte| (acc << 8) | byte as u32);
+    match data.get(12..16)? {
+        b"VP8X" => Some((le(&payload[4..7]) + 1, le(&payload[7..10]) + 1)),
+        b"VP8L" if payload[0] == 0x2f => {
+            let bits = le(&payload[1..5]);
+            Some(((bits & 0x3fff) + 1, ((bits >> 14) & 0x3fff) + 1))
+        }
+        b"VP8 " if payload[3..6] == [0x9d, 0x01, 0x2a] => Some((le(&payload[6..8]) & 0x3fff, le(&payload[8..10]) & 0x3fff)),
+        _ => None,
+    }
+}
+
+/// GIF logical screen size; every frame is drawn onto a canvas this big
+fn gif_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    if !data.starts_with(b"GIF87a") && !data.starts_with(b"GIF89a") {
+        return None;
+    }
+    let u16_at = |i: usize| data.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    Some((u16_at(6)?, u16_at(8)?))
+}
+
+/// AVIF via libheif's primary image (the `image` crate is built without its dav1d decoder)
+#[cfg(feature = "avif-decode")]
+fn decode_avif(bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::error::{DecodingError, ImageFormatHint, LimitError, LimitErrorKind};
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+    let undecodable = |e: libheif_rs::HeifError| image::ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Avif), e));
+    let ctx = HeifContext::read_from_bytes(bytes).map_err(undecodable)?;
+    let handle = ctx.primary_image_handle().map_err(undecodable)?;
+    if handle.width() as u64 * handle.height() as u64 * 4 > DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(image::ImageError::Limits(LimitError::from_kind(LimitErrorKind::InsufficientMemory)));
+    }
+    let decoded = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None).map_err(undecodable)?;
+    let truncated = || image::ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Avif), "truncated AVIF image"));
+    let plane = decoded.planes().interleaved.ok_or_else(truncated)?;
+    // Rows are padded out to `stride`
+    let row = plane.width as usize * 4;
+    let rgba = plane.data.chunks(plane.stride).take(plane.height as usize).flat_map(|r| &r[..row]).copied().collect();
+    image::RgbaImage::from_raw(plane.width, plane.height, rgba).map(DynamicImage::ImageRgba8).ok_or_else(truncated)
+}
+
+#[cfg(not(feature = "avif-decode"))]
+fn decode_avif(_bytes: &[u8]) -> image::ImageResult<DynamicImage> {
+    use image::error::{ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+    Err(image::ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        ImageFormatHint::Exact(ImageFormat::Avif),
+        UnsupportedErrorKind::GenericFeature("AVIF decoding (rebuild with --features avif-decode)".to_string()),
+    )))
+}
+
+/// `image::load_from_memory_with_format` under the decode memory limit
+fn load_image_as(bytes: &[u8], format: ImageFormat) -> image::ImageResult<DynamicImage> {
+    let mut reader = image::ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(decode_limits());
+    reader.decode()
+}
+
+/// JPEG: re-encode with mozjpeg
+fn compress_jpeg_bytes(
+    input: &[u8],
+    quality: u8,
+    smoothing: u8,
+    restart_interval: u16,
+    scan_mode: JpegScanMode,
+    arithmetic: bool,
+    effort: JpegEffort,
+) -> Result<Vec<u8>> {
+    let rgb = match cmyk_jpeg_to_rgb(input) {
+        Some(rgb) => rgb?,
+        None => load_image(input)?.to_rgb8(),
+    };
+    let jpeg = encode_jpeg_rgb(rgb, quality, smoothing, effort)?;
+    // The mozjpeg wrapper can't set restart markers, the scan mode or arithmetic coding,
+    // so apply them in a lossless second pass
+    if restart_interval > 0 || scan_mode != JpegScanMode::AllComponentsTogether || arithmetic {
+        return optimize_jpeg_lossless(&jpeg, restart_interval, scan_mode, arithmetic);
+    }
+    Ok(jpeg)
+}
+
+/// Decode a four-component (CMYK/YCCK) JPEG to RGB; `None` for any other JPEG.
+/// Photoshop writes inverted CMYK and flags it with an Adobe APP14 segment, while plain
+/// CMYK stores ink amounts directly, so the inversion follows the marker.
+fn cmyk_jpeg_to_rgb(input: &[u8]) -> Option<Result<image::RgbImage>> {
+    let segments = jpeg_segments(input);
+    let (_, sof) = segments.iter().find(|(marker, _)| matches!(marker, 0xC0..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF))?;
+    if sof.get(5) != Some(&4) {
+        return None;
+    }
+    let adobe = segments.iter().any(|(marker, payload)| *marker == 0xEE && payload.starts_with(b"Adobe"));
+
+    let decode = || -> Result<image::RgbImage> {
+        // libjpeg turns YCCK into CMYK itself; only the ink convention is left to us
+        let mut dec = mozjpeg::Decompress::new_mem(input)?.to_colorspace(ColorSpace::JCS_CMYK)?;
+        let (w, h) = (dec.width() as u32, dec.height() as u32);
+        let pixels: Vec<[u8; 4]> = dec.read_scanlines()?;
+        dec.finish()?;
+        let rgb = pixels
+            .iter()
+            .flat_map(|&[c, m, y, k]| {
+                let [c, m, y, k] = if adobe { [c, m, y, k] } else { [255 - c, 255 - m, 255 - y, 255 - k] };
+                [c, m, y].map(|v| ((v as u32 * k as u32 + 127) / 255) as u8)
+            })
+            .collect();
+        image::RgbImage::from_raw(w, h, rgb).ok_or_else(|| anyhow!("truncated CMYK JPEG"))
+    };
+    Some(decode())
+}
+
+/// Encode decoded RGB pixels with mozjpeg
+fn encode_jpeg_rgb(rgb: image::RgbImage, quality: u8, smoothing: u8, effort: JpegEffort) -> Result<Vec<u8>> {
+    let (w, h) = (rgb.width() as usize, rgb.height() as usize);
+
+    let mut comp = Compress::new(ColorSpace::JCS_RGB);
+    // Both of these reapply libjpeg's defaults (quality included), so they must precede
+    // every other setting; the second also resets the DC scan mode itself, which is why
+    // --jpeg-scan-mode is applied in the lossless pass instead
+    if effort == JpegEffort::Fast {
+        comp.set_fastest_defaults();
+    }
     comp.set_scan_optimization_mode(ScanMode::AllComponentsTogether);
-    
-    // For max compression, enable additional optimization
-    if quality <= 60 {
+    comp.set_size(w, h);
+    comp.set_quality(quality as f32);
+    if smoothing > 0 {
+        comp.set_smoothing_factor(smoothing.min(100));
+    }
+
+    if effort != JpegEffort::Fast {
         comp.set_optimize_coding(true);
         comp.set_optimize_scans(true);
+        comp.set_use_scans_in_trellis(effort == JpegEffort::Best);
+        // Builds the scan script from the settings above
+        comp.set_progressive_mode();
     }
 
     let mut dest = Vec::new();
@@ -215,12 +1687,70 @@ fn compress_jpeg_bytes(input: &[u8], quality: u8) -> Result<Vec<u8>> {
     Ok(dest)
 }
 
-/// WebP via webp crate (lossy) 
-fn to_webp_bytes(input: &[u8], quality: f32) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
+/// 8x8 blocks whose luma deviates less than this are treated as flat by --perceptual
+const PERCEPTUAL_FLAT_STDDEV: f32 = 6.0;
+
+/// Luminance-masking pre-pass: 3x3 box blur inside flat 8x8 blocks (grain there costs bits
+/// nobody sees), then quality moved up to 5 up or down as detailed or flat blocks dominate
+fn perceptual_prepare(rgba: &image::RgbaImage, quality: f32) -> (image::RgbaImage, f32) {
+    let (w, h) = rgba.dimensions();
+    let luma = |x: u32, y: u32| {
+        let p = rgba.get_pixel(x, y);
+        0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32
+    };
+    let mut out = rgba.clone();
+    let (mut blocks, mut detailed) = (0u32, 0u32);
+    for by in (0..h).step_by(8) {
+        for bx in (0..w).step_by(8) {
+            let (x1, y1) = ((bx + 8).min(w), (by + 8).min(h));
+            let values: Vec<f32> = (by..y1).flat_map(|y| (bx..x1).map(move |x| (x, y))).map(|(x, y)| luma(x, y)).collect();
+            let mean = values.iter().sum::<f32>() / values.len() as f32;
+            let stddev = (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32).sqrt();
+            blocks += 1;
+            if stddev >= PERCEPTUAL_FLAT_STDDEV {
+                detailed += 1;
+                continue;
+            }
+            for y in by..y1 {
+                for x in bx..x1 {
+                    let mut sum = [0u32; 3];
+                    let mut n = 0;
+                    for ny in y.saturating_sub(1).max(by)..(y + 2).min(y1) {
+                        for nx in x.saturating_sub(1).max(bx)..(x + 2).min(x1) {
+                            let p = rgba.get_pixel(nx, ny);
+                            (0..3).for_each(|c| sum[c] += p[c] as u32);
+                            n += 1;
+                        }
+                    }
+                    let [r, g, b] = sum.map(|c| ((c + n / 2) / n) as u8);
+                    out.put_pixel(x, y, image::Rgba([r, g, b, rgba.get_pixel(x, y)[3]]));
+                }
+            }
+        }
+    }
+    let detail = detailed as f32 / blocks.max(1) as f32;
+    (out, (quality + 10.0 * (detail - 0.5)).clamp(1.0, 100.0))
+}
+
+/// WebP via webp crate (lossy, or lossless where `quality` is the effort);
+/// `method` trades speed for size (0..=6)
+fn to_webp_bytes(input: &[u8], quality: f32, method: u8, lossless: bool, sharp_yuv: bool, exact: bool, perceptual: bool) -> Result<Vec<u8>> {
+    let img = load_image(input)?;
     let rgba = img.to_rgba8();
+    let (rgba, quality) = if perceptual && !lossless { perceptual_prepare(&rgba, quality) } else { (rgba, quality) };
     let enc = WebpEncoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height());
-    let webp = enc.encode(quality); // 0..=100
+    let mut config = webp::WebPConfig::new().map_err(|_| anyhow!("failed to init WebP config"))?;
+    config.quality = quality; // 0..=100
+    config.method = method.min(6) as i32;
+    config.use_sharp_yuv = sharp_yuv as i32;
+    // Keep RGB under fully transparent pixels; always on for lossless
+    config.exact = (exact || lossless) as i32;
+    if lossless {
+        config.lossless = 1;
+    }
+    let webp = enc
+        .encode_advanced(&config)
+        .map_err(|e| anyhow!("WebP encoding failed: {:?}", e))?;
     Ok(webp.to_vec())
 }
 
@@ -228,7 +1758,7 @@ fn to_webp_bytes(input: &[u8], quality: f32) -> Result<Vec<u8>> {
 fn heic_to_jpeg_bytes(input: &[u8], quality: u8) -> Result<Vec<u8>> {
     // Try to decode as HEIC using image crate fallback
     // If image crate doesn't support HEIC, we'll get an error and handle gracefully
-    let img = image::load_from_memory(input)
+    let img = load_image(input)
         .map_err(|_| anyhow!("Unsupported HEIC format or corrupted file"))?;
         
     let rgb = img.to_rgb8();
@@ -236,18 +1766,21 @@ fn heic_to_jpeg_bytes(input: &[u8], quality: u8) -> Result<Vec<u8>> {
         let mut cursor = Cursor::new(Vec::new());
         DynamicImage::ImageRgb8(rgb).write_to(&mut cursor, ImageFormat::Jpeg)?;
         cursor.into_inner()
-    }, quality)
+    }, quality, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced)
 }
 
 /// Convert to PNG
-fn to_png_bytes(input: &[u8], quality_range: &str, use_oxipng: bool) -> Result<Vec<u8>> {
-    // Use PNG compression with quality settings
-    compress_png_bytes(input, quality_range, use_oxipng)
+fn to_png_bytes(input: &[u8], opts: &CompressionOptions) -> Result<Vec<u8>> {
+    // Use PNG compression with quality settings, unless quantization is off
+    if !opts.png_lossy {
+        return lossless_png_bytes(input, opts.png_interlace);
+    }
+    compress_png_bytes(input, opts)
 }
 
 /// Convert to TIFF
 fn to_tiff_bytes(input: &[u8]) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
+    let img = load_image(input)?;
     let mut cursor = Cursor::new(Vec::new());
     img.write_to(&mut cursor, ImageFormat::Tiff)?;
     Ok(cursor.into_inner())
@@ -255,7 +1788,7 @@ fn to_tiff_bytes(input: &[u8]) -> Result<Vec<u8>> {
 
 /// Convert to BMP
 fn to_bmp_bytes(input: &[u8]) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
+    let img = load_image(input)?;
     let mut cursor = Cursor::new(Vec::new());
     img.write_to(&mut cursor, ImageFormat::Bmp)?;
     Ok(cursor.into_inner())
@@ -263,7 +1796,7 @@ fn to_bmp_bytes(input: &[u8]) -> Result<Vec<u8>> {
 
 /// Convert to ICO (fallback to PNG if ICO not supported)
 fn to_ico_bytes(input: &[u8]) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
+    let img = load_image(input)?;
     // Resize to common icon size if needed
     let resized = if img.width() > 256 || img.height() > 256 {
         img.resize(256, 256, image::imageops::FilterType::Lanczos3)
@@ -284,13 +1817,33 @@ fn to_ico_bytes(input: &[u8]) -> Result<Vec<u8>> {
     }
 }
 
-/// AVIF via ravif crate (lossy)
-fn to_avif_bytes(input: &[u8], quality: f32) -> Result<Vec<u8>> {
-    let img = image::load_from_memory(input)?;
+/// AVIF via ravif crate (lossy); `perceptual` has no effect on 16-bit sources kept at 10 bits
+fn to_avif_bytes(
+    input: &[u8],
+    quality: f32,
+    depth: u8,
+    subsampling: AvifSubsampling,
+    tiles: Option<(usize, usize)>,
+    perceptual: bool,
+) -> Result<Vec<u8>> {
+    let img = load_image(input)?;
     let rgba = img.to_rgba8();
+    let (rgba, quality) = if perceptual { perceptual_prepare(&rgba, quality) } else { (rgba, quality) };
     let (w, h) = (img.width(), img.height());
     let speed = 6u8; // 0 best / slowest, 10 fastest
     let enc = AvifEncoder::new().with_quality(quality).with_speed(speed);
+    let enc = match depth {
+        // ravif only writes 4:4:4 with its own tiling, so anything else is encoded with rav1e directly
+        8 if subsampling != AvifSubsampling::S444 || tiles.is_some() => {
+            return encode_avif_subsampled(&rgba, quality, speed, subsampling, tiles.unwrap_or((0, 0)));
+        }
+        10 if tiles.is_some() => return Err(anyhow!("--avif-tiles is only supported for 8-bit AVIF")),
+        10 if subsampling != AvifSubsampling::S444 => return Err(anyhow!("--avif-subsampling 420/422 is only supported for 8-bit AVIF")),
+        8 => enc.with_bit_depth(ravif::BitDepth::Eight),
+        10 if is_high_bit_depth(&img) => return encode_avif_10bit(&img, &enc.with_bit_depth(ravif::BitDepth::Ten)),
+        10 => enc.with_bit_depth(ravif::BitDepth::Ten),
+        other => return Err(anyhow!("{}-bit AVIF is not supported by the encoder (use 8 or 10)", other)),
+    };
     
     // Convert to proper RGBA format
     let rgba_pixels: Vec<rgb::RGBA<u8>> = rgba.chunks_exact(4)
@@ -302,546 +1855,5445 @@ fn to_avif_bytes(input: &[u8], quality: f32) -> Result<Vec<u8>> {
     Ok(avif.avif_file)
 }
 
-/// In-process compress dispatcher
-fn compress_image_inproc(input_bytes: &[u8], ext_lower: &str, opts: &CompressionOptions) -> Result<(Vec<u8>, String)> {
-    // Handle HEIC files first (convert to JPEG like TinyPNG)
-    if ext_lower == "heic" || ext_lower == "heif" {
-        let bytes = heic_to_jpeg_bytes(input_bytes, 85)?; // High quality for HEIC conversion
-        return Ok((bytes, "image/jpeg".to_string()));
-    }
-    
-    // Parse quality range to determine compression level
-    let (min_q, max_q) = parse_quality_range(&opts.png_quality);
-    let webp_quality = ((min_q + max_q) / 2) as f32;
-    let jpeg_quality = (min_q + max_q) / 2;
-    let avif_quality = ((min_q + max_q) / 2) as f32;
-    
-    // If conversion requested, honor it next
-    if opts.to_webp {
-        let bytes = to_webp_bytes(input_bytes, webp_quality)?;
-        return Ok((bytes, "image/webp".to_string()));
-    }
-    if opts.to_avif {
-        let bytes = to_avif_bytes(input_bytes, avif_quality)?;
-        return Ok((bytes, "image/avif".to_string()));
-    }
-    if opts.to_jpeg {
-        let bytes = compress_jpeg_bytes(input_bytes, jpeg_quality)?;
-        return Ok((bytes, "image/jpeg".to_string()));
-    }
-    if opts.to_png {
-        let bytes = to_png_bytes(input_bytes, &opts.png_quality, opts.oxipng)?;
-        return Ok((bytes, "image/png".to_string()));
-    }
-    if opts.to_tiff {
-        let bytes = to_tiff_bytes(input_bytes)?;
-        return Ok((bytes, "image/tiff".to_string()));
-    }
-    if opts.to_bmp {
-        let bytes = to_bmp_bytes(input_bytes)?;
-        return Ok((bytes, "image/bmp".to_string()));
-    }
-    if opts.to_ico {
-        let bytes = to_ico_bytes(input_bytes)?;
-        return Ok((bytes, "image/x-icon".to_string()));
-    }
+/// Same curve ravif uses to turn quality 1-100 into an AV1 quantizer
+fn avif_quantizer(quality: f32) -> u8 {
+    let q = quality.clamp(1.0, 100.0) / 100.0;
+    let x = if q >= 0.85 { (1.0 - q) * 3.0 } else if q > 0.25 { 1.0 - 0.125 - q * 0.5 } else { 1.0 - q };
+    (x * 255.0).round() as u8
+}
 
-    match ext_lower {
-        "png" => {
-            if opts.png_lossy {
-                let bytes = compress_png_bytes(input_bytes, &opts.png_quality, opts.oxipng)?;
-                Ok((bytes, "image/png".into()))
-            } else {
-                // lossless re-encode
-                let img = image::load_from_memory(input_bytes)?;
-                let mut cursor = Cursor::new(Vec::new());
-                img.write_to(&mut cursor, ImageFormat::Png)?;
-                let buf = cursor.into_inner();
-                Ok((buf, "image/png".into()))
-            }
-        }
-        "jpg" | "jpeg" => {
-            let bytes = compress_jpeg_bytes(input_bytes, 75)?;
-            Ok((bytes, "image/jpeg".into()))
-        }
-        // Other formats → PNG by default
-        _ => {
-            let bytes = compress_png_bytes(input_bytes, &opts.png_quality, opts.oxipng)?;
-            Ok((bytes, "image/png".into()))
+/// One AV1 still frame from 8-bit planes (Y plus optional Cb/Cr at the chroma resolution),
+/// split into `(cols, rows)` tiles ((0, 0) for a single tile)
+fn encode_av1_still(
+    planes: &[(Vec<u8>, usize)],
+    (width, height): (usize, usize),
+    (tile_cols, tile_rows): (usize, usize),
+    chroma_sampling: rav1e::prelude::ChromaSampling,
+    quantizer: u8,
+    speed: u8,
+    color_description: Option<rav1e::prelude::ColorDescription>,
+) -> Result<Vec<u8>> {
+    use rav1e::prelude::*;
+    let enc = EncoderConfig {
+        width,
+        height,
+        bit_depth: 8,
+        chroma_sampling,
+        pixel_range: PixelRange::Full,
+        color_description,
+        still_picture: true,
+        quantizer: quantizer as usize,
+        min_quantizer: quantizer,
+        speed_settings: SpeedSettings::from_preset(speed),
+        tile_cols,
+        tile_rows,
+        ..Default::default()
+    };
+    let mut ctx: Context<u8> = Config::new()
+        .with_encoder_config(enc)
+        .new_context()
+        .map_err(|e| anyhow!("AV1 encoder setup failed: {}", e))?;
+    let mut frame = ctx.new_frame();
+    for (plane, (data, stride)) in frame.planes.iter_mut().zip(planes) {
+        plane.copy_from_raw_u8(data, *stride, 1);
+    }
+    ctx.send_frame(frame).map_err(|e| anyhow!("AV1 encoding failed: {}", e))?;
+    ctx.flush();
+    let mut out = Vec::new();
+    loop {
+        match ctx.receive_packet() {
+            Ok(mut packet) => out.append(&mut packet.data),
+            Err(EncoderStatus::Encoded) => continue,
+            Err(EncoderStatus::LimitReached) => break,
+            Err(e) => return Err(anyhow!("AV1 encoding failed: {}", e)),
         }
     }
+    Ok(out)
 }
 
-fn discover_files(input_path: &Path) -> Vec<PathBuf> {
-    if input_path.is_file() {
-        if let Some(ext) = input_path.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()) {
-            if SUPPORTED_EXTS.contains(&ext.as_str()) {
-                return vec![input_path.to_path_buf()];
-            }
-        }
-        return vec![];
-    }
+/// ravif's default alpha quality; it isn't tied to the color quality
+const AVIF_ALPHA_QUALITY: f32 = 80.0;
 
-    let mut files = vec![];
-    for entry in WalkDir::new(input_path).into_iter().filter_map(Result::ok) {
-        let p = entry.path();
-        if p.is_file() {
-            if let Some(ext) = p.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()) {
-                if SUPPORTED_EXTS.contains(&ext.as_str()) {
-                    files.push(p.to_path_buf());
+/// Give fully transparent pixels the alpha-weighted average of their visible neighbors
+/// (or the image's mean visible color), like ravif's `UnassociatedClean`: hidden color
+/// then costs almost nothing and doesn't bleed into edges, least of all in subsampled chroma
+fn clean_transparent_color(rgba: &image::RgbaImage) -> image::RgbaImage {
+    let visible: Vec<_> = rgba.pixels().filter(|p| p[3] > 0).collect();
+    let Some(n) = std::num::NonZeroUsize::new(visible.len()) else {
+        return rgba.clone();
+    };
+    let mean = [0, 1, 2].map(|c| (visible.iter().map(|p| p[c] as usize).sum::<usize>() / n) as u8);
+    let (w, h) = rgba.dimensions();
+    image::RgbaImage::from_fn(w, h, |x, y| {
+        let p = *rgba.get_pixel(x, y);
+        if p[3] > 0 {
+            return p;
+        }
+        let (mut sum, mut weight) = ([0u32; 3], 0u32);
+        for ny in y.saturating_sub(1)..(y + 2).min(h) {
+            for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                let q = rgba.get_pixel(nx, ny);
+                for c in 0..3 {
+                    sum[c] += q[c] as u32 * q[3] as u32;
                 }
+                weight += q[3] as u32;
             }
         }
-    }
-    files.sort();
-    files.dedup();
-    files
+        let [r, g, b] = if weight == 0 { mean } else { sum.map(|s| (s / weight) as u8) };
+        image::Rgba([r, g, b, 0])
+    })
 }
 
-fn build_output_path(
-    src: &Path,
-    output_dir: &Option<PathBuf>,
-    overwrite: bool,
-    target_ext: Option<&str>,
-) -> PathBuf {
-    let base = if overwrite {
-        src.with_file_name(format!(
-            "c_{}",
-            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
-        ))
-    } else if let Some(out) = output_dir {
-        let _ = fs::create_dir_all(out);
-        out.join(format!(
-            "c_{}",
-            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
-        ))
-    } else {
-        src.with_file_name(format!(
-            "c_{}",
-            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
-        ))
+/// 8-bit YCbCr AVIF with box-averaged chroma, optional alpha and explicit tiling. Same
+/// full-range BT.601 matrix, alpha quality and transparent-color cleanup as ravif, which
+/// only writes 4:4:4 and picks its own tiles
+fn encode_avif_subsampled(
+    rgba: &image::RgbaImage,
+    quality: f32,
+    speed: u8,
+    subsampling: AvifSubsampling,
+    tiles: (usize, usize),
+) -> Result<Vec<u8>> {
+    use rav1e::prelude::{ChromaSampling, ColorDescription, ColorPrimaries, MatrixCoefficients, TransferCharacteristics};
+    let (w, h) = (rgba.width() as usize, rgba.height() as usize);
+    let (sx, sy) = match subsampling {
+        AvifSubsampling::S420 => (2, 2),
+        AvifSubsampling::S422 => (2, 1),
+        AvifSubsampling::S444 => (1, 1),
     };
+    let (cw, ch) = (w.div_ceil(sx), h.div_ceil(sy));
+    let rgba = &clean_transparent_color(rgba);
 
-    if let Some(ext) = target_ext {
-        base.with_extension(ext)
+    let mut luma = vec![0u8; w * h];
+    let mut chroma = [vec![0f32; cw * ch], vec![0f32; cw * ch]];
+    let mut counts = vec![0f32; cw * ch];
+    for (x, y, p) in rgba.enumerate_pixels() {
+        let [r, g, b] = [p[0], p[1], p[2]].map(f32::from);
+        let luma_f = 0.299 * r + 0.587 * g + 0.114 * b;
+        let (x, y) = (x as usize, y as usize);
+        luma[y * w + x] = luma_f.round().clamp(0.0, 255.0) as u8;
+        let at = (y / sy) * cw + x / sx;
+        chroma[0][at] += 128.0 + (b - luma_f) * 0.5 / (1.0 - 0.114);
+        chroma[1][at] += 128.0 + (r - luma_f) * 0.5 / (1.0 - 0.299);
+        counts[at] += 1.0;
+    }
+    let [cb, cr] = chroma.map(|c| c.iter().zip(&counts).map(|(sum, n)| (sum / n).round().clamp(0.0, 255.0) as u8).collect::<Vec<u8>>());
+
+    let quantizer = avif_quantizer(quality);
+    let sampling = match subsampling {
+        AvifSubsampling::S420 => ChromaSampling::Cs420,
+        AvifSubsampling::S422 => ChromaSampling::Cs422,
+        AvifSubsampling::S444 => ChromaSampling::Cs444,
+    };
+    let color = Some(ColorDescription {
+        transfer_characteristics: TransferCharacteristics::SRGB,
+        color_primaries: ColorPrimaries::BT709,
+        matrix_coefficients: MatrixCoefficients::BT601,
+    });
+    let color_av1 = encode_av1_still(&[(luma, w), (cb, cw), (cr, cw)], (w, h), tiles, sampling, quantizer, speed, color)?;
+    let alpha_av1 = if rgba.pixels().any(|p| p[3] != 255) {
+        let alpha: Vec<u8> = rgba.pixels().map(|p| p[3]).collect();
+        let alpha_quantizer = avif_quantizer(AVIF_ALPHA_QUALITY);
+        Some(encode_av1_still(&[(alpha, w)], (w, h), tiles, ChromaSampling::Cs400, alpha_quantizer, speed, None)?)
     } else {
-        base
+        None
+    };
+
+    let mut avif = avif_serialize::Aviffy::new();
+    avif.set_matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+        .set_full_color_range(true)
+        .set_chroma_subsampling((sx == 2, sy == 2))
+        // 8-bit 4:2:0 is Main profile, 4:4:4 High and 4:2:2 Professional
+        .set_seq_profile(match subsampling {
+            AvifSubsampling::S420 => 0,
+            AvifSubsampling::S444 => 1,
+            AvifSubsampling::S422 => 2,
+        });
+    Ok(avif.to_vec(&color_av1, alpha_av1.as_deref(), w as u32, h as u32, 8))
+}
+
+/// Lossless AVIF: quantizer 0 on untransformed 8-bit RGB planes with untouched alpha
+fn to_avif_lossless_bytes(input: &[u8]) -> Result<Vec<u8>> {
+    let img = load_image(input)?;
+    let rgba = img.to_rgba8();
+    let enc = AvifEncoder::new()
+        .with_quality(100.0)
+        .with_alpha_quality(100.0)
+        .with_speed(6)
+        .with_bit_depth(ravif::BitDepth::Eight)
+        .with_internal_color_model(ravif::ColorModel::RGB)
+        .with_alpha_color_mode(ravif::AlphaColorMode::UnassociatedDirty);
+    let pixels: Vec<rgb::RGBA<u8>> = rgba.chunks_exact(4)
+        .map(|c| rgb::RGBA::new(c[0], c[1], c[2], c[3]))
+        .collect();
+    let avif = enc.encode_rgba(ravif::Img::new(pixels.as_slice(), rgba.width() as usize, rgba.height() as usize))?;
+    Ok(avif.avif_file)
+}
+
+/// Accept the AVIF bit depths the encoder can write (rav1e has no 12-bit output)
+fn parse_avif_depth(s: &str) -> std::result::Result<u8, String> {
+    match s.trim() {
+        "8" => Ok(8),
+        "10" => Ok(10),
+        other => Err(format!("invalid AVIF depth \"{}\" (expected 8 or 10)", other)),
     }
 }
 
-// Web server handlers
-async fn serve_index() -> Html<&'static str> {
-    Html(INDEX_HTML)
+/// Sources with more than 8 bits per channel
+fn is_high_bit_depth(img: &DynamicImage) -> bool {
+    use image::ColorType::*;
+    matches!(img.color(), L16 | La16 | Rgb16 | Rgba16 | Rgb32F | Rgba32F)
+}
+
+/// Bits per channel of a decoded color type
+fn channel_bits(color: image::ColorType) -> u16 {
+    color.bits_per_pixel() / color.channel_count() as u16
 }
 
-async fn compress_api(mut multipart: Multipart) -> Result<Response, StatusCode> {
-    let mut file_bytes = Vec::new();
-    let mut filename = String::new();
-    // Default: webp output, mid compression, lossy PNG with oxipng
-    let mut opts = CompressionOptions {
-        png_lossy: true,
-        png_quality: "50-80".to_string(),
-        oxipng: true,
-        to_webp: true, // Default to WebP
-        to_avif: false,
-        to_jpeg: false,
-        to_png: false,
-        to_tiff: false,
-        to_bmp: false,
-        to_ico: false,
+/// The precision warning for writing `input` as `output`, if the output keeps fewer bits per
+/// channel than the source had
+fn depth_reduction(input: &[u8], output: &[u8], mime: &str, opts: &CompressionOptions) -> Option<String> {
+    let source = channel_bits(load_image(input).ok()?.color());
+    let written = match mime {
+        "image/avif" if opts.avif_depth > 8 && !opts.lossless => opts.avif_depth as u16,
+        "image/jpeg" | "image/webp" | "image/avif" => 8,
+        _ => channel_bits(load_image(output).ok()?.color()),
     };
+    (written < source).then(|| format!("{}-bit source written as {}-bit {}: precision is lost", source, written, mime))
+}
 
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
-        let field_name = field.name().unwrap_or("").to_string();
-        
-        match field_name.as_str() {
-            "file" => {
-                filename = field.file_name().unwrap_or("image").to_string();
-                file_bytes = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec();
-            }
-            "compression_lvl" => {
-                // Primary parameter: low, mid, or max (with optional granular control)
-                let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                opts.png_quality = compression_level_to_range(&value);
+/// Encode full-precision pixels as 10-bit BT.601 YCbCr AVIF (no 8-bit round trip)
+fn encode_avif_10bit(img: &DynamicImage, enc: &AvifEncoder) -> Result<Vec<u8>> {
+    let rgba = img.to_rgba16();
+    let (w, h) = (rgba.width() as usize, rgba.height() as usize);
+    let to_ten = |v: f32| v.round().clamp(0.0, 1023.0) as u16;
+    let planes: Vec<[u16; 3]> = rgba
+        .pixels()
+        .map(|p| {
+            let [r, g, b] = [p[0], p[1], p[2]].map(|c| c as f32 * 1023.0 / 65535.0);
+            let y = 0.299 * r + 0.587 * g + 0.114 * b;
+            let cb = 512.0 + (b - y) * 0.5 / (1.0 - 0.114);
+            let cr = 512.0 + (r - y) * 0.5 / (1.0 - 0.299);
+            [to_ten(y), to_ten(cb), to_ten(cr)]
+        })
+        .collect();
+    let alpha = img
+        .color()
+        .has_alpha()
+        .then(|| rgba.pixels().map(|p| p[3] >> 6).collect::<Vec<u16>>());
+    let avif = enc.encode_raw_planes_10_bit(
+        w,
+        h,
+        planes,
+        alpha,
+        rav1e::prelude::PixelRange::Full,
+        ravif::MatrixCoefficients::BT601,
+    )?;
+    Ok(avif.avif_file)
+}
+
+/// Parse an SVG length attribute ("120", "120px", "1.5e2"); relative units yield None
+fn parse_svg_length(value: &str) -> Option<f32> {
+    let v = value.trim();
+    let v = v.strip_suffix("px").unwrap_or(v);
+    v.parse::<f32>().ok().filter(|n| *n > 0.0)
+}
+
+/// Read an attribute value from the root `<svg ...>` tag
+fn svg_root_attr<'a>(svg: &'a str, name: &str) -> Option<&'a str> {
+    let start = svg.find("<svg")?;
+    let tag = &svg[start..start + svg[start..].find('>')?];
+    let mut rest = tag;
+    while let Some(pos) = rest.find(name) {
+        let before_ok = rest[..pos].ends_with(|c: char| c.is_whitespace());
+        let after = rest[pos + name.len()..].trim_start();
+        if before_ok && let Some(after_eq) = after.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let quote = after_eq.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &after_eq[1..];
+                return value.find(quote).map(|end| &value[..end]);
             }
-            "media_url" => {
-                // Fetch remote image from URL
-                let url = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                
-                // Fetch the image
-                let response = reqwest::get(&url).await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                if !response.status().is_success() {
-                    log::error!("❌ API: Failed to fetch image from URL: {}", url);
-                    return Err(StatusCode::BAD_REQUEST);
-                }
-                
-                file_bytes = response.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?.to_vec();
-                
-                // Try to extract filename from URL
-                if let Ok(parsed_url) = url::Url::parse(&url) {
-                    if let Some(segment) = parsed_url.path_segments().and_then(|segments| segments.last()) {
-                        filename = segment.to_string();
-                    }
-                }
-                
-                if filename.is_empty() {
-                    filename = "image".to_string();
+        }
+        rest = &rest[pos + name.len()..];
+    }
+    None
+}
+
+/// Intrinsic (width, height) of an SVG from its width/height attributes or viewBox
+fn svg_intrinsic_size(data: &[u8]) -> Option<(f32, f32)> {
+    let svg = std::str::from_utf8(data).ok()?;
+    let width = svg_root_attr(svg, "width").and_then(parse_svg_length);
+    let height = svg_root_attr(svg, "height").and_then(parse_svg_length);
+    let view_box = svg_root_attr(svg, "viewBox").and_then(|vb| {
+        let nums: Vec<f32> = vb
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|p| !p.is_empty())
+            .filter_map(|p| p.parse().ok())
+            .collect();
+        (nums.len() == 4 && nums[2] > 0.0 && nums[3] > 0.0).then(|| (nums[2], nums[3]))
+    });
+    match (width, height, view_box) {
+        (Some(w), Some(h), _) => Some((w, h)),
+        (Some(w), None, Some((vw, vh))) => Some((w, w * vh / vw)),
+        (None, Some(h), Some((vw, vh))) => Some((h * vw / vh, h)),
+        (_, _, Some(vb)) => Some(vb),
+        _ => None,
+    }
+}
+
+/// Output pixel size for an SVG: explicit width wins, otherwise intrinsic size * scale
+fn svg_output_size(data: &[u8], scale: f32, width: Option<u32>) -> Result<(u32, u32)> {
+    match (svg_intrinsic_size(data), width) {
+        (Some((iw, ih)), Some(w)) => Ok((w, ((w as f32) * ih / iw).round().max(1.0) as u32)),
+        (None, Some(w)) => Ok((w, w)),
+        (Some((iw, ih)), None) => Ok(((iw * scale).round().max(1.0) as u32, (ih * scale).round().max(1.0) as u32)),
+        (None, None) => Err(anyhow!("SVG has no intrinsic size; pass an explicit --svg-width")),
+    }
+}
+
+/// Shrink SVG markup without touching the drawing: drop comments, whitespace-only text
+/// between tags (outside `<text>`) and runs of whitespace inside tags. CDATA is kept as is
+fn minify_svg(data: &[u8]) -> Result<Vec<u8>> {
+    let svg = std::str::from_utf8(data).map_err(|_| anyhow!("SVG is not valid UTF-8"))?;
+    let mut out = String::with_capacity(svg.len());
+    let mut text_depth = 0usize;
+    let mut rest = svg;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("<!--") {
+            rest = after.find("-->").map_or("", |end| &after[end + 3..]);
+        } else if rest.starts_with("<![CDATA[") {
+            let end = rest.find("]]>").map_or(rest.len(), |end| end + 3);
+            out.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if rest.starts_with('<') {
+            // Copy the tag, collapsing whitespace runs (quoted or not) to one space
+            let mut quote = None;
+            let mut end = rest.len();
+            let mut tag = String::new();
+            for (i, c) in rest.char_indices() {
+                match (quote, c) {
+                    (None, '>') => {
+                        end = i + 1;
+                        break;
+                    }
+                    (None, '"' | '\'') => quote = Some(c),
+                    (Some(q), _) if c == q => quote = None,
+                    _ => {}
                 }
-            }
-            "output_format" => {
-                let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                // Reset format flags
-                opts.to_webp = false;
-                opts.to_avif = false;
-                opts.to_jpeg = false;
-                opts.to_png = false;
-                opts.to_tiff = false;
-                opts.to_bmp = false;
-                opts.to_ico = false;
-                
-                match value.as_str() {
-                    "webp" => opts.to_webp = true,
-                    "avif" => opts.to_avif = true,
-                    "jpeg" => opts.to_jpeg = true,
-                    "png" => opts.to_png = true,
-                    "tiff" => opts.to_tiff = true,
-                    "bmp" => opts.to_bmp = true,
-                    "ico" => opts.to_ico = true,
-                    "original" => {} // keep original format
-                    _ => {} // default to webp (already set)
+                if c.is_whitespace() {
+                    let redundant = tag.ends_with(' ') || (quote.is_none() && tag.ends_with('='));
+                    if !redundant {
+                        tag.push(' ');
+                    }
+                } else {
+                    if quote.is_none() && matches!(c, '/' | '=') && tag.ends_with(' ') {
+                        tag.pop();
+                    }
+                    tag.push(c);
                 }
             }
-            "oxipng" => {
-                let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                opts.oxipng = value == "true";
+            let tag = tag.trim_end().to_string() + ">";
+            if tag.starts_with("<text") && !tag.ends_with("/>") {
+                text_depth += 1;
+            } else if tag.starts_with("</text") {
+                text_depth = text_depth.saturating_sub(1);
             }
-            "png_lossy" => {
-                let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-                opts.png_lossy = value == "true";
+            out.push_str(&tag);
+            rest = &rest[end..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let text = &rest[..end];
+            if text_depth > 0 || !text.trim().is_empty() {
+                out.push_str(text);
             }
-            _ => {}
+            rest = &rest[end..];
         }
     }
+    Ok(out.into_bytes())
+}
+
+/// Whether an SVG input stays vector: `--svg-minify` without any raster conversion
+fn svg_passthrough(opts: &CompressionOptions) -> bool {
+    opts.svg_minify
+        && !(opts.to_webp || opts.to_avif || opts.to_jpeg || opts.to_png || opts.to_tiff || opts.to_bmp || opts.to_ico)
+}
 
-    if file_bytes.is_empty() {
-        log::error!("❌ API: No file data received");
-        return Err(StatusCode::BAD_REQUEST);
+/// Rasterize an SVG to an `out_w` x `out_h` RGBA image via resvg
+#[cfg(feature = "svg")]
+fn rasterize_svg(data: &[u8], out_w: u32, out_h: u32) -> Result<DynamicImage> {
+    use resvg::{tiny_skia, usvg};
+
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())
+        .map_err(|e| anyhow!("invalid SVG: {}", e))?;
+    let size = tree.size();
+    let mut pixmap = tiny_skia::Pixmap::new(out_w, out_h)
+        .ok_or_else(|| anyhow!("invalid SVG output size {}x{}", out_w, out_h))?;
+    let transform = tiny_skia::Transform::from_scale(out_w as f32 / size.width(), out_h as f32 / size.height());
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied alpha; the encoders expect straight alpha
+    let mut rgba = Vec::with_capacity((out_w * out_h * 4) as usize);
+    for px in pixmap.pixels() {
+        let c = px.demultiply();
+        rgba.extend_from_slice(&[c.red(), c.green(), c.blue(), c.alpha()]);
     }
+    let img = image::RgbaImage::from_raw(out_w, out_h, rgba)
+        .ok_or_else(|| anyhow!("failed to build rasterized SVG image"))?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
 
-    // Detect file extension
-    let ext = filename.split('.').last().unwrap_or("").to_lowercase();
-    log::info!("🔍 API: Processing {} file: {} ({} bytes)", ext.to_uppercase(), filename, file_bytes.len());
-    
-    // Compress the image
-    let start_time = std::time::Instant::now();
-    let (compressed_bytes, mime_type) = compress_image_inproc(&file_bytes, &ext, &opts)
-        .map_err(|e| {
-            log::error!("❌ API: Compression failed for {}: {:?}", filename, e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
-    
-    let duration = start_time.elapsed();
-    let compression_ratio = (1.0 - (compressed_bytes.len() as f64 / file_bytes.len() as f64)) * 100.0;
-    log::info!("✅ API: Compressed {} in {:?} - {} -> {} bytes ({:.1}% reduction)", 
-               filename, duration, file_bytes.len(), compressed_bytes.len(), compression_ratio);
-
-    // Determine output filename
-    let output_filename = if opts.to_webp {
-        filename.replace(&format!(".{}", ext), ".webp")
-    } else if opts.to_avif {
-        filename.replace(&format!(".{}", ext), ".avif")
-    } else if opts.to_jpeg {
-        filename.replace(&format!(".{}", ext), ".jpg")
-    } else if opts.to_png {
-        filename.replace(&format!(".{}", ext), ".png")
-    } else if opts.to_tiff {
-        filename.replace(&format!(".{}", ext), ".tiff")
-    } else if opts.to_bmp {
-        filename.replace(&format!(".{}", ext), ".bmp")
-    } else if opts.to_ico {
-        filename.replace(&format!(".{}", ext), ".ico")
-    } else if ext == "heic" || ext == "heif" {
-        // HEIC files are automatically converted to JPEG
-        filename.replace(&format!(".{}", ext), ".jpg")
-    } else {
-        format!("c_{}", filename)
+#[cfg(not(feature = "svg"))]
+fn rasterize_svg(_data: &[u8], _out_w: u32, _out_h: u32) -> Result<DynamicImage> {
+    Err(anyhow!("SVG support not compiled in (rebuild with --features svg)"))
+}
+
+/// Target size when the capped side exceeds `cap`, preserving aspect ratio (never upscales)
+fn capped_dimensions(width: u32, height: u32, cap: u32, side: CapSide) -> Option<(u32, u32)> {
+    let capped = match side {
+        CapSide::Longest => width.max(height),
+        CapSide::Shortest => width.min(height),
     };
+    if capped <= cap {
+        return None;
+    }
+    let scale = cap as f64 / capped as f64;
+    let fit = |v: u32| ((v as f64 * scale).round() as u32).max(1);
+    Some((fit(width), fit(height)))
+}
 
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, mime_type)
-        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", output_filename))
-        .body(compressed_bytes.into())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Dimensions scaled by sqrt(target / current) so the pixel count fits `megapixels`;
+/// None when the image is already small enough
+fn megapixel_dimensions(width: u32, height: u32, megapixels: f64) -> Option<(u32, u32)> {
+    let current = width as f64 * height as f64;
+    let target = megapixels * 1_000_000.0;
+    if current <= target {
+        return None;
+    }
+    let scale = (target / current).sqrt();
+    let fit = |v: u32| ((v as f64 * scale).floor() as u32).max(1);
+    Some((fit(width), fit(height)))
+}
 
-    Ok(response)
+fn parse_png_bit_depth(s: &str) -> std::result::Result<u8, String> {
+    match s.trim().parse::<u8>() {
+        Ok(bits @ (1 | 2 | 4 | 8)) => Ok(bits),
+        _ => Err(format!("invalid PNG bit depth \"{}\" (expected 1, 2, 4 or 8)", s)),
+    }
 }
 
-async fn start_web_server(port: u16) -> Result<()> {
-    let app = Router::new()
-        .route("/", get(serve_index))
-        .route("/api/compress", post(compress_api))
-        .layer(
-            ServiceBuilder::new()
-                .layer(CorsLayer::permissive())
-        );
+/// "#RRGGBB" (the `#` is optional) as an RGB triple
+fn parse_hex_color(s: &str) -> std::result::Result<[u8; 3], String> {
+    let hex = s.trim().trim_start_matches('#');
+    let channel = |i: usize| hex.get(i..i + 2).and_then(|c| u8::from_str_radix(c, 16).ok());
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok([r, g, b]),
+        _ => Err(format!("invalid color \"{}\" (expected #RRGGBB)", s)),
+    }
+}
 
-    let addr = format!("127.0.0.1:{}", port);
-    let listener = TcpListener::bind(&addr).await
-        .map_err(|e| anyhow!("Failed to bind to {}: {}", addr, e))?;
-        
-    println!("🚀 Web UI running at http://localhost:{}", port);
-    
-    // Auto-open browser
-    if webbrowser::open(&format!("http://localhost:{}", port)).is_err() {
-        println!("💡 Open http://localhost:{} in your browser", port);
+fn parse_megapixels(s: &str) -> std::result::Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(mp) if mp.is_finite() && mp > 0.0 => Ok(mp),
+        _ => Err(format!("invalid megapixel target \"{}\" (expected a positive number)", s)),
     }
-    
-    axum::serve(listener, app)
-        .await
-        .map_err(|e| anyhow!("Server error: {}", e))?;
-        
-    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// "COLSxROWS" AVIF tiling; AV1 allows power-of-two counts up to 64 each way
+fn parse_avif_tiles(s: &str) -> std::result::Result<(usize, usize), String> {
+    let count = |v: &str| v.trim().parse::<usize>().ok().filter(|n| n.is_power_of_two() && *n <= 64);
+    s.split_once(['x', 'X'])
+        .and_then(|(cols, rows)| Some((count(cols)?, count(rows)?)))
+        .ok_or_else(|| format!("invalid AVIF tiling \"{}\" (expected COLSxROWS with powers of two up to 64, e.g. 2x2)", s))
+}
 
-    // Auto-detect mode: web if no input provided or --web flag
-    if args.web || args.input.is_none() {
-        return start_web_server(args.port).await;
+fn parse_fit(s: &str) -> std::result::Result<(u32, u32), String> {
+    let side = |v: &str| v.trim().parse::<u32>().ok().filter(|n| *n > 0);
+    s.split_once(['x', 'X'])
+        .and_then(|(w, h)| Some((side(w)?, side(h)?)))
+        .ok_or_else(|| format!("invalid fit box \"{}\" (expected WIDTHxHEIGHT, e.g. 512x512)", s))
+}
+
+fn parse_fit_background(s: &str) -> std::result::Result<[u8; 4], String> {
+    if s.trim().eq_ignore_ascii_case("transparent") {
+        return Ok([0, 0, 0, 0]);
     }
+    parse_hex_color(s).map(|[r, g, b]| [r, g, b, 255])
+}
 
-    // CLI mode
-    run_cli_mode(&args).await
+/// Bounding box (x, y, width, height) of what differs from the border color, the top-left
+/// pixel's, by more than `tolerance` in any channel; fully transparent pixels all count as
+/// border on a transparent border. `None` when there's no border, or nothing but border
+fn trim_bounds(img: &image::RgbaImage, tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let border = *img.get_pixel_checked(0, 0)?;
+    let is_border = |p: &image::Rgba<u8>| {
+        (border[3] == 0 && p[3] == 0) || p.0.iter().zip(border.0).all(|(&c, b)| c.abs_diff(b) <= tolerance)
+    };
+    let (w, h) = img.dimensions();
+    let (mut x0, mut y0, mut x1, mut y1) = (w, h, 0, 0);
+    for (x, y, p) in img.enumerate_pixels() {
+        if !is_border(p) {
+            (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x), y1.max(y));
+        }
+    }
+    if x0 > x1 {
+        return None;
+    }
+    let bounds = (x0, y0, x1 - x0 + 1, y1 - y0 + 1);
+    (bounds != (0, 0, w, h)).then_some(bounds)
 }
 
-async fn run_cli_mode(args: &Args) -> Result<()> {
-    let jobs = args.jobs.unwrap_or_else(|| num_cpus::get());
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(jobs)
-        .build_global()
-        .ok();
+/// `img` scaled to fit inside `width`x`height` and centered on a canvas of exactly that size
+fn fit_to_canvas(img: &DynamicImage, width: u32, height: u32, background: [u8; 4]) -> image::RgbaImage {
+    let scale = (width as f64 / img.width() as f64).min(height as f64 / img.height() as f64);
+    let w = ((img.width() as f64 * scale).round() as u32).clamp(1, width);
+    let h = ((img.height() as f64 * scale).round() as u32).clamp(1, height);
+    let resized = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3).to_rgba8();
+    let mut canvas = image::RgbaImage::from_pixel(width, height, image::Rgba(background));
+    image::imageops::overlay(&mut canvas, &resized, ((width - w) / 2) as i64, ((height - h) / 2) as i64);
+    canvas
+}
 
-    // Ensure input present
-    if args.input.is_none() {
-        return Err(anyhow!("No input provided for CLI mode"));
+fn parse_fps(s: &str) -> std::result::Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(fps) if fps.is_finite() && fps > 0.0 && fps <= 100.0 => Ok(fps),
+        _ => Err(format!("invalid frame rate \"{}\" (expected a number above 0, at most 100)", s)),
     }
-    let input_path = args.input.as_ref().unwrap().canonicalize()?;
-    if !input_path.exists() {
-        return Err(anyhow!("Input path does not exist: {}", input_path.display()));
+}
+
+/// Marker segments before the first scan of a JPEG: (marker, payload)
+fn jpeg_segments(jpeg: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = Vec::new();
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return segments;
+    }
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            break; // image data starts; metadata comes before it
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let Some(payload) = jpeg.get(pos + 4..pos + 2 + len) else {
+            break;
+        };
+        segments.push((marker, payload));
+        pos += 2 + len;
     }
+    segments
+}
 
-    let output_dir = args
-        .output
-        .as_ref()
-        .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()));
+/// EXIF Orientation (tag 0x0112) from a JPEG's APP1 segment, if present
+fn exif_orientation(jpeg: &[u8]) -> Option<u16> {
+    let tiff = jpeg_segments(jpeg)
+        .into_iter()
+        .find_map(|(marker, payload)| (marker == 0xE1).then(|| payload.strip_prefix(EXIF_JPEG_SIG)).flatten())?;
+    let be = tiff_big_endian(tiff)?;
+    let entry = tiff_ifd_entry(tiff, be, tiff_u32(tiff, be, 4)? as usize, 0x0112)?;
+    tiff_u16(tiff, be, entry + 8)
+}
 
-    let files = discover_files(&input_path);
-    if files.is_empty() {
-        eprintln!("No supported image files found.");
-        return Ok(());
+/// Byte order of a TIFF/EXIF block ("MM" big endian, "II" little endian)
+fn tiff_big_endian(tiff: &[u8]) -> Option<bool> {
+    match tiff.get(..2)? {
+        b"MM" => Some(true),
+        b"II" => Some(false),
+        _ => None,
     }
+}
 
-    // Determine quality from compression level
-    let quality = compression_level_to_range(&args.compression_lvl);
+fn tiff_u16(tiff: &[u8], be: bool, at: usize) -> Option<u16> {
+    let b = tiff.get(at..at + 2)?;
+    Some(if be { u16::from_be_bytes([b[0], b[1]]) } else { u16::from_le_bytes([b[0], b[1]]) })
+}
 
-    let results: Vec<_> = files
-        .par_iter()
-        .map(|f| {
-            let fname = f.to_path_buf();
-            let before = fs::metadata(&fname).map(|m| m.len()).unwrap_or(0);
-
-            // load file
-            let mut input_bytes = Vec::new();
-            if let Err(e) = fs::File::open(&fname).and_then(|mut r| r.read_to_end(&mut input_bytes)) {
-                return (fname, before, 0u64, false, format!("read-failed: {}", e));
-            }
-
-            let ext = fname
-                .extension()
-                .and_then(OsStr::to_str)
-                .map(|s| s.to_lowercase())
-                .unwrap_or_default();
-
-            // Determine output extension if conversion requested
-            let mut target_ext: Option<&str> = None;
-            if args.to_webp {
-                target_ext = Some("webp");
-            } else if args.to_avif {
-                target_ext = Some("avif");
-            }
-
-            // Compute output path
-            let mut out_path = build_output_path(&fname, &output_dir, args.overwrite, target_ext);
-
-            // Create compression options from CLI args
-            let opts = CompressionOptions {
-                png_lossy: args.png_lossy,
-                png_quality: quality.clone(),
-                oxipng: args.oxipng,
-                to_webp: args.to_webp,
-                to_avif: args.to_avif,
-                to_jpeg: false,
-                to_png: false,
-                to_tiff: false,
-                to_bmp: false,
-                to_ico: false,
-            };
+fn tiff_u32(tiff: &[u8], be: bool, at: usize) -> Option<u32> {
+    let b = tiff.get(at..at + 4)?;
+    let b = [b[0], b[1], b[2], b[3]];
+    Some(if be { u32::from_be_bytes(b) } else { u32::from_le_bytes(b) })
+}
 
-            // Compress in-process
-            let result = compress_image_inproc(&input_bytes, &ext, &opts);
-            let (out_bytes, _mime) = match result {
-                Ok((b, m)) => (b, m),
-                Err(e) => return (fname, before, 0u64, false, format!("compress-failed: {}", e)),
-            };
+fn tiff_put(tiff: &mut [u8], be: bool, at: usize, value: u32, width: usize) {
+    let bytes = if be { value.to_be_bytes() } else { value.to_le_bytes() };
+    let bytes = if be { &bytes[4 - width..] } else { &bytes[..width] };
+    if let Some(dst) = tiff.get_mut(at..at + width) {
+        dst.copy_from_slice(bytes);
+    }
+}
 
-            // If no explicit target_ext and we converted non-png to png as fallback, update ext to png
-            if target_ext.is_none() {
-                if !["png", "jpg", "jpeg"].contains(&ext.as_str()) {
-                    out_path.set_extension("png");
-                }
-            }
+/// Offset of the 12-byte entry for `tag` in the IFD at `ifd`
+fn tiff_ifd_entry(tiff: &[u8], be: bool, ifd: usize, tag: u16) -> Option<usize> {
+    let count = tiff_u16(tiff, be, ifd)? as usize;
+    (0..count).map(|i| ifd + 2 + i * 12).find(|&entry| tiff_u16(tiff, be, entry) == Some(tag))
+}
 
-            // Write to out_path
-            if let Some(parent) = out_path.parent() {
-                let _ = fs::create_dir_all(parent);
-            }
-            if let Err(e) = fs::File::create(&out_path).and_then(|mut w| w.write_all(&out_bytes)) {
-                return (fname, before, 0u64, false, format!("write-failed: {}", e));
-            }
+/// Zero an IFD's entries (and their out-of-line values) and mark it empty
+fn tiff_clear_ifd(tiff: &mut [u8], be: bool, ifd: usize) {
+    let Some(count) = tiff_u16(tiff, be, ifd) else {
+        return;
+    };
+    for i in 0..count as usize {
+        let entry = ifd + 2 + i * 12;
+        let (Some(kind), Some(n)) = (tiff_u16(tiff, be, entry + 2), tiff_u32(tiff, be, entry + 4)) else {
+            continue;
+        };
+        let unit = match kind {
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => 1,
+        };
+        let size = unit * n as usize;
+        if size > 4 && let Some(offset) = tiff_u32(tiff, be, entry + 8) {
+            let end = (offset as usize).saturating_add(size).min(tiff.len());
+            tiff.get_mut(offset as usize..end).into_iter().for_each(|v| v.fill(0));
+        }
+    }
+    let end = (ifd + 2 + count as usize * 12 + 4).min(tiff.len());
+    tiff.get_mut(ifd..end).into_iter().for_each(|v| v.fill(0));
+}
 
-            // Overwrite semantics
-            let mut final_path = out_path.clone();
-            if args.overwrite {
-                let backup = fname.with_extension(format!(
-                    "{}{}",
-                    fname.extension().and_then(OsStr::to_str).unwrap_or(""),
-                    ".bak"
-                ));
-                if let Err(e) = fs::rename(&fname, &backup) {
-                    return (fname, before, 0u64, false, format!("backup-failed: {}", e));
-                }
-                if let Err(e) = fs::rename(&out_path, &fname) {
-                    let _ = fs::rename(&backup, &fname);
-                    return (fname, before, 0u64, false, format!("overwrite-failed: {}", e));
-                }
-                let _ = fs::remove_file(&backup);
-                final_path = fname.clone();
-            }
+/// Empty the GPS IFD so no location data survives in the EXIF block
+fn scrub_exif_gps(exif: &mut [u8]) {
+    let Some(be) = tiff_big_endian(exif) else { return };
+    let gps = tiff_u32(exif, be, 4)
+        .and_then(|ifd0| tiff_ifd_entry(exif, be, ifd0 as usize, 0x8825))
+        .and_then(|entry| tiff_u32(exif, be, entry + 8));
+    if let Some(gps) = gps.filter(|&g| g != 0) {
+        tiff_clear_ifd(exif, be, gps as usize);
+    }
+}
+
+/// Drop the embedded preview (IFD1 and its JPEG bytes) from an EXIF block
+fn scrub_exif_thumbnail(exif: &mut [u8]) {
+    let Some(be) = tiff_big_endian(exif) else { return };
+    let Some(ifd0) = tiff_u32(exif, be, 4).map(|o| o as usize) else { return };
+    let Some(count) = tiff_u16(exif, be, ifd0) else { return };
+    let next_ptr = ifd0 + 2 + count as usize * 12;
+    let Some(ifd1) = tiff_u32(exif, be, next_ptr).filter(|&o| o != 0).map(|o| o as usize) else { return };
+    let thumb_at = tiff_ifd_entry(exif, be, ifd1, 0x0201).and_then(|e| tiff_u32(exif, be, e + 8));
+    let thumb_len = tiff_ifd_entry(exif, be, ifd1, 0x0202).and_then(|e| tiff_u32(exif, be, e + 8));
+    if let (Some(at), Some(len)) = (thumb_at, thumb_len) {
+        let end = (at as usize).saturating_add(len as usize).min(exif.len());
+        exif.get_mut(at as usize..end).into_iter().for_each(|v| v.fill(0));
+    }
+    tiff_clear_ifd(exif, be, ifd1);
+    tiff_put(exif, be, next_ptr, 0, 4);
+}
 
-            let after = fs::metadata(&final_path).map(|m| m.len()).unwrap_or(0);
-            (fname, before, after, true, String::new())
+/// Whether an EXIF block still carries an IFD1 preview
+fn exif_has_thumbnail(exif: &[u8]) -> bool {
+    let Some(be) = tiff_big_endian(exif) else { return false };
+    tiff_u32(exif, be, 4)
+        .and_then(|ifd0| {
+            let count = tiff_u16(exif, be, ifd0 as usize)?;
+            tiff_u32(exif, be, ifd0 as usize + 2 + count as usize * 12)
         })
-        .collect();
+        .is_some_and(|ifd1| ifd1 != 0)
+}
 
-    let mut total_before: u64 = 0;
-    let mut total_after: u64 = 0;
-    let mut processed: usize = 0;
+/// (offset, length) of the IFD1 preview JPEG inside an EXIF block
+fn exif_thumbnail_span(exif: &[u8]) -> Option<(usize, usize)> {
+    let be = tiff_big_endian(exif)?;
+    let ifd0 = tiff_u32(exif, be, 4)? as usize;
+    let count = tiff_u16(exif, be, ifd0)? as usize;
+    let ifd1 = tiff_u32(exif, be, ifd0 + 2 + count * 12).filter(|&o| o != 0)? as usize;
+    let at = tiff_u32(exif, be, tiff_ifd_entry(exif, be, ifd1, 0x0201)? + 8)? as usize;
+    let len = tiff_u32(exif, be, tiff_ifd_entry(exif, be, ifd1, 0x0202)? + 8)? as usize;
+    (at.checked_add(len)? <= exif.len()).then_some((at, len))
+}
 
-    for (name, before, after, ok, msg) in &results {
-        if !*ok {
-            eprintln!("{}: failed ({})", name.display(), msg);
-        } else {
-            let saved = before.saturating_sub(*after);
-            let pct = if *before > 0 {
-                (saved as f64) / (*before as f64) * 100.0
-            } else {
-                0.0
-            };
-            println!(
-                "{}: {} → {} (saved {} / {:.2}%)",
-                name.file_name().and_then(OsStr::to_str).unwrap_or("file"),
-                human_size(*before),
-                human_size(*after),
-                human_size(saved),
-                pct
-            );
+/// Quality `--compress-embedded-thumb` re-encodes EXIF previews at; they are only ever shown small
+const EMBEDDED_THUMB_QUALITY: u8 = 40;
+
+/// Re-encode the EXIF preview in place at low quality; the block shrinks when the preview
+/// sits at its end (as cameras write it), otherwise the freed tail is zeroed
+fn compress_exif_thumbnail(exif: &mut Vec<u8>) {
+    let Some((at, len)) = exif_thumbnail_span(exif) else { return };
+    let Ok(thumb) = load_image(&exif[at..at + len]) else { return };
+    let Ok(jpeg) = encode_jpeg_rgb(thumb.to_rgb8(), EMBEDDED_THUMB_QUALITY, 0, JpegEffort::Balanced) else { return };
+    if jpeg.len() >= len {
+        return;
+    }
+    let Some(be) = tiff_big_endian(exif) else { return };
+    let Some(len_entry) = tiff_u32(exif, be, 4).and_then(|ifd0| {
+        let count = tiff_u16(exif, be, ifd0 as usize)?;
+        let ifd1 = tiff_u32(exif, be, ifd0 as usize + 2 + count as usize * 12)?;
+        tiff_ifd_entry(exif, be, ifd1 as usize, 0x0202)
+    }) else {
+        return;
+    };
+    exif[at..at + jpeg.len()].copy_from_slice(&jpeg);
+    if at + len == exif.len() {
+        exif.truncate(at + jpeg.len());
+    } else {
+        exif[at + jpeg.len()..at + len].fill(0);
+    }
+    tiff_put(exif, be, len_entry + 8, jpeg.len() as u32, 4);
+}
+
+/// Replace the EXIF preview with `jpeg`, appended as a fresh IFD1 at the end of the block
+fn set_exif_thumbnail(exif: &mut Vec<u8>, jpeg: &[u8]) {
+    scrub_exif_thumbnail(exif);
+    let Some(be) = tiff_big_endian(exif) else { return };
+    let Some(ifd0) = tiff_u32(exif, be, 4).map(|o| o as usize) else { return };
+    let Some(count) = tiff_u16(exif, be, ifd0) else { return };
+    let next_ptr = ifd0 + 2 + count as usize * 12;
+    if next_ptr + 4 > exif.len() {
+        return;
+    }
+    exif.resize(exif.len().next_multiple_of(2), 0);
+    let ifd1 = exif.len();
+    // Compression = 6 (JPEG), then the preview's offset and length
+    let entries = [(0x0103, 3, 6), (0x0201, 4, (ifd1 + 42) as u32), (0x0202, 4, jpeg.len() as u32)];
+    let mut ifd = vec![0u8; 42];
+    tiff_put(&mut ifd, be, 0, entries.len() as u32, 2);
+    for (i, (tag, kind, value)) in entries.into_iter().enumerate() {
+        let e = 2 + i * 12;
+        tiff_put(&mut ifd, be, e, tag, 2);
+        tiff_put(&mut ifd, be, e + 2, kind, 2);
+        tiff_put(&mut ifd, be, e + 4, 1, 4);
+        tiff_put(&mut ifd, be, e + 8, value, if kind == 3 { 2 } else { 4 });
+    }
+    exif.extend_from_slice(&ifd);
+    exif.extend_from_slice(jpeg);
+    tiff_put(exif, be, next_ptr, ifd1 as u32, 4);
+}
+
+/// A JPEG preview of an encoded output, at most 160px on a side, for `--regen-thumbnail`
+fn exif_thumbnail_jpeg(output: &[u8], quality: u8) -> Option<Vec<u8>> {
+    let img = load_image(output).ok()?;
+    let img = if img.width().max(img.height()) > 160 { img.thumbnail(160, 160) } else { img };
+    encode_jpeg_rgb(img.to_rgb8(), quality, 0, JpegEffort::Balanced).ok()
+}
+
+/// Keep a carried-over EXIF preview in step with the output: rebuilt or dropped once the size
+/// changed, and re-encoded at low quality when `compress` is set
+fn normalize_exif_thumbnail(exif: &mut Vec<u8>, input: &[u8], output: &[u8], regen: bool, compress: bool) {
+    if !exif_has_thumbnail(exif) {
+        return;
+    }
+    if regen {
+        // Previews that would push EXIF past a single APP1 segment are dropped instead
+        let quality = if compress { EMBEDDED_THUMB_QUALITY } else { 75 };
+        match exif_thumbnail_jpeg(output, quality).filter(|jpeg| exif.len() + jpeg.len() + 44 < 0xFFF0) {
+            Some(jpeg) => set_exif_thumbnail(exif, &jpeg),
+            None => scrub_exif_thumbnail(exif),
         }
-        total_before = total_before.saturating_add(*before);
-        total_after = total_after.saturating_add(*after);
-        if *ok {
-            processed += 1;
+    } else if output_dimensions(output) != output_dimensions(input) {
+        scrub_exif_thumbnail(exif);
+    } else if compress {
+        compress_exif_thumbnail(exif);
+    }
+}
+
+/// Mark pixels as upright (orientation 1) after they were rotated physically
+fn reset_exif_orientation(exif: &mut [u8]) {
+    let Some(be) = tiff_big_endian(exif) else { return };
+    if let Some(entry) = tiff_u32(exif, be, 4).and_then(|ifd0| tiff_ifd_entry(exif, be, ifd0 as usize, 0x0112)) {
+        tiff_put(exif, be, entry + 8, 1, 2);
+    }
+}
+
+/// The EXIF orientation `--autorotate` will bake into this input's pixels, if any.
+/// JPEG only: HEIF keeps orientation in `irot`/`imir` boxes, which this tree can't decode
+fn autorotation(input: &[u8], ext_lower: &str, opts: &CompressionOptions) -> Option<u16> {
+    if !opts.autorotate || opts.lossless || !matches!(ext_lower, "jpg" | "jpeg") {
+        return None;
+    }
+    exif_orientation(input).filter(|o| (2..=8).contains(o))
+}
+
+/// Physically apply an EXIF orientation (1-8) to decoded pixels
+fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Scale each pixel's RGB by its alpha (straight -> premultiplied), rounding to nearest
+fn premultiply_alpha(img: &mut image::RgbaImage) {
+    for p in img.pixels_mut() {
+        let a = p[3] as u32;
+        for c in &mut p.0[..3] {
+            *c = ((*c as u32 * a + 127) / 255) as u8;
         }
     }
+}
 
-    if processed > 0 {
-        let total_saved = total_before.saturating_sub(total_after);
-        let pct_total = if total_before > 0 {
-            (total_saved as f64) / (total_before as f64) * 100.0
-        } else {
-            0.0
-        };
-        println!(
-            "\nProcessed {} files. Total saved: {} ({:.2}%)",
-            processed,
-            human_size(total_saved),
-            pct_total
-        );
-    } else {
-        eprintln!("No files compressed.");
+/// Linearly stretch RGB so the 0.5th..99.5th luminance percentiles span 0..=255
+fn auto_contrast(img: &mut image::RgbaImage) {
+    let mut histogram = [0u64; 256];
+    for p in img.pixels() {
+        let luma = (299 * p[0] as u32 + 587 * p[1] as u32 + 114 * p[2] as u32) / 1000;
+        histogram[luma as usize] += 1;
+    }
+    let total: u64 = histogram.iter().sum();
+    let clip = total / 200;
+    let first_past_clip = |levels: &mut dyn Iterator<Item = usize>| {
+        let mut seen = 0;
+        for v in levels {
+            seen += histogram[v];
+            if seen > clip {
+                return Some(v);
+            }
+        }
+        None
+    };
+    let (Some(lo), Some(hi)) = (first_past_clip(&mut (0..256)), first_past_clip(&mut (0..256).rev())) else {
+        return;
+    };
+    if hi <= lo || (lo == 0 && hi == 255) {
+        return;
+    }
+    let scale = 255.0 / (hi - lo) as f32;
+    for p in img.pixels_mut() {
+        for c in &mut p.0[..3] {
+            *c = ((*c as f32 - lo as f32) * scale).round().clamp(0.0, 255.0) as u8;
+        }
     }
+}
 
-    Ok(())
+const EXIF_JPEG_SIG: &[u8] = b"Exif\0\0";
+const XMP_JPEG_SIG: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ICC_JPEG_SIG: &[u8] = b"ICC_PROFILE\0";
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Metadata categories `--strip` can remove
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum MetadataKind {
+    Exif,
+    Icc,
+    Xmp,
+    Gps,
+    Thumbnail,
+    All,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Metadata blocks carried from a source image to its output
+#[derive(Debug, Default, Clone, PartialEq)]
+struct ImageMetadata {
+    icc: Option<Vec<u8>>,
+    /// TIFF-structured EXIF, without the JPEG "Exif\0\0" prefix
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+}
 
-    fn create_test_png() -> Vec<u8> {
-        // Create a simple 100x100 red PNG image
-        let img = image::ImageBuffer::from_fn(100, 100, |_, _| {
-            image::Rgb([255, 0, 0]) // Red pixel
-        });
-        let dynamic_img = DynamicImage::ImageRgb8(img);
-        let mut bytes = Vec::new();
-        dynamic_img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
-        bytes
+impl ImageMetadata {
+    /// Drop the categories listed in `strip`; GPS and thumbnails are scrubbed inside EXIF
+    fn without(mut self, strip: &[MetadataKind]) -> Self {
+        let strips = |kind| strip.contains(&kind) || strip.contains(&MetadataKind::All);
+        if strips(MetadataKind::Icc) {
+            self.icc = None;
+        }
+        if strips(MetadataKind::Xmp) {
+            self.xmp = None;
+        }
+        if strips(MetadataKind::Exif) {
+            self.exif = None;
+        }
+        if let Some(exif) = &mut self.exif {
+            if strips(MetadataKind::Gps) {
+                scrub_exif_gps(exif);
+            }
+            if strips(MetadataKind::Thumbnail) {
+                scrub_exif_thumbnail(exif);
+            }
+        }
+        self
     }
+}
 
-    fn create_test_jpeg() -> Vec<u8> {
-        // Create a simple 100x100 blue JPEG image
-        let img = image::ImageBuffer::from_fn(100, 100, |_, _| {
-            image::Rgb([0, 0, 255]) // Blue pixel
-        });
-        let dynamic_img = DynamicImage::ImageRgb8(img);
-        let mut bytes = Vec::new();
-        dynamic_img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg).unwrap();
-        bytes
+/// Chunks of a PNG stream: (type, data)
+fn png_chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    let mut i = 8;
+    while i + 12 <= png.len() {
+        let len = u32::from_be_bytes([png[i], png[i + 1], png[i + 2], png[i + 3]]) as usize;
+        let Some(data) = png.get(i + 8..i + 8 + len) else {
+            break;
+        };
+        chunks.push(([png[i + 4], png[i + 5], png[i + 6], png[i + 7]], data));
+        i += 12 + len;
     }
+    chunks
+}
 
-    #[test]
-    fn test_png_compression() {
-        let png_data = create_test_png();
-        let opts = CompressionOptions {
-            png_lossy: true,
-            png_quality: "50-80".to_string(),
-            oxipng: true,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+/// RIFF chunks of a WebP file: (fourcc, data)
+fn webp_chunks(webp: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    if webp.len() < 12 || &webp[..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return chunks;
+    }
+    let mut i = 12;
+    while i + 8 <= webp.len() {
+        let len = u32::from_le_bytes([webp[i + 4], webp[i + 5], webp[i + 6], webp[i + 7]]) as usize;
+        let Some(data) = webp.get(i + 8..i + 8 + len) else {
+            break;
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts);
-        assert!(result.is_ok());
-        
-        let (compressed, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/png");
-        assert!(compressed.len() > 0);
-        // Compressed should typically be smaller, but for small test images it might not be
+        chunks.push(([webp[i], webp[i + 1], webp[i + 2], webp[i + 3]], data));
+        i += 8 + len + (len & 1);
     }
+    chunks
+}
 
-    #[test]
-    fn test_jpeg_compression() {
-        let jpeg_data = create_test_jpeg();
-        let opts = CompressionOptions {
-            png_lossy: false,
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+    let _ = enc.write_all(data);
+    enc.finish().unwrap_or_default()
+}
+
+fn unzlib(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    flate2::read::ZlibDecoder::new(data).read_to_end(&mut out).ok()?;
+    Some(out)
+}
+
+/// ICC, EXIF and XMP from a JPEG, PNG or WebP source
+fn read_metadata(data: &[u8]) -> ImageMetadata {
+    let mut meta = ImageMetadata::default();
+    if is_jpeg(data) {
+        let mut icc_parts = Vec::new();
+        for (marker, payload) in jpeg_segments(data) {
+            match marker {
+                0xE1 if payload.starts_with(EXIF_JPEG_SIG) => meta.exif = Some(payload[EXIF_JPEG_SIG.len()..].to_vec()),
+                0xE1 if payload.starts_with(XMP_JPEG_SIG) => meta.xmp = Some(payload[XMP_JPEG_SIG.len()..].to_vec()),
+                0xE2 if payload.starts_with(ICC_JPEG_SIG) && payload.len() > 14 => icc_parts.push((payload[12], &payload[14..])),
+                _ => {}
+            }
+        }
+        icc_parts.sort_by_key(|(seq, _)| *seq);
+        if !icc_parts.is_empty() {
+            meta.icc = Some(icc_parts.into_iter().flat_map(|(_, part)| part.to_vec()).collect());
+        }
+    } else if data.starts_with(b"\x89PNG") {
+        for (kind, chunk) in png_chunks(data) {
+            match &kind {
+                b"iCCP" => {
+                    // name\0, compression method, zlib profile
+                    meta.icc = chunk.iter().position(|&b| b == 0).and_then(|nul| unzlib(chunk.get(nul + 2..)?));
+                }
+                b"eXIf" => meta.exif = Some(chunk.to_vec()),
+                b"iTXt" if chunk.starts_with(XMP_PNG_KEYWORD) && chunk.get(XMP_PNG_KEYWORD.len()) == Some(&0) => {
+                    // keyword\0, compressed flag, method, language\0, translated keyword\0, text
+                    let rest = &chunk[XMP_PNG_KEYWORD.len() + 1..];
+                    let compressed = rest.first() == Some(&1);
+                    let mut fields = rest.get(2..).unwrap_or_default().splitn(3, |&b| b == 0);
+                    let text = fields.nth(2).unwrap_or_default();
+                    meta.xmp = if compressed { unzlib(text) } else { Some(text.to_vec()) };
+                }
+                _ => {}
+            }
+        }
+    } else {
+        for (kind, chunk) in webp_chunks(data) {
+            match &kind {
+                b"ICCP" => meta.icc = Some(chunk.to_vec()),
+                b"EXIF" => meta.exif = Some(chunk.strip_prefix(EXIF_JPEG_SIG).unwrap_or(chunk).to_vec()),
+                b"XMP " => meta.xmp = Some(chunk.to_vec()),
+                _ => {}
+            }
+        }
+    }
+    meta
+}
+
+/// Replace an encoded output's metadata with `meta` (JPEG, PNG and WebP; other formats pass through)
+fn embed_metadata(encoded: Vec<u8>, mime: &str, meta: &ImageMetadata) -> Vec<u8> {
+    match mime {
+        "image/jpeg" => embed_jpeg_metadata(&encoded, meta),
+        "image/png" => embed_png_metadata(&encoded, meta),
+        "image/webp" if meta != &ImageMetadata::default() => embed_webp_metadata(&encoded, meta).unwrap_or(encoded),
+        _ => encoded,
+    }
+}
+
+fn embed_jpeg_metadata(jpeg: &[u8], meta: &ImageMetadata) -> Vec<u8> {
+    let segment = |marker: u8, parts: &[&[u8]]| {
+        let len: usize = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+        let mut seg = vec![0xFF, marker];
+        seg.extend_from_slice(&(len as u16).to_be_bytes());
+        parts.iter().for_each(|p| seg.extend_from_slice(p));
+        (len <= 0xFFFF).then_some(seg)
+    };
+    let mut inserted = Vec::new();
+    if let Some(exif) = &meta.exif {
+        inserted.extend(segment(0xE1, &[EXIF_JPEG_SIG, exif]).unwrap_or_default());
+    }
+    if let Some(xmp) = &meta.xmp {
+        inserted.extend(segment(0xE1, &[XMP_JPEG_SIG, xmp]).unwrap_or_default());
+    }
+    if let Some(icc) = &meta.icc {
+        let parts: Vec<&[u8]> = icc.chunks(65_519).collect();
+        for (i, part) in parts.iter().enumerate() {
+            inserted.extend(segment(0xE2, &[ICC_JPEG_SIG, &[i as u8 + 1, parts.len() as u8], part]).unwrap_or_default());
+        }
+    }
+
+    // Keep SOI + JFIF, drop any existing APP1/APP2 blocks, then add ours
+    let mut out = vec![0xFF, 0xD8];
+    let mut pos = 2;
+    let mut placed = false;
+    for (marker, payload) in jpeg_segments(jpeg) {
+        let seg_len = payload.len() + 4;
+        if marker != 0xE0 && !placed {
+            out.extend_from_slice(&inserted);
+            placed = true;
+        }
+        if marker != 0xE1 && marker != 0xE2 {
+            out.extend_from_slice(&jpeg[pos..pos + seg_len]);
+        }
+        pos += seg_len;
+    }
+    if !placed {
+        out.extend_from_slice(&inserted);
+    }
+    out.extend_from_slice(&jpeg[pos..]);
+    out
+}
+
+fn embed_png_metadata(png: &[u8], meta: &ImageMetadata) -> Vec<u8> {
+    let mut out = png[..8.min(png.len())].to_vec();
+    let mut end = 8;
+    for (kind, data) in png_chunks(png) {
+        end += data.len() + 12;
+        let managed = matches!(&kind, b"iCCP" | b"eXIf")
+            || (&kind == b"sRGB" && meta.icc.is_some())
+            || (&kind == b"iTXt" && data.starts_with(XMP_PNG_KEYWORD));
+        if !managed {
+            out.extend_from_slice(&png[end - data.len() - 12..end]);
+        }
+        if &kind == b"IHDR" {
+            if let Some(icc) = &meta.icc {
+                out.extend(png_chunk(b"iCCP", &[b"icc\0\0".as_slice(), &zlib(icc)].concat()));
+            }
+            if let Some(exif) = &meta.exif {
+                out.extend(png_chunk(b"eXIf", exif));
+            }
+            if let Some(xmp) = &meta.xmp {
+                out.extend(png_chunk(b"iTXt", &[XMP_PNG_KEYWORD, b"\0\0\0\0\0", xmp].concat()));
+            }
+        }
+    }
+    out.extend_from_slice(&png[end.min(png.len())..]);
+    out
+}
+
+/// Tag written into PNG (`tEXt Software`) and JPEG (COM) output so re-runs can recognize it
+const OUTPUT_MARKER: &[u8] = b"rust_tinypng_clone";
+
+/// Add our output marker to a PNG or JPEG; other formats come back unchanged
+fn mark_output(bytes: &[u8]) -> Vec<u8> {
+    if bytes.starts_with(b"\x89PNG") && bytes.len() >= 33 {
+        // IHDR is always first: 8-byte signature + 25-byte chunk
+        let text = png_chunk(b"tEXt", &[b"Software\0".as_slice(), OUTPUT_MARKER].concat());
+        return [&bytes[..33], &text, &bytes[33..]].concat();
+    }
+    if is_jpeg(bytes) {
+        // Stay behind APP0 so JFIF readers still find it first
+        let at = match jpeg_segments(bytes).first() {
+            Some((0xE0, payload)) => 2 + 4 + payload.len(),
+            _ => 2,
+        };
+        let mut com = vec![0xFF, 0xFE];
+        com.extend_from_slice(&(OUTPUT_MARKER.len() as u16 + 2).to_be_bytes());
+        com.extend_from_slice(OUTPUT_MARKER);
+        return [&bytes[..at], &com, &bytes[at..]].concat();
+    }
+    bytes.to_vec()
+}
+
+/// `--comment` text: it has to fit in a single JPEG COM segment
+fn parse_comment(s: &str) -> std::result::Result<String, String> {
+    match s.len() {
+        0 => Err("comment is empty".to_string()),
+        len if len > 65_533 => Err(format!("comment is {} bytes; at most 65533 fit in a JPEG comment", len)),
+        _ => Ok(s.to_string()),
+    }
+}
+
+/// Minimal XMP packet with `comment` as the dc:description
+fn comment_xmp(comment: &str) -> Vec<u8> {
+    let escaped = comment.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    format!(
+        concat!(
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+            "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><dc:description><rdf:Alt>",
+            "<rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description></rdf:Description></rdf:RDF></x:xmpmeta>"
+        ),
+        escaped
+    )
+    .into_bytes()
+}
+
+/// Add `comment` to an encoded output: PNG `Comment` text chunk (iTXt if not ASCII), JPEG COM
+/// segment, or WebP XMP when the output has no XMP of its own. Other formats pass through.
+fn embed_comment(bytes: Vec<u8>, mime: &str, comment: &str) -> Vec<u8> {
+    match mime {
+        "image/png" if bytes.len() >= 33 => {
+            let chunk = if comment.is_ascii() {
+                png_chunk(b"tEXt", &[b"Comment\0".as_slice(), comment.as_bytes()].concat())
+            } else {
+                png_chunk(b"iTXt", &[b"Comment\0\0\0\0\0".as_slice(), comment.as_bytes()].concat())
+            };
+            [&bytes[..33], &chunk, &bytes[33..]].concat()
+        }
+        "image/jpeg" => {
+            let at = match jpeg_segments(&bytes).first() {
+                Some((0xE0, payload)) => 2 + 4 + payload.len(),
+                _ => 2,
+            };
+            let mut com = vec![0xFF, 0xFE];
+            com.extend_from_slice(&(comment.len() as u16 + 2).to_be_bytes());
+            com.extend_from_slice(comment.as_bytes());
+            [&bytes[..at], &com, &bytes[at..]].concat()
+        }
+        "image/webp" => {
+            let mut meta = read_metadata(&bytes);
+            if meta.xmp.is_some() {
+                log::warn!("--comment not added: the WebP output already carries XMP");
+                return bytes;
+            }
+            meta.xmp = Some(comment_xmp(comment));
+            embed_webp_metadata(&bytes, &meta).unwrap_or(bytes)
+        }
+        _ => bytes,
+    }
+}
+
+fn has_output_marker(bytes: &[u8]) -> bool {
+    let png_text = [b"Software\0".as_slice(), OUTPUT_MARKER].concat();
+    png_chunks(bytes).iter().any(|(kind, data)| kind == b"tEXt" && *data == png_text.as_slice())
+        || jpeg_segments(bytes).iter().any(|&(marker, payload)| marker == 0xFE && payload == OUTPUT_MARKER)
+}
+
+/// Rewrap a simple WebP in an extended (VP8X) container carrying ICC/EXIF/XMP
+fn embed_webp_metadata(webp: &[u8], meta: &ImageMetadata) -> Option<Vec<u8>> {
+    let features = webp::BitstreamFeatures::new(webp)?;
+    let chunks = webp_chunks(webp);
+    let mut flags = 0u8;
+    if meta.icc.is_some() {
+        flags |= 0x20;
+    }
+    if features.has_alpha() {
+        flags |= 0x10;
+    }
+    if meta.exif.is_some() {
+        flags |= 0x08;
+    }
+    if meta.xmp.is_some() {
+        flags |= 0x04;
+    }
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&(features.width() - 1).to_le_bytes()[..3]);
+    vp8x.extend_from_slice(&(features.height() - 1).to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    let mut push = |fourcc: &[u8; 4], data: &[u8]| {
+        body.extend_from_slice(fourcc);
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+    };
+    push(b"VP8X", &vp8x);
+    if let Some(icc) = &meta.icc {
+        push(b"ICCP", icc);
+    }
+    for (fourcc, data) in &chunks {
+        if matches!(fourcc, b"ALPH" | b"VP8 " | b"VP8L") {
+            push(fourcc, data);
+        }
+    }
+    if let Some(exif) = &meta.exif {
+        push(b"EXIF", exif);
+    }
+    if let Some(xmp) = &meta.xmp {
+        push(b"XMP ", xmp);
+    }
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+/// One image entry in an ICO directory
+struct IcoEntry<'a> {
+    width: u32,
+    height: u32,
+    bit_count: u16,
+    data: &'a [u8],
+}
+
+/// Parse the ICONDIR/ICONDIRENTRY table of an .ico file
+fn parse_ico_entries(data: &[u8]) -> Result<Vec<IcoEntry<'_>>> {
+    let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+    if data.len() < 6 || u16_at(0) != 0 || u16_at(2) != 1 {
+        return Err(anyhow!("not an ICO file"));
+    }
+    let count = u16_at(4) as usize;
+    if data.len() < 6 + count * 16 {
+        return Err(anyhow!("truncated ICO directory"));
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let base = 6 + i * 16;
+        // A stored 0 means 256 pixels
+        let dim = |b: u8| if b == 0 { 256 } else { b as u32 };
+        let (size, offset) = (u32_at(base + 8) as usize, u32_at(base + 12) as usize);
+        let payload = data
+            .get(offset..offset.saturating_add(size))
+            .ok_or_else(|| anyhow!("ICO entry {} points outside the file", i))?;
+        entries.push(IcoEntry {
+            width: dim(data[base]),
+            height: dim(data[base + 1]),
+            bit_count: u16_at(base + 6),
+            data: payload,
+        });
+    }
+    Ok(entries)
+}
+
+/// Decode the largest (then deepest) image embedded in an ICO
+fn decode_ico_largest(data: &[u8]) -> Result<DynamicImage> {
+    let entries = parse_ico_entries(data)?;
+    let best = entries
+        .iter()
+        .max_by_key(|e| (e.width * e.height, e.bit_count))
+        .ok_or_else(|| anyhow!("ICO has no images"))?;
+
+    if best.data.starts_with(b"\x89PNG") {
+        return Ok(load_image_as(best.data, ImageFormat::Png)?);
+    }
+    // BMP/DIB payload: re-wrap it as a single-entry ICO so the image crate handles the AND mask
+    let single = build_ico(&[(best.width, best.height, best.bit_count, best.data)]);
+    Ok(load_image_as(&single, ImageFormat::Ico)?)
+}
+
+/// ICO readers expect 32-bit RGBA PNG payloads, so expand the (possibly quantized)
+/// PNG back to RGBA and only let oxipng optimize the deflate stream
+fn ico_png_payload(png: &[u8], oxipng: bool) -> Result<(Vec<u8>, u32, u32)> {
+    let rgba = load_image_as(png, ImageFormat::Png)?.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let mut cursor = Cursor::new(Vec::new());
+    DynamicImage::ImageRgba8(rgba).write_to(&mut cursor, ImageFormat::Png)?;
+    if !oxipng {
+        return Ok((cursor.into_inner(), w, h));
+    }
+    let mut o = OxipngOptions::from_preset(4);
+    o.bit_depth_reduction = false;
+    o.color_type_reduction = false;
+    o.palette_reduction = false;
+    o.grayscale_reduction = false;
+    Ok((optimize_from_memory(&cursor.into_inner(), &o)?, w, h))
+}
+
+/// Assemble an ICO from (width, height, bit depth, PNG/DIB payload) entries
+fn build_ico(entries: &[(u32, u32, u16, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+
+    let mut offset = 6 + entries.len() * 16;
+    for (w, h, bits, payload) in entries {
+        out.push(if *w >= 256 { 0 } else { *w as u8 });
+        out.push(if *h >= 256 { 0 } else { *h as u8 });
+        out.push(0); // palette size
+        out.push(0); // reserved
+        out.extend_from_slice(&1u16.to_le_bytes()); // color planes
+        out.extend_from_slice(&bits.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += payload.len();
+    }
+    for (_, _, _, payload) in entries {
+        out.extend_from_slice(payload);
+    }
+    out
+}
+
+fn find_bytes(hay: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    hay.get(from..)?.windows(needle.len()).position(|w| w == needle).map(|p| p + from)
+}
+
+/// Width and height of an image XObject; untrusted sizes that don't fit a u32 give None
+#[cfg(feature = "pdf")]
+fn pdf_image_size(dict: &lopdf::Dictionary) -> Option<(u32, u32)> {
+    if dict.get(b"Subtype").and_then(|s| s.as_name()).ok()? != b"Image" {
+        return None;
+    }
+    let dim = |key: &[u8]| u32::try_from(dict.get(key).and_then(|v| v.as_i64()).ok()?).ok();
+    Some((dim(b"Width")?, dim(b"Height")?))
+}
+
+/// Loader settings for untrusted PDFs: object and xref streams are inflated while the file is
+/// read, so each is held to the decode memory limit
+#[cfg(feature = "pdf")]
+fn pdf_load_options() -> lopdf::LoadOptions {
+    let limit = DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed);
+    lopdf::LoadOptions { max_decompressed_size: usize::try_from(limit).ok(), ..Default::default() }
+}
+
+/// Re-encode an 8-bit RGB/gray image XObject as JPEG, returning the new stream when smaller
+#[cfg(feature = "pdf")]
+fn recompress_pdf_image(stream: &lopdf::Stream, quality: u8, smoothing: u8, effort: JpegEffort) -> Option<lopdf::Stream> {
+    let dict = &stream.dict;
+    let (w, h) = pdf_image_size(dict)?;
+    if [&b"DecodeParms"[..], b"Decode", b"ImageMask", b"Mask"].iter().any(|k| dict.has(k))
+        || dict.get(b"BitsPerComponent").and_then(|b| b.as_i64()).ok()? != 8
+    {
+        return None;
+    }
+    let gray = match dict.get(b"ColorSpace").and_then(|c| c.as_name()).ok()? {
+        b"DeviceRGB" => false,
+        b"DeviceGray" => true,
+        _ => return None,
+    };
+
+    let rgb = match stream.filters().ok()?.as_slice() {
+        [b"DCTDecode"] => load_image_as(&stream.content, ImageFormat::Jpeg).ok()?.to_rgb8(),
+        [b"FlateDecode"] => {
+            // Inflate no further than the declared pixels, and not at all past the decode limit
+            let n = (w as usize).checked_mul(h as usize)?.checked_mul(if gray { 1 } else { 3 })?;
+            if n as u64 > DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed) {
+                return None;
+            }
+            let mut raw = Vec::new();
+            flate2::read::ZlibDecoder::new(&stream.content[..]).take(n as u64).read_to_end(&mut raw).ok()?;
+            if gray {
+                DynamicImage::ImageLuma8(image::GrayImage::from_raw(w, h, raw)?).to_rgb8()
+            } else {
+                image::RgbImage::from_raw(w, h, raw)?
+            }
+        }
+        _ => return None,
+    };
+    if rgb.dimensions() != (w, h) {
+        return None;
+    }
+
+    let jpeg = encode_jpeg_rgb(rgb, quality, smoothing, effort).ok()?;
+    if jpeg.len() >= stream.content.len() {
+        return None;
+    }
+    let mut dict = dict.clone();
+    dict.set("Filter", lopdf::Object::Name(b"DCTDecode".to_vec()));
+    dict.set("ColorSpace", lopdf::Object::Name(b"DeviceRGB".to_vec()));
+    Some(lopdf::Stream::new(dict, jpeg).with_compression(false))
+}
+
+/// Keep only image XObject dictionaries (without their data) while loading a PDF. Object
+/// streams are kept so the images packed inside them still reach this filter.
+#[cfg(feature = "pdf")]
+fn pdf_image_dicts_only(id: lopdf::ObjectId, object: &mut lopdf::Object) -> Option<(lopdf::ObjectId, lopdf::Object)> {
+    let lopdf::Object::Stream(stream) = object else { return None };
+    if !stream.dict.has_type(b"ObjStm") {
+        pdf_image_size(&stream.dict)?;
+        *object = lopdf::Object::Dictionary(std::mem::take(&mut stream.dict));
+    }
+    Some((id, lopdf::Object::Null))
+}
+
+/// RGBA size of a PDF's largest image XObject; they're recompressed one at a time
+#[cfg(feature = "pdf")]
+fn pdf_decode_cost(input: &[u8]) -> Option<u64> {
+    if !input.starts_with(b"%PDF-") {
+        return None;
+    }
+    let options = lopdf::LoadOptions { filter: Some(pdf_image_dicts_only), ..pdf_load_options() };
+    lopdf::Document::load_mem_with_options(input, options)
+        .ok()?
+        .objects
+        .values()
+        .filter_map(|obj| pdf_image_size(obj.as_dict().ok()?))
+        .map(|(w, h)| w as u64 * h as u64 * 4)
+        .max()
+}
+
+/// Recompress the embedded images of a PDF and write it back out with lopdf. Encrypted
+/// files are rejected.
+#[cfg(feature = "pdf")]
+fn compress_pdf_bytes(input: &[u8], opts: &CompressionOptions) -> Result<Vec<u8>> {
+    if !input.starts_with(b"%PDF-") {
+        return Err(anyhow!("not a PDF file"));
+    }
+    let mut doc = lopdf::Document::load_mem_with_options(input, pdf_load_options())
+        .map_err(|e| anyhow!("unreadable PDF: {}", e))?;
+    if doc.is_encrypted() {
+        return Err(anyhow!("encrypted PDFs are not supported"));
+    }
+
+    // JPEG re-encoding is lossy, so --lossless only rewrites the structure
+    if !opts.lossless {
+        // Soft masks must stay grayscale, so they're never converted
+        let masks: std::collections::HashSet<lopdf::ObjectId> = doc
+            .objects
+            .values()
+            .filter_map(|obj| obj.as_stream().ok()?.dict.get(b"SMask").ok()?.as_reference().ok())
+            .collect();
+        let quality = opts.qualities().jpeg;
+        for (id, obj) in doc.objects.iter_mut() {
+            if let lopdf::Object::Stream(stream) = obj
+                && !masks.contains(id)
+                && let Some(smaller) = recompress_pdf_image(stream, quality, opts.jpeg_smoothing, opts.jpeg_effort)
+            {
+                *stream = smaller;
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    doc.save_to(&mut out).map_err(|e| anyhow!("failed to write PDF: {}", e))?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "pdf"))]
+fn compress_pdf_bytes(_input: &[u8], _opts: &CompressionOptions) -> Result<Vec<u8>> {
+    Err(anyhow!("PDF support not compiled in (rebuild with --features pdf)"))
+}
+
+/// One decompressed ZIP member; `modified` is the MS-DOS (time, date) pair
+#[derive(Debug, Clone, PartialEq)]
+struct ZipEntry {
+    name: String,
+    modified: (u16, u16),
+    data: Vec<u8>,
+}
+
+/// Member names that stay inside the archive root when extracted: relative, `/`-separated,
+/// no `..` or drive prefixes (zip-slip)
+fn safe_zip_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('/')
+        && !name.contains('\\')
+        && !name.contains(':')
+        && name.split('/').all(|part| part != "..")
+}
+
+/// Every member of a ZIP archive; together they may not inflate past the decode memory limit
+fn read_zip(bytes: &[u8]) -> Result<Vec<ZipEntry>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| anyhow!("not a ZIP archive ({})", e))?;
+    let mut budget = DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed);
+
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| anyhow!("corrupt ZIP archive ({})", e))?;
+        let name = file.name().map_err(|e| anyhow!("corrupt ZIP archive ({})", e))?.into_owned();
+        if file.size() > budget {
+            return Err(anyhow!("{}: {} bytes uncompressed exceeds the decode memory limit", name, file.size()));
+        }
+        let modified = file.last_modified().map_or((0, 0x21), |t| (t.timepart(), t.datepart()));
+
+        // The declared size is only a hint; reading to the end checks it and the CRC
+        let mut data = Vec::with_capacity(file.size() as usize);
+        (&mut file).take(budget + 1).read_to_end(&mut data).map_err(|e| anyhow!("{}: {}", name, e))?;
+        if data.len() as u64 > budget {
+            return Err(anyhow!("{}: uncompressed size exceeds the decode memory limit", name));
+        }
+        budget -= data.len() as u64;
+        entries.push(ZipEntry { name, modified, data });
+    }
+    Ok(entries)
+}
+
+/// A ZIP of `entries`, each deflated unless storing is smaller
+fn write_zip(entries: &[ZipEntry]) -> Result<Vec<u8>> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for entry in entries {
+        let mut deflater = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+        deflater.write_all(&entry.data)?;
+        let (method, level) = if deflater.finish()?.len() < entry.data.len() {
+            (zip::CompressionMethod::Deflated, Some(9))
+        } else {
+            (zip::CompressionMethod::Stored, None)
+        };
+        let (time, date) = entry.modified;
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+            .last_modified_time(zip::DateTime::try_from_msdos(date, time).unwrap_or_default())
+            .large_file(entry.data.len() as u64 >= u32::MAX as u64);
+        if entry.name.ends_with('/') {
+            zip.add_directory(entry.name.as_str(), options)?;
+        } else {
+            zip.start_file(entry.name.as_str(), options)?;
+            zip.write_all(&entry.data)?;
+        }
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// File extension for an encoder's output MIME type
+fn mime_extension(mime: &str) -> Option<&'static str> {
+    Some(match mime {
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "image/tiff" => "tiff",
+        "image/bmp" => "bmp",
+        "image/x-icon" => "ico",
+        "image/svg+xml" => "svg",
+        "application/pdf" => "pdf",
+        _ => return None,
+    })
+}
+
+/// Re-encode the images inside a ZIP and repackage it. Members keep their original bytes when
+/// the re-encode fails or isn't smaller; converted members take the new extension unless that
+/// name is already taken.
+fn compress_zip_bytes(input: &[u8], opts: &CompressionOptions) -> Result<Vec<u8>> {
+    let entries = read_zip(input)?;
+    if let Some(unsafe_name) = entries.iter().find(|e| !safe_zip_name(&e.name)) {
+        return Err(anyhow!("refusing ZIP member with unsafe path \"{}\"", unsafe_name.name));
+    }
+    let names: std::collections::HashSet<String> = entries.iter().map(|e| e.name.clone()).collect();
+    let entries: Vec<ZipEntry> = entries
+        .into_par_iter()
+        .map(|entry| {
+            let path = Path::new(&entry.name);
+            let ext = path.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()).unwrap_or_default();
+            if entry.name.ends_with('/') || !SUPPORTED_EXTS.contains(&ext.as_str()) {
+                return entry;
+            }
+            let (bytes, mime) = match compress_image_inproc(&entry.data, &ext, opts) {
+                Ok(out) => out,
+                Err(e) => {
+                    log::warn!("{}: kept as-is ({})", entry.name, e);
+                    return entry;
+                }
+            };
+            let new_ext = mime_extension(&mime).filter(|new| canonical_ext(new) != canonical_ext(&ext));
+            match new_ext {
+                Some(new_ext) => {
+                    let renamed = path.with_extension(new_ext).to_string_lossy().replace('\\', "/");
+                    if names.contains(&renamed) {
+                        return entry;
+                    }
+                    ZipEntry { name: renamed, data: bytes, ..entry }
+                }
+                None if bytes.len() < entry.data.len() => ZipEntry { data: bytes, ..entry },
+                None => entry,
+            }
+        })
+        .collect();
+    write_zip(&entries)
+}
+
+/// Input rejected before decoding because it can't be a complete image
+#[derive(Debug, Clone, PartialEq)]
+enum InvalidInput {
+    Empty,
+    Truncated(&'static str),
+}
+
+impl std::fmt::Display for InvalidInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvalidInput::Empty => write!(f, "file is empty (0 bytes)"),
+            InvalidInput::Truncated(why) => write!(f, "file is truncated: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for InvalidInput {}
+
+/// Cheap completeness checks so empty or cut-off files don't half-decode into an output
+fn check_input_complete(input: &[u8]) -> std::result::Result<(), InvalidInput> {
+    if input.is_empty() {
+        return Err(InvalidInput::Empty);
+    }
+    // Walked chunk by chunk, so "IEND" bytes inside compressed data or text don't count
+    if input.starts_with(b"\x89PNG\r\n\x1a\n") && !png_chunks(input).iter().any(|(kind, _)| kind == b"IEND") {
+        return Err(InvalidInput::Truncated("PNG has no IEND chunk"));
+    }
+    // Plenty of cameras and tools drop the EOI marker, so only JPEGs that also fail to
+    // decode are refused
+    if is_jpeg(input) {
+        let scan = find_bytes(input, &[0xFF, 0xDA], 2);
+        if scan.is_none_or(|at| find_bytes(input, &[0xFF, 0xD9], at).is_none()) && load_image(input).is_err() {
+            return Err(InvalidInput::Truncated("JPEG ends before its end-of-image marker"));
+        }
+    }
+    if input.starts_with(b"RIFF") && input.get(8..12) == Some(b"WEBP") {
+        let declared = u32::from_le_bytes([input[4], input[5], input[6], input[7]]) as usize;
+        if declared + 8 > input.len() {
+            return Err(InvalidInput::Truncated("WebP is shorter than its RIFF header declares"));
+        }
+    }
+    Ok(())
+}
+
+/// In-process compress dispatcher
+fn compress_image_inproc(input_bytes: &[u8], ext_lower: &str, opts: &CompressionOptions) -> Result<(Vec<u8>, String)> {
+    check_input_complete(input_bytes)?;
+
+    // Archives from --scan-archives: every member goes through this dispatcher on its own
+    if ext_lower == "zip" {
+        let bytes = compress_zip_bytes(input_bytes, opts)?;
+        return Ok((bytes, "application/zip".to_string()));
+    }
+
+    // Everything below sees a static PNG of frame 0 instead of the animation
+    if opts.first_frame && animation_frames(input_bytes).is_some() {
+        let mut still = Vec::new();
+        first_frame(input_bytes)?.write_to(&mut Cursor::new(&mut still), ImageFormat::Png)?;
+        let mut single = opts.clone();
+        single.first_frame = false;
+        return compress_image_inproc(&still, ext_lower, &single);
+    }
+
+    // Stamped last, so --strip never removes it
+    if let Some(comment) = &opts.comment
+        && ext_lower != "pdf"
+    {
+        let mut uncommented = opts.clone();
+        uncommented.comment = None;
+        let (bytes, mime) = compress_image_inproc(input_bytes, ext_lower, &uncommented)?;
+        return Ok((embed_comment(bytes, &mime, comment), mime));
+    }
+
+    // Compared against the original bytes, before any step below re-encodes them
+    if opts.bit_depth_check && ext_lower != "pdf" {
+        let mut unchecked = opts.clone();
+        unchecked.bit_depth_check = false;
+        let (bytes, mime) = compress_image_inproc(input_bytes, ext_lower, &unchecked)?;
+        if let Some(warning) = depth_reduction(input_bytes, &bytes, &mime, opts) {
+            if opts.strict {
+                return Err(anyhow!(warning));
+            }
+            log::warn!("{}", warning);
+        }
+        return Ok((bytes, mime));
+    }
+
+    // Decided on the original bytes, before any step below re-encodes them
+    if let Some(limit) = opts.shrink_if_over {
+        let mut decided = opts.clone();
+        decided.shrink_if_over = None;
+        if input_bytes.len() as u64 <= limit {
+            decided.max_dimension = None;
+        }
+        return compress_image_inproc(input_bytes, ext_lower, &decided);
+    }
+
+    // Carry over the metadata `--strip` keeps; everything below only sees bare pixels
+    if !opts.strip.contains(&MetadataKind::All) && ext_lower != "pdf" {
+        let mut metadata = read_metadata(input_bytes).without(&opts.strip);
+        if autorotation(input_bytes, ext_lower, opts).is_some()
+            && let Some(exif) = &mut metadata.exif
+        {
+            reset_exif_orientation(exif);
+        }
+        let mut bare = opts.clone();
+        bare.strip = vec![MetadataKind::All];
+        let (bytes, mime) = compress_image_inproc(input_bytes, ext_lower, &bare)?;
+        if let Some(exif) = &mut metadata.exif {
+            normalize_exif_thumbnail(exif, input_bytes, &bytes, opts.regen_thumbnail, opts.compress_embedded_thumb);
+        }
+        return Ok((embed_metadata(bytes, &mime, &metadata), mime));
+    }
+
+    // PDFs stay PDFs; only their embedded images are re-encoded
+    if ext_lower == "pdf" {
+        let bytes = compress_pdf_bytes(input_bytes, opts)?;
+        return Ok((bytes, "application/pdf".to_string()));
+    }
+
+    // A per-extension mapping beats the global conversion flags
+    if let Some(format) = opts.format_map.get(canonical_ext(ext_lower)) {
+        let mut mapped = opts.clone();
+        mapped.format_map.clear();
+        format.apply(&mut mapped);
+        return compress_image_inproc(input_bytes, ext_lower, &mapped);
+    }
+
+    // Bake EXIF orientation into the pixels; encoders below don't carry metadata over
+    if let Some(orientation) = autorotation(input_bytes, ext_lower, opts) {
+        let img = apply_orientation(load_image(input_bytes)?, orientation);
+        let mut cursor = Cursor::new(Vec::new());
+        img.write_to(&mut cursor, ImageFormat::Png)?;
+        let mut upright = opts.clone();
+        upright.autorotate = false;
+        return compress_image_inproc(&cursor.into_inner(), ext_lower, &upright);
+    }
+
+    // Tone normalization happens on decoded pixels, before any encoder sees them
+    if opts.auto_contrast && !matches!(ext_lower, "svg" | "ico") {
+        let mut rgba = load_image(input_bytes)?.to_rgba8();
+        auto_contrast(&mut rgba);
+        let mut cursor = Cursor::new(Vec::new());
+        rgba.write_to(&mut cursor, ImageFormat::Png)?;
+        let mut stretched = opts.clone();
+        stretched.auto_contrast = false;
+        return compress_image_inproc(&cursor.into_inner(), ext_lower, &stretched);
+    }
+
+    // Premultiplied output: convert once, then encode the result like any other source
+    if opts.premultiply_alpha && !matches!(ext_lower, "svg" | "ico") {
+        let img = load_image(input_bytes)?;
+        let mut straight = opts.clone();
+        straight.premultiply_alpha = false;
+        if img.color().has_alpha() {
+            let mut rgba = img.to_rgba8();
+            premultiply_alpha(&mut rgba);
+            let mut cursor = Cursor::new(Vec::new());
+            rgba.write_to(&mut cursor, ImageFormat::Png)?;
+            return compress_image_inproc(&cursor.into_inner(), ext_lower, &straight);
+        }
+        return compress_image_inproc(input_bytes, ext_lower, &straight);
+    }
+
+    // Border trimming comes first, so the steps below size the content alone
+    if let Some(tolerance) = opts.trim
+        && !matches!(ext_lower, "svg" | "ico")
+    {
+        let img = load_image(input_bytes)?;
+        let mut untrimmed = opts.clone();
+        untrimmed.trim = None;
+        if let Some((x, y, w, h)) = trim_bounds(&img.to_rgba8(), tolerance) {
+            let mut cursor = Cursor::new(Vec::new());
+            img.crop_imm(x, y, w, h).write_to(&mut cursor, ImageFormat::Png)?;
+            return compress_image_inproc(&cursor.into_inner(), ext_lower, &untrimmed);
+        }
+        return compress_image_inproc(input_bytes, ext_lower, &untrimmed);
+    }
+
+    // Letterboxing: scale into the box, pad the rest
+    if let Some((width, height)) = opts.fit
+        && !matches!(ext_lower, "svg" | "ico")
+    {
+        let canvas = fit_to_canvas(&load_image(input_bytes)?, width, height, opts.fit_background);
+        let mut cursor = Cursor::new(Vec::new());
+        canvas.write_to(&mut cursor, ImageFormat::Png)?;
+        let mut fitted = opts.clone();
+        fitted.fit = None;
+        return compress_image_inproc(&cursor.into_inner(), ext_lower, &fitted);
+    }
+
+    // Downscale oversized rasters up front (SVG/ICO resize after they're decoded)
+    if let Some(cap) = opts.max_dimension
+        && ext_lower != "svg"
+        && ext_lower != "ico"
+    {
+        let img = load_image(input_bytes)?;
+        let mut unresized = opts.clone();
+        unresized.max_dimension = None;
+        if let Some((w, h)) = capped_dimensions(img.width(), img.height(), cap, opts.cap_side) {
+            let resized = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+            let mut cursor = Cursor::new(Vec::new());
+            resized.write_to(&mut cursor, ImageFormat::Png)?;
+            return compress_image_inproc(&cursor.into_inner(), ext_lower, &unresized);
+        }
+        return compress_image_inproc(input_bytes, ext_lower, &unresized);
+    }
+    if let Some(megapixels) = opts.max_megapixels
+        && ext_lower != "svg"
+        && ext_lower != "ico"
+    {
+        let img = load_image(input_bytes)?;
+        let mut unresized = opts.clone();
+        unresized.max_megapixels = None;
+        if let Some((w, h)) = megapixel_dimensions(img.width(), img.height(), megapixels) {
+            let resized = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+            let mut cursor = Cursor::new(Vec::new());
+            resized.write_to(&mut cursor, ImageFormat::Png)?;
+            return compress_image_inproc(&cursor.into_inner(), ext_lower, &unresized);
+        }
+        return compress_image_inproc(input_bytes, ext_lower, &unresized);
+    }
+
+    // Rasterize SVG first, then treat it like any other PNG source
+    if ext_lower == "svg" && svg_passthrough(opts) {
+        return Ok((minify_svg(input_bytes)?, "image/svg+xml".to_string()));
+    }
+    if ext_lower == "svg" {
+        let (out_w, out_h) = svg_output_size(input_bytes, opts.svg_scale, opts.svg_width)?;
+        let raster = rasterize_svg(input_bytes, out_w, out_h)?;
+        let mut cursor = Cursor::new(Vec::new());
+        raster.write_to(&mut cursor, ImageFormat::Png)?;
+        return compress_image_inproc(&cursor.into_inner(), "png", opts);
+    }
+
+    // Favicons: optimize the largest embedded image; keep it an ICO unless converting
+    if ext_lower == "ico" {
+        let largest = decode_ico_largest(input_bytes)?;
+        let mut cursor = Cursor::new(Vec::new());
+        largest.write_to(&mut cursor, ImageFormat::Png)?;
+        let converting = opts.to_webp || opts.to_avif || opts.to_jpeg || opts.to_png || opts.to_tiff || opts.to_bmp || opts.to_ico;
+        if converting {
+            return compress_image_inproc(&cursor.into_inner(), "png", opts);
+        }
+        let (png, _) = compress_image_inproc(&cursor.into_inner(), "png", opts)?;
+        let (png, w, h) = ico_png_payload(&png, opts.oxipng)?;
+        let ico = build_ico(&[(w, h, 32, &png)]);
+        return Ok((ico, "image/x-icon".to_string()));
+    }
+
+    // Handle HEIC files first (convert to JPEG like TinyPNG)
+    if ext_lower == "heic" || ext_lower == "heif" {
+        let bytes = heic_to_jpeg_bytes(input_bytes, opts.qualities().heic)?;
+        return Ok((bytes, "image/jpeg".to_string()));
+    }
+    
+    let qualities = opts.qualities();
+    
+    // Document scans: black and white beats any palette
+    if let Some((threshold, dither)) = opts.bilevel {
+        let bytes = to_bilevel_png(input_bytes, threshold, dither)?;
+        return Ok((bytes, "image/png".to_string()));
+    }
+
+    // If conversion requested, honor it next
+    if opts.to_webp {
+        let bytes = to_webp_bytes(input_bytes, qualities.webp, opts.webp_method, opts.lossless, opts.sharp_yuv, opts.webp_exact, opts.perceptual)?;
+        return Ok((bytes, "image/webp".to_string()));
+    }
+    if opts.to_avif && opts.lossless {
+        let bytes = to_avif_lossless_bytes(input_bytes)?;
+        return Ok((bytes, "image/avif".to_string()));
+    }
+    if opts.to_avif {
+        let bytes = to_avif_bytes(input_bytes, qualities.avif, opts.avif_depth, opts.avif_subsampling, opts.avif_tiles, opts.perceptual)?;
+        return Ok((bytes, "image/avif".to_string()));
+    }
+    if opts.to_jpeg && opts.lossless {
+        if !is_jpeg(input_bytes) {
+            return Err(anyhow!("lossless JPEG output needs an unmodified JPEG source (got .{})", ext_lower));
+        }
+        let bytes = optimize_jpeg_lossless(input_bytes, opts.jpeg_restart, opts.jpeg_scan_mode, opts.jpeg_arithmetic)?;
+        return Ok((bytes, "image/jpeg".to_string()));
+    }
+    if opts.to_jpeg {
+        let bytes = compress_jpeg_bytes(input_bytes, qualities.jpeg, opts.jpeg_smoothing, opts.jpeg_restart, opts.jpeg_scan_mode, opts.jpeg_arithmetic, opts.jpeg_effort)?;
+        return Ok((bytes, "image/jpeg".to_string()));
+    }
+    if opts.to_png {
+        let bytes = to_png_bytes(input_bytes, opts)?;
+        return Ok((bytes, "image/png".to_string()));
+    }
+    if opts.to_tiff {
+        let bytes = to_tiff_bytes(input_bytes)?;
+        return Ok((bytes, "image/tiff".to_string()));
+    }
+    if opts.to_bmp {
+        let bytes = to_bmp_bytes(input_bytes)?;
+        return Ok((bytes, "image/bmp".to_string()));
+    }
+    if opts.to_ico {
+        let bytes = to_ico_bytes(input_bytes)?;
+        return Ok((bytes, "image/x-icon".to_string()));
+    }
+
+    match ext_lower {
+        "png" => {
+            if opts.png_lossy && !opts.lossless {
+                let bytes = compress_png_bytes(input_bytes, opts)?;
+                Ok((bytes, "image/png".into()))
+            } else if opts.oxipng {
+                // true lossless: optimize the original stream, pixels are never decoded
+                let bytes = optimize_png_lossless(input_bytes, opts.png_interlace)?;
+                Ok((bytes, "image/png".into()))
+            } else {
+                // lossless re-encode
+                let img = load_image(input_bytes)?;
+                let mut cursor = Cursor::new(Vec::new());
+                img.write_to(&mut cursor, ImageFormat::Png)?;
+                let buf = cursor.into_inner();
+                Ok((buf, "image/png".into()))
+            }
+        }
+        "jpg" | "jpeg" if opts.lossless && is_jpeg(input_bytes) => {
+            let bytes = optimize_jpeg_lossless(input_bytes, opts.jpeg_restart, opts.jpeg_scan_mode, opts.jpeg_arithmetic)?;
+            Ok((bytes, "image/jpeg".into()))
+        }
+        "jpg" | "jpeg" => {
+            let bytes = compress_jpeg_bytes(input_bytes, qualities.jpeg_reencode, opts.jpeg_smoothing, opts.jpeg_restart, opts.jpeg_scan_mode, opts.jpeg_arithmetic, opts.jpeg_effort)?;
+            Ok((bytes, "image/jpeg".into()))
+        }
+        // Other formats → PNG by default
+        _ => {
+            let bytes = compress_png_bytes(input_bytes, opts)?;
+            Ok((bytes, "image/png".into()))
+        }
+    }
+}
+
+/// JPEG SOI marker at the start of the stream
+fn is_jpeg(bytes: &[u8]) -> bool {
+    bytes.starts_with(&[0xFF, 0xD8, 0xFF])
+}
+
+/// Canonical spelling of an image extension so aliases compare equal
+fn canonical_ext(ext: &str) -> &str {
+    match ext {
+        "jpeg" => "jpg",
+        "tiff" => "tif",
+        "heic" => "heif",
+        other => other,
+    }
+}
+
+/// Detect animated GIF/WebP/APNG inputs, returning (format, frame count) when
+/// there's more than one frame
+fn animation_frames(data: &[u8]) -> Option<(&'static str, u32)> {
+    let frames = if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        ("GIF", gif_frame_count(data))
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        ("WebP", webp_frame_count(data))
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ("APNG", apng_frame_count(data))
+    } else {
+        return None;
+    };
+    (frames.1 > 1).then_some(frames)
+}
+
+/// Frame 0 of an animated input, as it appears on the canvas. APNG's default image is
+/// already what a static decoder returns
+fn first_frame(data: &[u8]) -> Result<image::RgbaImage> {
+    let check_canvas = |w: u32, h: u32, frames: u32| {
+        if w as u64 * h as u64 * 4 * frames as u64 > DECODE_MEMORY_LIMIT.load(Ordering::Relaxed) {
+            return Err(anyhow!("{}x{} animation exceeds the decode memory limit", w, h));
+        }
+        Ok(())
+    };
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        return gif_first_frame(data, check_canvas);
+    }
+    if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        let features = webp::BitstreamFeatures::new(data).ok_or_else(|| anyhow!("invalid WebP data"))?;
+        // libwebp's animation decoder reconstructs every frame up front
+        check_canvas(features.width(), features.height(), webp_frame_count(data))?;
+        let decoded = webp::AnimDecoder::new(data).decode().map_err(|e| anyhow!("WebP animation decoding failed: {}", e))?;
+        let frame = decoded.get_frame(0).ok_or_else(|| anyhow!("WebP animation has no frames"))?;
+        return image::RgbaImage::from_raw(frame.width(), frame.height(), frame.get_image().to_vec())
+            .ok_or_else(|| anyhow!("truncated WebP frame"));
+    }
+    Ok(load_image(data)?.to_rgba8())
+}
+
+/// Decode the first image of a GIF onto a transparent logical screen
+fn gif_first_frame(data: &[u8], check_canvas: impl Fn(u32, u32, u32) -> Result<()>) -> Result<image::RgbaImage> {
+    let truncated = || anyhow!("truncated GIF");
+    let u16_at = |i: usize| data.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32).ok_or_else(truncated);
+    let table_len = |packed: u8| if packed & 0x80 != 0 { 3usize << ((packed & 0x07) + 1) } else { 0 };
+    let (screen_w, screen_h) = (u16_at(6)?, u16_at(8)?);
+    check_canvas(screen_w, screen_h, 1)?;
+    let packed = *data.get(10).ok_or_else(truncated)?;
+    let global = data.get(13..13 + table_len(packed)).ok_or_else(truncated)?;
+    let mut i = 13 + global.len();
+    let mut transparent = None;
+
+    // Concatenate len-prefixed sub-blocks starting at `i`; returns them and the index past the terminator
+    let sub_blocks = |mut i: usize| -> Result<(Vec<u8>, usize)> {
+        let mut out = Vec::new();
+        loop {
+            let len = *data.get(i).ok_or_else(truncated)? as usize;
+            out.extend_from_slice(data.get(i + 1..i + 1 + len).ok_or_else(truncated)?);
+            i += 1 + len;
+            if len == 0 {
+                return Ok((out, i));
+            }
+        }
+    };
+    loop {
+        match data.get(i) {
+            Some(0x21) => {
+                let (body, next) = sub_blocks(i + 2)?;
+                // Graphic control extension: the transparency flag and index apply to the next image
+                if data.get(i + 1) == Some(&0xF9) && body.len() >= 4 {
+                    transparent = (body[0] & 1 != 0).then_some(body[3]);
+                }
+                i = next;
+            }
+            Some(0x2C) => break,
+            _ => return Err(anyhow!("GIF has no image")),
+        }
+    }
+
+    let (left, top, w, h) = (u16_at(i + 1)?, u16_at(i + 3)?, u16_at(i + 5)?, u16_at(i + 7)?);
+    // The frame declares its own size, and a tiny file can claim a huge one
+    check_canvas(w, h, 1)?;
+    let packed = *data.get(i + 9).ok_or_else(truncated)?;
+    let local = data.get(i + 10..i + 10 + table_len(packed)).ok_or_else(truncated)?;
+    let palette = if local.is_empty() { global } else { local };
+    if palette.is_empty() {
+        return Err(anyhow!("GIF has no color table"));
+    }
+    i += 10 + local.len();
+    let min_code_size = *data.get(i).ok_or_else(truncated)?;
+    if !(1..=11).contains(&min_code_size) {
+        return Err(anyhow!("invalid GIF LZW code size {}", min_code_size));
+    }
+    let (lzw, _) = sub_blocks(i + 1)?;
+    let mut decoder = weezl::decode::Decoder::new(weezl::BitOrder::Lsb, min_code_size);
+    // Decoded into a frame-sized buffer, so an over-long stream stops at w*h indices.
+    // Encoders often omit the end code, so a short stream is fine; garbage is not
+    let mut indices = vec![0u8; w as usize * h as usize];
+    let (mut read, mut filled) = (0, 0);
+    while filled < indices.len() {
+        let res = decoder.decode_bytes(&lzw[read..], &mut indices[filled..]);
+        read += res.consumed_in;
+        filled += res.consumed_out;
+        match res.status {
+            Ok(weezl::LzwStatus::Ok) if res.consumed_in + res.consumed_out > 0 => {}
+            Ok(_) => break,
+            Err(e) if filled == 0 => return Err(anyhow!("GIF LZW decoding failed: {}", e)),
+            Err(_) => break,
+        }
+    }
+    indices.truncate(filled);
+
+    // Interlaced rows are stored as every 8th from 0, every 8th from 4, every 4th from 2, every 2nd from 1
+    let rows: Vec<u32> = if packed & 0x40 != 0 {
+        [(0, 8), (4, 8), (2, 4), (1, 2)].iter().flat_map(|&(start, step)| (start..h).step_by(step)).collect()
+    } else {
+        (0..h).collect()
+    };
+    let mut canvas = image::RgbaImage::new(screen_w, screen_h);
+    for (n, y) in rows.into_iter().enumerate() {
+        for x in 0..w {
+            let Some(&idx) = indices.get(n * w as usize + x as usize) else { break };
+            let (cx, cy) = (left + x, top + y);
+            if Some(idx) == transparent || cx >= screen_w || cy >= screen_h {
+                continue;
+            }
+            if let Some(rgb) = palette.get(idx as usize * 3..idx as usize * 3 + 3) {
+                canvas.put_pixel(cx, cy, image::Rgba([rgb[0], rgb[1], rgb[2], 255]));
+            }
+        }
+    }
+    Ok(canvas)
+}
+
+/// Count image descriptors by walking the GIF block structure
+fn gif_frame_count(data: &[u8]) -> u32 {
+    // Skip the len-prefixed sub-blocks starting at `i`, returning the index past the terminator
+    fn skip_sub_blocks(data: &[u8], mut i: usize) -> Option<usize> {
+        loop {
+            let len = *data.get(i)? as usize;
+            i += 1 + len;
+            if len == 0 {
+                return Some(i);
+            }
+        }
+    }
+    let color_table = |packed: u8| if packed & 0x80 != 0 { 3 << ((packed & 0x07) + 1) } else { 0 };
+
+    let Some(&packed) = data.get(10) else { return 0 };
+    let mut i = 13 + color_table(packed);
+    let mut frames = 0;
+    while let Some(&block) = data.get(i) {
+        match block {
+            0x2C => {
+                frames += 1;
+                let Some(&packed) = data.get(i + 9) else { break };
+                // descriptor, local color table, LZW minimum code size
+                i += 10 + color_table(packed) + 1;
+            }
+            0x21 => i += 2,
+            _ => break, // 0x3B trailer or garbage
+        }
+        match skip_sub_blocks(data, i) {
+            Some(next) => i = next,
+            None => break,
+        }
+    }
+    frames
+}
+
+/// Animated WebPs carry the VP8X animation flag and one ANMF chunk per frame
+fn webp_frame_count(data: &[u8]) -> u32 {
+    let mut i = 12;
+    let (mut animated, mut frames) = (false, 0);
+    while i + 8 <= data.len() {
+        let fourcc = &data[i..i + 4];
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        match fourcc {
+            b"VP8X" => animated = data.get(i + 8).is_some_and(|flags| flags & 0x02 != 0),
+            b"ANMF" => frames += 1,
+            _ => {}
+        }
+        // chunks are padded to even sizes
+        i += 8 + len + (len & 1);
+    }
+    if animated { frames } else { 1 }
+}
+
+/// APNGs declare their frame count in an acTL chunk ahead of IDAT
+fn apng_frame_count(data: &[u8]) -> u32 {
+    let mut i = 8;
+    while i + 8 <= data.len() {
+        let len = u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
+        match &data[i + 4..i + 8] {
+            b"acTL" if i + 12 <= data.len() => {
+                return u32::from_be_bytes([data[i + 8], data[i + 9], data[i + 10], data[i + 11]]);
+            }
+            b"IDAT" => break,
+            _ => {}
+        }
+        i += 12 + len;
+    }
+    1
+}
+
+/// Check that the sniffed (magic-byte) format agrees with the file extension
+fn check_format_matches(bytes: &[u8], ext: &str) -> Result<()> {
+    match infer::get(bytes) {
+        Some(kind) if canonical_ext(kind.extension()) == canonical_ext(ext) => Ok(()),
+        Some(kind) => Err(anyhow!(
+            "extension .{} but content is {}",
+            ext,
+            kind.mime_type()
+        )),
+        None => Err(anyhow!("extension .{} but content is not a recognized image", ext)),
+    }
+}
+
+/// Validate one `--only` entry against SUPPORTED_EXTS
+fn parse_supported_ext(s: &str) -> std::result::Result<String, String> {
+    let ext = s.trim().trim_start_matches('.').to_lowercase();
+    if SUPPORTED_EXTS.contains(&ext.as_str()) {
+        Ok(ext)
+    } else {
+        Err(format!("unsupported extension \"{}\" (expected one of: {})", ext, SUPPORTED_EXTS.join(", ")))
+    }
+}
+
+/// Keep only files whose extension is in `only` (jpg/jpeg and tif/tiff match each other)
+fn filter_extensions(files: Vec<PathBuf>, only: &[String]) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|f| {
+            let ext = f.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()).unwrap_or_default();
+            only.iter().any(|o| canonical_ext(o) == canonical_ext(&ext))
+        })
+        .collect()
+}
+
+/// Keep only files whose mtime is at or after `cutoff`; unreadable mtimes are dropped
+fn filter_modified_since(files: Vec<PathBuf>, cutoff: std::time::SystemTime) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|f| fs::metadata(f).and_then(|m| m.modified()).is_ok_and(|t| t >= cutoff))
+        .collect()
+}
+
+/// Per-directory option file; applies to everything below the directory it sits in
+const COMPRESSRC: &str = ".compressrc";
+
+/// Settings one `.compressrc` may override; unset keys fall through to shallower files
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RcSettings {
+    /// Quality range, from `compression-lvl = low|mid|max` or `quality = MIN-MAX`
+    quality: Option<String>,
+}
+
+/// `key = value` lines, blank lines and `#` comments skipped
+fn parse_compressrc(text: &str) -> Result<RcSettings, String> {
+    let mut settings = RcSettings::default();
+    for (n, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| format!("line {}: expected key = value", n))?;
+        let value = value.trim();
+        match key.trim().replace('_', "-").as_str() {
+            "compression-lvl" => {
+                if !["low", "mid", "max"].contains(&value.to_lowercase().as_str()) {
+                    return Err(format!("line {}: compression-lvl must be low, mid or max", n));
+                }
+                settings.quality = Some(compression_level_to_range(value));
+            }
+            "quality" => {
+                let valid = value
+                    .split_once('-')
+                    .and_then(|(lo, hi)| Some((lo.parse::<u8>().ok()?, hi.parse::<u8>().ok()?)))
+                    .is_some_and(|(lo, hi)| lo <= hi && hi <= 100);
+                if !valid {
+                    return Err(format!("line {}: quality must be a MIN-MAX range within 0-100", n));
+                }
+                settings.quality = Some(value.to_string());
+            }
+            other => return Err(format!("line {}: unknown key '{}'", n, other)),
+        }
+    }
+    Ok(settings)
+}
+
+/// Every `.compressrc` found while walking, keyed by the directory it applies to
+#[derive(Debug, Default)]
+struct DirOverrides {
+    dirs: std::collections::BTreeMap<PathBuf, RcSettings>,
+}
+
+impl DirOverrides {
+    /// Merge the `.compressrc` files above `file`, closest first, so deeper directories win
+    fn resolve(&self, file: &Path) -> RcSettings {
+        let mut merged = RcSettings::default();
+        for dir in file.ancestors().skip(1) {
+            if let Some(rc) = self.dirs.get(dir) {
+                merged.quality = merged.quality.or_else(|| rc.quality.clone());
+            }
+        }
+        merged
+    }
+}
+
+/// Supported images under `input_path` (and files with `extra_exts`, such as ZIP archives
+/// for --scan-archives), plus the `.compressrc` overrides met on the way
+fn discover_files(input_path: &Path, extra_exts: &[&str]) -> (Vec<PathBuf>, DirOverrides) {
+    let wanted = |ext: &str| SUPPORTED_EXTS.contains(&ext) || extra_exts.contains(&ext);
+    let mut overrides = DirOverrides::default();
+    if input_path.is_file() {
+        if let Some(ext) = input_path.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase())
+            && wanted(&ext)
+        {
+            return (vec![input_path.to_path_buf()], overrides);
+        }
+        return (vec![], overrides);
+    }
+
+    let mut files = vec![];
+    for entry in WalkDir::new(input_path).into_iter().filter_map(Result::ok) {
+        let p = entry.path();
+        if p.is_file() && p.file_name() == Some(OsStr::new(COMPRESSRC)) {
+            match fs::read_to_string(p).map_err(|e| e.to_string()).and_then(|text| parse_compressrc(&text)) {
+                Ok(rc) => {
+                    overrides.dirs.insert(p.parent().unwrap_or(input_path).to_path_buf(), rc);
+                }
+                Err(e) => eprintln!("{}: ignored: {}", p.display(), e),
+            }
+        } else if p.is_file()
+            && let Some(ext) = p.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase())
+            && wanted(&ext)
+        {
+            files.push(p.to_path_buf());
+        }
+    }
+    files.sort();
+    files.dedup();
+    (files, overrides)
+}
+
+/// Extensions beyond SUPPORTED_EXTS that this run picks up: archives for --scan-archives, and
+/// GIFs when --first-frame can flatten them or --preserve-animation should report them
+fn discovery_exts(args: &Args) -> Vec<&'static str> {
+    let mut exts = Vec::new();
+    if args.scan_archives {
+        exts.push("zip");
+    }
+    if args.first_frame || args.preserve_animation {
+        exts.push("gif");
+    }
+    exts
+}
+
+/// Paths from an --input-list body: one per line, blank lines and `#` comments skipped
+fn parse_input_list(text: &str) -> Vec<PathBuf> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+fn read_input_list(list: &Path) -> Result<Vec<PathBuf>> {
+    let text = if list == Path::new("-") {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        fs::read_to_string(list).map_err(|e| anyhow!("cannot read input list {}: {}", list.display(), e))?
+    };
+    Ok(parse_input_list(&text))
+}
+
+fn build_output_path(
+    src: &Path,
+    output_dir: &Option<PathBuf>,
+    overwrite: bool,
+    target_ext: Option<&str>,
+) -> PathBuf {
+    let base = if overwrite {
+        src.with_file_name(format!(
+            "c_{}",
+            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
+        ))
+    } else if let Some(out) = output_dir {
+        let _ = fs::create_dir_all(out);
+        out.join(format!(
+            "c_{}",
+            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
+        ))
+    } else {
+        src.with_file_name(format!(
+            "c_{}",
+            src.file_name().and_then(OsStr::to_str).unwrap_or("out")
+        ))
+    };
+
+    if let Some(ext) = target_ext {
+        base.with_extension(ext)
+    } else {
+        base
+    }
+}
+
+// Web server handlers
+async fn serve_index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+/// Formats this build can read and write, for the web UI's format picker
+#[derive(serde::Serialize)]
+struct FormatsResponse {
+    inputs: Vec<&'static str>,
+    outputs: Vec<String>,
+    /// Optional cargo features compiled in
+    features: Vec<&'static str>,
+}
+
+async fn formats_api() -> axum::Json<FormatsResponse> {
+    let outputs = OutputFormat::value_variants()
+        .iter()
+        .filter_map(|f| f.to_possible_value())
+        .map(|v| v.get_name().to_string())
+        .collect();
+    let mut features = Vec::new();
+    if cfg!(feature = "pdf") {
+        features.push("pdf");
+    }
+    if cfg!(feature = "avif-decode") {
+        features.push("avif-decode");
+    }
+    if cfg!(feature = "svg") {
+        features.push("svg");
+    }
+    axum::Json(FormatsResponse { inputs: SUPPORTED_EXTS.to_vec(), outputs, features })
+}
+
+/// How long a compressed upload waits for its download
+const SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// A finished `/api/upload` response, held until downloaded or expired
+struct StoredResult {
+    created: std::time::Instant,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Most results `/api/upload` holds at once, and their combined body size; uploads past
+/// either cap get a 503 until downloads or expiry free room
+const MAX_STORED_RESULTS: usize = 256;
+const MAX_STORED_BYTES: usize = 256 * 1024 * 1024;
+
+/// In-memory results of per-file uploads, keyed by download token
+struct UploadSessions {
+    results: Mutex<HashMap<String, StoredResult>>,
+    max_results: usize,
+    max_bytes: usize,
+}
+
+impl Default for UploadSessions {
+    fn default() -> Self {
+        Self::with_limits(MAX_STORED_RESULTS, MAX_STORED_BYTES)
+    }
+}
+
+impl UploadSessions {
+    fn with_limits(max_results: usize, max_bytes: usize) -> Self {
+        Self { results: Mutex::new(HashMap::new()), max_results, max_bytes }
+    }
+
+    /// Store a result under a fresh token, or None when the store is full
+    fn insert(&self, result: StoredResult) -> Option<String> {
+        let mut results = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        results.retain(|_, r| r.created.elapsed() < SESSION_TTL);
+        let held: usize = results.values().map(|r| r.body.len()).sum();
+        if results.len() >= self.max_results || held + result.body.len() > self.max_bytes {
+            return None;
+        }
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        results.insert(token.clone(), result);
+        Some(token)
+    }
+
+    /// Take a result out of the store (expired entries are dropped on the way)
+    fn take(&self, token: &str) -> Option<StoredResult> {
+        let mut results = self.results.lock().unwrap_or_else(|e| e.into_inner());
+        results.retain(|_, r| r.created.elapsed() < SESSION_TTL);
+        results.remove(token)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct UploadResponse {
+    token: String,
+    expires_in_secs: u64,
+}
+
+/// Compress one upload now (same fields as /api/compress) and return a token for its result
+async fn upload_api(
+    axum::Extension(sessions): axum::Extension<Arc<UploadSessions>>,
+    hub: axum::Extension<Arc<ProgressHub>>,
+    server_opts: axum::Extension<Arc<ServerOptions>>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> Result<axum::Json<UploadResponse>, ApiError> {
+    let response = compress_api(hub, server_opts, headers, multipart).await?;
+    let (parts, body) = response.into_parts();
+    // A result bigger than the whole store could never be held
+    let body = axum::body::to_bytes(body, sessions.max_bytes)
+        .await
+        .map_err(|e| ApiError::Internal(format!("result too large to hold for download: {}", e)))?;
+    let token = sessions
+        .insert(StoredResult {
+            created: std::time::Instant::now(),
+            status: parts.status,
+            headers: parts.headers,
+            body: body.to_vec(),
+        })
+        .ok_or_else(|| ApiError::Unavailable("too many results waiting for download".to_string()))?;
+    Ok(axum::Json(UploadResponse { token, expires_in_secs: SESSION_TTL.as_secs() }))
+}
+
+/// Hand back (once) the compressed result stored under `token`
+async fn download_api(
+    axum::Extension(sessions): axum::Extension<Arc<UploadSessions>>,
+    axum::extract::Path(token): axum::extract::Path<String>,
+) -> Result<Response, StatusCode> {
+    let stored = sessions.take(&token).ok_or(StatusCode::NOT_FOUND)?;
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() = stored.status;
+    *response.headers_mut() = stored.headers;
+    Ok(response)
+}
+
+/// One step of a web batch, streamed to `/api/progress/:session` subscribers
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum ProgressEvent {
+    Started { filename: String, original_size: usize },
+    Completed { filename: String, original_size: usize, compressed_size: usize },
+    Failed { filename: String, error: String },
+    Done { files: usize },
+}
+
+/// Per-session broadcast channels for progress events. Events for a session nobody
+/// is listening to are dropped.
+#[derive(Default)]
+struct ProgressHub {
+    sessions: Mutex<HashMap<String, tokio::sync::broadcast::Sender<ProgressEvent>>>,
+}
+
+impl ProgressHub {
+    fn subscribe(&self, session: &str) -> tokio::sync::broadcast::Receiver<ProgressEvent> {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        sessions.retain(|_, tx| tx.receiver_count() > 0);
+        sessions.entry(session.to_string()).or_insert_with(|| tokio::sync::broadcast::channel(64).0).subscribe()
+    }
+
+    fn publish(&self, session: &str, event: ProgressEvent) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let done = matches!(event, ProgressEvent::Done { .. });
+        if let Some(tx) = sessions.get(session) {
+            let _ = tx.send(event);
+        }
+        if done {
+            sessions.remove(session);
+        }
+    }
+}
+
+/// Where a compression request reports progress (if it named a session)
+struct ProgressReporter<'a> {
+    hub: &'a ProgressHub,
+    session: String,
+}
+
+/// Stream a session's progress events; the stream ends after its `done` event
+async fn progress_api(
+    axum::Extension(hub): axum::Extension<Arc<ProgressHub>>,
+    axum::extract::Path(session): axum::extract::Path<String>,
+) -> axum::response::sse::Sse<impl futures_util::Stream<Item = std::result::Result<axum::response::sse::Event, std::convert::Infallible>>> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use tokio::sync::broadcast::error::RecvError;
+
+    let rx = hub.subscribe(&session);
+    let stream = futures_util::stream::unfold((rx, false), |(mut rx, finished)| async move {
+        if finished {
+            return None;
+        }
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let done = matches!(event, ProgressEvent::Done { .. });
+                    let sse = Event::default().json_data(&event).unwrap_or_default();
+                    return Some((Ok(sse), (rx, done)));
+                }
+                // A slow client misses events rather than stalling the batch
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Correlation ID assigned by `SetRequestIdLayer` ("-" if the layer isn't installed)
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string()
+}
+
+/// Failure of a web API request, sent as `{"error": "...", "code": "..."}`
+#[derive(Debug)]
+enum ApiError {
+    BadRequest(String),
+    UnsupportedFormat(String),
+    Internal(String),
+    /// Temporarily out of capacity; clients should retry after RETRY_AFTER_SECS
+    Unavailable(String),
+}
+
+#[derive(serde::Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+}
+
+impl ApiError {
+    /// Undecodable (corrupt or truncated) input is the client's problem, unknown formats
+    /// get 415, the rest is ours
+    fn from_compress_error(e: &anyhow::Error) -> Self {
+        if e.downcast_ref::<InvalidInput>().is_some() {
+            return ApiError::BadRequest(e.to_string());
+        }
+        match e.downcast_ref::<image::ImageError>() {
+            Some(image::ImageError::Unsupported(_)) => ApiError::UnsupportedFormat(e.to_string()),
+            Some(image::ImageError::Decoding(_) | image::ImageError::IoError(_) | image::ImageError::Limits(_)) => {
+                ApiError::BadRequest(e.to_string())
+            }
+            _ => ApiError::Internal(e.to_string()),
+        }
+    }
+
+    fn parts(&self) -> (StatusCode, &'static str, &str) {
+        match self {
+            ApiError::BadRequest(msg) => (StatusCode::BAD_REQUEST, "bad_request", msg),
+            ApiError::UnsupportedFormat(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_format", msg),
+            ApiError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", msg),
+            ApiError::Unavailable(msg) => (StatusCode::SERVICE_UNAVAILABLE, "unavailable", msg),
+        }
+    }
+}
+
+impl axum::response::IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, code, msg) = self.parts();
+        let mut res = (status, axum::Json(ErrorBody { error: msg.to_string(), code })).into_response();
+        if matches!(self, ApiError::Unavailable(_)) {
+            res.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        }
+        res
+    }
+}
+
+/// Malformed multipart data or an unreadable field
+fn bad_form(e: impl std::fmt::Display) -> ApiError {
+    ApiError::BadRequest(format!("malformed form data: {}", e))
+}
+
+/// One uploaded (or fetched) input in a compress request
+struct UploadedFile {
+    filename: String,
+    bytes: Vec<u8>,
+}
+
+/// An input that never made it to compression (e.g. a remote fetch failure)
+struct FailedUpload {
+    filename: String,
+    error: String,
+    /// Transient failure the client may retry (reported via Retry-After)
+    retryable: bool,
+}
+
+/// Per-file entry in a batch manifest
+#[derive(serde::Serialize)]
+struct BatchEntry {
+    filename: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output_filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime_type: Option<String>,
+    original_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compressed_size: Option<usize>,
+    /// Base64-encoded output for successful files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    retryable: bool,
+}
+
+/// JSON body returned for multi-file uploads
+#[derive(serde::Serialize)]
+struct BatchManifest {
+    succeeded: usize,
+    failed: usize,
+    results: Vec<BatchEntry>,
+}
+
+// Seconds clients should wait before retrying transient batch failures
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// `compress_upload`, announcing the file's start and outcome to `progress`
+fn compress_upload_reporting(
+    req_id: &str,
+    upload: &UploadedFile,
+    opts: &CompressionOptions,
+    progress: Option<&ProgressReporter>,
+) -> Result<(Vec<u8>, String, String)> {
+    let Some(progress) = progress else {
+        return compress_upload(req_id, upload, opts);
+    };
+    let filename = upload.filename.clone();
+    let original_size = upload.bytes.len();
+    progress.hub.publish(&progress.session, ProgressEvent::Started { filename: filename.clone(), original_size });
+    let result = compress_upload(req_id, upload, opts);
+    let event = match &result {
+        Ok((bytes, _, _)) => ProgressEvent::Completed { filename, original_size, compressed_size: bytes.len() },
+        Err(e) => ProgressEvent::Failed { filename, error: e.to_string() },
+    };
+    progress.hub.publish(&progress.session, event);
+    result
+}
+
+/// Download name for a web API result: `c_<name>` when the output keeps the upload's format,
+/// else the name with only its last extension swapped for the output's
+/// ("my.photo.backup.png" -> "my.photo.backup.webp")
+fn api_output_filename(filename: &str, mime: &str) -> String {
+    let path = Path::new(filename);
+    let ext = path.extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+    match mime_extension(mime) {
+        Some(out_ext) if canonical_ext(out_ext) != canonical_ext(&ext) => path.with_extension(out_ext).to_string_lossy().into_owned(),
+        _ => format!("c_{}", filename),
+    }
+}
+
+/// Compress a single upload, returning (bytes, mime type, download filename)
+fn compress_upload(req_id: &str, upload: &UploadedFile, opts: &CompressionOptions) -> Result<(Vec<u8>, String, String)> {
+    if upload.bytes.is_empty() {
+        return Err(anyhow!("empty file"));
+    }
+
+    let filename = &upload.filename;
+
+    // Detect file extension
+    let ext = Path::new(filename).extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+    log::info!("🔍 API [{}]: Processing {} file: {} ({} bytes)", req_id, ext.to_uppercase(), filename, upload.bytes.len());
+    
+    // Compress the image
+    let start_time = std::time::Instant::now();
+    let (compressed_bytes, mime_type) = compress_image_inproc(&upload.bytes, &ext, opts)
+        .inspect_err(|e| log::error!("❌ API [{}]: Compression failed for {}: {:?}", req_id, filename, e))?;
+    
+    let duration = start_time.elapsed();
+    let compression_ratio = (1.0 - (compressed_bytes.len() as f64 / upload.bytes.len() as f64)) * 100.0;
+    log::info!("✅ API [{}]: Compressed {} in {:?} - {} -> {} bytes ({:.1}% reduction)", 
+               req_id, filename, duration, upload.bytes.len(), compressed_bytes.len(), compression_ratio);
+
+    let output_filename = api_output_filename(filename, &mime_type);
+    Ok((compressed_bytes, mime_type, output_filename))
+}
+
+/// Compress every upload independently; one bad file doesn't sink the batch.
+/// Returns 200 when all succeed, 207 Multi-Status on partial success, and
+/// 422 (or 503 if every failure is transient) when nothing succeeded.
+fn batch_response(
+    req_id: &str,
+    uploads: Vec<std::result::Result<UploadedFile, FailedUpload>>,
+    opts: &CompressionOptions,
+    progress: Option<&ProgressReporter>,
+) -> Result<Response, ApiError> {
+    use base64::Engine as _;
+
+    let results: Vec<BatchEntry> = uploads
+        .into_iter()
+        .map(|upload| match upload {
+            Ok(upload) => match compress_upload_reporting(req_id, &upload, opts, progress) {
+                Ok((bytes, mime_type, output_filename)) => BatchEntry {
+                    filename: upload.filename,
+                    ok: true,
+                    output_filename: Some(output_filename),
+                    mime_type: Some(mime_type),
+                    original_size: upload.bytes.len(),
+                    compressed_size: Some(bytes.len()),
+                    data: Some(base64::engine::general_purpose::STANDARD.encode(&bytes)),
+                    error: None,
+                    retryable: false,
+                },
+                Err(e) => BatchEntry {
+                    filename: upload.filename,
+                    ok: false,
+                    output_filename: None,
+                    mime_type: None,
+                    original_size: upload.bytes.len(),
+                    compressed_size: None,
+                    data: None,
+                    error: Some(e.to_string()),
+                    retryable: false,
+                },
+            },
+            Err(failed) => {
+                if let Some(progress) = progress {
+                    let event = ProgressEvent::Failed { filename: failed.filename.clone(), error: failed.error.clone() };
+                    progress.hub.publish(&progress.session, event);
+                }
+                BatchEntry {
+                    filename: failed.filename,
+                    ok: false,
+                    output_filename: None,
+                    mime_type: None,
+                    original_size: 0,
+                    compressed_size: None,
+                    data: None,
+                    error: Some(failed.error),
+                    retryable: failed.retryable,
+                }
+            }
+        })
+        .collect();
+
+    let succeeded = results.iter().filter(|r| r.ok).count();
+    let failed = results.len() - succeeded;
+    let any_retryable = results.iter().any(|r| r.retryable);
+    let all_failures_retryable = results.iter().filter(|r| !r.ok).all(|r| r.retryable);
+    log::info!("📦 API [{}]: Batch finished - {} succeeded, {} failed", req_id, succeeded, failed);
+
+    let status = if failed == 0 {
+        StatusCode::OK
+    } else if succeeded > 0 {
+        StatusCode::MULTI_STATUS
+    } else if all_failures_retryable {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+
+    let body = serde_json::to_vec(&BatchManifest { succeeded, failed, results })
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let mut builder = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json");
+    if any_retryable {
+        builder = builder.header(header::RETRY_AFTER, RETRY_AFTER_SECS.to_string());
+    }
+    builder.body(body.into()).map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// Web API defaults: webp output, mid compression, lossy PNG with oxipng
+fn api_default_options() -> CompressionOptions {
+    CompressionOptions { to_webp: true, ..Default::default() }
+}
+
+async fn compress_api(
+    axum::Extension(hub): axum::Extension<Arc<ProgressHub>>,
+    axum::Extension(server_opts): axum::Extension<Arc<ServerOptions>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, ApiError> {
+    let req_id = request_id(&headers);
+    let mut uploads: Vec<std::result::Result<UploadedFile, FailedUpload>> = Vec::new();
+    let mut opts = api_default_options();
+    let mut session = None;
+
+    while let Some(field) = multipart.next_field().await.map_err(bad_form)? {
+        let field_name = field.name().unwrap_or("").to_string();
+        
+        match field_name.as_str() {
+            "file" => {
+                let filename = field.file_name().unwrap_or("image").to_string();
+                let bytes = field.bytes().await.map_err(bad_form)?.to_vec();
+                uploads.push(Ok(UploadedFile { filename, bytes }));
+            }
+            "compression_lvl" => {
+                // Primary parameter: low, mid, or max (with optional granular control)
+                let value = field.text().await.map_err(bad_form)?;
+                opts.png_quality = compression_level_to_range(&value);
+            }
+            "preset" => {
+                // Same per-format qualities as --preset
+                let value = field.text().await.map_err(bad_form)?;
+                let preset = QualityPreset::from_str(&value, true)
+                    .map_err(|_| ApiError::BadRequest(format!("unknown preset \"{}\" (expected web, archive, max or lossless)", value)))?;
+                opts.preset = Some(preset);
+                opts.png_quality = preset.qualities().png_range();
+                opts.lossless |= preset == QualityPreset::Lossless;
+            }
+            "media_url" => {
+                // Fetch remote image from URL
+                let url = field.text().await.map_err(bad_form)?;
+                
+                // Try to extract filename from URL
+                let mut filename = String::new();
+                if let Ok(parsed_url) = url::Url::parse(&url)
+                    && let Some(segment) = parsed_url.path_segments().and_then(|mut segments| segments.next_back())
+                {
+                    filename = segment.to_string();
+                }
+                
+                if filename.is_empty() {
+                    filename = "image".to_string();
+                }
+
+                // Same host/address checks, size cap and timeouts as /api/compress-url;
+                // failures are kept so a batch can report them per file
+                match fetch_remote_image(&url, &server_opts.fetch_hosts, server_opts.fetch_private, MAX_UPLOAD_BYTES).await {
+                    Ok(bytes) => uploads.push(Ok(UploadedFile { filename, bytes })),
+                    Err(e) => {
+                        log::error!("❌ API [{}]: Failed to fetch image from URL: {}", req_id, url);
+                        let error = e.parts().2.to_string();
+                        uploads.push(Err(FailedUpload { filename, error, retryable: true }));
+                    }
+                }
+            }
+            "output_format" => {
+                let value = field.text().await.map_err(bad_form)?;
+                // Unknown values fall back to the webp default
+                OutputFormat::from_str(&value, false)
+                    .unwrap_or(OutputFormat::Webp)
+                    .apply(&mut opts);
+            }
+            "oxipng" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.oxipng = value == "true";
+            }
+            "png_lossy" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.png_lossy = value == "true";
+            }
+            "lossless" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.lossless = value == "true";
+            }
+            "preserve_bit_depth" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.preserve_bit_depth = value == "true";
+            }
+            "jpeg_restart" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.jpeg_restart = value
+                    .trim()
+                    .parse::<u16>()
+                    .map_err(|_| ApiError::BadRequest("jpeg_restart must be 0-65535".to_string()))?;
+            }
+            "jpeg_scan_mode" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.jpeg_scan_mode = JpegScanMode::from_str(value.trim(), true).map_err(|_| {
+                    ApiError::BadRequest("jpeg_scan_mode must be all-components-together, scan-per-component or auto".to_string())
+                })?;
+            }
+            "jpeg_smoothing" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.jpeg_smoothing = match value.trim().parse::<u8>() {
+                    Ok(v) if v <= 100 => v,
+                    _ => return Err(ApiError::BadRequest("jpeg_smoothing must be 0-100".to_string())),
+                };
+            }
+            "jpeg_effort" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.jpeg_effort = JpegEffort::from_str(value.trim(), true)
+                    .map_err(|_| ApiError::BadRequest("jpeg_effort must be fast, balanced or best".to_string()))?;
+            }
+            "webp_method" => {
+                let value = field.text().await.map_err(bad_form)?;
+                opts.webp_method = match value.trim().parse::<u8>() {
+                    Ok(m) if m <= 6 => m,
+                    _ => return Err(ApiError::BadRequest("webp_method must be 0-6".to_string())),
+                };
+            }
+            "session" => {
+                // Report per-file progress to GET /api/progress/<session>
+                let value = field.text().await.map_err(bad_form)?;
+                session = Some(value.trim().to_string()).filter(|s| !s.is_empty());
+            }
+            _ => {}
+        }
+    }
+
+    let progress = session.map(|session| ProgressReporter { hub: &hub, session });
+    let files = uploads.len();
+    let response = compress_uploads_response(&req_id, uploads, &opts, progress.as_ref());
+    if let Some(progress) = &progress {
+        progress.hub.publish(&progress.session, ProgressEvent::Done { files });
+    }
+    response
+}
+
+/// The /api/compress response: the image itself for one upload, a JSON report for several
+fn compress_uploads_response(
+    req_id: &str,
+    mut uploads: Vec<std::result::Result<UploadedFile, FailedUpload>>,
+    opts: &CompressionOptions,
+    progress: Option<&ProgressReporter>,
+) -> Result<Response, ApiError> {
+    // Several files: compress each independently and report per-file results
+    if uploads.len() > 1 {
+        return batch_response(req_id, uploads, opts, progress);
+    }
+
+    let upload = match uploads.pop() {
+        Some(Ok(upload)) if !upload.bytes.is_empty() => upload,
+        Some(Err(failed)) => return Err(ApiError::BadRequest(failed.error)),
+        _ => {
+            log::error!("❌ API [{}]: No file data received", req_id);
+            return Err(ApiError::BadRequest("no file data received".to_string()));
+        }
+    };
+
+    let (compressed_bytes, mime_type, output_filename) =
+        compress_upload_reporting(req_id, &upload, opts, progress).map_err(|e| ApiError::from_compress_error(&e))?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime_type)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", output_filename))
+        .body(compressed_bytes.into())
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    Ok(response)
+}
+
+/// Largest request body (upload) axum accepts, and the largest remote image the web API fetches
+const MAX_UPLOAD_BYTES: usize = 2 * 1024 * 1024;
+
+/// JSON body of `POST /api/compress-url`
+#[derive(serde::Deserialize)]
+struct CompressUrlRequest {
+    url: String,
+    /// Same values as the form's `output_format` (default webp)
+    #[serde(default)]
+    format: Option<String>,
+    /// Same values as the form's `compression_lvl` (default mid)
+    #[serde(default)]
+    quality: Option<String>,
+    /// Return a JSON entry with base64 data instead of the raw image
+    #[serde(default)]
+    base64: bool,
+}
+
+/// Loopback, private (RFC 1918, IPv6 unique local), link-local (including the 169.254.169.254
+/// cloud metadata endpoint) and unspecified addresses: the server's own network
+fn is_internal_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified() || v4.is_broadcast(),
+        std::net::IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_unique_local()
+                || v6.is_unicast_link_local()
+                || v6.to_ipv4_mapped().is_some_and(|v4| is_internal_ip(v4.into()))
+        }
+    }
+}
+
+/// Whether the web API may fetch from `url`: http(s) only, one of `allow_hosts` if any are set,
+/// and no internal IP literal unless `allow_private`. Names are checked once resolved.
+fn check_fetch_url(url: &url::Url, allow_hosts: &[String], allow_private: bool) -> std::result::Result<(), String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("only http and https URLs can be fetched (got {}:)", url.scheme()));
+    }
+    let host = url.host_str().unwrap_or("");
+    if !allow_hosts.is_empty() && !allow_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)) {
+        return Err(format!("host \"{}\" is not an allowed fetch host", host));
+    }
+    let ip = match url.host() {
+        Some(url::Host::Ipv4(v4)) => Some(std::net::IpAddr::V4(v4)),
+        Some(url::Host::Ipv6(v6)) => Some(std::net::IpAddr::V6(v6)),
+        _ => None,
+    };
+    if !allow_private && ip.is_some_and(is_internal_ip) {
+        return Err(format!("host \"{}\" is a loopback, private or link-local address", host));
+    }
+    Ok(())
+}
+
+/// DNS for remote image fetches that drops internal addresses, so a public-looking name can't
+/// point the server back at its own network. It runs for every connection, redirects included.
+struct PublicOnlyResolver;
+
+impl reqwest::dns::Resolve for PublicOnlyResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<std::net::SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.filter(|addr| !is_internal_ip(addr.ip())).collect();
+            if addrs.is_empty() {
+                return Err(format!("{} only resolves to loopback, private or link-local addresses", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// How long remote image fetches wait to connect, and then between body reads
+const FETCH_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const FETCH_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Download a remote image for the web API, refusing anything over `max_bytes`.
+/// Redirects are held to the same URL checks as the original request.
+async fn fetch_remote_image(
+    url: &str,
+    allow_hosts: &[String],
+    allow_private: bool,
+    max_bytes: usize,
+) -> std::result::Result<Vec<u8>, ApiError> {
+    let parsed = url::Url::parse(url).map_err(|e| ApiError::BadRequest(format!("invalid url \"{}\": {}", url, e)))?;
+    check_fetch_url(&parsed, allow_hosts, allow_private).map_err(ApiError::BadRequest)?;
+    let redirect_hosts = allow_hosts.to_vec();
+    let mut client = reqwest::Client::builder()
+        .connect_timeout(FETCH_CONNECT_TIMEOUT)
+        .read_timeout(FETCH_READ_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 5 {
+                attempt.error("too many redirects")
+            } else if let Err(e) = check_fetch_url(attempt.url(), &redirect_hosts, allow_private) {
+                attempt.error(e)
+            } else {
+                attempt.follow()
+            }
+        }));
+    if !allow_private {
+        client = client.dns_resolver(Arc::new(PublicOnlyResolver));
+    }
+    let client = client.build().map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    let mut response = client.get(parsed).send().await.map_err(|e| {
+        // reqwest's message leaves out the cause, e.g. the resolver's refusal
+        let mut msg = e.to_string();
+        let mut cause = std::error::Error::source(&e);
+        while let Some(c) = cause {
+            msg.push_str(&format!(": {}", c));
+            cause = c.source();
+        }
+        ApiError::BadRequest(format!("failed to fetch {}: {}", url, msg))
+    })?;
+    if !response.status().is_success() {
+        return Err(ApiError::BadRequest(format!("fetching {} returned {}", url, response.status())));
+    }
+    let too_large = || ApiError::BadRequest(format!("{} is larger than the {} byte upload limit", url, max_bytes));
+    if response.content_length().is_some_and(|len| len > max_bytes as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(|e| ApiError::BadRequest(format!("failed to read body from {}: {}", url, e)))? {
+        body.extend_from_slice(&chunk);
+        if body.len() > max_bytes {
+            return Err(too_large());
+        }
+    }
+    Ok(body)
+}
+
+/// Fetch an image server-side and compress it; JSON in, image (or base64 JSON) out
+async fn compress_url_api(
+    axum::Extension(server_opts): axum::Extension<Arc<ServerOptions>>,
+    headers: HeaderMap,
+    axum::Json(req): axum::Json<CompressUrlRequest>,
+) -> Result<Response, ApiError> {
+    use base64::Engine as _;
+
+    let req_id = request_id(&headers);
+    let mut opts = api_default_options();
+    if let Some(format) = &req.format {
+        OutputFormat::from_str(format, false)
+            .map_err(|_| ApiError::BadRequest(format!("unknown format \"{}\"", format)))?
+            .apply(&mut opts);
+    }
+    if let Some(quality) = &req.quality {
+        opts.png_quality = compression_level_to_range(quality);
+    }
+
+    let bytes = fetch_remote_image(&req.url, &server_opts.fetch_hosts, server_opts.fetch_private, MAX_UPLOAD_BYTES).await?;
+    let filename = url::Url::parse(&req.url)
+        .ok()
+        .and_then(|u| u.path_segments().and_then(|mut segments| segments.next_back()).map(str::to_string))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "image".to_string());
+    let upload = UploadedFile { filename, bytes };
+    let (compressed_bytes, mime_type, output_filename) =
+        compress_upload(&req_id, &upload, &opts).map_err(|e| ApiError::from_compress_error(&e))?;
+
+    if req.base64 {
+        let entry = BatchEntry {
+            filename: upload.filename,
+            ok: true,
+            output_filename: Some(output_filename),
+            mime_type: Some(mime_type),
+            original_size: upload.bytes.len(),
+            compressed_size: Some(compressed_bytes.len()),
+            data: Some(base64::engine::general_purpose::STANDARD.encode(&compressed_bytes)),
+            error: None,
+            retryable: false,
+        };
+        return Ok(axum::response::IntoResponse::into_response(axum::Json(entry)));
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime_type)
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", output_filename))
+        .body(compressed_bytes.into())
+        .map_err(|e| ApiError::Internal(e.to_string()))
+}
+
+/// Web server settings derived from CLI args
+#[derive(Debug, Clone, Default)]
+struct ServerOptions {
+    http_compression: bool,
+    open_browser: bool,
+    /// Allowed CORS origins; empty means permissive
+    cors_origins: Vec<HeaderValue>,
+    /// Hosts /api/compress-url may fetch from; empty means any public http(s) host
+    fetch_hosts: Vec<String>,
+    /// Whether /api/compress-url may reach loopback, private and link-local addresses
+    fetch_private: bool,
+}
+
+impl ServerOptions {
+    fn from_args(args: &Args) -> Self {
+        Self {
+            http_compression: args.http_compression,
+            open_browser: !args.no_open,
+            cors_origins: args.cors_origins.clone(),
+            fetch_hosts: args.fetch_hosts.clone(),
+            fetch_private: args.fetch_allow_private,
+        }
+    }
+}
+
+/// "scheme://host[:port]" exactly as browsers send it in the Origin header
+fn parse_cors_origin(s: &str) -> std::result::Result<HeaderValue, String> {
+    let origin = s.trim().trim_end_matches('/');
+    let host = origin.strip_prefix("https://").or_else(|| origin.strip_prefix("http://")).unwrap_or("");
+    if host.is_empty() || host.contains('/') {
+        return Err(format!("invalid CORS origin \"{}\" (expected e.g. https://app.example.com)", s));
+    }
+    HeaderValue::from_str(origin).map_err(|e| format!("invalid CORS origin \"{}\": {}", s, e))
+}
+
+/// Only the listed origins, or anything when none were given
+fn cors_layer(origins: &[HeaderValue]) -> CorsLayer {
+    if origins.is_empty() {
+        return CorsLayer::permissive();
+    }
+    CorsLayer::new()
+        .allow_origin(tower_http::cors::AllowOrigin::list(origins.iter().cloned()))
+        .allow_methods([axum::http::Method::GET, axum::http::Method::POST])
+        .allow_headers(tower_http::cors::Any)
+        .expose_headers([header::HeaderName::from_static(REQUEST_ID_HEADER)])
+}
+
+/// Brotli/gzip for clients that accept it; already-compressed images and the SSE progress
+/// stream (which has to reach the browser event by event) are sent as-is
+fn http_compression_layer() -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::default()
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::const_new("image/png"))
+        .and(NotForContentType::const_new("image/jpeg"))
+        .and(NotForContentType::const_new("image/webp"))
+        .and(NotForContentType::const_new("image/avif"))
+        .and(NotForContentType::const_new("image/gif"));
+    CompressionLayer::new().gzip(true).br(true).compress_when(predicate)
+}
+
+fn build_router(server_opts: &ServerOptions) -> Router {
+    let router = Router::new()
+        .route("/", get(serve_index))
+        .route("/api/compress", post(compress_api))
+        .route("/api/formats", get(formats_api))
+        .route("/api/upload", post(upload_api))
+        .route("/api/download/:token", get(download_api))
+        .route("/api/compress-url", post(compress_url_api))
+        .route("/api/progress/:session", get(progress_api))
+        .layer(axum::Extension(Arc::new(UploadSessions::default())))
+        .layer(axum::Extension(Arc::new(ProgressHub::default())))
+        .layer(axum::Extension(Arc::new(server_opts.clone())))
+        .layer(axum::extract::DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .layer(
+            ServiceBuilder::new()
+                // Tag every request with a UUID, log it with timing/status, and echo it back
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(|req: &Request<_>| {
+                            tracing::info_span!(
+                                "request",
+                                request_id = %request_id(req.headers()),
+                                method = %req.method(),
+                                uri = %req.uri(),
+                            )
+                        })
+                        .on_response(|res: &Response, latency: std::time::Duration, _span: &tracing::Span| {
+                            log::info!(
+                                "📡 HTTP [{}]: {} in {:?}",
+                                request_id(res.headers()),
+                                res.status(),
+                                latency
+                            );
+                        }),
+                )
+                .layer(PropagateRequestIdLayer::x_request_id())
+                .layer(cors_layer(&server_opts.cors_origins))
+        );
+
+    if server_opts.http_compression {
+        router.layer(http_compression_layer())
+    } else {
+        router
+    }
+}
+
+async fn start_web_server(port: u16, server_opts: ServerOptions) -> Result<()> {
+    let app = build_router(&server_opts);
+
+    let addr = format!("127.0.0.1:{}", port);
+    let listener = TcpListener::bind(&addr).await
+        .map_err(|e| anyhow!("Failed to bind to {}: {}", addr, e))?;
+        
+    println!("🚀 Web UI running at http://localhost:{}", port);
+    if server_opts.cors_origins.is_empty() {
+        eprintln!("⚠️  CORS is permissive (any site may call the API); pass --cors-origin to restrict it");
+    }
+    
+    // Auto-open browser unless --no-open
+    if !server_opts.open_browser || webbrowser::open(&format!("http://localhost:{}", port)).is_err() {
+        println!("💡 Open http://localhost:{} in your browser", port);
+    }
+    
+    axum::serve(listener, app)
+        .await
+        .map_err(|e| anyhow!("Server error: {}", e))?;
+        
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    apply_decode_memory_limit(&args);
+
+    // Auto-detect mode: web if no input provided or --web flag
+    if args.web || (args.input.is_none() && args.input_list.is_none()) {
+        return start_web_server(args.port, ServerOptions::from_args(&args)).await;
+    }
+
+    // CLI mode
+    run_cli_mode(&args).await
+}
+
+/// Byte sink for archive output that needs an explicit finish (e.g. the gzip trailer)
+trait ArchiveSink: Write + Send {
+    fn finish_sink(self: Box<Self>) -> std::io::Result<()>;
+}
+
+impl ArchiveSink for fs::File {
+    fn finish_sink(self: Box<Self>) -> std::io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl<W: Write + Send> ArchiveSink for flate2::write::GzEncoder<W> {
+    fn finish_sink(self: Box<Self>) -> std::io::Result<()> {
+        self.finish().map(|_| ())
+    }
+}
+
+/// Tar archive of compressed outputs: regular files only
+struct TarWriter {
+    builder: tar::Builder<Box<dyn ArchiveSink>>,
+    /// Modification time stamped on every entry (0 in deterministic mode)
+    mtime: u64,
+}
+
+impl TarWriter {
+    /// Create `path`, gzip-compressing when it ends in .tar.gz or .tgz
+    fn create(path: &Path) -> Result<Self> {
+        let file = fs::File::create(path)?;
+        let name = path.to_string_lossy().to_lowercase();
+        let out: Box<dyn ArchiveSink> = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Box::new(flate2::write::GzEncoder::new(file, flate2::Compression::default()))
+        } else {
+            Box::new(file)
+        };
+        let mtime = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Self { builder: tar::Builder::new(out), mtime })
+    }
+
+    /// Long names go into the ustar prefix field, or a GNU long-name entry past that
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(self.mtime);
+        self.builder.append_data(&mut header, name, data)?;
+        Ok(())
+    }
+
+    /// Write the end-of-archive marker and flush everything to disk
+    fn finish(self) -> Result<()> {
+        let mut out = self.builder.into_inner()?;
+        out.flush()?;
+        out.finish_sink()?;
+        Ok(())
+    }
+}
+
+/// Per-run state shared by every file in CLI mode
+struct CliContext {
+    /// Directory relative paths are computed from (archive entry names)
+    input_root: PathBuf,
+    output_dir: Option<PathBuf>,
+    quality: String,
+    tar: Option<Mutex<TarWriter>>,
+    /// Original → fingerprinted output names collected for --hash-names
+    hashed_names: Mutex<std::collections::BTreeMap<String, String>>,
+    /// Per-directory `.compressrc` settings found during discovery
+    overrides: DirOverrides,
+    /// Written outputs collected for --manifest-out
+    outputs: Mutex<std::collections::BTreeMap<String, OutputEntry>>,
+    /// Set by the first failure under --fail-fast
+    cancelled: AtomicBool,
+    /// Input → final output path, for --dedup to link duplicates to and --contact-sheet
+    /// to read the compressed pixels from
+    written: Mutex<HashMap<PathBuf, PathBuf>>,
+}
+
+/// Size and content hash of one written output in a --manifest-out file
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+struct OutputEntry {
+    size: u64,
+    sha256: String,
+}
+
+/// `p` relative to `base`, with `/` separators on every platform
+fn slash_relative(p: &Path, base: &Path) -> String {
+    let p = p.strip_prefix(base).unwrap_or(p);
+    p.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+impl CliContext {
+    fn new(input_root: PathBuf, output_dir: Option<PathBuf>, quality: &str) -> Self {
+        Self {
+            input_root,
+            output_dir,
+            quality: quality.to_string(),
+            tar: None,
+            hashed_names: Mutex::new(std::collections::BTreeMap::new()),
+            overrides: DirOverrides::default(),
+            outputs: Mutex::new(std::collections::BTreeMap::new()),
+            cancelled: AtomicBool::new(false),
+            written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Quality range for `file`: its closest `.compressrc`, else the run-wide one
+    fn quality_for(&self, file: &Path) -> String {
+        self.overrides.resolve(file).quality.unwrap_or_else(|| self.quality.clone())
+    }
+
+    /// Directory the hash manifest lives in; its entries are relative to it
+    fn manifest_dir(&self) -> &Path {
+        self.output_dir.as_deref().unwrap_or(&self.input_root)
+    }
+
+    fn record_hashed_name(&self, original: &Path, hashed: &Path) {
+        let (key, value) = (slash_relative(original, &self.input_root), slash_relative(hashed, self.manifest_dir()));
+        self.hashed_names.lock().unwrap_or_else(|e| e.into_inner()).insert(key, value);
+    }
+
+    fn record_output(&self, path: &Path, bytes: &[u8]) {
+        let entry = OutputEntry { size: bytes.len() as u64, sha256: sha256_hex(bytes) };
+        self.outputs.lock().unwrap_or_else(|e| e.into_inner()).insert(slash_relative(path, self.manifest_dir()), entry);
+    }
+}
+
+/// Full lowercase hex SHA-256 of `bytes`
+fn sha256_hex(bytes: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, bytes).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Short hex fingerprint of output bytes (truncated BLAKE3)
+fn content_hash(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex()[..8].to_string()
+}
+
+/// `<stem>.<hash>.<ext>` for cache-busting asset names
+fn hashed_file_name(stem: &str, bytes: &[u8], ext: &str) -> String {
+    if ext.is_empty() {
+        format!("{}.{}", stem, content_hash(bytes))
+    } else {
+        format!("{}.{}.{}", stem, content_hash(bytes), ext)
+    }
+}
+
+const TEMPLATE_PLACEHOLDERS: &[&str] = &["name", "ext", "width", "height", "quality", "hash"];
+
+/// Check an output name template: balanced braces and only known placeholders
+fn parse_template(s: &str) -> std::result::Result<String, String> {
+    let mut rest = s;
+    while let Some(open) = rest.find(['{', '}']) {
+        if rest.as_bytes()[open] == b'}' {
+            return Err(format!("unmatched '}}' in template \"{}\"", s));
+        }
+        let close = rest[open..].find('}').ok_or_else(|| format!("unclosed '{{' in template \"{}\"", s))?;
+        let name = &rest[open + 1..open + close];
+        if !TEMPLATE_PLACEHOLDERS.contains(&name) {
+            return Err(format!("unknown placeholder {{{}}} (expected one of {})", name, TEMPLATE_PLACEHOLDERS.join(", ")));
+        }
+        rest = &rest[open + close + 1..];
+    }
+    if s.trim().is_empty() {
+        return Err("template is empty".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Values available to `--template` for one output
+struct TemplateVars<'a> {
+    name: &'a str,
+    ext: &'a str,
+    dimensions: Option<(u32, u32)>,
+    quality: &'a str,
+    bytes: &'a [u8],
+}
+
+/// Substitute placeholders in a template already checked by `parse_template`
+fn expand_template(template: &str, vars: &TemplateVars) -> String {
+    let (width, height) = vars.dimensions.map_or(("0".to_string(), "0".to_string()), |(w, h)| (w.to_string(), h.to_string()));
+    template
+        .replace("{name}", vars.name)
+        .replace("{ext}", vars.ext)
+        .replace("{width}", &width)
+        .replace("{height}", &height)
+        .replace("{quality}", vars.quality)
+        .replace("{hash}", &content_hash(vars.bytes))
+}
+
+/// Pixel size of an encoded output, including the WebP/AVIF the image crate can't read
+fn output_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if let Ok(dims) = image::ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?.into_dimensions() {
+        return Some(dims);
+    }
+    if let Some(features) = webp::BitstreamFeatures::new(bytes) {
+        return Some((features.width(), features.height()));
+    }
+    // AVIF: the primary item's `ispe` property (version/flags, then width and height)
+    let at = find_bytes(bytes, b"ispe", 0)? + 8;
+    let field = |o: usize| bytes.get(at + o..at + o + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    Some((field(0)?, field(4)?))
+}
+
+/// Parse "5MB", "800KB", "1.5GiB" or a plain byte count
+fn parse_byte_size(s: &str) -> std::result::Result<u64, String> {
+    let t = s.trim();
+    let split = t.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(t.len());
+    let (num, unit) = t.split_at(split);
+    let num: f64 = num.parse().map_err(|_| format!("invalid size \"{}\"", s))?;
+    let scale = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1.0,
+        "kb" | "k" => 1e3,
+        "mb" | "m" => 1e6,
+        "gb" | "g" => 1e9,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        other => return Err(format!("unknown size unit \"{}\" in \"{}\"", other, s)),
+    };
+    Ok((num * scale) as u64)
+}
+
+/// Combined output size of `files` at `quality`, compressed in memory
+fn batch_output_size(files: &[PathBuf], args: &Args, quality: &str) -> u64 {
+    let opts = CompressionOptions::from_args(args, quality);
+    files
+        .par_iter()
+        .filter_map(|f| {
+            let ext = f.extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+            let bytes = fs::read(f).ok()?;
+            compress_image_inproc(&bytes, &ext, &opts).ok().map(|(out, _)| out.len() as u64)
+        })
+        .sum()
+}
+
+/// Lower the quality range until the batch fits `budget`: each pass scales it by
+/// budget / total. Returns the range and its predicted total
+fn fit_quality_to_budget(files: &[PathBuf], args: &Args, quality: &str, budget: u64) -> (String, u64) {
+    const MIN_QUALITY: f64 = 5.0;
+    let mut quality = quality.to_string();
+    let mut total = batch_output_size(files, args, &quality);
+    for _ in 0..6 {
+        if total <= budget {
+            break;
+        }
+        let (min_q, max_q) = parse_quality_range(&quality);
+        if (max_q as f64) <= MIN_QUALITY {
+            break;
+        }
+        let factor = budget as f64 / total as f64;
+        let max_next = (max_q as f64 * factor).floor().clamp(MIN_QUALITY, max_q as f64 - 1.0);
+        let min_next = (min_q as f64 * factor).floor().min(max_next);
+        let next = format!("{}-{}", min_next as u8, max_next as u8);
+        let next_total = batch_output_size(files, args, &next);
+        log::info!("💰 Budget: {} at quality {} -> {} at {}", human_size(total), quality, human_size(next_total), next);
+        (quality, total) = (next, next_total);
+    }
+    (quality, total)
+}
+
+/// Outcome of processing a single file in CLI mode
+#[derive(Debug, Clone, PartialEq)]
+enum FileStatus {
+    Compressed,
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+struct FileResult {
+    path: PathBuf,
+    before: u64,
+    after: u64,
+    status: FileStatus,
+}
+
+impl FileResult {
+    fn failed(path: PathBuf, before: u64, msg: String) -> Self {
+        Self { path, before, after: 0, status: FileStatus::Failed(msg) }
+    }
+}
+
+/// Percentage saved going from `before` to `after` bytes (negative if it grew)
+fn savings_percent(before: u64, after: u64) -> f64 {
+    if before == 0 {
+        return 0.0;
+    }
+    (before as f64 - after as f64) / before as f64 * 100.0
+}
+
+/// True when the reduction doesn't reach `min_pct` percent
+fn below_min_savings(before: u64, after: u64, min_pct: f64) -> bool {
+    savings_percent(before, after) < min_pct
+}
+
+/// Check that encoded output is a complete, decodable image
+fn validate_output(bytes: &[u8]) -> Result<()> {
+    // Minified SVG: text, so only the root element can be checked
+    if let Ok(text) = std::str::from_utf8(bytes)
+        && text.contains("<svg")
+    {
+        return if text.trim_end().ends_with("</svg>") { Ok(()) } else { Err(anyhow!("output SVG is truncated")) };
+    }
+    let kind = infer::get(bytes).ok_or_else(|| anyhow!("output is not a recognized image"))?;
+    match kind.mime_type() {
+        "image/webp" => {
+            webp::Decoder::new(bytes)
+                .decode()
+                .ok_or_else(|| anyhow!("output WebP does not decode"))?;
+        }
+        // No AVIF decoder is compiled in; the container signature is the best we can check
+        "image/avif" => {}
+        "application/zip" => {
+            read_zip(bytes)?;
+        }
+        _ => {
+            load_image(bytes).map_err(|e| anyhow!("output does not decode: {}", e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the marker left next to a file whose in-place compression failed
+fn failure_marker_path(src: &Path) -> PathBuf {
+    let mut name = src.file_name().unwrap_or_default().to_os_string();
+    name.push(".failed");
+    src.with_file_name(name)
+}
+
+/// First wait between attempts under --retries; doubles after each one
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// IO failures worth another attempt; bad input fails the same way every time
+fn is_transient_failure(res: &FileResult) -> bool {
+    matches!(&res.status, FileStatus::Failed(msg) if msg.starts_with("read-failed") || msg.starts_with("write-failed"))
+}
+
+/// Run `attempt` until it succeeds or fails for good, at most `retries` extra times.
+/// Returns the last result and how many retries it took
+fn with_retries(retries: u32, backoff: std::time::Duration, mut attempt: impl FnMut() -> FileResult) -> (FileResult, u32) {
+    let mut res = attempt();
+    let mut used = 0;
+    while used < retries && is_transient_failure(&res) {
+        std::thread::sleep(backoff * 2u32.saturating_pow(used));
+        used += 1;
+        res = attempt();
+    }
+    (res, used)
+}
+
+/// `process_file` once per `--formats` entry (as if each were a `--map` for this file's
+/// extension), or just once without it
+fn process_file_formats(f: &Path, args: &Args, ctx: &CliContext) -> Vec<FileResult> {
+    if let Some(qualities) = &args.quality_sweep {
+        return process_quality_sweep(f, qualities, args, ctx);
+    }
+    let Some(formats) = &args.formats else {
+        return vec![process_file(f, args, ctx)];
+    };
+    let ext = f.extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+    per_output(formats, args, |&format| {
+        let mut single = args.clone();
+        single.formats = None;
+        single.map = Some(HashMap::from([(canonical_ext(&ext).to_string(), format)]));
+        process_file(f, &single, ctx)
+    })
+}
+
+/// Map over the outputs of one input: in parallel normally, but one at a time under
+/// --memory-budget, which only reserved a single decode for this file
+fn per_output<I: Sync, T: Send>(items: &[I], args: &Args, work: impl Fn(&I) -> T + Sync + Send) -> Vec<T> {
+    if args.memory_budget.is_some() {
+        items.iter().map(work).collect()
+    } else {
+        items.par_iter().map(work).collect()
+    }
+}
+
+/// One `<stem>-q<N>.<ext>` output per `--quality-sweep` entry, each encoded at exactly that
+/// quality; sizes are printed as they're written
+fn process_quality_sweep(f: &Path, qualities: &[u8], args: &Args, ctx: &CliContext) -> Vec<FileResult> {
+    let before = fs::metadata(f).map(|m| m.len()).unwrap_or(0);
+    let input_bytes = match fs::read(f) {
+        Ok(bytes) => bytes,
+        Err(e) => return vec![FileResult::failed(f.to_path_buf(), before, format!("read-failed: {}", e))],
+    };
+    let ext = f.extension().and_then(OsStr::to_str).map(str::to_lowercase).unwrap_or_default();
+    let stem = f.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+    let out_dir = build_output_path(f, &ctx.output_dir, false, None).parent().map(Path::to_path_buf).unwrap_or_default();
+    per_output(qualities, args, |&q| {
+        let opts = CompressionOptions::from_args(args, &format!("{}-{}", q, q));
+        let (bytes, mime) = match compress_image_inproc(&input_bytes, &ext, &opts) {
+            Ok(out) => out,
+            Err(e) => return FileResult::failed(f.to_path_buf(), before, format!("compress-failed at q{}: {}", q, e)),
+        };
+        let out_path = out_dir.join(format!("{}-q{}.{}", stem, q, mime_extension(&mime).unwrap_or(&ext)));
+        if !args.dry_run
+            && let Err(e) = write_atomic(&out_path, |w| w.write_all(&bytes))
+        {
+            return FileResult::failed(f.to_path_buf(), before, format!("write-failed: {}", e));
+        }
+        println!("{}: {} at quality {}", out_path.display(), human_size(bytes.len() as u64), q);
+        FileResult { path: f.to_path_buf(), before, after: bytes.len() as u64, status: FileStatus::Compressed }
+    })
+}
+
+/// Read, compress and write a single file according to CLI args
+fn process_file(f: &Path, args: &Args, ctx: &CliContext) -> FileResult {
+    if args.fail_fast && ctx.cancelled.load(Ordering::Relaxed) {
+        let before = fs::metadata(f).map(|m| m.len()).unwrap_or(0);
+        return FileResult {
+            path: f.to_path_buf(),
+            before,
+            after: before,
+            status: FileStatus::Skipped("not started (--fail-fast)".to_string()),
+        };
+    }
+    let (res, retried) = with_retries(args.retries, RETRY_BACKOFF, || process_file_inner(f, args, ctx));
+    if args.verbose && retried > 0 {
+        let outcome = if matches!(res.status, FileStatus::Failed(_)) { "still failing" } else { "succeeded" };
+        eprintln!("{}: {} after {} retr{}", f.display(), outcome, retried, if retried == 1 { "y" } else { "ies" });
+    }
+    if args.fail_fast && matches!(res.status, FileStatus::Failed(_)) {
+        ctx.cancelled.store(true, Ordering::Relaxed);
+    }
+    if args.overwrite
+        && args.keep_original_on_error
+        && !args.dry_run
+        && let FileStatus::Failed(msg) = &res.status
+    {
+        let marker = failure_marker_path(f);
+        if let Err(e) = fs::write(&marker, format!("{}\n", msg)) {
+            eprintln!("{}: could not write failure marker: {}", marker.display(), e);
+        }
+    }
+    res
+}
+
+fn process_file_inner(f: &Path, args: &Args, ctx: &CliContext) -> FileResult {
+    let fname = f.to_path_buf();
+    let before = fs::metadata(&fname).map(|m| m.len()).unwrap_or(0);
+
+    // load file
+    let input_bytes = match read_input(&fname, before, args.stream_threshold) {
+        Ok(bytes) => bytes,
+        Err(e) => return FileResult::failed(fname, before, format!("read-failed: {}", e)),
+    };
+
+    let ext = fname
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|s| s.to_lowercase())
+        .unwrap_or_default();
+
+    let skip_compressed = args.skip_compressed && !args.no_skip_compressed;
+    if skip_compressed {
+        let prefixed = fname.file_name().and_then(OsStr::to_str).is_some_and(|n| n.starts_with("c_"));
+        if prefixed || has_output_marker(&input_bytes) {
+            return FileResult {
+                path: fname,
+                before,
+                after: before,
+                status: FileStatus::Skipped("already compressed".to_string()),
+            };
+        }
+    }
+
+    if args.strict_format
+        && let Err(e) = check_format_matches(&input_bytes, &ext)
+    {
+        return FileResult::failed(fname, before, format!("format-mismatch: {}", e));
+    }
+
+    // None of the encode paths write multi-frame output, so any animation would be flattened
+    if args.preserve_animation
+        && let Some((format, frames)) = animation_frames(&input_bytes)
+    {
+        return FileResult::failed(
+            fname,
+            before,
+            format!("animation-lost: {} has {} frames but the output keeps only the first", format, frames),
+        );
+    }
+
+    // Determine output extension if conversion requested
+    let mut target_ext: Option<&str> = None;
+    let mapped = args.map.as_ref().and_then(|m| m.get(canonical_ext(&ext)));
+    if ext == "pdf" || ext == "zip" {
+        // PDFs and archives are never converted, so they keep their name and extension
+    } else if let Some(format) = mapped {
+        target_ext = format.extension();
+    } else if args.to_webp {
+        target_ext = Some("webp");
+    } else if args.to_avif {
+        target_ext = Some("avif");
+    } else if args.bilevel {
+        target_ext = Some("png");
+    }
+
+    // Compute output path
+    let mut out_path = build_output_path(&fname, &ctx.output_dir, args.overwrite, target_ext);
+
+    // Create compression options from CLI args and any .compressrc above the file
+    let quality = ctx.quality_for(&fname);
+    let opts = CompressionOptions::from_args(args, &quality);
+
+    // Compress in-process
+    let result = compress_image_inproc(&input_bytes, &ext, &opts);
+    let (out_bytes, mime) = match result {
+        Ok((b, m)) => (b, m),
+        Err(e) if e.downcast_ref::<InvalidInput>().is_some() => {
+            return FileResult::failed(fname, before, format!("invalid/truncated: {}", e));
+        }
+        Err(e) => return FileResult::failed(fname, before, format!("compress-failed: {}", e)),
+    };
+    // Data URIs are never re-discovered as images, so they stay byte-identical to the encoder
+    let out_bytes = if skip_compressed && !args.data_uri { mark_output(&out_bytes) } else { out_bytes };
+
+    // Leave the original untouched when the gain isn't worth a re-encode
+    if let Some(min_pct) = args.min_savings {
+        let (input_len, out_len) = (input_bytes.len() as u64, out_bytes.len() as u64);
+        if below_min_savings(input_len, out_len, min_pct) {
+            return FileResult {
+                path: fname,
+                before,
+                after: before,
+                status: FileStatus::Skipped(format!(
+                    "below threshold: {:.2}% < {:.2}%",
+                    savings_percent(input_len, out_len),
+                    min_pct
+                )),
+            };
+        }
+    }
+
+    // If no explicit target_ext and we converted non-png to png as fallback, update ext to png
+    if target_ext.is_none() && mapped.is_none() && mime != "image/svg+xml" && !["png", "jpg", "jpeg", "ico", "pdf", "zip"].contains(&ext.as_str()) {
+        out_path.set_extension("png");
+    }
+
+    // Fingerprinted names replace the c_ prefix: image.a1b2c3d4.webp
+    if args.hash_names {
+        let stem = fname.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+        let out_ext = out_path.extension().and_then(OsStr::to_str).unwrap_or("").to_string();
+        out_path.set_file_name(hashed_file_name(stem, &out_bytes, &out_ext));
+        ctx.record_hashed_name(&fname, &out_path);
+    }
+    if let Some(template) = &args.template {
+        let out_ext = out_path.extension().and_then(OsStr::to_str).unwrap_or("").to_string();
+        let vars = TemplateVars {
+            name: fname.file_stem().and_then(OsStr::to_str).unwrap_or("out"),
+            ext: &out_ext,
+            dimensions: output_dimensions(&out_bytes),
+            quality: &quality,
+            bytes: &out_bytes,
+        };
+        out_path.set_file_name(expand_template(template, &vars));
+    }
+
+    // Archive mode: mirror the input tree inside the tar instead of writing loose files
+    if let Some(tar) = &ctx.tar {
+        let rel = fname.strip_prefix(&ctx.input_root).unwrap_or(&fname);
+        let rel = match out_path.extension() {
+            Some(ext) => rel.with_extension(ext),
+            None => rel.to_path_buf(),
+        };
+        let entry = rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+        let mut tar = tar.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = tar.append(&entry, &out_bytes) {
+            return FileResult::failed(fname, before, format!("archive-failed: {}", e));
+        }
+        return FileResult { path: fname, before, after: out_bytes.len() as u64, status: FileStatus::Compressed };
+    }
+
+    // Data URI mode: inline text next to where the binary would have gone
+    if args.data_uri {
+        let mut txt_name = out_path.clone().into_os_string();
+        txt_name.push(".txt");
+        out_path = PathBuf::from(txt_name);
+    }
+    let out_bytes = if args.data_uri { to_data_uri(&mime, &out_bytes).into_bytes() } else { out_bytes };
+
+    // An output left by an earlier run (--overwrite replaces the input itself, not an output)
+    if !args.overwrite && out_path.exists() {
+        match args.on_conflict {
+            OnConflict::Overwrite => {}
+            OnConflict::Skip => {
+                return FileResult {
+                    path: fname,
+                    before,
+                    after: before,
+                    status: FileStatus::Skipped(format!("{} already exists", out_path.display())),
+                };
+            }
+            OnConflict::Rename => out_path = free_output_path(&out_path),
+        }
+    }
+
+    // Dry run: everything above ran in memory; report the projected size and stop
+    if args.dry_run {
+        return FileResult { path: fname, before, after: out_bytes.len() as u64, status: FileStatus::Compressed };
+    }
+
+    let mut final_path = out_path.clone();
+    if args.overwrite {
+        // Staged in --temp-dir (the source directory may be read-only or slow) with the
+        // original's mode rather than tempfile's owner-only default; the guard deletes the
+        // staged file on every early return below
+        let staged = match fs::metadata(&fname)
+            .and_then(|meta| stage_temp_file(args.temp_dir.as_deref(), &out_bytes, meta.permissions()))
+        {
+            Ok(staged) => staged,
+            Err(e) => return FileResult::failed(fname, before, format!("write-failed: {}", e)),
+        };
+
+        // Re-read what actually landed on disk before it replaces the original
+        if args.keep_original_on_error {
+            let check = fs::read(staged.path())
+                .map_err(|e| anyhow!("re-read failed: {}", e))
+                .and_then(|written| {
+                    if written != out_bytes {
+                        return Err(anyhow!("written bytes differ from encoder output"));
+                    }
+                    validate_output(&written)
+                });
+            if let Err(e) = check {
+                return FileResult::failed(fname, before, format!("validation-failed: {}", e));
+            }
+        }
+
+        let backup = fname.with_extension(format!(
+            "{}{}",
+            fname.extension().and_then(OsStr::to_str).unwrap_or(""),
+            ".bak"
+        ));
+        if let Err(e) = fs::rename(&fname, &backup) {
+            return FileResult::failed(fname, before, format!("backup-failed: {}", e));
+        }
+        if let Err(e) = persist_temp_file(staged, &fname) {
+            let _ = fs::rename(&backup, &fname);
+            return FileResult::failed(fname, before, format!("overwrite-failed: {}", e));
+        }
+        let _ = fs::remove_file(&backup);
+        final_path = fname.clone();
+    } else {
+        if let Some(parent) = out_path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        if let Err(e) = write_atomic(&out_path, |w| w.write_all(&out_bytes)) {
+            return FileResult::failed(fname, before, format!("write-failed: {}", e));
+        }
+    }
+    if args.manifest_out.is_some() {
+        ctx.record_output(&final_path, &out_bytes);
+    }
+    if args.dedup || args.contact_sheet.is_some() {
+        ctx.written.lock().unwrap_or_else(|e| e.into_inner()).insert(fname.clone(), final_path.clone());
+    }
+
+    // Gallery preview sidecar: image.thumb.webp next to the main output
+    if let Some(size) = args.thumbnail
+        && ext != "pdf"
+        && ext != "zip"
+    {
+        let written = thumbnail_bytes(&input_bytes, size, args.thumbnail_format, &opts).and_then(|(bytes, thumb_ext)| {
+            let stem = final_path.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+            let thumb_path = final_path.with_file_name(format!("{}.thumb.{}", stem, thumb_ext));
+            Ok(write_atomic(&thumb_path, |w| w.write_all(&bytes))?)
+        });
+        if let Err(e) = written {
+            return FileResult::failed(fname, before, format!("thumbnail-failed: {}", e));
+        }
+    }
+
+    if args.placeholder && ext != "pdf" && ext != "zip" {
+        let written = placeholder(&input_bytes).and_then(|lqip| {
+            let stem = final_path.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+            let json = serde_json::to_vec_pretty(&lqip)?;
+            Ok(write_atomic(&final_path.with_file_name(format!("{}.lqip.json", stem)), |w| w.write_all(&json))?)
+        });
+        if let Err(e) = written {
+            return FileResult::failed(fname, before, format!("placeholder-failed: {}", e));
+        }
+    }
+
+    let after = fs::metadata(&final_path).map(|m| m.len()).unwrap_or(0);
+    FileResult { path: fname, before, after, status: FileStatus::Compressed }
+}
+
+/// SHA-256 of an image's decoded pixels and layout, so re-encodes of the same picture match
+fn pixel_hash(bytes: &[u8]) -> Option<String> {
+    let img = load_image(bytes).ok()?;
+    let mut data = format!("{}x{} {:?}\n", img.width(), img.height(), img.color()).into_bytes();
+    data.extend_from_slice(img.as_bytes());
+    Some(sha256_hex(&data))
+}
+
+/// Split `files` for --dedup into the ones to compress and (duplicate, original) pairs of
+/// images whose pixels match an earlier file. Undecodable files are always compressed, so
+/// their errors are reported
+fn find_duplicates(files: &[PathBuf]) -> (Vec<PathBuf>, Vec<(PathBuf, PathBuf)>) {
+    let hashes: Vec<Option<String>> = files.par_iter().map(|f| fs::read(f).ok().and_then(|b| pixel_hash(&b))).collect();
+    let mut first: HashMap<String, PathBuf> = HashMap::new();
+    let mut unique = Vec::new();
+    let mut duplicates = Vec::new();
+    for (f, hash) in files.iter().zip(hashes) {
+        match hash {
+            Some(hash) => match first.get(&hash) {
+                Some(original) => duplicates.push((f.clone(), original.clone())),
+                None => {
+                    first.insert(hash, f.clone());
+                    unique.push(f.clone());
+                }
+            },
+            None => unique.push(f.clone()),
+        }
+    }
+    (unique, duplicates)
+}
+
+/// 64-bit difference hash: one bit per horizontally adjacent pair in a 9x8 grayscale
+/// thumbnail, set when the left pixel is brighter. Near-identical images differ in few bits
+fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, image::imageops::FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash = (hash << 1) | (small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0]) as u64;
+        }
+    }
+    hash
+}
+
+/// Groups of files whose hashes are within `threshold` bits of another member (single
+/// linkage), each listed with its distance to the group's first file. Singletons are left out
+fn similar_clusters(hashes: &[(PathBuf, u64)], threshold: u32) -> Vec<Vec<(PathBuf, u32)>> {
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    for i in 0..hashes.len() {
+        for j in i + 1..hashes.len() {
+            if (hashes[i].1 ^ hashes[j].1).count_ones() <= threshold {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut groups: std::collections::BTreeMap<usize, Vec<usize>> = std::collections::BTreeMap::new();
+    for i in 0..hashes.len() {
+        let r = root(&mut parent, i);
+        groups.entry(r).or_default().push(i);
+    }
+    groups
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .map(|members| {
+            let first = hashes[members[0]].1;
+            members.iter().map(|&i| (hashes[i].0.clone(), (hashes[i].1 ^ first).count_ones())).collect()
+        })
+        .collect()
+}
+
+/// Give a --dedup duplicate the output of the file it duplicates: a hard link where possible,
+/// else a copy. Returns the duplicate's result and whether it was hard-linked
+fn write_duplicate(dup: &Path, original: &FileResult, args: &Args, ctx: &CliContext) -> (FileResult, bool) {
+    let before = fs::metadata(dup).map(|m| m.len()).unwrap_or(0);
+    let name = original.path.file_name().and_then(OsStr::to_str).unwrap_or("file");
+    let result = |status| FileResult { path: dup.to_path_buf(), before, after: original.after, status };
+    match &original.status {
+        FileStatus::Compressed => {}
+        FileStatus::Skipped(reason) => return (result(FileStatus::Skipped(format!("duplicate of {}: {}", name, reason))), false),
+        FileStatus::Failed(msg) => return (FileResult::failed(dup.to_path_buf(), before, format!("duplicate of {}: {}", name, msg)), false),
+    }
+    if args.dry_run {
+        return (result(FileStatus::Compressed), false);
+    }
+    let Some(source) = ctx.written.lock().unwrap_or_else(|e| e.into_inner()).get(&original.path).cloned() else {
+        return (FileResult::failed(dup.to_path_buf(), before, format!("duplicate of {}, which has no output", name)), false);
+    };
+    let dest = if args.overwrite {
+        dup.to_path_buf()
+    } else {
+        let mut dest = build_output_path(dup, &ctx.output_dir, false, None);
+        if let Some(ext) = source.extension() {
+            dest.set_extension(ext);
+        }
+        dest
+    };
+    // Link under a temporary name so a failure never leaves the destination missing
+    let mut tmp_name = dest.clone().into_os_string();
+    tmp_name.push(".dedup-tmp");
+    let tmp = PathBuf::from(tmp_name);
+    let _ = fs::remove_file(&tmp);
+    let linked = fs::hard_link(&source, &tmp).is_ok();
+    let placed = if linked { Ok(()) } else { fs::copy(&source, &tmp).map(|_| ()) }.and_then(|_| fs::rename(&tmp, &dest));
+    if let Err(e) = placed {
+        let _ = fs::remove_file(&tmp);
+        return (FileResult::failed(dup.to_path_buf(), before, format!("write-failed: {}", e)), false);
+    }
+    if args.manifest_out.is_some()
+        && let Ok(bytes) = fs::read(&dest)
+    {
+        ctx.record_output(&dest, &bytes);
+    }
+    if args.contact_sheet.is_some() {
+        ctx.written.lock().unwrap_or_else(|e| e.into_inner()).insert(dup.to_path_buf(), dest);
+    }
+    (result(FileStatus::Compressed), linked)
+}
+
+/// Compare names so embedded numbers sort by value ("frame_2" < "frame_10")
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    let digits = |s: &str| s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (mut a, mut b) = (a, b);
+    while let (Some(ca), Some(cb)) = (a.chars().next(), b.chars().next()) {
+        let (da, db) = (digits(a), digits(b));
+        let (ord, step_a, step_b) = if da > 0 && db > 0 {
+            let (na, nb) = (a[..da].trim_start_matches('0'), b[..db].trim_start_matches('0'));
+            (na.len().cmp(&nb.len()).then_with(|| na.cmp(nb)), da, db)
+        } else {
+            (ca.cmp(&cb), ca.len_utf8(), cb.len_utf8())
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+        (a, b) = (&a[step_a..], &b[step_b..]);
+    }
+    a.len().cmp(&b.len())
+}
+
+/// Decode the images directly inside `dir`, in natural filename order
+fn load_animation_frames(dir: &Path) -> Result<Vec<image::RgbaImage>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| {
+            p.is_file()
+                && p.extension().and_then(OsStr::to_str).is_some_and(|e| SUPPORTED_EXTS.contains(&e.to_lowercase().as_str()))
+        })
+        .collect();
+    let name = |p: &PathBuf| p.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    paths.sort_by(|a, b| natural_cmp(&name(a), &name(b)));
+
+    let mut frames: Vec<image::RgbaImage> = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let frame = load_image(&fs::read(path)?)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            .to_rgba8();
+        if let Some(first) = frames.first()
+            && first.dimensions() != frame.dimensions()
+        {
+            return Err(anyhow!(
+                "{} is {}x{} but the first frame is {}x{}",
+                path.display(), frame.width(), frame.height(), first.width(), first.height()
+            ));
+        }
+        frames.push(frame);
+    }
+    if frames.is_empty() {
+        return Err(anyhow!("no image frames found in {}", dir.display()));
+    }
+    Ok(frames)
+}
+
+/// Looping GIF89a with a per-frame quantized palette; alpha below 50% becomes transparent
+fn encode_gif(frames: &[image::RgbaImage], fps: f64, quality: &str) -> Result<Vec<u8>> {
+    let (w, h) = frames.first().map(|f| f.dimensions()).ok_or_else(|| anyhow!("no frames"))?;
+    let (w16, h16) = (u16::try_from(w)?, u16::try_from(h)?);
+    // GIF delays are in hundredths of a second
+    let delay = (100.0 / fps).round().clamp(1.0, u16::MAX as f64) as u16;
+    let (_, max_q) = parse_quality_range(quality);
+
+    let mut out = b"GIF89a".to_vec();
+    out.extend_from_slice(&w16.to_le_bytes());
+    out.extend_from_slice(&h16.to_le_bytes());
+    out.extend_from_slice(&[0, 0, 0]); // no global color table
+    out.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00"); // loop forever
+
+    for frame in frames {
+        let mut attr = Attributes::new();
+        attr.set_speed(4)?;
+        attr.set_quality(0, max_q)?;
+        let pixels: Vec<rgb::RGBA<u8>> = frame.pixels().map(|p| rgb::RGBA::new(p[0], p[1], p[2], p[3])).collect();
+        let mut img = LiqImage::new(&attr, pixels.as_slice(), w as usize, h as usize, 0.0)?;
+        let mut res = attr.quantize(&mut img)?;
+        res.set_dithering_level(1.0)?;
+        let (palette, mut indices) = res.remapped(&mut img)?;
+
+        let transparent = palette.iter().position(|p| p.a < 128);
+        if let Some(t) = transparent {
+            for idx in indices.iter_mut() {
+                if palette[*idx as usize].a < 128 {
+                    *idx = t as u8;
+                }
+            }
+        }
+        // Color table holds 2^bits entries, bits in 1..=8
+        let bits = (usize::BITS - (palette.len().max(2) - 1).leading_zeros()) as u8;
+
+        out.extend_from_slice(&[0x21, 0xF9, 0x04, (2 << 2) | transparent.is_some() as u8]);
+        out.extend_from_slice(&delay.to_le_bytes());
+        out.extend_from_slice(&[transparent.unwrap_or(0) as u8, 0]);
+        out.extend_from_slice(&[0x2C, 0, 0, 0, 0]);
+        out.extend_from_slice(&w16.to_le_bytes());
+        out.extend_from_slice(&h16.to_le_bytes());
+        out.push(0x80 | (bits - 1));
+        for i in 0..1usize << bits {
+            let p = palette.get(i).map_or([0, 0, 0], |p| [p.r, p.g, p.b]);
+            out.extend_from_slice(&p);
+        }
+        let min_code_size = bits.max(2);
+        out.push(min_code_size);
+        let lzw = weezl::encode::Encoder::new(weezl::BitOrder::Lsb, min_code_size)
+            .encode(&indices)
+            .map_err(|e| anyhow!("GIF LZW encoding failed: {}", e))?;
+        for block in lzw.chunks(255) {
+            out.push(block.len() as u8);
+            out.extend_from_slice(block);
+        }
+        out.push(0);
+    }
+    out.push(0x3B);
+    Ok(out)
+}
+
+/// Looping animated WebP (lossy at `quality`)
+fn encode_animated_webp(frames: &[image::RgbaImage], fps: f64, quality: f32) -> Result<Vec<u8>> {
+    let (w, h) = frames.first().map(|f| f.dimensions()).ok_or_else(|| anyhow!("no frames"))?;
+    let mut config = webp::WebPConfig::new().map_err(|_| anyhow!("failed to init WebP config"))?;
+    config.quality = quality;
+    let mut encoder = webp::AnimEncoder::new(w, h, &config);
+    encoder.set_loop_count(0);
+    let frame_ms = 1000.0 / fps;
+    for (i, frame) in frames.iter().enumerate() {
+        let timestamp = (i as f64 * frame_ms).round() as i32;
+        encoder.add_frame(webp::AnimFrame::from_rgba(frame.as_raw(), w, h, timestamp));
+    }
+    let webp = encoder.try_encode().map_err(|e| anyhow!("animated WebP encoding failed: {:?}", e))?;
+    Ok(webp.to_vec())
+}
+
+/// Build the --make-animation output for a directory of frames: (bytes, frame count)
+fn make_animation(dir: &Path, fps: f64, format: AnimationFormat, quality: &str) -> Result<(Vec<u8>, usize)> {
+    let frames = load_animation_frames(dir)?;
+    let bytes = match format {
+        AnimationFormat::Gif => encode_gif(&frames, fps, quality)?,
+        AnimationFormat::Webp => {
+            let (min_q, max_q) = parse_quality_range(quality);
+            encode_animated_webp(&frames, fps, ((min_q + max_q) / 2) as f32)?
+        }
+    };
+    Ok((bytes, frames.len()))
+}
+
+/// Center-crop to a square, then resize to `size` x `size`
+fn make_thumbnail(img: &DynamicImage, size: u32) -> DynamicImage {
+    let side = img.width().min(img.height());
+    let x = (img.width() - side) / 2;
+    let y = (img.height() - side) / 2;
+    img.crop_imm(x, y, side, side)
+        .resize_exact(size, size, image::imageops::FilterType::Lanczos3)
+}
+
+/// Encode a thumbnail of `input` with the main options but the thumbnail's format
+fn thumbnail_bytes(input: &[u8], size: u32, format: OutputFormat, opts: &CompressionOptions) -> Result<(Vec<u8>, &'static str)> {
+    let thumb = make_thumbnail(&load_image(input)?, size);
+    let mut cursor = Cursor::new(Vec::new());
+    thumb.write_to(&mut cursor, ImageFormat::Png)?;
+    let mut thumb_opts = opts.clone();
+    thumb_opts.format_map.clear();
+    thumb_opts.max_dimension = None;
+    thumb_opts.max_megapixels = None;
+    format.apply(&mut thumb_opts);
+    let (bytes, _) = compress_image_inproc(&cursor.into_inner(), "png", &thumb_opts)?;
+    Ok((bytes, format.extension().unwrap_or("png")))
+}
+
+/// 3x5 bitmap glyphs (rows top to bottom, bit 2 = left column) for contact sheet labels
+const LABEL_GLYPHS: &[(char, [u8; 5])] = &[
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]), ('B', [0b110, 0b101, 0b110, 0b101, 0b110]), ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]), ('E', [0b111, 0b100, 0b110, 0b100, 0b111]), ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]), ('H', [0b101, 0b101, 0b111, 0b101, 0b101]), ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]), ('K', [0b101, 0b101, 0b110, 0b101, 0b101]), ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]), ('N', [0b110, 0b101, 0b101, 0b101, 0b101]), ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]), ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]), ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]), ('T', [0b111, 0b010, 0b010, 0b010, 0b010]), ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]), ('W', [0b101, 0b101, 0b111, 0b111, 0b101]), ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]), ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]), ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]), ('2', [0b110, 0b001, 0b010, 0b100, 0b111]), ('3', [0b110, 0b001, 0b010, 0b001, 0b110]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]), ('5', [0b111, 0b100, 0b110, 0b001, 0b110]), ('6', [0b011, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]), ('8', [0b111, 0b101, 0b111, 0b101, 0b111]), ('9', [0b111, 0b101, 0b111, 0b001, 0b110]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]), ('-', [0b000, 0b000, 0b111, 0b000, 0b000]), ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+];
+
+const SHEET_THUMB: u32 = 160;
+const SHEET_PAD: u32 = 8;
+const LABEL_SCALE: u32 = 2;
+const LABEL_HEIGHT: u32 = 5 * LABEL_SCALE + 4;
+
+/// Draw `text` (uppercased; unknown characters become `?`) with its top-left corner at (x, y)
+fn draw_label(canvas: &mut image::RgbaImage, text: &str, x: u32, y: u32) {
+    for (i, ch) in text.chars().enumerate() {
+        let ch = ch.to_ascii_uppercase();
+        let rows = LABEL_GLYPHS.iter().find(|(c, _)| *c == ch).or_else(|| LABEL_GLYPHS.iter().find(|(c, _)| *c == '?')).map(|(_, g)| g);
+        let gx = x + i as u32 * 4 * LABEL_SCALE;
+        for (row, bits) in rows.into_iter().flatten().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..LABEL_SCALE {
+                    for dx in 0..LABEL_SCALE {
+                        let (px, py) = (gx + col * LABEL_SCALE + dx, y + row as u32 * LABEL_SCALE + dy);
+                        if px < canvas.width() && py < canvas.height() {
+                            canvas.put_pixel(px, py, image::Rgba([0, 0, 0, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Grid of square thumbnails, each captioned with its name, `columns` per row
+fn build_contact_sheet(images: &[(String, DynamicImage)], columns: u32) -> image::RgbImage {
+    let columns = columns.clamp(1, images.len().max(1) as u32);
+    let rows = (images.len() as u32).div_ceil(columns).max(1);
+    let width = columns * SHEET_THUMB + (columns + 1) * SHEET_PAD;
+    let height = rows * (SHEET_THUMB + LABEL_HEIGHT) + (rows + 1) * SHEET_PAD;
+    let mut sheet = image::RgbaImage::from_pixel(width, height, image::Rgba([240, 240, 240, 255]));
+    // Labels longer than the thumbnail are cut to fit
+    let max_chars = (SHEET_THUMB / (4 * LABEL_SCALE)) as usize;
+    for (i, (name, img)) in images.iter().enumerate() {
+        let (col, row) = (i as u32 % columns, i as u32 / columns);
+        let x = SHEET_PAD + col * (SHEET_THUMB + SHEET_PAD);
+        let y = SHEET_PAD + row * (SHEET_THUMB + LABEL_HEIGHT + SHEET_PAD);
+        let thumb = make_thumbnail(img, SHEET_THUMB).to_rgba8();
+        image::imageops::overlay(&mut sheet, &thumb, x as i64, y as i64);
+        let label: String = name.chars().take(max_chars).collect();
+        draw_label(&mut sheet, &label, x, y + SHEET_THUMB + 2);
+    }
+    DynamicImage::ImageRgba8(sheet).to_rgb8()
+}
+
+/// Contact sheet of every successfully processed file, read back from its output in
+/// `outputs` (input → written path) so it shows the compressed pixels; files without an
+/// output on disk, or that we can't decode, are left out
+fn write_contact_sheet(path: &Path, results: &[FileResult], outputs: &HashMap<PathBuf, PathBuf>, columns: u32) -> Result<usize> {
+    let images: Vec<(String, DynamicImage)> = results
+        .iter()
+        .filter(|r| r.status == FileStatus::Compressed)
+        .filter_map(|r| {
+            let img = load_image(&fs::read(outputs.get(&r.path)?).ok()?).ok()?;
+            Some((r.path.file_name()?.to_string_lossy().into_owned(), img))
+        })
+        .collect();
+    let sheet = build_contact_sheet(&images, columns);
+    let format = ImageFormat::from_path(path).unwrap_or(ImageFormat::Png);
+    let mut cursor = Cursor::new(Vec::new());
+    sheet.write_to(&mut cursor, format)?;
+    write_atomic(path, |w| w.write_all(cursor.get_ref()))?;
+    Ok(images.len())
+}
+
+const PLACEHOLDER_WIDTH: u32 = 20;
+
+/// --placeholder sidecar: the LQIP plus the full image's size so layouts can reserve space
+#[derive(serde::Serialize)]
+struct Placeholder {
+    width: u32,
+    height: u32,
+    data_uri: String,
+}
+
+/// Downscale to 20px wide, blur and encode as low-quality WebP
+fn placeholder(input: &[u8]) -> Result<Placeholder> {
+    let img = load_image(input)?;
+    let (width, height) = (img.width(), img.height());
+    let small_h = ((height as f64 * PLACEHOLDER_WIDTH as f64 / width.max(1) as f64).round() as u32).max(1);
+    let small = img.resize_exact(PLACEHOLDER_WIDTH, small_h, image::imageops::FilterType::Triangle).to_rgba8();
+    let blurred = image::imageops::blur(&small, 1.5);
+    let webp = WebpEncoder::from_rgba(blurred.as_raw(), blurred.width(), blurred.height()).encode(20.0);
+    Ok(Placeholder { width, height, data_uri: to_data_uri("image/webp", &webp) })
+}
+
+/// Input file contents, either copied into memory or mapped from disk
+enum InputBytes {
+    Buffered(Vec<u8>),
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for InputBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputBytes::Buffered(v) => v,
+            InputBytes::Mapped(m) => m,
+        }
+    }
+}
+
+/// Load an input, mapping it when it's larger than `stream_threshold` bytes.
+/// Falls back to a buffered read if mapping fails.
+fn read_input(path: &Path, size: u64, stream_threshold: Option<u64>) -> std::io::Result<InputBytes> {
+    if stream_threshold.is_some_and(|t| size > t) {
+        // SAFETY: read-only private mapping; like any reader we assume the input isn't
+        // truncated underneath us while it's being compressed
+        match fs::File::open(path).and_then(|file| unsafe { memmap2::Mmap::map(&file) }) {
+            Ok(map) => return Ok(InputBytes::Mapped(map)),
+            Err(e) => log::debug!("mmap of {} failed, reading instead: {}", path.display(), e),
+        }
+    }
+
+    let mut buf = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut buf)?;
+    Ok(InputBytes::Buffered(buf))
+}
+
+/// Write via a temp file in the destination directory, then rename it into place,
+/// so readers never observe a half-written output
+fn write_atomic(path: &Path, write: impl FnOnce(&mut fs::File) -> std::io::Result<()>) -> std::io::Result<()> {
+    let name = path.file_name().and_then(OsStr::to_str).unwrap_or("out");
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // Next to the destination so the final rename stays on one filesystem; dropping the
+    // guard on any error path deletes it
+    let prefix = format!(".{}.", name);
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(&prefix).suffix(".tmp");
+    // 0o666 less the umask, like fs::File::create (tempfile's own default is owner-only)
+    #[cfg(unix)]
+    builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let mut tmp = builder.tempfile_in(dir)?;
+    write(tmp.as_file_mut())?;
+    tmp.as_file().sync_all()?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Write `bytes` to a new file in `temp_dir` (default: the system temp directory) carrying
+/// `permissions`, deleted when the returned guard drops unless it's persisted first
+fn stage_temp_file(
+    temp_dir: Option<&Path>,
+    bytes: &[u8],
+    permissions: fs::Permissions,
+) -> std::io::Result<tempfile::NamedTempFile> {
+    let dir = temp_dir.map_or_else(std::env::temp_dir, Path::to_path_buf);
+    let mut staged = tempfile::Builder::new().prefix(".tinypng-").suffix(".tmp").tempfile_in(dir)?;
+    // Set after creation so the umask can't narrow the copied mode
+    staged.as_file().set_permissions(permissions)?;
+    staged.write_all(bytes)?;
+    staged.as_file().sync_all()?;
+    Ok(staged)
+}
+
+/// Move a staged file to `dest`: a rename on the same filesystem, else an atomic write of
+/// its bytes and mode next to `dest` (the staged file is deleted either way)
+fn persist_temp_file(staged: tempfile::NamedTempFile, dest: &Path) -> std::io::Result<()> {
+    let staged = match staged.persist(dest) {
+        Ok(_) => return Ok(()),
+        Err(e) => e.file,
+    };
+    let permissions = staged.as_file().metadata()?.permissions();
+    write_atomic(dest, |w| {
+        std::io::copy(&mut fs::File::open(staged.path())?, w)?;
+        w.set_permissions(permissions)
+    })
+}
+
+/// First `<stem>-N.<ext>` next to `path` that doesn't exist yet
+fn free_output_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+    let ext = path.extension().and_then(OsStr::to_str).map(|e| format!(".{}", e)).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{}-{}{}", stem, n, ext)))
+        .find(|candidate| !candidate.exists())
+        .expect("unbounded range")
+}
+
+/// Encode bytes as an RFC 2397 `data:` URI for inlining in CSS/HTML
+fn to_data_uri(mime: &str, bytes: &[u8]) -> String {
+    use base64::Engine as _;
+    format!("data:{};base64,{}", mime, base64::engine::general_purpose::STANDARD.encode(bytes))
+}
+
+/// Size of the global rayon pool. The encoders (imagequant, ravif) share it, so
+/// deterministic mode pins everything to one thread for byte-identical reruns.
+fn worker_threads(args: &Args) -> usize {
+    if args.deterministic {
+        1
+    } else {
+        args.jobs.unwrap_or_else(num_cpus::get)
+    }
+}
+
+/// Blocks workers while the images they hold would exceed `--memory-budget`
+struct MemoryBudget {
+    limit: Option<u64>,
+    in_use: Mutex<u64>,
+    freed: Condvar,
+}
+
+/// A reservation on a `MemoryBudget`, returned when dropped
+struct BudgetPermit<'a> {
+    budget: &'a MemoryBudget,
+    cost: u64,
+}
+
+impl MemoryBudget {
+    fn new(limit: Option<u64>) -> Self {
+        MemoryBudget { limit, in_use: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    /// Wait until `cost` fits alongside what's already reserved; an idle budget admits anything
+    fn acquire(&self, cost: u64) -> BudgetPermit<'_> {
+        let mut in_use = self.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        while *in_use > 0 && in_use.saturating_add(cost) > self.limit.unwrap_or(u64::MAX) {
+            in_use = self.freed.wait(in_use).unwrap_or_else(|e| e.into_inner());
+        }
+        *in_use += cost;
+        BudgetPermit { budget: self, cost }
+    }
+}
+
+impl Drop for BudgetPermit<'_> {
+    fn drop(&mut self) {
+        let mut in_use = self.budget.in_use.lock().unwrap_or_else(|e| e.into_inner());
+        *in_use -= self.cost;
+        self.budget.freed.notify_all();
+    }
+}
+
+/// Bytes of an input read to find its dimensions for `decode_cost`
+const DECODE_COST_PROBE_BYTES: u64 = 64 * 1024;
+
+/// Estimated memory to decode `path`: its RGBA pixels from the header (for PDFs, those of its
+/// largest image), or the file size if no header says
+fn decode_cost(path: &Path) -> u64 {
+    let dims = image::ImageReader::open(path).ok().and_then(|r| r.with_guessed_format().ok()?.into_dimensions().ok());
+    if let Some((w, h)) = dims {
+        return w as u64 * h as u64 * 4;
+    }
+    let Ok(mut file) = fs::File::open(path) else { return 0 };
+    let mut head = Vec::new();
+    if (&mut file).take(DECODE_COST_PROBE_BYTES).read_to_end(&mut head).is_err() {
+        return 0;
+    }
+    if let Some((w, h)) = webp_dimensions(&head).or_else(|| gif_dimensions(&head)).or_else(|| heif_dimensions(&head)) {
+        return w as u64 * h as u64 * 4;
+    }
+    // Image dictionaries can sit anywhere in a PDF, so it's mapped rather than read in
+    #[cfg(feature = "pdf")]
+    if head.starts_with(b"%PDF-") {
+        // SAFETY: read-only mapping, dropped before this returns
+        if let Some(cost) = unsafe { memmap2::Mmap::map(&file) }.ok().and_then(|map| pdf_decode_cost(&map)) {
+            return cost;
+        }
+    }
+    file.metadata().map_or(0, |meta| meta.len())
+}
+
+/// Run `work` over `files` on `jobs` worker threads, each file admitted through `budget`.
+/// Plain threads rather than rayon tasks: a rayon worker waiting on the budget could steal
+/// another file while blocked inside an encoder and deadlock on its own reservation
+fn run_bounded<T: Send>(files: &[PathBuf], jobs: usize, budget: &MemoryBudget, work: impl Fn(&Path) -> T + Sync) -> Vec<T> {
+    let next = AtomicUsize::new(0);
+    let done = Mutex::new(Vec::with_capacity(files.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len().max(1)) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(file) = files.get(i) else { break };
+                    // Without --memory-budget nothing waits, so don't spend I/O estimating
+                    let cost = if budget.limit.is_some() { decode_cost(file) } else { 0 };
+                    let _permit = budget.acquire(cost);
+                    let result = work(file);
+                    done.lock().unwrap_or_else(|e| e.into_inner()).push((i, result));
+                }
+            });
+        }
+    });
+    let mut done = done.into_inner().unwrap_or_else(|e| e.into_inner());
+    done.sort_by_key(|(i, _)| *i);
+    done.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Compress one file and stream the result to `out` (for --stdout)
+fn write_compressed(path: &Path, args: &Args, out: &mut impl Write) -> Result<()> {
+    let input_bytes = fs::read(path)?;
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(|s| s.to_lowercase())
+        .unwrap_or_default();
+    let opts = CompressionOptions::from_args(args, &base_quality(args));
+    let (bytes, _mime) = compress_image_inproc(&input_bytes, &ext, &opts)?;
+    out.write_all(&bytes)?;
+    Ok(())
+}
+
+/// MIME type to offer a compressed output under on the clipboard; formats paste targets
+/// don't take are refused rather than silently converted
+fn clipboard_mime(mime: &str) -> Result<&'static str> {
+    match mime {
+        "image/png" => Ok("image/png"),
+        "image/jpeg" => Ok("image/jpeg"),
+        "image/gif" => Ok("image/gif"),
+        "image/webp" => Ok("image/webp"),
+        other => Err(anyhow!("{} output can't be pasted from the clipboard (use --to-webp, or a PNG or JPEG map)", other)),
+    }
+}
+
+/// Put a compressed output on the desktop clipboard with arboard. Clipboards hold pixels
+/// rather than files, so the decoded image (lossy artifacts included) is what pastes. On X11
+/// the image outlives this process only if a clipboard manager takes it over.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(bytes: &[u8]) -> Result<()> {
+    let rgba = load_image(bytes).map_err(|e| anyhow!("output does not decode: {}", e))?.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let image = arboard::ImageData { width, height, bytes: rgba.into_raw().into() };
+    let mut clipboard = arboard::Clipboard::new().map_err(|e| anyhow!("no clipboard available ({})", e))?;
+    clipboard.set_image(image).map_err(|e| anyhow!("could not set the clipboard ({})", e))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "clipboard"))]
+fn copy_to_clipboard(_bytes: &[u8]) -> Result<()> {
+    Err(anyhow!("clipboard support not compiled in (rebuild with --features clipboard)"))
+}
+
+async fn run_cli_mode(args: &Args) -> Result<()> {
+    let jobs = worker_threads(args);
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build_global()
+        .ok();
+
+    // Ensure input present; a file list alone works from the current directory
+    let input_path = match (&args.input, &args.input_list) {
+        (Some(input), _) => input.canonicalize()?,
+        (None, Some(_)) => std::env::current_dir()?,
+        (None, None) => return Err(anyhow!("No input provided for CLI mode")),
+    };
+    if !input_path.exists() {
+        return Err(anyhow!("Input path does not exist: {}", input_path.display()));
+    }
+    if args.avif_depth != 8 && args.avif_subsampling.is_some_and(|s| s != AvifSubsampling::S444) {
+        return Err(anyhow!("--avif-subsampling 420/422 is only supported with --avif-depth 8"));
+    }
+    if args.jpeg_arithmetic {
+        eprintln!("⚠️  --jpeg-arithmetic: many browsers and image viewers can't open arithmetic-coded JPEGs");
+    }
+
+    if args.stdout {
+        if !input_path.is_file() {
+            return Err(anyhow!("--stdout needs a single file input, but {} is a directory", input_path.display()));
+        }
+        // Keep the binary stream clean of log lines
+        log::set_max_level(log::LevelFilter::Off);
+        let stdout = std::io::stdout();
+        let mut lock = stdout.lock();
+        write_compressed(&input_path, args, &mut lock)?;
+        lock.flush()?;
+        return Ok(());
+    }
+
+    if args.clipboard {
+        if !input_path.is_file() {
+            return Err(anyhow!("--clipboard needs a single file input, but {} is a directory", input_path.display()));
+        }
+        let input = fs::read(&input_path)?;
+        let ext = input_path.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()).unwrap_or_default();
+        let opts = CompressionOptions::from_args(args, &base_quality(args));
+        let (bytes, mime) = compress_image_inproc(&input, &ext, &opts)?;
+        let clip_mime = clipboard_mime(&mime)?;
+        copy_to_clipboard(&bytes)?;
+        println!(
+            "📋 Copied {} to the clipboard ({} → {}, {})",
+            input_path.file_name().and_then(OsStr::to_str).unwrap_or("image"),
+            human_size(input.len() as u64),
+            human_size(bytes.len() as u64),
+            clip_mime
+        );
+        return Ok(());
+    }
+
+    if args.benchmark {
+        if !input_path.is_file() {
+            return Err(anyhow!("--benchmark needs a single file input, but {} is a directory", input_path.display()));
+        }
+        let input = fs::read(&input_path)?;
+        let ext = input_path.extension().and_then(OsStr::to_str).map(|s| s.to_lowercase()).unwrap_or_default();
+        let opts = CompressionOptions::from_args(args, &base_quality(args));
+        let rows = run_benchmark(&input, &ext, &opts);
+        println!("📊 {} ({})", input_path.display(), human_size(input.len() as u64));
+        print!("{}", benchmark_table(&rows, input.len() as u64));
+        if let Some(dir) = &args.benchmark_out {
+            fs::create_dir_all(dir)?;
+            let stem = input_path.file_stem().and_then(OsStr::to_str).unwrap_or("out");
+            for row in &rows {
+                if let (Ok(bytes), Some(ext)) = (&row.output, row.format.extension()) {
+                    write_atomic(&dir.join(format!("{}.{}", stem, ext)), |w| w.write_all(bytes))?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let output_dir = args
+        .output
+        .as_ref()
+        .map(|p| p.canonicalize().unwrap_or_else(|_| p.clone()));
+
+    // Animation mode: one output for the whole directory, written next to it (or into -o)
+    if let Some(fps) = args.make_animation {
+        if !input_path.is_dir() {
+            return Err(anyhow!("--make-animation needs a directory of frames, but {} is a file", input_path.display()));
+        }
+        let quality = base_quality(args);
+        let (bytes, frames) = make_animation(&input_path, fps, args.animation_format, &quality)?;
+        let ext = match args.animation_format {
+            AnimationFormat::Gif => "gif",
+            AnimationFormat::Webp => "webp",
+        };
+        let name = input_path.file_name().and_then(OsStr::to_str).unwrap_or("animation");
+        let dest_dir = output_dir.clone().or_else(|| input_path.parent().map(Path::to_path_buf)).unwrap_or_default();
+        fs::create_dir_all(&dest_dir)?;
+        let dest = dest_dir.join(format!("{}.{}", name, ext));
+        write_atomic(&dest, |w| w.write_all(&bytes))?;
+        println!("🎞️  Wrote {} ({} frames at {} fps, {})", dest.display(), frames, fps, human_size(bytes.len() as u64));
+        return Ok(());
+    }
+
+    let (mut files, overrides) = match &args.input_list {
+        Some(list) => (read_input_list(list)?, DirOverrides::default()),
+        None => discover_files(&input_path, &discovery_exts(args)),
+    };
+    if let Some(only) = &args.only {
+        files = filter_extensions(files, only);
+    }
+    if let Some(window) = args.since {
+        let cutoff = std::time::SystemTime::now().checked_sub(window).unwrap_or(std::time::UNIX_EPOCH);
+        files = filter_modified_since(files, cutoff);
+    }
+    if files.is_empty() {
+        eprintln!("No supported image files found.");
+        return Ok(());
+    }
+
+    if let Some(threshold) = args.perceptual_dedup {
+        let root = if input_path.is_file() { input_path.parent().unwrap_or(&input_path) } else { &input_path };
+        let hashes: Vec<(PathBuf, u64)> = files
+            .par_iter()
+            .filter_map(|f| {
+                let img = fs::read(f).ok().and_then(|bytes| load_image(&bytes).ok());
+                if img.is_none() {
+                    eprintln!("{}: skipped (not decodable)", f.display());
+                }
+                Some((f.clone(), dhash(&img?)))
+            })
+            .collect();
+        let clusters = similar_clusters(&hashes, threshold);
+        if clusters.is_empty() {
+            println!("No similar images found among {} files (distance ≤ {}).", hashes.len(), threshold);
+        } else {
+            println!("🔍 {} group(s) of similar images among {} files (distance ≤ {}):", clusters.len(), hashes.len(), threshold);
+        }
+        for (n, cluster) in clusters.iter().enumerate() {
+            println!("\nGroup {}:", n + 1);
+            for (i, (path, distance)) in cluster.iter().enumerate() {
+                if i == 0 {
+                    println!("  {}", slash_relative(path, root));
+                } else {
+                    println!("  {} (distance {})", slash_relative(path, root), distance);
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // Determine quality from the preset or compression level
+    let mut quality = base_quality(args);
+    if let Some(budget) = args.total_budget {
+        let (fitted, predicted) = fit_quality_to_budget(&files, args, &quality, budget);
+        if predicted > budget {
+            eprintln!("⚠️  Even at quality {} the outputs total {} (budget {})", fitted, human_size(predicted), human_size(budget));
+        }
+        quality = fitted;
+    }
+
+    let input_root = if input_path.is_file() {
+        input_path.parent().map(Path::to_path_buf).unwrap_or_default()
+    } else {
+        input_path.clone()
+    };
+    if args.interactive {
+        let mut preview_args = args.clone();
+        preview_args.dry_run = true;
+        let mut preview_ctx = CliContext::new(input_root.clone(), output_dir.clone(), &quality);
+        preview_ctx.overrides = DirOverrides { dirs: overrides.dirs.clone() };
+        let budget = MemoryBudget::new(args.memory_budget);
+        let preview = run_bounded(&files, jobs, &budget, |f| process_file(f, &preview_args, &preview_ctx));
+        let Some(chosen) = choose_files(&preview, &input_root, &mut std::io::stdin().lock())? else {
+            println!("Cancelled; nothing was written.");
+            return Ok(());
+        };
+        files = chosen;
+        if files.is_empty() {
+            println!("No files selected; nothing was written.");
+            return Ok(());
+        }
+    }
+    let mut duplicates = Vec::new();
+    if args.dedup {
+        (files, duplicates) = find_duplicates(&files);
+    }
+    let mut ctx = CliContext::new(input_root, output_dir, &quality);
+    ctx.overrides = overrides;
+    if let Some(tar_path) = &args.tar {
+        let mut tar = TarWriter::create(tar_path)?;
+        if args.deterministic {
+            tar.mtime = 0;
+        }
+        ctx.tar = Some(Mutex::new(tar));
+    }
+
+    let budget = MemoryBudget::new(args.memory_budget);
+    let mut results: Vec<FileResult> = run_bounded(&files, jobs, &budget, |f| process_file_formats(f, args, &ctx))
+        .into_iter()
+        .flatten()
+        .collect();
+    let (mut linked, mut dedup_saved) = (0usize, 0u64);
+    for (dup, original) in &duplicates {
+        let Some(original) = results.iter().find(|r| &r.path == original).cloned() else { continue };
+        let (res, hard_linked) = write_duplicate(dup, &original, args, &ctx);
+        if hard_linked {
+            linked += 1;
+            dedup_saved += res.after;
+        }
+        results.push(res);
+    }
+
+    if let (Some(tar_path), Some(tar)) = (&args.tar, ctx.tar.take()) {
+        tar.into_inner().unwrap_or_else(|e| e.into_inner()).finish()?;
+        println!("📦 Wrote archive {}", tar_path.display());
+    }
+
+    if args.dry_run && args.overwrite {
+        let (lines, growing) = overwrite_preview(&results);
+        println!("Dry run: --overwrite would replace these files in place (nothing was written)");
+        for line in &lines {
+            println!("{}", line);
+        }
+        if growing > 0 {
+            eprintln!("⚠️  {} file(s) would grow; raise --min-savings to leave them untouched", growing);
+        }
+        return Ok(());
+    }
+
+    if args.hash_names && !args.dry_run {
+        let manifest_path = ctx.manifest_dir().join("hash-manifest.json");
+        let names = ctx.hashed_names.into_inner().unwrap_or_else(|e| e.into_inner());
+        fs::write(&manifest_path, serde_json::to_string_pretty(&names)?)?;
+        println!("🔖 Wrote hash manifest {}", manifest_path.display());
+    }
+    if let Some(manifest_path) = &args.manifest_out
+        && !args.dry_run
+    {
+        let outputs = ctx.outputs.into_inner().unwrap_or_else(|e| e.into_inner());
+        write_atomic(manifest_path, |w| serde_json::to_writer_pretty(w, &outputs).map_err(Into::into))?;
+        println!("🧾 Wrote output manifest {} ({} files)", manifest_path.display(), outputs.len());
+    }
+
+    if let Some(sheet_path) = &args.contact_sheet {
+        let outputs = ctx.written.lock().unwrap_or_else(|e| e.into_inner());
+        let count = write_contact_sheet(sheet_path, &results, &outputs, args.sheet_columns)?;
+        println!("🗂️  Wrote contact sheet {} ({} images)", sheet_path.display(), count);
+    }
+
+    let mut total_before: u64 = 0;
+    let mut total_after: u64 = 0;
+    let mut processed: usize = 0;
+    let mut skipped: usize = 0;
+
+    for res in &results {
+        let name = &res.path;
+        match &res.status {
+            FileStatus::Failed(msg) => eprintln!("{}: failed ({})", name.display(), msg),
+            FileStatus::Skipped(reason) => {
+                println!(
+                    "{}: skipped ({})",
+                    name.file_name().and_then(OsStr::to_str).unwrap_or("file"),
+                    reason
+                );
+                skipped += 1;
+                continue;
+            }
+            FileStatus::Compressed => {
+                let saved = res.before.saturating_sub(res.after);
+                let pct = if res.before > 0 {
+                    (saved as f64) / (res.before as f64) * 100.0
+                } else {
+                    0.0
+                };
+                println!(
+                    "{}: {} → {} (saved {} / {:.2}%)",
+                    name.file_name().and_then(OsStr::to_str).unwrap_or("file"),
+                    human_size(res.before),
+                    human_size(res.after),
+                    human_size(saved),
+                    pct
+                );
+                processed += 1;
+            }
+        }
+        total_before = total_before.saturating_add(res.before);
+        total_after = total_after.saturating_add(res.after);
+    }
+
+    if skipped > 0 {
+        println!("Skipped {} files (already compressed or below --min-savings).", skipped);
+    }
+    if !duplicates.is_empty() {
+        println!(
+            "🔗 {} duplicate(s) reused an earlier output; {} hard-linked, saving {}",
+            duplicates.len(),
+            linked,
+            human_size(dedup_saved)
+        );
+    }
+    if let Some(budget) = args.total_budget {
+        println!("Total output: {} of {} budget (quality {})", human_size(total_after), human_size(budget), quality);
+    }
+
+    if processed > 0 {
+        let total_saved = total_before.saturating_sub(total_after);
+        let pct_total = if total_before > 0 {
+            (total_saved as f64) / (total_before as f64) * 100.0
+        } else {
+            0.0
+        };
+        println!(
+            "\nProcessed {} files. Total saved: {} ({:.2}%)",
+            processed,
+            human_size(total_saved),
+            pct_total
+        );
+    } else {
+        eprintln!("No files compressed.");
+    }
+    if args.dry_run {
+        println!("Dry run: nothing was written.");
+    }
+    if args.report == Some(ReportFormat::Markdown) {
+        println!("\n{}", markdown_report(&results, &ctx.input_root));
+    }
+    if args.bandwidth_report
+        && let Some(views) = args.monthly_views
+    {
+        let (saved, dollars) = bandwidth_savings(&results, views, args.cost_per_gb);
+        println!("\n📉 At {} views per file per month: {} less bandwidth per month", views, human_size(saved));
+        if let (Some(dollars), Some(price)) = (dollars, args.cost_per_gb) {
+            println!("💵 Estimated savings: ${:.2}/month (${:.2}/year) at ${}/GB", dollars, dollars * 12.0, price);
+        }
+    }
+    if ctx.cancelled.load(Ordering::Relaxed) {
+        return Err(anyhow!("stopped after the first failure (--fail-fast)"));
+    }
+
+    Ok(())
+}
+
+/// Monthly bytes (and dollars, given a per-GB price) saved by serving each file's
+/// compressed version `monthly_views` times
+fn bandwidth_savings(results: &[FileResult], monthly_views: u64, cost_per_gb: Option<f64>) -> (u64, Option<f64>) {
+    let saved: u64 = results
+        .iter()
+        .filter(|r| r.status == FileStatus::Compressed)
+        .map(|r| r.before.saturating_sub(r.after).saturating_mul(monthly_views))
+        .fold(0, u64::saturating_add);
+    (saved, cost_per_gb.map(|price| saved as f64 / 1e9 * price))
+}
+
+/// Escape text for a Markdown table cell
+fn markdown_cell(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('|', "\\|").replace(['\r', '\n'], " ")
+}
+
+/// Formats `--benchmark` compares, in table order
+const BENCHMARK_FORMATS: [(&str, OutputFormat); 4] = [
+    ("PNG (lossy)", OutputFormat::Png),
+    ("WebP", OutputFormat::Webp),
+    ("AVIF", OutputFormat::Avif),
+    ("JPEG", OutputFormat::Jpeg),
+];
+
+/// One `--benchmark` encode: the output bytes (or why it failed) and how long it took
+struct BenchmarkRow {
+    label: &'static str,
+    format: OutputFormat,
+    output: std::result::Result<Vec<u8>, String>,
+    elapsed: std::time::Duration,
+}
+
+/// Encode `input` once per benchmark format with `opts` otherwise unchanged
+fn run_benchmark(input: &[u8], ext: &str, opts: &CompressionOptions) -> Vec<BenchmarkRow> {
+    BENCHMARK_FORMATS
+        .iter()
+        .map(|&(label, format)| {
+            let mut opts = opts.clone();
+            opts.format_map.clear();
+            format.apply(&mut opts);
+            let started = std::time::Instant::now();
+            let output = compress_image_inproc(input, ext, &opts).map(|(bytes, _)| bytes).map_err(|e| e.to_string());
+            BenchmarkRow { label, format, output, elapsed: started.elapsed() }
+        })
+        .collect()
+}
+
+/// Aligned text table of benchmark rows, sizes relative to the `original` input length
+fn benchmark_table(rows: &[BenchmarkRow], original: u64) -> String {
+    let mut out = format!("{:<12} {:>10} {:>8} {:>10}\n", "Format", "Size", "Saved", "Time");
+    for row in rows {
+        let line = match &row.output {
+            Ok(bytes) => format!(
+                "{:<12} {:>10} {:>7.1}% {:>8.1}ms",
+                row.label,
+                human_size(bytes.len() as u64),
+                savings_percent(original, bytes.len() as u64),
+                row.elapsed.as_secs_f64() * 1000.0
+            ),
+            Err(e) => format!("{:<12} failed: {}", row.label, e),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// GitHub-flavored Markdown table of per-file savings, with a totals row over compressed files
+fn markdown_report(results: &[FileResult], root: &Path) -> String {
+    let mut out = String::from("| File | Before | After | Saved | Status |\n|------|-------:|------:|------:|--------|\n");
+    let (mut before, mut after) = (0u64, 0u64);
+    for res in results {
+        let name = markdown_cell(&res.path.strip_prefix(root).unwrap_or(&res.path).display().to_string());
+        let row = match &res.status {
+            FileStatus::Compressed => {
+                before += res.before;
+                after += res.after;
+                let pct = savings_percent(res.before, res.after);
+                format!("| {} | {} | {} | {:.1}% | compressed |", name, human_size(res.before), human_size(res.after), pct)
+            }
+            FileStatus::Skipped(reason) => {
+                format!("| {} | {} | {} | – | skipped: {} |", name, human_size(res.before), human_size(res.before), markdown_cell(reason))
+            }
+            FileStatus::Failed(msg) => format!("| {} | {} | – | – | failed: {} |", name, human_size(res.before), markdown_cell(msg)),
+        };
+        out.push_str(&row);
+        out.push('\n');
+    }
+    out.push_str(&format!(
+        "| **Total** | **{}** | **{}** | **{:.1}%** | |",
+        human_size(before),
+        human_size(after),
+        savings_percent(before, after)
+    ));
+    out
+}
+
+/// Which of the previewed files `--interactive` will compress
+#[derive(Debug, Clone, PartialEq)]
+struct Selection {
+    chosen: Vec<bool>,
+}
+
+/// What the user asked for at the `--interactive` prompt
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SelectionStep {
+    Continue,
+    Confirm,
+    Cancel,
+}
+
+impl Selection {
+    /// Start with every file that would actually shrink
+    fn from_preview(preview: &[FileResult]) -> Self {
+        let chosen = preview.iter().map(|r| r.status == FileStatus::Compressed && r.after < r.before).collect();
+        Selection { chosen }
+    }
+
+    /// Apply one prompt line: file numbers or ranges ("2 5-7") toggle, "all"/"none" set
+    /// everything, an empty line or "y" confirms and "q" cancels
+    fn apply(&mut self, line: &str) -> std::result::Result<SelectionStep, String> {
+        match line.trim().to_lowercase().as_str() {
+            "" | "y" | "yes" => return Ok(SelectionStep::Confirm),
+            "q" | "quit" => return Ok(SelectionStep::Cancel),
+            "a" | "all" => self.chosen.fill(true),
+            "n" | "none" => self.chosen.fill(false),
+            picks => {
+                let mut toggled = Vec::new();
+                for pick in picks.split(|c: char| c == ',' || c.is_whitespace()).filter(|p| !p.is_empty()) {
+                    let number = |s: &str| s.parse::<usize>().ok().filter(|n| (1..=self.chosen.len()).contains(n));
+                    let range = match pick.split_once('-') {
+                        Some((a, b)) => number(a).zip(number(b)).filter(|(a, b)| a <= b),
+                        None => number(pick).map(|n| (n, n)),
+                    };
+                    let (first, last) = range.ok_or_else(|| format!("\"{}\" is not a file number (1-{})", pick, self.chosen.len()))?;
+                    toggled.extend(first - 1..last);
+                }
+                for i in toggled {
+                    self.chosen[i] = !self.chosen[i];
+                }
+            }
+        }
+        Ok(SelectionStep::Continue)
+    }
+
+    fn count(&self) -> usize {
+        self.chosen.iter().filter(|&&c| c).count()
+    }
+}
+
+/// Show the preview and read selection commands from `input` until the user confirms
+/// (the chosen files) or cancels/closes the input (`None`)
+fn choose_files(preview: &[FileResult], root: &Path, input: &mut impl std::io::BufRead) -> Result<Option<Vec<PathBuf>>> {
+    let mut selection = Selection::from_preview(preview);
+    loop {
+        for (i, res) in preview.iter().enumerate() {
+            let name = res.path.strip_prefix(root).unwrap_or(&res.path).display();
+            let projection = match &res.status {
+                FileStatus::Compressed => format!(
+                    "{} → {} ({:.1}%)",
+                    human_size(res.before),
+                    human_size(res.after),
+                    savings_percent(res.before, res.after)
+                ),
+                FileStatus::Skipped(reason) => format!("left as is ({})", reason),
+                FileStatus::Failed(msg) => format!("would fail ({})", msg),
+            };
+            println!("{} {:>3}  {}  {}", if selection.chosen[i] { "[x]" } else { "[ ]" }, i + 1, name, projection);
+        }
+        print!("{} of {} selected. Toggle numbers/ranges, all, none; Enter to compress, q to cancel: ", selection.count(), preview.len());
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        match selection.apply(&line) {
+            Ok(SelectionStep::Continue) => {}
+            Ok(SelectionStep::Confirm) => {
+                let chosen = preview.iter().zip(&selection.chosen).filter(|(_, c)| **c).map(|(r, _)| r.path.clone());
+                return Ok(Some(chosen.collect()));
+            }
+            Ok(SelectionStep::Cancel) => return Ok(None),
+            Err(msg) => eprintln!("{}", msg),
+        }
+    }
+}
+
+/// Per-file report for `--dry-run --overwrite`, plus how many files would get bigger
+fn overwrite_preview(results: &[FileResult]) -> (Vec<String>, usize) {
+    let mut growing = 0;
+    let lines = results
+        .iter()
+        .map(|res| {
+            let name = res.path.file_name().and_then(OsStr::to_str).unwrap_or("file");
+            match &res.status {
+                FileStatus::Failed(msg) => format!("{}: would fail ({})", name, msg),
+                FileStatus::Skipped(reason) => format!("{}: would be left as is ({})", name, reason),
+                FileStatus::Compressed if res.after > res.before => {
+                    growing += 1;
+                    format!(
+                        "{}: {} → {} (grows {:.2}%) ⚠️",
+                        name, human_size(res.before), human_size(res.after), -savings_percent(res.before, res.after)
+                    )
+                }
+                FileStatus::Compressed => format!(
+                    "{}: {} → {} (would save {} / {:.2}%)",
+                    name,
+                    human_size(res.before),
+                    human_size(res.after),
+                    human_size(res.before - res.after),
+                    savings_percent(res.before, res.after)
+                ),
+            }
+        })
+        .collect();
+    (lines, growing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_png() -> Vec<u8> {
+        // Create a simple 100x100 red PNG image
+        let img = image::ImageBuffer::from_fn(100, 100, |_, _| {
+            image::Rgb([255, 0, 0]) // Red pixel
+        });
+        let dynamic_img = DynamicImage::ImageRgb8(img);
+        let mut bytes = Vec::new();
+        dynamic_img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    fn create_gradient_png() -> Vec<u8> {
+        // Create a 128x128 RGB gradient so encoders have real content to work with
+        let img = image::ImageBuffer::from_fn(128, 128, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 2) as u8, ((x + y) % 256) as u8])
+        });
+        let dynamic_img = DynamicImage::ImageRgb8(img);
+        let mut bytes = Vec::new();
+        dynamic_img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    fn create_test_jpeg() -> Vec<u8> {
+        // Create a simple 100x100 blue JPEG image
+        let img = image::ImageBuffer::from_fn(100, 100, |_, _| {
+            image::Rgb([0, 0, 255]) // Blue pixel
+        });
+        let dynamic_img = DynamicImage::ImageRgb8(img);
+        let mut bytes = Vec::new();
+        dynamic_img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_png_compression() {
+        let png_data = create_test_png();
+        let opts = CompressionOptions {
+            png_lossy: true,
+            png_quality: "50-80".to_string(),
+            oxipng: true,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts);
+        assert!(result.is_ok());
+        
+        let (compressed, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/png");
+        assert!(!compressed.is_empty());
+        // Compressed should typically be smaller, but for small test images it might not be
+    }
+
+    #[test]
+    fn test_jpeg_compression() {
+        let jpeg_data = create_test_jpeg();
+        let opts = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&jpeg_data, "jpeg", &opts);
+        assert!(result.is_ok());
+        
+        let (compressed, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/jpeg");
+        assert!(!compressed.is_empty());
+    }
+
+    #[test]
+    fn test_format_conversions() {
+        let png_data = create_test_png();
+        
+        // Test PNG to WebP conversion
+        let opts_webp = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: true,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts_webp);
+        assert!(result.is_ok());
+        let (_, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/webp");
+        
+        // Test PNG to TIFF conversion
+        let opts_tiff = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: true,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts_tiff);
+        assert!(result.is_ok());
+        let (_, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/tiff");
+        
+        // Test PNG to BMP conversion
+        let opts_bmp = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: true,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts_bmp);
+        assert!(result.is_ok());
+        let (_, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/bmp");
+        
+        // Test PNG to ICO conversion
+        let opts_ico = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: true,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts_ico);
+        assert!(result.is_ok());
+        let (_, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/x-icon");
+    }
+
+    #[test]
+    fn test_heic_conversion() {
+        // For this test, we'll use a JPEG as a stand-in for HEIC
+        // since actual HEIC files require special libraries
+        let jpeg_data = create_test_jpeg();
+        let opts = CompressionOptions {
+            png_lossy: false,
+            png_quality: "50-80".to_string(),
+            oxipng: false,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        // Test HEIC extension triggers JPEG conversion
+        let result = compress_image_inproc(&jpeg_data, "heic", &opts);
+        assert!(result.is_ok());
+        let (_, mime_type) = result.unwrap();
+        assert_eq!(mime_type, "image/jpeg");
+    }
+
+    #[test]
+    fn test_quality_parsing() {
+        assert_eq!(parse_quality_range("50-80"), (50, 80));
+        assert_eq!(parse_quality_range("40-70"), (40, 70));
+        assert_eq!(parse_quality_range("20-60"), (20, 60)); // Max compression
+        assert_eq!(parse_quality_range("70-90"), (70, 90)); // Low compression
+        assert_eq!(parse_quality_range("60"), (60, 80)); // Default max
+        assert_eq!(parse_quality_range("invalid"), (50, 80)); // Default values
+    }
+    
+    #[test] 
+    fn test_max_compression_levels() {
+        let png_data = create_test_png();
+        
+        // Test max compression PNG (20-60 range)
+        let opts_max = CompressionOptions {
+            png_lossy: true,
+            png_quality: "20-60".to_string(),
+            oxipng: true,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result_max = compress_image_inproc(&png_data, "png", &opts_max);
+        assert!(result_max.is_ok());
+        
+        // Test low compression PNG (70-90 range)
+        let opts_low = CompressionOptions {
+            png_lossy: true,
+            png_quality: "70-90".to_string(),
+            oxipng: true,
+            to_webp: false,
+            to_avif: false,
+            to_jpeg: false,
+            to_png: false,
+            to_tiff: false,
+            to_bmp: false,
+            to_ico: false,
+            ..Default::default()
+        };
+        
+        let result_low = compress_image_inproc(&png_data, "png", &opts_low);
+        assert!(result_low.is_ok());
+        
+        // Max compression should typically produce smaller files
+        let (max_bytes, _) = result_max.unwrap();
+        let (low_bytes, _) = result_low.unwrap();
+        
+        // For very small test images this might not always hold, but ensure both work
+        assert!(!max_bytes.is_empty());
+        assert!(!low_bytes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_web_api_compression() {
+        // This would require more complex setup to test the actual multipart handling
+        // For now, we'll just test that the core compression functions work
+        let png_data = create_test_png();
+        
+        // Test that our compression function can handle the data
+        let opts = CompressionOptions {
+            png_lossy: true,
             png_quality: "50-80".to_string(),
             oxipng: false,
             to_webp: false,
@@ -851,200 +7303,3132 @@ mod tests {
             to_tiff: false,
             to_bmp: false,
             to_ico: false,
+            ..Default::default()
+        };
+        
+        let result = compress_image_inproc(&png_data, "png", &opts);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_png_interlace_control() {
+        let png_data = create_gradient_png();
+        assert!(!png_is_interlaced(&png_data));
+
+        let opts_on = CompressionOptions {
+            png_interlace: PngInterlace::On,
+            ..Default::default()
+        };
+        let (interlaced, _) = compress_image_inproc(&png_data, "png", &opts_on).unwrap();
+        assert!(png_is_interlaced(&interlaced));
+
+        let opts_off = CompressionOptions {
+            png_interlace: PngInterlace::Off,
+            ..Default::default()
+        };
+        let (plain, _) = compress_image_inproc(&png_data, "png", &opts_off).unwrap();
+        assert!(!png_is_interlaced(&plain));
+        assert_ne!(interlaced.len(), plain.len());
+
+        // Keep follows the source: re-compressing interlaced output stays interlaced
+        let opts_keep = CompressionOptions {
+            png_quality: "0-80".to_string(),
+            ..Default::default()
+        };
+        let (kept, _) = compress_image_inproc(&interlaced, "png", &opts_keep).unwrap();
+        assert!(png_is_interlaced(&kept));
+    }
+
+    #[test]
+    fn test_min_savings_threshold() {
+        // 2% gain is below a 5% threshold, 20% is not
+        assert!(below_min_savings(1000, 980, 5.0));
+        assert!(!below_min_savings(1000, 800, 5.0));
+        // Growth never clears a threshold
+        assert!(below_min_savings(1000, 1100, 0.0));
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("gradient.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+
+        let strict = Args::parse_from(["rust_tinypng_clone", "--min-savings", "99.9"]);
+        let res = process_file(&src, &strict, &ctx);
+        assert!(matches!(res.status, FileStatus::Skipped(_)));
+        assert!(!dir.path().join("out/c_gradient.png").exists());
+
+        let lenient = Args::parse_from(["rust_tinypng_clone", "--min-savings", "1"]);
+        let res = process_file(&src, &lenient, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+        assert!(dir.path().join("out/c_gradient.png").exists());
+    }
+
+    #[tokio::test]
+    async fn test_request_id_header() {
+        use tower::ServiceExt;
+
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let res = build_router(&ServerOptions::default()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let id = res.headers().get(REQUEST_ID_HEADER).expect("missing X-Request-Id");
+        assert!(uuid::Uuid::parse_str(id.to_str().unwrap()).is_ok());
+
+        // A client-supplied ID is preserved rather than replaced
+        let req = Request::builder()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "trace-me")
+            .body(Body::empty())
+            .unwrap();
+        let res = build_router(&ServerOptions::default()).oneshot(req).await.unwrap();
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "trace-me");
+    }
+
+    #[test]
+    fn test_strict_format_mismatch() {
+        assert!(check_format_matches(&create_test_png(), "png").is_ok());
+        assert!(check_format_matches(&create_test_jpeg(), "jpeg").is_ok());
+        assert!(check_format_matches(&create_test_jpeg(), "png").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let misnamed = dir.path().join("photo.png");
+        fs::write(&misnamed, create_test_jpeg()).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+
+        let strict = Args::parse_from(["rust_tinypng_clone", "--strict-format"]);
+        let res = process_file(&misnamed, &strict, &ctx);
+        match res.status {
+            FileStatus::Failed(msg) => assert!(msg.starts_with("format-mismatch"), "{}", msg),
+            other => panic!("expected failure, got {:?}", other),
+        }
+
+        // Default mode trusts the bytes and carries on
+        let lenient = Args::parse_from(["rust_tinypng_clone"]);
+        let res = process_file(&misnamed, &lenient, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+    }
+
+    #[tokio::test]
+    async fn test_http_compression() {
+        use std::io::Read as _;
+        use tower::ServiceExt;
+
+        let server_opts = ServerOptions { http_compression: true, ..Default::default() };
+        let req = Request::builder()
+            .uri("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = build_router(&server_opts).oneshot(req).await.unwrap();
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let mut html = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut html).unwrap();
+        assert_eq!(html, INDEX_HTML);
+
+        let req = Request::builder()
+            .uri("/")
+            .header(header::ACCEPT_ENCODING, "br")
+            .body(Body::empty())
+            .unwrap();
+        let res = build_router(&server_opts).oneshot(req).await.unwrap();
+        assert_eq!(res.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+
+        // SSE progress has to stream event by event, so it's never encoded
+        let req = Request::builder()
+            .uri("/api/progress/some-batch")
+            .header(header::ACCEPT_ENCODING, "gzip, br")
+            .body(Body::empty())
+            .unwrap();
+        let res = build_router(&server_opts).oneshot(req).await.unwrap();
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/event-stream");
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+
+        // Opt-in only: without the flag responses are left as-is
+        let req = Request::builder()
+            .uri("/")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = build_router(&ServerOptions::default()).oneshot(req).await.unwrap();
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn test_svg_intrinsic_size() {
+        let sized = br#"<svg xmlns="http://www.w3.org/2000/svg" width="120px" height="60"><rect width="10" height="10"/></svg>"#;
+        assert_eq!(svg_intrinsic_size(sized), Some((120.0, 60.0)));
+        assert_eq!(svg_output_size(sized, 2.0, None).unwrap(), (240, 120));
+        assert_eq!(svg_output_size(sized, 1.0, Some(60)).unwrap(), (60, 30));
+
+        let view_box = br#"<svg viewBox="0 0 32 16" xmlns="http://www.w3.org/2000/svg"></svg>"#;
+        assert_eq!(svg_intrinsic_size(view_box), Some((32.0, 16.0)));
+
+        // Percent sizes and no viewBox: caller must pick a width
+        let unsized_svg = br#"<svg width="100%" height="100%"></svg>"#;
+        assert_eq!(svg_intrinsic_size(unsized_svg), None);
+        assert!(svg_output_size(unsized_svg, 1.0, None).is_err());
+        assert_eq!(svg_output_size(unsized_svg, 1.0, Some(48)).unwrap(), (48, 48));
+    }
+
+    #[cfg(feature = "svg")]
+    #[test]
+    fn test_svg_rasterize_to_png() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20"><rect width="40" height="20" fill="#ff0000"/></svg>"##;
+        let opts = CompressionOptions {
+            svg_scale: 2.0,
+            ..Default::default()
+        };
+        let (png, mime_type) = compress_image_inproc(svg, "svg", &opts).unwrap();
+        assert_eq!(mime_type, "image/png");
+        let img = image::load_from_memory(&png).unwrap();
+        assert_eq!((img.width(), img.height()), (80, 40));
+        let px = img.to_rgba8().get_pixel(40, 20).0;
+        assert!(px[0] > 200 && px[1] < 50 && px[3] == 255);
+    }
+
+    #[test]
+    fn test_keep_original_on_error() {
+        assert!(validate_output(&create_test_png()).is_ok());
+        assert!(validate_output(b"not an image").is_err());
+        let mut truncated = create_test_jpeg();
+        truncated.truncate(truncated.len() / 3);
+        assert!(validate_output(&truncated).is_err());
+
+        // Garbage behind a .png name makes the encoder fail
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("broken.png");
+        let mut original = b"\x89PNG\r\n\x1a\n".to_vec();
+        original.extend_from_slice(&png_chunk(b"IHDR", b"garbage"));
+        original.extend_from_slice(&png_chunk(b"IEND", &[]));
+        fs::write(&src, &original).unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--overwrite", "--keep-original-on-error"]);
+        let ctx = CliContext::new(dir.path().into(), None, "50-80");
+        let res = process_file(&src, &args, &ctx);
+        assert!(matches!(res.status, FileStatus::Failed(_)));
+        assert_eq!(fs::read(&src).unwrap(), original);
+        assert!(!dir.path().join("c_broken.png").exists());
+        let marker = fs::read_to_string(failure_marker_path(&src)).unwrap();
+        assert!(marker.starts_with("compress-failed"));
+    }
+
+    #[test]
+    fn test_adaptive_palette_choice() {
+        // Flat four-color logo
+        let logo = DynamicImage::ImageRgba8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+            match (x < 32, y < 32) {
+                (true, true) => image::Rgba([255, 0, 0, 255]),
+                (false, true) => image::Rgba([0, 255, 0, 255]),
+                (true, false) => image::Rgba([0, 0, 255, 255]),
+                (false, false) => image::Rgba([255, 255, 255, 0]),
+            }
+        }));
+        // Noisy photo-like content
+        let mut seed = 12345u32;
+        let photo = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let n = (seed >> 16) as u8 % 64;
+            image::Rgb([(x * 3) as u8 ^ n, (y * 3) as u8 ^ n, n.wrapping_mul(3)])
+        }));
+
+        let (_, _, logo_colors) = choose_adaptive_quality(&logo, 50, 80);
+        let (photo_min, _, photo_colors) = choose_adaptive_quality(&photo, 50, 80);
+        assert_eq!(logo_colors, 4);
+        assert_eq!(photo_colors, 256);
+        assert!(photo_min >= 50);
+
+        let mut logo_png = Vec::new();
+        logo.write_to(&mut Cursor::new(&mut logo_png), ImageFormat::Png).unwrap();
+        let opts = CompressionOptions {
+            adaptive: true,
+            ..Default::default()
+        };
+        let (out, _) = compress_image_inproc(&logo_png, "png", &opts).unwrap();
+        let decoded = image::load_from_memory(&out).unwrap();
+        assert!(count_colors(&decoded, 256) <= 4);
+    }
+
+    /// List (name, size) entries of an uncompressed tar stream
+    fn read_tar_entries(data: &[u8]) -> Vec<(String, usize)> {
+        tar::Archive::new(data)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.path().unwrap().to_string_lossy().into_owned(), entry.size() as usize)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tar_gz_output() {
+        use std::io::Read as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("assets");
+        fs::create_dir_all(input.join("icons")).unwrap();
+        fs::write(input.join("hero.png"), create_gradient_png()).unwrap();
+        fs::write(input.join("icons/dot.jpg"), create_test_jpeg()).unwrap();
+        let tar_path = dir.path().join("bundle.tar.gz");
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--tar", tar_path.to_str().unwrap()]);
+        let mut ctx = CliContext::new(input.clone(), None, "50-80");
+        ctx.tar = Some(Mutex::new(TarWriter::create(&tar_path).unwrap()));
+        for f in discover_files(&input, &[]).0 {
+            assert_eq!(process_file(&f, &args, &ctx).status, FileStatus::Compressed);
+        }
+        ctx.tar.unwrap().into_inner().unwrap().finish().unwrap();
+
+        // Nothing written loose next to the sources
+        assert!(!input.join("c_hero.png").exists());
+
+        let mut raw = Vec::new();
+        flate2::read::GzDecoder::new(fs::File::open(&tar_path).unwrap()).read_to_end(&mut raw).unwrap();
+        let mut names: Vec<String> = read_tar_entries(&raw).into_iter().map(|(n, size)| {
+            assert!(size > 0);
+            n
+        }).collect();
+        names.sort();
+        assert_eq!(names, vec!["hero.png".to_string(), "icons/dot.jpg".to_string()]);
+
+        let mut w = TarWriter::create(&dir.path().join("long.tar")).unwrap();
+        let long_name = format!("{}/{}.png", "d".repeat(120), "f".repeat(40));
+        w.append(&long_name, b"x").unwrap();
+        w.finish().unwrap();
+        let raw = fs::read(dir.path().join("long.tar")).unwrap();
+        assert_eq!(read_tar_entries(&raw), vec![(long_name, 1)]);
+    }
+
+    #[test]
+    fn test_encode_only_lossless() {
+        let png_data = create_gradient_png();
+        let args = Args::parse_from(["rust_tinypng_clone", "--encode-only"]);
+        let opts = CompressionOptions::from_args(&args, "50-80");
+        let (out, mime_type) = compress_image_inproc(&png_data, "png", &opts).unwrap();
+        assert_eq!(mime_type, "image/png");
+        assert!(out.len() < png_data.len());
+
+        let before = image::load_from_memory(&png_data).unwrap().to_rgba8();
+        let after = image::load_from_memory(&out).unwrap().to_rgba8();
+        assert_eq!(before.dimensions(), after.dimensions());
+        assert_eq!(before.as_raw(), after.as_raw());
+    }
+
+    #[test]
+    fn test_deterministic_output() {
+        let args = Args::parse_from(["rust_tinypng_clone", "--deterministic", "--jobs", "8"]);
+        assert_eq!(worker_threads(&args), 1);
+        assert_eq!(worker_threads(&Args::parse_from(["rust_tinypng_clone", "--jobs", "8"])), 8);
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("gradient.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let out = dir.path().join("out/c_gradient.png");
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(worker_threads(&args)).build().unwrap();
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            pool.install(|| assert_eq!(process_file(&src, &args, &ctx).status, FileStatus::Compressed));
+            runs.push(fs::read(&out).unwrap());
+        }
+        assert_eq!(runs[0], runs[1]);
+
+        let webp_args = Args::parse_from(["rust_tinypng_clone", "--deterministic", "--to-webp"]);
+        let opts = CompressionOptions::from_args(&webp_args, "50-80");
+        let png = create_gradient_png();
+        let first = pool.install(|| compress_image_inproc(&png, "png", &opts).unwrap().0);
+        let second = pool.install(|| compress_image_inproc(&png, "png", &opts).unwrap().0);
+        assert_eq!(first, second);
+    }
+
+    /// Build a multipart/form-data body from (name, filename, bytes) parts
+    fn multipart_body(parts: &[(&str, Option<&str>, &[u8])]) -> (String, Vec<u8>) {
+        let boundary = "XTESTBOUNDARYX".to_string();
+        let mut body = Vec::new();
+        for (name, filename, data) in parts {
+            body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+            match filename {
+                Some(f) => body.extend_from_slice(
+                    format!("Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\r\n", name, f).as_bytes(),
+                ),
+                None => body.extend_from_slice(format!("Content-Disposition: form-data; name=\"{}\"\r\n\r\n", name).as_bytes()),
+            }
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+        (boundary, body)
+    }
+
+    /// POST a multipart body to the router and return (status, headers, body)
+    async fn post_multipart(uri: &str, parts: &[(&str, Option<&str>, &[u8])]) -> (StatusCode, HeaderMap, Vec<u8>) {
+        use tower::ServiceExt;
+
+        let (boundary, body) = multipart_body(parts);
+        let req = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(Body::from(body))
+            .unwrap();
+        let res = build_router(&ServerOptions::default()).oneshot(req).await.unwrap();
+        let (status, headers) = (res.status(), res.headers().clone());
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, headers, bytes.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_web_batch_partial_failure() {
+        use base64::Engine as _;
+
+        let png = create_test_png();
+        let (status, headers, body) = post_multipart(
+            "/api/compress",
+            &[
+                ("output_format", None, b"png"),
+                ("file", Some("good.png"), &png),
+                ("file", Some("corrupt.png"), b"\x89PNG definitely not an image"),
+            ],
+        )
+        .await;
+        assert_eq!(status, StatusCode::MULTI_STATUS);
+        assert!(headers.get(header::RETRY_AFTER).is_none());
+
+        let manifest: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(manifest["succeeded"], 1);
+        assert_eq!(manifest["failed"], 1);
+        let good = &manifest["results"][0];
+        assert_eq!(good["ok"], true);
+        assert_eq!(good["mime_type"], "image/png");
+        let data = base64::engine::general_purpose::STANDARD.decode(good["data"].as_str().unwrap()).unwrap();
+        assert!(image::load_from_memory(&data).is_ok());
+        let bad = &manifest["results"][1];
+        assert_eq!(bad["ok"], false);
+        assert!(bad["error"].is_string());
+
+        // Single uploads keep the plain binary response
+        let (status, headers, body) = post_multipart("/api/compress", &[("file", Some("good.png"), &png)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "image/webp");
+        assert!(!body.is_empty());
+    }
+
+    #[test]
+    fn test_webp_method_changes_output() {
+        // Busy texture, where method 6's extra search pays off (about a quarter smaller)
+        let texture = image::RgbImage::from_fn(256, 256, |x, y| {
+            let (fx, fy) = (x as f32 / 16.0, y as f32 / 21.0);
+            image::Rgb([(128.0 + 100.0 * fx.sin() * fy.cos()) as u8, (128.0 + 90.0 * (fx + fy).sin()) as u8, ((x * y) % 251) as u8])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(texture).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let fast = to_webp_bytes(&png, 75.0, 0, false, false, false, false).unwrap();
+        let thorough = to_webp_bytes(&png, 75.0, 6, false, false, false, false).unwrap();
+        assert!(webp::Decoder::new(&thorough).decode().is_some());
+        assert!(thorough.len() < fast.len(), "method 6 {} vs method 0 {}", thorough.len(), fast.len());
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--webp-method", "6"]);
+        assert_eq!(CompressionOptions::from_args(&args, "50-80").webp_method, 6);
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--webp-method", "7"]).is_err());
+    }
+
+    #[test]
+    fn test_data_uri_output() {
+        use base64::Engine as _;
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("icon.png");
+        fs::write(&src, create_test_png()).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--data-uri"]);
+
+        let res = process_file(&src, &args, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+        assert!(!dir.path().join("out/c_icon.png").exists());
+        let uri = fs::read_to_string(dir.path().join("out/c_icon.png.txt")).unwrap();
+        let payload = uri.strip_prefix("data:image/png;base64,").expect("not a PNG data URI");
+        let decoded = base64::engine::general_purpose::STANDARD.decode(payload).unwrap();
+
+        let opts = CompressionOptions::from_args(&args, "50-80");
+        let (expected, _) = compress_image_inproc(&create_test_png(), "png", &opts).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_format_map_per_extension() {
+        let map = parse_format_map("png=webp, .JPEG=jpeg,tif=original").unwrap();
+        assert_eq!(map.get("png"), Some(&OutputFormat::Webp));
+        assert_eq!(map.get("jpg"), Some(&OutputFormat::Jpeg));
+        assert_eq!(map.get("tif"), Some(&OutputFormat::Original));
+        assert!(parse_format_map("png").is_err());
+        assert!(parse_format_map("png=gif").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.png"), create_test_png()).unwrap();
+        fs::write(dir.path().join("b.jpg"), create_test_jpeg()).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        // --to-avif is the global default, but both extensions are mapped
+        let args = Args::parse_from(["rust_tinypng_clone", "--to-avif", "--map", "png=webp,jpg=jpeg"]);
+
+        for name in ["a.png", "b.jpg"] {
+            let res = process_file(&dir.path().join(name), &args, &ctx);
+            assert_eq!(res.status, FileStatus::Compressed, "{}", name);
+        }
+        let webp = fs::read(dir.path().join("out/c_a.webp")).unwrap();
+        assert_eq!(infer::get(&webp).unwrap().mime_type(), "image/webp");
+        let jpeg = fs::read(dir.path().join("out/c_b.jpg")).unwrap();
+        assert_eq!(infer::get(&jpeg).unwrap().mime_type(), "image/jpeg");
+        assert!(!dir.path().join("out/c_a.avif").exists());
+    }
+
+    #[test]
+    fn test_mapped_input_matches_buffered() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("big.png");
+        let png = create_gradient_png();
+        fs::write(&src, &png).unwrap();
+        let size = png.len() as u64;
+
+        let buffered = read_input(&src, size, None).unwrap();
+        let mapped = read_input(&src, size, Some(size - 1)).unwrap();
+        assert!(matches!(buffered, InputBytes::Buffered(_)));
+        assert!(matches!(mapped, InputBytes::Mapped(_)));
+        assert_eq!(&*mapped, &png[..]);
+
+        let opts = CompressionOptions::default();
+        let (from_buf, _) = compress_image_inproc(&buffered, "png", &opts).unwrap();
+        let (from_map, _) = compress_image_inproc(&mapped, "png", &opts).unwrap();
+        assert_eq!(from_buf, from_map);
+
+        // Empty files are never over the threshold, so they're simply read
+        let empty = dir.path().join("empty.png");
+        fs::write(&empty, b"").unwrap();
+        assert!(matches!(read_input(&empty, 0, Some(0)).unwrap(), InputBytes::Buffered(_)));
+    }
+
+    #[test]
+    fn test_ico_decode_and_reencode() {
+        let png_of = |size: u32| {
+            let img = image::RgbaImage::from_fn(size, size, |x, y| image::Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255]));
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+            bytes
+        };
+        let (small, large) = (png_of(16), png_of(48));
+        let ico = build_ico(&[(16, 16, 32, &small), (48, 48, 32, &large)]);
+
+        let entries = parse_ico_entries(&ico).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].data, &large[..]);
+        let largest = decode_ico_largest(&ico).unwrap();
+        assert_eq!((largest.width(), largest.height()), (48, 48));
+
+        let (out, mime) = compress_image_inproc(&ico, "ico", &CompressionOptions::default()).unwrap();
+        assert_eq!(mime, "image/x-icon");
+        let reread = load_image_as(&out, ImageFormat::Ico).unwrap();
+        assert_eq!((reread.width(), reread.height()), (48, 48));
+
+        // A single-size ICO written by the image crate (BMP payload) decodes too
+        let mut bmp_ico = Vec::new();
+        image::load_from_memory(&small).unwrap().write_to(&mut Cursor::new(&mut bmp_ico), ImageFormat::Ico).unwrap();
+        assert_eq!(decode_ico_largest(&bmp_ico).unwrap().width(), 16);
+
+        assert!(parse_ico_entries(b"not an icon").is_err());
+    }
+
+    /// Minimal 1x1 GIF with `frames` image blocks
+    fn create_test_gif(frames: usize) -> Vec<u8> {
+        let mut gif = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\xff\x00\x00\x00\x00\xff".to_vec();
+        for _ in 0..frames {
+            gif.extend_from_slice(b"\x21\xf9\x04\x00\x0a\x00\x00\x00"); // graphic control, 100ms
+            gif.extend_from_slice(b"\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00");
+            gif.extend_from_slice(b"\x02\x02\x44\x01\x00");
+        }
+        gif.push(0x3b);
+        gif
+    }
+
+    #[test]
+    fn test_preserve_animation_guard() {
+        assert_eq!(animation_frames(&create_test_gif(3)), Some(("GIF", 3)));
+        assert_eq!(animation_frames(&create_test_gif(1)), None);
+        assert_eq!(animation_frames(&create_test_png()), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("spinner.gif"), create_test_gif(2)).unwrap();
+        fs::write(dir.path().join("dot.gif"), create_test_gif(1)).unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(out.path().into()), "50-80");
+        let mut args = Args::parse_from(["rust_tinypng_clone", "--preserve-animation"]);
+
+        // GIFs are discovered so the animated one is reported rather than silently skipped
+        let (files, _) = discover_files(dir.path(), &discovery_exts(&args));
+        assert_eq!(files, [dir.path().join("dot.gif"), dir.path().join("spinner.gif")]);
+        let results: Vec<_> = files.iter().map(|f| process_file(f, &args, &ctx)).collect();
+        assert_eq!(results[0].status, FileStatus::Compressed);
+        assert!(out.path().join("c_dot.png").exists());
+        match &results[1].status {
+            FileStatus::Failed(msg) => assert!(msg.starts_with("animation-lost"), "{}", msg),
+            other => panic!("expected failure, got {:?}", other),
+        }
+        assert!(!out.path().join("c_spinner.png").exists());
+
+        // Without the flag GIFs aren't picked up, and the check is skipped entirely
+        args.preserve_animation = false;
+        assert!(discover_files(dir.path(), &discovery_exts(&args)).0.is_empty());
+        let res = process_file(&dir.path().join("spinner.gif"), &args, &ctx);
+        assert!(!matches!(res.status, FileStatus::Failed(ref msg) if msg.starts_with("animation-lost")));
+    }
+
+    #[tokio::test]
+    async fn test_stdout_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("photo.jpg");
+        fs::write(&src, create_test_jpeg()).unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--stdout", src.to_str().unwrap()]);
+        let mut captured = Vec::new();
+        write_compressed(&src, &args, &mut captured).unwrap();
+        assert_eq!(infer::get(&captured).unwrap().mime_type(), "image/jpeg");
+        assert!(image::load_from_memory(&captured).is_ok());
+        // Nothing lands next to the input
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let dir_args = Args::parse_from(["rust_tinypng_clone", "--stdout", dir.path().to_str().unwrap()]);
+        let err = run_cli_mode(&dir_args).await.unwrap_err();
+        assert!(err.to_string().contains("single file"), "{}", err);
+    }
+
+    #[test]
+    fn test_jpeg_smoothing_shrinks_noisy_image() {
+        // Deterministic grain on a flat gray field
+        let mut seed = 0x2545_f491_u32;
+        let img = image::ImageBuffer::from_fn(128, 128, |_, _| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let v = 96 + ((seed >> 16) % 64) as u8;
+            image::Rgb([v, v, v])
+        });
+        let mut noisy = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut noisy), ImageFormat::Png).unwrap();
+
+        let plain = compress_jpeg_bytes(&noisy, 75, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+        let smoothed = compress_jpeg_bytes(&noisy, 75, 60, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+        assert!(smoothed.len() < plain.len(), "{} >= {}", smoothed.len(), plain.len());
+
+        let opts = CompressionOptions { jpeg_smoothing: 60, to_jpeg: true, ..Default::default() };
+        let (via_opts, _) = compress_image_inproc(&noisy, "png", &opts).unwrap();
+        assert!(via_opts.len() < compress_jpeg_bytes(&noisy, 65, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap().len());
+    }
+
+    #[test]
+    fn test_max_dimension_cap_side() {
+        let (portrait, landscape) = ((60, 120), (120, 60));
+        assert_eq!(capped_dimensions(portrait.0, portrait.1, 40, CapSide::Longest), Some((20, 40)));
+        assert_eq!(capped_dimensions(landscape.0, landscape.1, 40, CapSide::Longest), Some((40, 20)));
+        assert_eq!(capped_dimensions(portrait.0, portrait.1, 40, CapSide::Shortest), Some((40, 80)));
+        assert_eq!(capped_dimensions(landscape.0, landscape.1, 40, CapSide::Shortest), Some((80, 40)));
+        // Already within the cap: untouched
+        assert_eq!(capped_dimensions(portrait.0, portrait.1, 120, CapSide::Longest), None);
+        assert_eq!(capped_dimensions(portrait.0, portrait.1, 60, CapSide::Shortest), None);
+
+        let png_of = |(w, h): (u32, u32)| {
+            let img = image::RgbImage::from_fn(w, h, |x, y| image::Rgb([x as u8, y as u8, 90]));
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+            bytes
+        };
+        for (dims, side, expected) in [
+            (portrait, CapSide::Longest, (20, 40)),
+            (landscape, CapSide::Shortest, (80, 40)),
+        ] {
+            let opts = CompressionOptions { max_dimension: Some(40), cap_side: side, ..Default::default() };
+            let (out, mime) = compress_image_inproc(&png_of(dims), "png", &opts).unwrap();
+            assert_eq!(mime, "image/png");
+            let img = image::load_from_memory(&out).unwrap();
+            assert_eq!((img.width(), img.height()), expected);
+        }
+    }
+
+    #[test]
+    fn test_hash_names() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("icons")).unwrap();
+        let src = dir.path().join("icons/logo.png");
+        fs::write(&src, create_test_png()).unwrap();
+        let out_dir = dir.path().join("out");
+        let ctx = CliContext::new(dir.path().into(), Some(out_dir.clone()), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--hash-names", "--to-webp"]);
+
+        let res = process_file(&src, &args, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+
+        let names = ctx.hashed_names.lock().unwrap().clone();
+        let hashed = names.get("icons/logo.png").expect("missing manifest entry");
+        let bytes = fs::read(out_dir.join(hashed)).unwrap();
+        let parts: Vec<&str> = hashed.split('.').collect();
+        assert_eq!(parts.len(), 3, "{}", hashed);
+        assert_eq!((parts[0], parts[2]), ("logo", "webp"));
+        assert_eq!(parts[1], content_hash(&bytes));
+        assert_eq!(parts[1].len(), 8);
+        assert_eq!(content_hash(b"abc"), "6437b3ac");
+    }
+
+    /// Single-page PDF with one Flate-compressed RGB image
+    #[cfg(feature = "pdf")]
+    fn create_test_pdf(w: u32, h: u32) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..h)
+            .flat_map(|y| (0..w).flat_map(move |x| {
+                let v = ((x as f32 / 9.0).sin() * 60.0 + (y as f32 / 13.0).cos() * 60.0 + 128.0) as u8;
+                [v, v / 2, 255 - v]
+            }))
+            .collect();
+        let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&pixels).unwrap();
+        let image = enc.finish().unwrap();
+
+        let content = format!("q {} 0 0 {} 0 0 cm /Im1 Do Q", w, h);
+        let mut objects: Vec<Vec<u8>> = vec![
+            b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+            b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+            format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /XObject << /Im1 5 0 R >> >> /Contents 4 0 R >>", w, h).into_bytes(),
+            format!("<< /Length {} >>\nstream\n{}\nendstream", content.len(), content).into_bytes(),
+        ];
+        let mut img_obj = format!(
+            "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceRGB /BitsPerComponent 8 /Filter /FlateDecode /Length {} >>\nstream\n",
+            w, h, image.len()
+        )
+        .into_bytes();
+        img_obj.extend_from_slice(&image);
+        img_obj.extend_from_slice(b"\nendstream");
+        objects.push(img_obj);
+
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = Vec::new();
+        for (i, body) in objects.iter().enumerate() {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+            pdf.extend_from_slice(body);
+            pdf.extend_from_slice(b"\nendobj\n");
+        }
+        let xref = pdf.len();
+        pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for off in offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", off).as_bytes());
+        }
+        pdf.extend_from_slice(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes());
+        pdf
+    }
+
+    #[cfg(feature = "pdf")]
+    #[test]
+    fn test_pdf_image_recompression() {
+        let pdf = create_test_pdf(300, 200);
+        let (out, mime) = compress_image_inproc(&pdf, "pdf", &CompressionOptions::default()).unwrap();
+        assert_eq!(mime, "application/pdf");
+        assert!(out.len() < pdf.len(), "{} >= {}", out.len(), pdf.len());
+
+        let doc = lopdf::Document::load_mem(&out).unwrap();
+        assert_eq!(doc.objects.keys().map(|id| id.0).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+        let image = doc.get_object((5, 0)).unwrap().as_stream().unwrap();
+        assert_eq!(image.filters().unwrap(), [b"DCTDecode"]);
+        let jpeg = load_image_as(&image.content, ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (300, 200));
+        // Untouched objects are carried over as they were
+        let contents = doc.get_object((4, 0)).unwrap().as_stream().unwrap();
+        assert!(String::from_utf8_lossy(&contents.content).contains("/Im1 Do"));
+
+        // --lossless keeps the Flate image as is
+        let lossless = CompressionOptions { lossless: true, ..Default::default() };
+        let (out, _) = compress_image_inproc(&pdf, "pdf", &lossless).unwrap();
+        let doc = lopdf::Document::load_mem(&out).unwrap();
+        assert_eq!(doc.get_object((5, 0)).unwrap().as_stream().unwrap().filters().unwrap(), [b"FlateDecode"]);
+
+        // Absurd declared sizes are skipped rather than overflowing
+        let doc = lopdf::Document::load_mem(&pdf).unwrap();
+        let stream = doc.get_object((5, 0)).unwrap().as_stream().unwrap();
+        for (w, h) in [(4294967296, 1), (4294967295, 4294967295), (-1, 200)] {
+            let mut huge = stream.clone();
+            huge.dict.set("Width", lopdf::Object::Integer(w));
+            huge.dict.set("Height", lopdf::Object::Integer(h));
+            assert!(recompress_pdf_image(&huge, 75, 0, JpegEffort::Balanced).is_none());
+        }
+
+        // A /Length running past the end of the file (or of usize) fails cleanly
+        let at = find_bytes(&pdf, b"/FlateDecode /Length ", 0).unwrap() + 21;
+        let end = at + pdf[at..].iter().position(|b| !b.is_ascii_digit()).unwrap();
+        for len in ["18446744073709551615", "99999999"] {
+            let mut bad = pdf[..at].to_vec();
+            bad.extend_from_slice(len.as_bytes());
+            bad.extend_from_slice(&pdf[end..]);
+            let _ = compress_image_inproc(&bad, "pdf", &CompressionOptions::default());
+            let _ = pdf_decode_cost(&bad);
+        }
+
+        // PDF 1.5 object and xref streams load too
+        let mut modern = Vec::new();
+        lopdf::Document::load_mem(&pdf).unwrap().save_modern(&mut modern).unwrap();
+        let (out, _) = compress_image_inproc(&modern, "pdf", &CompressionOptions::default()).unwrap();
+        let doc = lopdf::Document::load_mem(&out).unwrap();
+        assert_eq!(doc.get_object((5, 0)).unwrap().as_stream().unwrap().filters().unwrap(), [b"DCTDecode"]);
+        assert_eq!(pdf_decode_cost(&modern), Some(300 * 200 * 4));
+    }
+
+    #[test]
+    fn test_only_extensions_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.png"), create_test_png()).unwrap();
+        fs::write(dir.path().join("b.JPEG"), create_test_jpeg()).unwrap();
+        fs::write(dir.path().join("c.bmp"), b"BM").unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--only", "png,.jpg"]);
+        let only = args.only.as_deref().unwrap();
+        assert_eq!(only, ["png", "jpg"]);
+        let kept = filter_extensions(discover_files(dir.path(), &[]).0, only);
+        let names: Vec<_> = kept.iter().map(|p| p.file_name().unwrap().to_str().unwrap()).collect();
+        assert_eq!(names, ["a.png", "b.JPEG"]);
+
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--only", "png,gif"]).is_err());
+    }
+
+    /// Pixels in horizontal runs of identical color at least `min_len` long (visible bands)
+    fn banded_pixels(img: &image::RgbaImage, min_len: u32) -> u32 {
+        let mut total = 0;
+        for y in 0..img.height() {
+            let mut run = 1;
+            for x in 1..=img.width() {
+                if x < img.width() && img.get_pixel(x, y) == img.get_pixel(x - 1, y) {
+                    run += 1;
+                } else {
+                    if run >= min_len {
+                        total += run;
+                    }
+                    run = 1;
+                }
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_gradient_noise_reduces_banding() {
+        // Two-axis ramp with more levels than the palette can hold
+        let img = image::RgbImage::from_fn(512, 64, |x, y| image::Rgb([(x / 2) as u8, (y * 2) as u8, 180]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let plain = CompressionOptions { png_quality: "0-20".to_string(), ..Default::default() };
+        let noisy = CompressionOptions { gradient_noise: 2.0, ..plain.clone() };
+        let decode = |opts: &CompressionOptions| {
+            image::load_from_memory(&compress_png_bytes(&png, opts).unwrap()).unwrap().to_rgba8()
+        };
+        let (banded, dithered) = (banded_pixels(&decode(&plain), 10), banded_pixels(&decode(&noisy), 10));
+        assert!(dithered < banded, "{} >= {}", dithered, banded);
+
+        // Flat images are left untouched
+        let mut flat = image::RgbaImage::from_pixel(16, 16, image::Rgba([10, 20, 30, 255]));
+        apply_gradient_noise(&mut flat, 2.0);
+        assert!(flat.pixels().all(|p| p.0 == [10, 20, 30, 255]));
+    }
+
+    /// Per-channel bit depth recorded in the first `pixi` box of an AVIF
+    fn avif_pixi_depth(avif: &[u8]) -> Option<u8> {
+        let at = avif.windows(4).position(|w| w == b"pixi")?;
+        // fourcc, version/flags, channel count, then one depth per channel
+        avif.get(at + 9).copied()
+    }
+
+    #[test]
+    fn test_avif_bit_depth() {
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| image::Rgb([(x * 2000) as u16, (y * 2000) as u16, 40000u16]));
+        let mut png16 = Vec::new();
+        DynamicImage::ImageRgb16(img).write_to(&mut Cursor::new(&mut png16), ImageFormat::Png).unwrap();
+
+        let deep = to_avif_bytes(&png16, 60.0, 10, AvifSubsampling::S444, None, false).unwrap();
+        assert_eq!(avif_pixi_depth(&deep), Some(10));
+        let shallow = to_avif_bytes(&png16, 60.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        assert_eq!(avif_pixi_depth(&shallow), Some(8));
+        assert!(to_avif_bytes(&png16, 60.0, 12, AvifSubsampling::S444, None, false).is_err());
+        assert!(to_avif_bytes(&png16, 60.0, 10, AvifSubsampling::S420, None, false).is_err());
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--avif-depth", "10"]);
+        assert_eq!(args.avif_depth, 10);
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--avif-depth", "9"]).is_err());
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--avif-depth", "12"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_formats_endpoint() {
+        use tower::ServiceExt;
+
+        let req = Request::builder().uri("/api/formats").body(Body::empty()).unwrap();
+        let res = build_router(&ServerOptions::default()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        let list = |key: &str| -> Vec<String> {
+            json[key].as_array().unwrap().iter().map(|v| v.as_str().unwrap().to_string()).collect()
+        };
+        assert!(list("inputs").contains(&"png".to_string()));
+        assert!(list("outputs").contains(&"webp".to_string()));
+        assert!(list("outputs").contains(&"original".to_string()));
+        assert_eq!(list("inputs").contains(&"svg".to_string()), cfg!(feature = "svg"));
+    }
+
+    #[test]
+    fn test_lossless_png_and_webp_keep_pixels() {
+        let png = create_gradient_png();
+        let original = image::load_from_memory(&png).unwrap().to_rgba8();
+        let opts = CompressionOptions { lossless: true, ..Default::default() };
+
+        let (out, mime) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(mime, "image/png");
+        assert_eq!(image::load_from_memory(&out).unwrap().to_rgba8(), original);
+
+        let webp_opts = CompressionOptions { to_webp: true, ..opts };
+        let (out, _) = compress_image_inproc(&png, "png", &webp_opts).unwrap();
+        let decoded = webp::Decoder::new(&out).decode().unwrap();
+        assert_eq!((decoded.width(), decoded.height()), original.dimensions());
+        let channels = if decoded.is_alpha() { 4 } else { 3 };
+        let expected: Vec<u8> = original.pixels().flat_map(|p| p.0[..channels].to_vec()).collect();
+        assert_eq!(&*decoded, expected.as_slice());
+    }
+
+    #[test]
+    fn test_lossless_jpeg_and_avif() {
+        let jpeg = {
+            let img = image::ImageBuffer::from_fn(96, 64, |x, y| image::Rgb([(x * 3) as u8, (y * 4) as u8, ((x * y) % 256) as u8]));
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg).unwrap();
+            bytes
+        };
+        let opts = CompressionOptions { lossless: true, ..Default::default() };
+        let (out, mime) = compress_image_inproc(&jpeg, "jpg", &opts).unwrap();
+        assert_eq!(mime, "image/jpeg");
+        assert!(out.len() <= jpeg.len());
+        // Same DCT coefficients decode to the same pixels
+        assert_eq!(
+            image::load_from_memory(&out).unwrap().to_rgb8(),
+            image::load_from_memory(&jpeg).unwrap().to_rgb8()
+        );
+        assert!(optimize_jpeg_lossless(b"not a jpeg", 0, JpegScanMode::AllComponentsTogether, false).is_err());
+
+        let to_jpeg = CompressionOptions { to_jpeg: true, ..opts.clone() };
+        assert!(compress_image_inproc(&create_gradient_png(), "png", &to_jpeg).is_err());
+
+        // No AVIF decoder here: check the lossless encode is far larger than the lossy default
+        let png = create_gradient_png();
+        let lossless = to_avif_lossless_bytes(&png).unwrap();
+        assert_eq!(&lossless[4..8], b"ftyp");
+        assert!(lossless.len() > to_avif_bytes(&png, 65.0, 8, AvifSubsampling::S444, None, false).unwrap().len() * 2);
+    }
+
+    #[test]
+    fn test_max_megapixels() {
+        // 12MP (4000x3000) down to 1MP keeps 4:3 and lands just under the target
+        let (w, h) = megapixel_dimensions(4000, 3000, 1.0).unwrap();
+        assert_eq!((w, h), (1154, 866));
+        let px = (w * h) as f64;
+        assert!(px <= 1_000_000.0 && px > 990_000.0);
+        assert_eq!(megapixel_dimensions(1000, 800, 1.0), None);
+        assert!(parse_megapixels("0").is_err());
+
+        let png = create_gradient_png(); // 128x128 = 16384 px
+        let opts = CompressionOptions { max_megapixels: Some(0.004), ..Default::default() };
+        let (out, _) = compress_image_inproc(&png, "png", &opts).unwrap();
+        let img = image::load_from_memory(&out).unwrap();
+        assert_eq!((img.width(), img.height()), (63, 63));
+    }
+
+    #[test]
+    fn test_write_atomic_leaves_no_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("c_photo.png");
+
+        // Writer dies halfway through: nothing may appear at the destination
+        let crashed = write_atomic(&dest, |w| {
+            w.write_all(b"\x89PNG half")?;
+            Err(std::io::Error::other("simulated crash"))
+        });
+        assert!(crashed.is_err());
+        assert!(!dest.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0, "temp file left behind");
+
+        fs::write(&dest, b"old").unwrap();
+        write_atomic(&dest, |w| w.write_all(b"new")).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_autorotate_applies_exif_orientation() {
+        // 40x20: left half red, right half blue, tagged "rotate 90 CW" (orientation 6)
+        let img = image::ImageBuffer::from_fn(40, 20, |x, _| if x < 20 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) });
+        let mut plain = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut plain), ImageFormat::Jpeg).unwrap();
+        let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        let jpeg = [&plain[..2], &app1, &plain[2..]].concat();
+        assert_eq!(exif_orientation(&jpeg), Some(6));
+
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &CompressionOptions::default()).unwrap();
+        assert_eq!(exif_orientation(&out), None);
+        let upright = image::load_from_memory(&out).unwrap().to_rgb8();
+        assert_eq!(upright.dimensions(), (20, 40));
+        assert!(upright.get_pixel(10, 5)[0] > 200, "left edge should now be on top");
+        assert!(upright.get_pixel(10, 35)[2] > 200);
+
+        let keep = CompressionOptions { autorotate: false, ..Default::default() };
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &keep).unwrap();
+        assert_eq!(image::load_from_memory(&out).unwrap().width(), 40);
+
+        // HEIC/HEIF aren't covered: their orientation lives in irot/imir, not a JPEG APP1
+        assert_eq!(autorotation(&jpeg, "jpg", &CompressionOptions::default()), Some(6));
+        assert_eq!(autorotation(&jpeg, "heic", &CompressionOptions::default()), None);
+    }
+
+    #[tokio::test]
+    async fn test_web_quality_presets() {
+        // A noisy gradient, posterized enough that the archive range can still be quantized
+        let img = image::ImageBuffer::from_fn(128, 128, |x, y| {
+            let n = ((x * 7919 + y * 104729) ^ (x * y)) % 23;
+            image::Rgb([(x * 2 + n) as u8 & 0xF0, (y * 2 + n) as u8 & 0xF0, (x + y + n) as u8 & 0xF0])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        for format in ["webp", "png"] {
+            let mut sizes = Vec::new();
+            // Smallest files first
+            for preset in ["max", "web", "archive", "lossless"] {
+                let (status, _, body) = post_multipart(
+                    "/api/compress",
+                    &[("preset", None, preset.as_bytes()), ("output_format", None, format.as_bytes()), ("file", Some("g.png"), &png)],
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK, "{} {}", format, preset);
+                sizes.push(body.len());
+            }
+            // Lossless PNG of few colors can beat a dithered palette, so only the lossy ones are ordered
+            let ordered = if format == "png" { &sizes[..3] } else { &sizes[..] };
+            assert!(ordered.windows(2).all(|w| w[0] < w[1]), "{} sizes not increasing: {:?}", format, sizes);
+        }
+
+        let (status, _, _) = post_multipart("/api/compress", &[("preset", None, b"ultra"), ("file", Some("g.png"), &png)]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_premultiply_alpha() {
+        // Solid red with alpha ramping 0..=255 left to right
+        let img = image::ImageBuffer::from_fn(64, 8, |x, _| image::Rgba([255, 0, 0, (x * 4 + x / 16) as u8]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let straight_opts = CompressionOptions { png_lossy: false, ..Default::default() };
+        let premul_opts = CompressionOptions { premultiply_alpha: true, ..straight_opts.clone() };
+        let (straight, _) = compress_image_inproc(&png, "png", &straight_opts).unwrap();
+        let (premul, _) = compress_image_inproc(&png, "png", &premul_opts).unwrap();
+        let straight = image::load_from_memory(&straight).unwrap().to_rgba8();
+        let premul = image::load_from_memory(&premul).unwrap().to_rgba8();
+
+        let mid = premul.get_pixel(32, 0);
+        assert_eq!(straight.get_pixel(32, 0)[0], 255);
+        assert_eq!(mid[3], 130);
+        assert_eq!(mid[0], 130, "red scaled by alpha");
+        assert!(premul.pixels().all(|p| p[0] <= p[3]));
+        assert_eq!(premul.get_pixel(63, 0)[0], 255);
+    }
+
+    #[test]
+    fn test_output_name_template() {
+        assert!(parse_template("{name}-{width}x{height}.{ext}").is_ok());
+        assert!(parse_template("{name}.{size}").unwrap_err().contains("{size}"));
+        assert!(parse_template("{name").is_err());
+        assert!(parse_template("name}").is_err());
+
+        let bytes = create_test_png();
+        let vars = TemplateVars { name: "hero", ext: "webp", dimensions: Some((100, 50)), quality: "50-80", bytes: &bytes };
+        assert_eq!(expand_template("{name}-{width}x{height}.{ext}", &vars), "hero-100x50.webp");
+        assert_eq!(expand_template("{ext}/{name}_q{quality}.{ext}", &vars), "webp/hero_q50-80.webp");
+        assert_eq!(expand_template("{name}.{hash}.{ext}", &vars), format!("hero.{}.webp", content_hash(&bytes)));
+
+        let webp = to_webp_bytes(&create_gradient_png(), 75.0, 4, false, false, false, false).unwrap();
+        assert_eq!(output_dimensions(&webp), Some((128, 128)));
+        let avif = to_avif_bytes(&create_test_png(), 60.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        assert_eq!(output_dimensions(&avif), Some((100, 100)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("hero.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        let out = dir.path().join("out");
+        let ctx = CliContext::new(dir.path().into(), Some(out.clone()), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--template", "{name}-{width}x{height}.{ext}"]);
+        assert_eq!(process_file(&src, &args, &ctx).status, FileStatus::Compressed);
+        assert!(out.join("hero-128x128.png").exists());
+    }
+
+    #[test]
+    fn test_auto_contrast_expands_range() {
+        // Dull scan: every channel squeezed into 100..=150
+        let img = image::ImageBuffer::from_fn(64, 64, |x, y| {
+            let v = 100 + ((x + y) * 50 / 126) as u8;
+            image::Rgb([v, v, v])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let range = |bytes: &[u8]| {
+            let img = image::load_from_memory(bytes).unwrap().to_luma8();
+            let (lo, hi) = img.pixels().fold((255, 0), |(lo, hi), p| (p[0].min(lo), p[0].max(hi)));
+            hi - lo
+        };
+
+        let opts = CompressionOptions { png_lossy: false, auto_contrast: true, ..Default::default() };
+        let (out, _) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(range(&png), 50);
+        assert!(range(&out) >= 250, "range {}", range(&out));
+
+        let plain = CompressionOptions { png_lossy: false, ..Default::default() };
+        let (out, _) = compress_image_inproc(&png, "png", &plain).unwrap();
+        assert_eq!(range(&out), 50);
+    }
+
+    #[test]
+    fn test_thumbnail_sidecar() {
+        let wide = DynamicImage::ImageRgb8(image::ImageBuffer::from_fn(300, 100, |x, _| image::Rgb([if (100..200).contains(&x) { 255 } else { 0 }, 0, 0])));
+        let thumb = make_thumbnail(&wide, 40);
+        assert_eq!((thumb.width(), thumb.height()), (40, 40));
+        assert!(thumb.to_rgb8().pixels().all(|p| p[0] > 200), "crop should keep the red center");
+
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("hero.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        let out = dir.path().join("out");
+        let ctx = CliContext::new(dir.path().into(), Some(out.clone()), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--thumbnail", "32"]);
+        assert_eq!(process_file(&src, &args, &ctx).status, FileStatus::Compressed);
+        assert!(out.join("c_hero.png").exists());
+        let thumb = fs::read(out.join("c_hero.thumb.webp")).unwrap();
+        assert_eq!(output_dimensions(&thumb), Some((32, 32)));
+    }
+
+    /// JPEG carrying an ICC profile, XMP and EXIF (orientation 1 plus a GPS IFD)
+    fn create_jpeg_with_metadata() -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08".to_vec();
+        exif.extend_from_slice(&[0, 2]);
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 1, 0, 0]);
+        exif.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif.extend_from_slice(&[0, 1, 0x00, 0x02, 0, 5, 0, 0, 0, 3, 0, 0, 0, 56, 0, 0, 0, 0]);
+        exif.extend(std::iter::repeat_n([0x11, 0x22, 0x33, 0x44], 6).flatten());
+        let meta = ImageMetadata { icc: Some(b"fake-icc-profile".to_vec()), exif: Some(exif), xmp: Some(b"<x:xmpmeta/>".to_vec()) };
+        embed_jpeg_metadata(&create_test_jpeg(), &meta)
+    }
+
+    #[test]
+    fn test_strip_exif_keeps_icc() {
+        let jpeg = create_jpeg_with_metadata();
+        let source = read_metadata(&jpeg);
+        assert_eq!(source.icc.as_deref(), Some(b"fake-icc-profile".as_slice()));
+        assert!(source.exif.is_some() && source.xmp.is_some());
+
+        let only_exif = CompressionOptions { strip: vec![MetadataKind::Exif], ..Default::default() };
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &only_exif).unwrap();
+        let kept = read_metadata(&out);
+        assert_eq!(kept.icc, source.icc);
+        assert_eq!(kept.xmp, source.xmp);
+        assert_eq!(kept.exif, None);
+        image::load_from_memory(&out).unwrap();
+
+        // Same policy for PNG and WebP outputs
+        let png_opts = CompressionOptions { to_png: true, ..only_exif.clone() };
+        let (png, _) = compress_image_inproc(&jpeg, "jpg", &png_opts).unwrap();
+        assert_eq!(read_metadata(&png).icc, source.icc);
+        assert_eq!(read_metadata(&png).exif, None);
+        image::load_from_memory(&png).unwrap();
+        let webp_opts = CompressionOptions { to_webp: true, ..only_exif };
+        let (webp, _) = compress_image_inproc(&jpeg, "jpg", &webp_opts).unwrap();
+        assert_eq!(read_metadata(&webp).icc, source.icc);
+        assert!(webp::Decoder::new(&webp).decode().is_some());
+
+        // GPS-only stripping keeps EXIF but empties its location IFD
+        let gps = CompressionOptions { strip: vec![MetadataKind::Gps], ..Default::default() };
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &gps).unwrap();
+        let exif = read_metadata(&out).exif.unwrap();
+        assert_eq!(tiff_u16(&exif, true, 38), Some(0));
+        assert!(find_bytes(&exif, &[0x11, 0x22, 0x33, 0x44], 0).is_none());
+
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &CompressionOptions::default()).unwrap();
+        assert_eq!(read_metadata(&out), ImageMetadata::default());
+    }
+
+    #[tokio::test]
+    async fn test_upload_then_download_by_token() {
+        use tower::ServiceExt;
+
+        let router = build_router(&ServerOptions::default());
+        let png = create_test_png();
+        let (boundary, body) = multipart_body(&[("output_format", None, b"png"), ("file", Some("a.png"), &png)]);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(Body::from(body))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let json: serde_json::Value =
+            serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        let token = json["token"].as_str().unwrap().to_string();
+
+        let download = |token: String| {
+            let router = router.clone();
+            async move {
+                let req = Request::builder().uri(format!("/api/download/{}", token)).body(Body::empty()).unwrap();
+                router.oneshot(req).await.unwrap()
+            }
+        };
+        let res = download(token.clone()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "image/png");
+        let bytes = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        image::load_from_memory(&bytes).unwrap();
+
+        // Results are handed out once
+        assert_eq!(download(token).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(download("nope".into()).await.status(), StatusCode::NOT_FOUND);
+
+        // The store is capped by entry count and total bytes; taking a result frees room
+        let sessions = UploadSessions::with_limits(2, 10);
+        let stored = |len: usize| StoredResult {
+            created: std::time::Instant::now(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: vec![0; len],
+        };
+        let first = sessions.insert(stored(4)).unwrap();
+        assert!(sessions.insert(stored(7)).is_none(), "over the byte cap");
+        sessions.insert(stored(6)).unwrap();
+        assert!(sessions.insert(stored(0)).is_none(), "over the entry cap");
+        sessions.take(&first).unwrap();
+        sessions.insert(stored(4)).unwrap();
+
+        let res = axum::response::IntoResponse::into_response(ApiError::Unavailable("full".into()));
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[header::RETRY_AFTER], RETRY_AFTER_SECS.to_string());
+    }
+
+    #[test]
+    fn test_png_posterize_shrinks_gradient() {
+        let png = create_gradient_png();
+        let size = |level: u8| {
+            let opts = CompressionOptions { png_posterize: level, ..Default::default() };
+            compress_image_inproc(&png, "png", &opts).unwrap().0.len()
+        };
+        assert!(size(2) < size(0), "posterize 2: {} vs off: {}", size(2), size(0));
+    }
+
+    #[tokio::test]
+    async fn test_web_errors_are_json() {
+        let error_of = |body: &[u8]| {
+            let json: serde_json::Value = serde_json::from_slice(body).unwrap();
+            (json["code"].as_str().unwrap().to_string(), json["error"].as_str().unwrap().to_string())
+        };
+
+        let (status, headers, body) = post_multipart("/api/compress", &[("output_format", None, b"png")]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(error_of(&body), ("bad_request".to_string(), "no file data received".to_string()));
+
+        let png = create_test_png();
+        let (status, _, body) = post_multipart("/api/compress", &[("webp_method", None, b"9"), ("file", Some("a.png"), &png)]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error_of(&body).1.contains("webp_method"));
+
+        let (status, _, body) = post_multipart("/api/compress", &[("file", Some("notes.png"), b"just some text")]).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(error_of(&body).0, "unsupported_format");
+
+        let (status, _, body) = post_multipart("/api/compress", &[("file", Some("cut.png"), &png[..60])]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error_of(&body).0, "bad_request");
+
+        let res = axum::response::IntoResponse::into_response(ApiError::Internal("boom".into()));
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(error_of(&body), ("internal_error".to_string(), "boom".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_total_budget_keeps_batch_under_limit() {
+        assert_eq!(parse_byte_size("5MB"), Ok(5_000_000));
+        assert_eq!(parse_byte_size("1.5 KiB"), Ok(1536));
+        assert!(parse_byte_size("3 parsecs").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        fs::create_dir(&input).unwrap();
+        for seed in 0..3u32 {
+            let img = image::ImageBuffer::from_fn(96, 96, |x, y| {
+                let n = ((x * 7919 + y * 104729 + seed * 31) ^ (x * y)) % 29;
+                image::Rgb([(x * 2 + n) as u8, (y * 2 + n) as u8, (x + y + n + seed * 40) as u8])
+            });
+            DynamicImage::ImageRgb8(img).save(input.join(format!("p{}.png", seed))).unwrap();
+        }
+        let (files, _) = discover_files(&input, &[]);
+        let probe = Args::parse_from(["rust_tinypng_clone", "--to-webp"]);
+        let unconstrained = batch_output_size(&files, &probe, "50-80");
+        let budget = unconstrained * 6 / 10;
+
+        let out = dir.path().join("out");
+        let args = Args::parse_from([
+            "rust_tinypng_clone",
+            input.to_str().unwrap(),
+            "--to-webp",
+            "--output",
+            out.to_str().unwrap(),
+            "--total-budget",
+            &budget.to_string(),
+        ]);
+        run_cli_mode(&args).await.unwrap();
+        let written: Vec<u64> = fs::read_dir(&out).unwrap().map(|e| e.unwrap().metadata().unwrap().len()).collect();
+        assert_eq!(written.len(), 3);
+        let total: u64 = written.iter().sum();
+        assert!(total <= budget, "{} > budget {}", total, budget);
+    }
+
+    #[test]
+    fn test_sharp_yuv_changes_webp() {
+        // Saturated red/orange stripes are where plain RGB->YUV smears chroma
+        let img = image::ImageBuffer::from_fn(64, 64, |x, y| {
+            if (x / 4 + y / 4) % 2 == 0 { image::Rgb([255, 20, 0]) } else { image::Rgb([255, 150, 30]) }
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let plain = to_webp_bytes(&png, 75.0, 4, false, false, false, false).unwrap();
+        let sharp = to_webp_bytes(&png, 75.0, 4, false, true, false, false).unwrap();
+        assert_ne!(plain, sharp);
+        assert!(webp::Decoder::new(&sharp).decode().is_some());
+    }
+
+    #[test]
+    fn test_skip_compressed_on_second_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("hero.png");
+        let jpg = dir.path().join("photo.jpg");
+        fs::write(&png, create_gradient_png()).unwrap();
+        fs::write(&jpg, create_test_jpeg()).unwrap();
+
+        // --no-skip-compressed: output carries no marker
+        let plain = Args::parse_from(["rust_tinypng_clone", "--overwrite", "--no-skip-compressed"]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "50-80");
+        let original = fs::read(&png).unwrap();
+        assert_eq!(process_file(&png, &plain, &ctx).status, FileStatus::Compressed);
+        assert!(!has_output_marker(&fs::read(&png).unwrap()));
+        fs::write(&png, &original).unwrap();
+
+        // On by default. In-place runs keep the name, so only the embedded marker can tell them apart
+        let args = Args::parse_from(["rust_tinypng_clone", "--overwrite"]);
+        for f in [&png, &jpg] {
+            assert_eq!(process_file(f, &args, &ctx).status, FileStatus::Compressed);
+            assert!(has_output_marker(&fs::read(f).unwrap()));
+            image::load_from_memory(&fs::read(f).unwrap()).unwrap();
+        }
+        let first = fs::read(&png).unwrap();
+        for f in [&png, &jpg] {
+            assert_eq!(process_file(f, &args, &ctx).status, FileStatus::Skipped("already compressed".to_string()));
+        }
+        assert_eq!(fs::read(&png).unwrap(), first);
+
+        // c_ outputs from a previous run are skipped by name; the last of the two flags wins
+        let prior = dir.path().join("c_old.png");
+        fs::write(&prior, create_gradient_png()).unwrap();
+        let by_name = Args::parse_from(["rust_tinypng_clone", "--no-skip-compressed", "--skip-compressed"]);
+        assert!(matches!(process_file(&prior, &by_name, &ctx).status, FileStatus::Skipped(_)));
+        let off = Args::parse_from(["rust_tinypng_clone", "--skip-compressed", "--no-skip-compressed"]);
+        assert_eq!(process_file(&prior, &off, &ctx).status, FileStatus::Compressed);
+    }
+
+    /// 16x16 CMYK JPEG of one flat color; `adobe` selects Photoshop's inverted convention
+    fn create_cmyk_jpeg(ink: [u8; 4], adobe: bool) -> Vec<u8> {
+        let mut comp = Compress::new(ColorSpace::JCS_CMYK);
+        comp.set_size(16, 16);
+        comp.set_quality(95.0);
+        let stored = if adobe { ink.map(|v| 255 - v) } else { ink };
+        let pixels: Vec<u8> = (0..16 * 16).flat_map(|_| stored).collect();
+        let mut jpeg = Vec::new();
+        let mut writer = comp.start_compress(&mut jpeg).unwrap();
+        writer.write_scanlines(&pixels).unwrap();
+        writer.finish().unwrap();
+        if adobe {
+            return jpeg;
+        }
+        // libjpeg always tags CMYK with APP14; plain CMYK files from other tools don't have it
+        let (marker, payload) = jpeg_segments(&jpeg)[0];
+        assert_eq!(marker, 0xEE);
+        [&jpeg[..2], &jpeg[2 + 4 + payload.len()..]].concat()
+    }
+
+    #[test]
+    fn test_cmyk_jpeg_colors() {
+        // Pure magenta + yellow ink is red; 50% black on top halves it
+        for adobe in [true, false] {
+            for (ink, expected) in [([0, 255, 255, 0], [255u8, 0, 0]), ([0, 0, 0, 128], [127, 127, 127])] {
+                let out = compress_jpeg_bytes(&create_cmyk_jpeg(ink, adobe), 90, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+                let img = image::load_from_memory(&out).unwrap().to_rgb8();
+                let px = img.get_pixel(8, 8).0;
+                for (got, want) in px.iter().zip(expected) {
+                    assert!((*got as i32 - want as i32).abs() <= 8, "adobe={} ink={:?} got {:?}", adobe, ink, px);
+                }
+            }
+        }
+        assert!(cmyk_jpeg_to_rgb(&create_test_jpeg()).is_none());
+    }
+
+    #[test]
+    fn test_preserve_bit_depth_16bit_png() {
+        // Distinct low bytes so a lossless reduction to 8 bits isn't possible
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| image::Luma([(x * 2048 + y * 37) as u16]));
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma16(img.clone()).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        assert_eq!(png_bit_depth(&png), Some(16));
+
+        let mut opts = CompressionOptions { preserve_bit_depth: true, ..Default::default() };
+        let (out, _) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(png_bit_depth(&out), Some(16));
+        assert_eq!(image::load_from_memory(&out).unwrap().into_luma16(), img);
+
+        opts.preserve_bit_depth = false;
+        let (out, _) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_ne!(png_bit_depth(&out), Some(16));
+    }
+
+    #[test]
+    fn test_contact_sheet_dimensions() {
+        let images: Vec<(String, DynamicImage)> = ["a.png", "b.jpg", "very_long_photo_name_2024.png"]
+            .iter()
+            .map(|n| (n.to_string(), image::load_from_memory(&create_gradient_png()).unwrap()))
+            .collect();
+        // Three images at two per row: 2 columns x 2 rows of 160px cells plus labels and padding
+        let sheet = build_contact_sheet(&images, 2);
+        assert_eq!(sheet.dimensions(), (2 * 160 + 3 * 8, 2 * (160 + 14) + 3 * 8));
+        // Label text is drawn in black under the first thumbnail
+        let label_row = (8 + 160 + 2..8 + 160 + 14).flat_map(|y| (8..8 + 160).map(move |x| (x, y)));
+        assert!(label_row.into_iter().any(|(x, y)| sheet.get_pixel(x, y).0 == [0, 0, 0]));
+        // Fewer images than columns shrinks the grid
+        assert_eq!(build_contact_sheet(&images[..1], 4).width(), 160 + 2 * 8);
+
+        // The sheet reads each output, not the input it came from
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let sheet_path = dir.path().join("sheet.png");
+        let args = Args::parse_from(["rust_tinypng_clone", "--to-webp", "--contact-sheet", sheet_path.to_str().unwrap()]);
+        let ctx = CliContext::new(dir.path().into(), Some(out.path().into()), "50-80");
+        let mut results = Vec::new();
+        for name in ["a.png", "b.png"] {
+            let src = dir.path().join(name);
+            fs::write(&src, create_gradient_png()).unwrap();
+            results.push(process_file(&src, &args, &ctx));
+        }
+        fs::remove_file(dir.path().join("a.png")).unwrap();
+        let outputs = ctx.written.lock().unwrap().clone();
+        assert_eq!(outputs[&dir.path().join("a.png")], out.path().join("c_a.webp"));
+        assert_eq!(write_contact_sheet(&sheet_path, &results, &outputs, 2).unwrap(), 2);
+        // An output that's gone leaves its cell out
+        fs::remove_file(out.path().join("c_b.webp")).unwrap();
+        assert_eq!(write_contact_sheet(&sheet_path, &results, &outputs, 2).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_jpeg_restart_markers() {
+        let jpeg = create_test_jpeg();
+        let plain = compress_jpeg_bytes(&jpeg, 75, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+        let restarted = compress_jpeg_bytes(&jpeg, 75, 0, 4, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+        assert_ne!(plain, restarted);
+        // DRI segment carries the interval; RSTn markers appear in the entropy-coded data
+        let dri = jpeg_segments(&restarted).into_iter().find(|(m, _)| *m == 0xDD).map(|(_, p)| p.to_vec());
+        assert_eq!(dri, Some(vec![0, 4]));
+        assert!(restarted.windows(2).any(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1])));
+        assert!(!jpeg_segments(&plain).iter().any(|(m, _)| *m == 0xDD));
+        image::load_from_memory(&restarted).unwrap();
+
+        // The restart pass keeps the encoder's baseline/progressive choice
+        let sof = |jpeg: &[u8]| jpeg_segments(jpeg).into_iter().find(|(m, _)| matches!(m, 0xC0..=0xC2)).map(|(m, _)| m);
+        let fast = compress_jpeg_bytes(&jpeg, 75, 0, 4, JpegScanMode::AllComponentsTogether, false, JpegEffort::Fast).unwrap();
+        assert_eq!(sof(&fast), Some(0xC0));
+        assert_eq!(sof(&restarted), Some(0xC2));
+        image::load_from_memory(&fast).unwrap();
+    }
+
+    #[test]
+    fn test_make_animation_gif_and_webp() {
+        let dir = tempfile::tempdir().unwrap();
+        let colors = [("frame_1.png", [255, 0, 0]), ("frame_2.png", [0, 255, 0]), ("frame_10.png", [0, 0, 255])];
+        for (name, rgb) in colors {
+            let img = image::RgbImage::from_pixel(12, 8, image::Rgb(rgb));
+            DynamicImage::ImageRgb8(img).save(dir.path().join(name)).unwrap();
+        }
+        let mut names = vec!["frame_10.png", "frame_2.png", "frame_1.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["frame_1.png", "frame_2.png", "frame_10.png"]);
+
+        // 25 fps = 4/100 s per frame, stored in each graphic control extension
+        let (gif, count) = make_animation(dir.path(), 25.0, AnimationFormat::Gif, "50-80").unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(animation_frames(&gif), Some(("GIF", 3)));
+        let delays: Vec<u16> = gif
+            .windows(8)
+            .filter(|w| w[..3] == [0x21, 0xF9, 0x04])
+            .map(|w| u16::from_le_bytes([w[4], w[5]]))
+            .collect();
+        assert_eq!(delays, [4, 4, 4]);
+
+        let (webp, _) = make_animation(dir.path(), 25.0, AnimationFormat::Webp, "50-80").unwrap();
+        let decoded = webp::AnimDecoder::new(&webp).decode().unwrap();
+        assert_eq!(decoded.len(), 3);
+        // Frames come out in natural order: the last one is frame_10 (blue)
+        let last = decoded.get_frame(2).unwrap();
+        let px = &last.get_image()[..3];
+        assert!(px[2] > 200 && px[0] < 60, "{:?}", px);
+        assert_eq!(last.get_time_ms(), 120);
+    }
+
+    #[test]
+    fn test_decode_memory_limit() {
+        // Valid 4000x4000 PNG (64 MB as RGBA) that compresses to a few KB
+        let img = image::GrayImage::new(4000, 4000);
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        assert!(png.len() < 100_000);
+        assert!(load_image(&png).is_ok());
+
+        // 8MB sits below this image (16MB as luma) but well above anything else the tests decode,
+        // so lowering the process-wide cap here doesn't trip tests running alongside
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("big.png");
+        fs::write(&path, &png).unwrap();
+        let args = Args::parse_from(["rust_tinypng_clone", "--decode-memory-limit", "8MB"]);
+        let default_limit = DECODE_MEMORY_LIMIT.load(std::sync::atomic::Ordering::Relaxed);
+        apply_decode_memory_limit(&args);
+        let inproc = compress_image_inproc(&png, "png", &CompressionOptions::from_args(&args, "50-80"));
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let res = process_file(&path, &args, &ctx);
+        DECODE_MEMORY_LIMIT.store(default_limit, std::sync::atomic::Ordering::Relaxed);
+
+        let err = inproc.unwrap_err();
+        assert!(matches!(err.downcast_ref::<image::ImageError>(), Some(image::ImageError::Limits(_))), "{:?}", err);
+        match res.status {
+            FileStatus::Failed(msg) => assert!(msg.to_lowercase().contains("memory") || msg.contains("limit"), "{}", msg),
+            other => panic!("expected the decode limit to fail the file, got {:?}", other),
+        }
+        assert!(!dir.path().join("out/big.png").exists());
+
+        // Surfaced through the web API as a client error, not a 500
+        let api = ApiError::from_compress_error(&err);
+        assert!(matches!(api, ApiError::BadRequest(_)));
+
+        // Header claiming 60000x60000 RGBA (14 GB) is refused before any pixel buffer exists
+        let ihdr = [60_000u32.to_be_bytes(), 60_000u32.to_be_bytes()].concat();
+        let hostile = [
+            b"\x89PNG\r\n\x1a\n".as_slice(),
+            &png_chunk(b"IHDR", &[ihdr.as_slice(), &[8, 6, 0, 0, 0]].concat()),
+            &png_chunk(b"IDAT", &zlib(&[0; 16])),
+            &png_chunk(b"IEND", &[]),
+        ]
+        .concat();
+        assert!(matches!(load_image(&hostile), Err(image::ImageError::Limits(_))));
+    }
+
+    #[test]
+    fn test_placeholder_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        // Hard-edged 8px checkerboard over a left-to-right red ramp
+        let src = image::RgbImage::from_fn(400, 200, |x, y| {
+            let v = if (x / 8 + y / 8) % 2 == 0 { 255 } else { 0 };
+            image::Rgb([(x * 255 / 399) as u8, v, v])
+        });
+        let path = dir.path().join("hero.png");
+        DynamicImage::ImageRgb8(src).save(&path).unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--placeholder"]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "50-80");
+        assert_eq!(process_file(&path, &args, &ctx).status, FileStatus::Compressed);
+        let sidecar: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.path().join("c_hero.lqip.json")).unwrap()).unwrap();
+        assert_eq!((sidecar["width"].as_u64(), sidecar["height"].as_u64()), (Some(400), Some(200)));
+
+        use base64::Engine as _;
+        let uri = sidecar["data_uri"].as_str().unwrap();
+        let webp = base64::engine::general_purpose::STANDARD.decode(uri.strip_prefix("data:image/webp;base64,").unwrap()).unwrap();
+        assert!(webp.len() < 1024, "{} bytes", webp.len());
+        let lqip = webp::Decoder::new(&webp).decode().unwrap();
+        assert_eq!((lqip.width(), lqip.height()), (20, 10));
+        // Checkerboard averages out to gray; the red ramp survives
+        let rgb: Vec<u8> = lqip.chunks(lqip.len() / (20 * 10)).flat_map(|p| p[..3].to_vec()).collect();
+        let greens: Vec<u8> = rgb.iter().skip(1).step_by(3).copied().collect();
+        assert!(greens.iter().all(|&g| (60..=195).contains(&g)), "{:?}", greens);
+        assert!(rgb[(9 * 20 + 19) * 3] > rgb[9 * 20 * 3] + 100);
+    }
+
+    #[test]
+    fn test_dry_run_overwrite_preview() {
+        let dir = tempfile::tempdir().unwrap();
+        let big = dir.path().join("hero.png");
+        let tiny = dir.path().join("dot.png");
+        fs::write(&big, create_gradient_png()).unwrap();
+        // 1x1 PNG is already minimal; even the output marker makes it bigger
+        let mut dot = Vec::new();
+        DynamicImage::ImageRgba8(image::RgbaImage::new(1, 1)).write_to(&mut Cursor::new(&mut dot), ImageFormat::Png).unwrap();
+        let dot = optimize_png_lossless(&dot, PngInterlace::Keep).unwrap();
+        fs::write(&tiny, &dot).unwrap();
+        let originals = [fs::read(&big).unwrap(), dot];
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--dry-run", "--overwrite"]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "50-80");
+        let results: Vec<FileResult> = [&big, &tiny].iter().map(|f| process_file(f, &args, &ctx)).collect();
+
+        // Nothing touched: same bytes, no c_/backup/temp files
+        assert_eq!([fs::read(&big).unwrap(), fs::read(&tiny).unwrap()], originals);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let (lines, growing) = overwrite_preview(&results);
+        assert!(results[0].after < results[0].before);
+        assert!(lines[0].contains(&human_size(results[0].after)) && lines[0].contains("would save"), "{}", lines[0]);
+        assert_eq!(growing, 1);
+        assert!(lines[1].contains("grows"), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_avif_subsampling() {
+        // Saturated color stripes: chroma detail that 4:2:0 throws away
+        let img = image::RgbaImage::from_fn(64, 48, |x, y| match (x + y) % 3 {
+            0 => image::Rgba([255, 0, 0, 255]),
+            1 => image::Rgba([0, 200, 255, 255]),
+            _ => image::Rgba([40, 255, 0, 255]),
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        // av1C byte 2 carries mono_chrome / subsampling_x / subsampling_y in bits 4..2
+        let av1c_subsampling = |avif: &[u8]| {
+            let at = find_bytes(avif, b"av1C", 0).unwrap() + 4;
+            ((avif[at + 2] >> 3) & 1, (avif[at + 2] >> 2) & 1)
         };
-        
-        let result = compress_image_inproc(&jpeg_data, "jpeg", &opts);
-        assert!(result.is_ok());
-        
-        let (compressed, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/jpeg");
-        assert!(compressed.len() > 0);
+        let s420 = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S420, None, false).unwrap();
+        let s422 = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S422, None, false).unwrap();
+        let s444 = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        assert_eq!(av1c_subsampling(&s420), (1, 1));
+        assert_eq!(av1c_subsampling(&s422), (1, 0));
+        assert_eq!(av1c_subsampling(&s444), (0, 0));
+        assert_ne!(s420, s444);
+        assert!(s444.len() > s420.len(), "444 {} vs 420 {}", s444.len(), s420.len());
+        assert_eq!(output_dimensions(&s420), Some((64, 48)));
+        assert_eq!(infer::get(&s420).map(|k| k.mime_type()), Some("image/avif"));
+        let subsampling = |argv: &[&str]| CompressionOptions::from_args(&Args::parse_from(argv), "50-80").avif_subsampling;
+        assert_eq!(subsampling(&["rust_tinypng_clone"]), AvifSubsampling::S420);
+        assert_eq!(subsampling(&["rust_tinypng_clone", "--avif-depth", "10"]), AvifSubsampling::S444);
+        assert_eq!(subsampling(&["rust_tinypng_clone", "--avif-subsampling", "444"]), AvifSubsampling::S444);
+        assert_eq!(CompressionOptions::default().avif_subsampling, AvifSubsampling::S420);
+
+        // Hidden color under alpha 0 is replaced by its visible neighbors'
+        let edge = image::RgbaImage::from_fn(4, 1, |x, _| if x == 0 { image::Rgba([200, 10, 10, 255]) } else { image::Rgba([0, 255, 0, 0]) });
+        let cleaned = clean_transparent_color(&edge);
+        assert_eq!(cleaned.get_pixel(0, 0).0, [200, 10, 10, 255]);
+        assert_eq!(cleaned.get_pixel(1, 0).0, [200, 10, 10, 0]);
+        assert_eq!(cleaned.get_pixel(3, 0).0, [200, 10, 10, 0]);
+
+        // Smooth gradient over an alpha ramp survives a 4:2:0 round trip, alpha included
+        let src = image::RgbaImage::from_fn(64, 48, |x, y| image::Rgba([(x * 4) as u8, (y * 5) as u8, 160, (x * 4).min(255) as u8]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(src.clone()).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let avif = to_avif_bytes(&png, 85.0, 8, AvifSubsampling::S420, None, false).unwrap();
+        assert!(find_bytes(&avif, b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha", 0).is_some());
+        #[cfg(feature = "avif-decode")]
+        {
+            let decoded = decode_avif(&avif).unwrap().to_rgba8();
+            assert_eq!(decoded.dimensions(), (64, 48));
+            let (mut color_err, mut alpha_err, mut n) = (0u64, 0u64, 0u64);
+            for (a, b) in src.pixels().zip(decoded.pixels()) {
+                alpha_err += a[3].abs_diff(b[3]) as u64;
+                if a[3] == 255 {
+                    color_err += (0..3).map(|c| a[c].abs_diff(b[c]) as u64).sum::<u64>();
+                    n += 3;
+                }
+            }
+            assert!(alpha_err / (64 * 48) <= 4, "mean alpha error {}", alpha_err / (64 * 48));
+            assert!(color_err / n <= 6, "mean color error {}", color_err / n);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_input_list_processes_only_listed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("assets");
+        fs::create_dir_all(&input).unwrap();
+        for name in ["a.png", "b.png", "c.png"] {
+            fs::write(input.join(name), create_gradient_png()).unwrap();
+        }
+        let list = dir.path().join("files.txt");
+        let body = format!("# chosen by the build tool\n{}\n\n  {}  \n", input.join("a.png").display(), input.join("c.png").display());
+        fs::write(&list, &body).unwrap();
+        assert_eq!(parse_input_list(&body), [input.join("a.png"), input.join("c.png")]);
+
+        let out = dir.path().join("out");
+        let args = Args::parse_from([
+            "rust_tinypng_clone",
+            "--input-list",
+            list.to_str().unwrap(),
+            "--output",
+            out.to_str().unwrap(),
+        ]);
+        run_cli_mode(&args).await.unwrap();
+        let mut written: Vec<String> = fs::read_dir(&out).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        written.sort();
+        assert_eq!(written, ["c_a.png", "c_c.png"]);
     }
 
     #[test]
-    fn test_format_conversions() {
-        let png_data = create_test_png();
-        
-        // Test PNG to WebP conversion
-        let opts_webp = CompressionOptions {
-            png_lossy: false,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: true,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
+    fn test_quantized_png_is_indexed_with_trns() {
+        // Gradient with a transparent corner and a half-transparent band
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| {
+            let a = if x < 16 && y < 16 { 0 } else if y > 48 { 128 } else { 255 };
+            image::Rgba([(x * 4) as u8, (y * 4) as u8, 128, a])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img.clone()).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        // oxipng off: it may legitimately pick a non-palette encoding if that's smaller
+        let opts = CompressionOptions { oxipng: false, ..Default::default() };
+        let out = compress_png_bytes(&png, &opts).unwrap();
+        let chunks = png_chunks(&out);
+        assert_eq!(chunks[0].1[9], 3, "IHDR color type should be indexed");
+        let plte = chunks.iter().find(|(k, _)| k == b"PLTE").unwrap().1;
+        let trns = chunks.iter().find(|(k, _)| k == b"tRNS").unwrap().1;
+        assert!(!trns.is_empty() && trns.len() < plte.len() / 3, "only translucent entries in tRNS");
+        let decoded = image::load_from_memory(&out).unwrap().to_rgba8();
+        // Same quantized pixels stored as RGBA (the old output) take more space
+        let mut as_rgba = Vec::new();
+        DynamicImage::ImageRgba8(decoded.clone()).write_to(&mut Cursor::new(&mut as_rgba), ImageFormat::Png).unwrap();
+        assert!(out.len() < as_rgba.len(), "{} >= {}", out.len(), as_rgba.len());
+        assert_eq!(decoded.get_pixel(3, 3)[3], 0);
+        assert!((decoded.get_pixel(40, 60)[3] as i32 - 128).abs() < 16);
+        assert_eq!(decoded.get_pixel(40, 30)[3], 255);
+
+        // Two-color art gets 1-bit indices
+        let bw = image::RgbaImage::from_fn(32, 8, |x, _| if x % 2 == 0 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255; 4]) });
+        let png = encode_indexed_png(32, 8, &[rgb::RGBA::new(0, 0, 0, 255), rgb::RGBA::new(255, 255, 255, 255)], &(0..256).map(|i| (i % 2) as u8).collect::<Vec<_>>(), false).unwrap();
+        assert_eq!(png_bit_depth(&png), Some(1));
+        assert_eq!(image::load_from_memory(&png).unwrap().to_rgba8(), bw);
+    }
+
+    #[test]
+    fn test_exif_thumbnail_follows_resize() {
+        // EXIF with an IFD1 preview, as cameras write it
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 1, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif.extend_from_slice(&[0xAB; 20]);
+        set_exif_thumbnail(&mut exif, &create_test_jpeg());
+        assert!(exif_has_thumbnail(&exif));
+        let meta = ImageMetadata { exif: Some(exif), ..Default::default() };
+        let jpeg = embed_jpeg_metadata(&create_test_jpeg(), &meta);
+
+        let resized = CompressionOptions { strip: vec![MetadataKind::Gps], max_dimension: Some(40), ..Default::default() };
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &resized).unwrap();
+        let exif = read_metadata(&out).exif.unwrap();
+        assert!(!exif_has_thumbnail(&exif));
+        assert_eq!(exif_orientation(&out), Some(1));
+
+        let regen = CompressionOptions { regen_thumbnail: true, ..resized };
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &regen).unwrap();
+        let exif = read_metadata(&out).exif.unwrap();
+        assert!(exif_has_thumbnail(&exif));
+        let at = find_bytes(&exif, &[0xFF, 0xD8], 8).unwrap();
+        let thumb = image::load_from_memory(&exif[at..]).unwrap();
+        assert_eq!(output_dimensions(&out), Some((thumb.width(), thumb.height())));
+    }
+
+    #[test]
+    fn test_webp_exact_keeps_hidden_rgb() {
+        // Fully transparent texture whose hidden RGB carries a red/green checker
+        let hidden = |x: u32, y: u32| if (x / 4 + y / 4).is_multiple_of(2) { [255u8, 0, 0] } else { [0, 255, 0] };
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| {
+            let [r, g, b] = hidden(x, y);
+            image::Rgba([r, g, b, 0])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let error = |webp: &[u8]| {
+            let decoded = webp::Decoder::new(webp).decode().unwrap();
+            let mut total = 0u32;
+            for (i, px) in decoded.chunks_exact(4).enumerate() {
+                let want = hidden(i as u32 % 32, i as u32 / 32);
+                total += (0..3).map(|c| px[c].abs_diff(want[c]) as u32).sum::<u32>();
+            }
+            total / (32 * 32)
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts_webp);
-        assert!(result.is_ok());
-        let (_, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/webp");
-        
-        // Test PNG to TIFF conversion
-        let opts_tiff = CompressionOptions {
-            png_lossy: false,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: true,
-            to_bmp: false,
-            to_ico: false,
+        let flattened = to_webp_bytes(&png, 75.0, 4, false, false, false, false).unwrap();
+        let exact = to_webp_bytes(&png, 75.0, 4, false, false, true, false).unwrap();
+        assert!(error(&flattened) > 2 * error(&exact), "{} vs {}", error(&flattened), error(&exact));
+    }
+
+    #[test]
+    fn test_memory_budget_throttles_workers() {
+        let dir = tempfile::tempdir().unwrap();
+        // Eight 100x100 PNGs, 40,000 decoded bytes each
+        let files: Vec<PathBuf> = (0..8)
+            .map(|i| {
+                let path = dir.path().join(format!("{}.png", i));
+                fs::write(&path, create_test_png()).unwrap();
+                path
+            })
+            .collect();
+        let cost = decode_cost(&files[0]);
+        assert_eq!(cost, 100 * 100 * 4);
+
+        let peak_with = |budget: Option<u64>| {
+            let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            let names = run_bounded(&files, 4, &MemoryBudget::new(budget), |f| {
+                peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                f.to_path_buf()
+            });
+            assert_eq!(names, files);
+            peak.into_inner()
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts_tiff);
-        assert!(result.is_ok());
-        let (_, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/tiff");
-        
-        // Test PNG to BMP conversion
-        let opts_bmp = CompressionOptions {
-            png_lossy: false,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: true,
-            to_ico: false,
+        assert_eq!(peak_with(Some(cost * 2)), 2);
+        // Under the budget of a single image, files still run, one at a time
+        assert_eq!(peak_with(Some(cost / 2)), 1);
+        assert!(peak_with(None) > 2);
+
+        // A file's --formats outputs share its one reservation, so they encode in turn
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let outputs_peak = |args: &Args| {
+            let (running, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+            pool.install(|| {
+                per_output(&[0; 4], args, |_| {
+                    peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            });
+            peak.into_inner()
+        };
+        assert_eq!(outputs_peak(&Args::parse_from(["rust_tinypng_clone", "--memory-budget", "1MB"])), 1);
+        assert!(outputs_peak(&Args::parse_from(["rust_tinypng_clone"])) > 1);
+    }
+
+    #[test]
+    fn test_since_keeps_recent_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = std::time::SystemTime::now();
+        let day = std::time::Duration::from_secs(24 * 3600);
+        for (name, age) in [("fresh.png", 0), ("yesterday.png", 1), ("old.png", 30)] {
+            let path = dir.path().join(name);
+            fs::write(&path, create_test_png()).unwrap();
+            fs::File::options().write(true).open(&path).unwrap().set_modified(now - day * age).unwrap();
+        }
+        let args = Args::parse_from(["rust_tinypng_clone", "--since", "7d"]);
+        let window = args.since.unwrap();
+        assert_eq!(window, day * 7);
+        let kept = filter_modified_since(discover_files(dir.path(), &[]).0, now - window);
+        let names: Vec<_> = kept.iter().filter_map(|p| p.file_name()?.to_str()).collect();
+        assert_eq!(names, ["fresh.png", "yesterday.png"]);
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--since", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_jpeg_scan_mode_changes_output() {
+        let photo = create_gradient_png();
+        let encode = |mode| compress_jpeg_bytes(&photo, 50, 0, 0, mode, false, JpegEffort::Balanced).unwrap();
+        let together = encode(JpegScanMode::AllComponentsTogether);
+        let per_component = encode(JpegScanMode::ScanPerComponent);
+        assert_ne!(together, per_component);
+        // Per-component DC scans: one SOS each for Y, Cb and Cr instead of a single shared one
+        let scans = |jpeg: &[u8]| jpeg.windows(2).filter(|w| w == &[0xFF, 0xDA]).count();
+        assert!(scans(&per_component) > scans(&together), "{} vs {}", scans(&per_component), scans(&together));
+        image::load_from_memory(&per_component).unwrap();
+        let args = Args::parse_from(["rust_tinypng_clone", "--jpeg-scan-mode", "scan-per-component"]);
+        assert_eq!(CompressionOptions::from_args(&args, "50-80").jpeg_scan_mode, JpegScanMode::ScanPerComponent);
+        assert_eq!(CompressionOptions::default().jpeg_scan_mode, JpegScanMode::AllComponentsTogether);
+    }
+
+    #[test]
+    fn test_interactive_selection() {
+        let preview = vec![
+            FileResult { path: PathBuf::from("/in/a.png"), before: 100, after: 60, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("/in/b.png"), before: 100, after: 120, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("/in/c.png"), before: 100, after: 100, status: FileStatus::Skipped("below --min-savings".into()) },
+            FileResult { path: PathBuf::from("/in/d.png"), before: 100, after: 50, status: FileStatus::Compressed },
+        ];
+        // Only files that would shrink start selected
+        let mut sel = Selection::from_preview(&preview);
+        assert_eq!(sel.chosen, [true, false, false, true]);
+        assert_eq!(sel.apply("2-3"), Ok(SelectionStep::Continue));
+        assert_eq!(sel.chosen, [true, true, true, true]);
+        assert_eq!(sel.apply(" 1, 4 "), Ok(SelectionStep::Continue));
+        assert_eq!(sel.chosen, [false, true, true, false]);
+        assert!(sel.apply("5").is_err());
+        assert!(sel.apply("3-2").is_err());
+        assert!(sel.apply("x").is_err());
+        assert_eq!(sel.chosen, [false, true, true, false]);
+        sel.apply("none").unwrap();
+        assert_eq!(sel.count(), 0);
+        sel.apply("ALL").unwrap();
+        assert_eq!(sel.count(), 4);
+        assert_eq!(sel.apply("q"), Ok(SelectionStep::Cancel));
+        assert_eq!(sel.apply(""), Ok(SelectionStep::Confirm));
+
+        let mut input = Cursor::new("1\nbogus\n\n");
+        let chosen = choose_files(&preview, Path::new("/in"), &mut input).unwrap().unwrap();
+        assert_eq!(chosen, [PathBuf::from("/in/d.png")]);
+        // Closing the prompt without confirming cancels the run
+        assert_eq!(choose_files(&preview, Path::new("/in"), &mut Cursor::new("2\n")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_svg_minify_passthrough() {
+        let svg = br##"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Generator: some editor -->
+<svg xmlns="http://www.w3.org/2000/svg"   width="40"
+     height = "20" viewBox="0 0 40 20">
+    <style><![CDATA[ rect  { fill: red } ]]></style>
+    <rect width="40"  height="20"  />
+    <text x="2" y="12">Hi <tspan>there</tspan></text>
+</svg>
+"##;
+        let opts = CompressionOptions { svg_minify: true, ..Default::default() };
+        let (out, mime) = compress_image_inproc(svg, "svg", &opts).unwrap();
+        assert_eq!(mime, "image/svg+xml");
+        assert_eq!(
+            String::from_utf8(out.clone()).unwrap(),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?><svg xmlns="http://www.w3.org/2000/svg" width="40" height="20" viewBox="0 0 40 20">"#,
+                r#"<style><![CDATA[ rect  { fill: red } ]]></style><rect width="40" height="20"/>"#,
+                r#"<text x="2" y="12">Hi <tspan>there</tspan></text></svg>"#
+            )
+        );
+        assert!(out.len() < svg.len());
+        validate_output(&out).unwrap();
+        assert_eq!(svg_intrinsic_size(&out), Some((40.0, 20.0)));
+        // Asking for a raster format still rasterizes
+        assert!(!svg_passthrough(&CompressionOptions { to_png: true, ..opts }));
+    }
+
+    #[test]
+    fn test_retries_transient_failures() {
+        let path = PathBuf::from("share/photo.png");
+        let backoff = std::time::Duration::from_millis(1);
+        // Flaky mount: the first read fails, the second one works
+        let mut attempts = 0;
+        let (res, used) = with_retries(3, backoff, || {
+            attempts += 1;
+            if attempts == 1 {
+                FileResult::failed(path.clone(), 100, "read-failed: Resource temporarily unavailable".into())
+            } else {
+                FileResult { path: path.clone(), before: 100, after: 60, status: FileStatus::Compressed }
+            }
+        });
+        assert_eq!((res.status, used, attempts), (FileStatus::Compressed, 1, 2));
+
+        // Give up after the allowed retries
+        let mut attempts = 0;
+        let (res, used) = with_retries(2, backoff, || {
+            attempts += 1;
+            FileResult::failed(path.clone(), 100, "write-failed: disk I/O error".into())
+        });
+        assert!(matches!(res.status, FileStatus::Failed(_)));
+        assert_eq!((used, attempts), (2, 3));
+
+        // A corrupt image fails the same way every time
+        let mut attempts = 0;
+        let (_, used) = with_retries(5, backoff, || {
+            attempts += 1;
+            FileResult::failed(path.clone(), 100, "compress-failed: bad header".into())
+        });
+        assert_eq!((used, attempts), (0, 1));
+    }
+
+    #[test]
+    fn test_avif_tiles() {
+        let img = image::RgbImage::from_fn(256, 256, |x, y| image::Rgb([(x ^ y) as u8, (x * 3) as u8, (y * 5) as u8]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let single = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S420, None, false).unwrap();
+        let tiled = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S420, Some((2, 2)), false).unwrap();
+        assert_ne!(single, tiled);
+        assert_eq!(output_dimensions(&tiled), Some((256, 256)));
+        // Tiling a 4:4:4 image takes the rav1e path too, with High profile in av1C
+        let tiled444 = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S444, Some((2, 1)), false).unwrap();
+        let at = find_bytes(&tiled444, b"av1C", 0).unwrap() + 4;
+        assert_eq!(tiled444[at + 1] >> 5, 1);
+
+        assert_eq!(parse_avif_tiles("4x2"), Ok((4, 2)));
+        assert!(parse_avif_tiles("3x2").is_err());
+        assert!(parse_avif_tiles("128x1").is_err());
+        assert!(parse_avif_tiles("2").is_err());
+    }
+
+    #[test]
+    fn test_markdown_report() {
+        let results = vec![
+            FileResult { path: PathBuf::from("/in/a|b.png"), before: 2000, after: 500, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("/in/sub/c.jpg"), before: 1000, after: 500, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("/in/d.png"), before: 300, after: 300, status: FileStatus::Skipped("already compressed".into()) },
+            FileResult::failed(PathBuf::from("/in/e.png"), 10, "compress-failed: bad | header".into()),
+        ];
+        let table = markdown_report(&results, Path::new("/in"));
+        let lines: Vec<&str> = table.lines().collect();
+        // Header, separator, one row per file, totals
+        assert_eq!(lines.len(), results.len() + 3);
+        let columns = |line: &str| line.replace("\\|", "").matches('|').count();
+        assert!(lines.iter().all(|line| columns(line) == 6), "{}", table);
+        assert!(lines[1].starts_with("|------|-------:|"));
+        assert_eq!(lines[2], "| a\\|b.png | 2 kB | 500 B | 75.0% | compressed |");
+        assert!(lines[3].starts_with("| sub/c.jpg |"));
+        assert!(lines[4].contains("skipped: already compressed"));
+        assert!(lines[5].contains("failed: compress-failed: bad \\| header"));
+        assert_eq!(lines[6], "| **Total** | **3 kB** | **1 kB** | **66.7%** | |");
+        assert_eq!(Args::parse_from(["rust_tinypng_clone", "--report", "markdown"]).report, Some(ReportFormat::Markdown));
+    }
+
+    #[tokio::test]
+    async fn test_formats_fan_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("assets");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(input.join("hero.png"), create_gradient_png()).unwrap();
+        fs::write(input.join("photo.jpg"), create_test_jpeg()).unwrap();
+
+        let out = dir.path().join("out");
+        let args = Args::parse_from([
+            "rust_tinypng_clone",
+            input.to_str().unwrap(),
+            "--formats",
+            "webp,avif,original",
+            "--output",
+            out.to_str().unwrap(),
+        ]);
+        run_cli_mode(&args).await.unwrap();
+        let mut written: Vec<String> = fs::read_dir(&out).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        written.sort();
+        assert_eq!(written, ["c_hero.avif", "c_hero.png", "c_hero.webp", "c_photo.avif", "c_photo.jpg", "c_photo.webp"]);
+        let kind = |name: &str| infer::get(&fs::read(out.join(name)).unwrap()).map(|k| k.mime_type());
+        assert_eq!(kind("c_hero.webp"), Some("image/webp"));
+        assert_eq!(kind("c_hero.avif"), Some("image/avif"));
+        assert_eq!(kind("c_photo.jpg"), Some("image/jpeg"));
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--formats", "webp", "--overwrite"]).is_err());
+    }
+
+    #[test]
+    fn test_no_open_disables_browser_launch() {
+        let args = Args::parse_from(["rust_tinypng_clone", "--web", "--no-open"]);
+        assert!(!ServerOptions::from_args(&args).open_browser);
+        assert!(ServerOptions::from_args(&Args::parse_from(["rust_tinypng_clone", "--web"])).open_browser);
+    }
+
+    #[test]
+    fn test_closest_compressrc_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let deep = dir.path().join("site/assets/icons");
+        fs::create_dir_all(&deep).unwrap();
+        let img = image::RgbImage::from_pixel(8, 8, image::Rgb([40, 90, 200]));
+        for d in [dir.path(), &dir.path().join("site"), &deep] {
+            img.save(d.join("a.png")).unwrap();
+        }
+        fs::write(dir.path().join(COMPRESSRC), "# repo-wide\ncompression-lvl = max\n").unwrap();
+        fs::write(dir.path().join("site/assets").join(COMPRESSRC), "quality = 85-95\n").unwrap();
+
+        let (files, overrides) = discover_files(dir.path(), &[]);
+        assert_eq!(files.len(), 3);
+        assert_eq!(overrides.dirs.len(), 2);
+        let mut ctx = CliContext::new(dir.path().into(), None, "50-80");
+        ctx.overrides = overrides;
+        assert_eq!(ctx.quality_for(&dir.path().join("a.png")), "20-60");
+        assert_eq!(ctx.quality_for(&dir.path().join("site/a.png")), "20-60");
+        assert_eq!(ctx.quality_for(&deep.join("a.png")), "85-95");
+
+        assert!(parse_compressrc("quality = 90-10").is_err());
+        assert!(parse_compressrc("colors = 16").is_err());
+    }
+
+    #[test]
+    fn test_perceptual_mode_spends_fewer_bits_on_flat_regions() {
+        // Grainy flat sky over a sharp checkerboard
+        let img = image::RgbImage::from_fn(128, 128, |x, y| {
+            if y < 96 {
+                let grain = ((x * 7919 + y * 104729) % 11) as u8;
+                image::Rgb([120 + grain, 160 + grain, 210 + grain])
+            } else if (x / 2 + y / 2) % 2 == 0 {
+                image::Rgb([20, 20, 20])
+            } else {
+                image::Rgb([235, 235, 235])
+            }
+        });
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+
+        let flat = to_webp_bytes(&png, 60.0, 4, false, false, false, false).unwrap();
+        let perceptual = to_webp_bytes(&png, 60.0, 4, false, false, false, true).unwrap();
+        assert_ne!(flat, perceptual);
+        assert!(perceptual.len() < flat.len(), "{} !< {}", perceptual.len(), flat.len());
+
+        let flat = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        let perceptual = to_avif_bytes(&png, 60.0, 8, AvifSubsampling::S444, None, true).unwrap();
+        assert_ne!(flat, perceptual);
+        assert!(perceptual.len() < flat.len(), "{} !< {}", perceptual.len(), flat.len());
+    }
+
+    #[test]
+    fn test_compress_embedded_thumb_shrinks_preview_only() {
+        let noisy = image::RgbImage::from_fn(160, 120, |x, y| {
+            let v = ((x * 7919 + y * 104729) % 251) as u8;
+            image::Rgb([v, v / 2, 255 - v])
+        });
+        let preview = encode_jpeg_rgb(noisy, 98, 0, JpegEffort::Balanced).unwrap();
+        let mut exif = b"MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        // Make = "Cam" (inline ASCII)
+        exif.extend_from_slice(&[0x01, 0x0F, 0, 2, 0, 0, 0, 4, b'C', b'a', b'm', 0]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        set_exif_thumbnail(&mut exif, &preview);
+        let meta = ImageMetadata { exif: Some(exif), ..Default::default() };
+        let jpeg = embed_jpeg_metadata(&create_test_jpeg(), &meta);
+
+        let opts = CompressionOptions { strip: vec![MetadataKind::Gps], ..Default::default() };
+        let compressed = CompressionOptions { compress_embedded_thumb: true, ..opts.clone() };
+        let (kept, _) = compress_image_inproc(&jpeg, "jpg", &opts).unwrap();
+        let (out, _) = compress_image_inproc(&jpeg, "jpg", &compressed).unwrap();
+        assert!(out.len() < kept.len());
+
+        let exif = read_metadata(&out).exif.unwrap();
+        let (at, len) = exif_thumbnail_span(&exif).unwrap();
+        assert!(len < preview.len(), "{} !< {}", len, preview.len());
+        let thumb = image::load_from_memory(&exif[at..at + len]).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (160, 120));
+        let make = tiff_ifd_entry(&exif, true, 8, 0x010F).unwrap();
+        assert_eq!(&exif[make + 8..make + 12], b"Cam\0");
+        assert_eq!(output_dimensions(&out), output_dimensions(&kept));
+    }
+
+    #[tokio::test]
+    async fn test_manifest_out_hashes_match_written_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("in");
+        fs::create_dir_all(input.join("icons")).unwrap();
+        fs::write(input.join("hero.png"), create_test_png()).unwrap();
+        fs::write(input.join("icons/logo.jpg"), create_test_jpeg()).unwrap();
+        let out = dir.path().join("out");
+        let manifest = dir.path().join("manifest.json");
+        let args = Args::parse_from([
+            "rust_tinypng_clone",
+            input.to_str().unwrap(),
+            "-o",
+            out.to_str().unwrap(),
+            "--manifest-out",
+            manifest.to_str().unwrap(),
+        ]);
+        run_cli_mode(&args).await.unwrap();
+
+        let entries: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+        assert_eq!(entries.len(), 2);
+        for (path, entry) in &entries {
+            let bytes = fs::read(out.join(path)).unwrap();
+            assert_eq!(entry["size"], bytes.len() as u64);
+            assert_eq!(entry["sha256"], sha256_hex(&bytes));
+        }
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_benchmark_reports_every_format() {
+        let rows = run_benchmark(&create_gradient_png(), "png", &CompressionOptions::default());
+        let labels: Vec<&str> = rows.iter().map(|r| r.label).collect();
+        assert_eq!(labels, ["PNG (lossy)", "WebP", "AVIF", "JPEG"]);
+        for row in &rows {
+            let bytes = row.output.as_ref().unwrap();
+            assert!(!bytes.is_empty(), "{} is empty", row.label);
+        }
+        let table = benchmark_table(&rows, create_gradient_png().len() as u64);
+        assert_eq!(table.lines().count(), 5);
+        assert!(labels.iter().all(|l| table.contains(l)));
+        assert!(!table.contains("failed"));
+    }
+
+    #[test]
+    fn test_keep_colors_survive_quantization() {
+        // A smooth blue-ish gradient that an 8-color palette can't hit exactly
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| image::Rgba([(x * 3) as u8, (y * 2 + 60) as u8, 200 + (x / 2) as u8, 255]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let brand = parse_hex_color("#1A73E8").unwrap();
+        assert_eq!(brand, [0x1A, 0x73, 0xE8]);
+        assert!(parse_hex_color("#12345").is_err());
+
+        let palette = |opts: &CompressionOptions| {
+            let out = compress_png_bytes(&png, opts).unwrap();
+            let reader = png::Decoder::new(Cursor::new(out)).read_info().unwrap();
+            reader.info().palette.as_ref().map(|p| p.chunks(3).map(|c| [c[0], c[1], c[2]]).collect::<Vec<_>>()).unwrap()
+        };
+        let plain = CompressionOptions { max_colors: Some(8), png_quality: "0-80".to_string(), oxipng: false, ..Default::default() };
+        assert!(!palette(&plain).contains(&brand));
+        let kept = CompressionOptions { keep_colors: vec![brand], ..plain };
+        assert!(palette(&kept).contains(&brand));
+    }
+
+    #[test]
+    fn test_empty_and_truncated_inputs_are_reported_distinctly() {
+        let opts = CompressionOptions::default();
+        let err = compress_image_inproc(&[], "png", &opts).unwrap_err();
+        assert_eq!(err.downcast_ref::<InvalidInput>(), Some(&InvalidInput::Empty));
+
+        let mut png = create_test_png();
+        png.truncate(png.len() - 12);
+        let err = compress_image_inproc(&png, "png", &opts).unwrap_err();
+        assert!(matches!(err.downcast_ref::<InvalidInput>(), Some(InvalidInput::Truncated(_))), "{}", err);
+
+        // "IEND" inside a text chunk doesn't make a cut-off PNG complete
+        let whole = create_test_png();
+        let mut texted = whole[..33].to_vec();
+        texted.extend_from_slice(&png_chunk(b"tEXt", b"Comment\0IEND"));
+        texted.extend_from_slice(&whole[33..whole.len() - 12]);
+        assert!(matches!(check_input_complete(&texted), Err(InvalidInput::Truncated(_))));
+
+        // A JPEG missing only its EOI still decodes, so it's accepted; one cut mid-scan isn't
+        let jpeg = create_test_jpeg();
+        assert_eq!(check_input_complete(&jpeg[..jpeg.len() - 2]), Ok(()));
+        let scan = find_bytes(&jpeg, &[0xFF, 0xDA], 2).unwrap();
+        assert!(matches!(check_input_complete(&jpeg[..scan + 4]), Err(InvalidInput::Truncated(_))));
+        assert_eq!(check_input_complete(&jpeg), Ok(()));
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("empty.png"), b"").unwrap();
+        fs::write(dir.path().join("cut.png"), &png).unwrap();
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone"]);
+        for name in ["empty.png", "cut.png"] {
+            let res = process_file(&dir.path().join(name), &args, &ctx);
+            match res.status {
+                FileStatus::Failed(msg) => assert!(msg.starts_with("invalid/truncated: "), "{}", msg),
+                other => panic!("{} was {:?}", name, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_shrink_if_over_only_resizes_heavy_files() {
+        let encode = |img: image::RgbImage| {
+            let mut bytes = Vec::new();
+            DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+            bytes
+        };
+        // Same 200x200 dimensions: flat art compresses to almost nothing, noise doesn't
+        let light = encode(image::RgbImage::from_pixel(200, 200, image::Rgb([30, 60, 90])));
+        let heavy = encode(image::RgbImage::from_fn(200, 200, |x, y| {
+            let n = ((x * 7919 + y * 104729) ^ (x * y)) % 251;
+            image::Rgb([n as u8, (n * 3) as u8, (n * 7) as u8])
+        }));
+        let limit = 20_000;
+        assert!((light.len() as u64) < limit && heavy.len() as u64 > limit);
+
+        let opts = CompressionOptions { max_dimension: Some(50), shrink_if_over: Some(limit), ..Default::default() };
+        let (out, _) = compress_image_inproc(&heavy, "png", &opts).unwrap();
+        assert_eq!(output_dimensions(&out), Some((50, 50)));
+        let (out, _) = compress_image_inproc(&light, "png", &opts).unwrap();
+        assert_eq!(output_dimensions(&out), Some((200, 200)));
+    }
+
+    #[test]
+    fn test_png_bit_depth_follows_palette_size() {
+        // Eight flat stripes: fits a 4-bit palette without any loss
+        let colors = [[0, 0, 0], [255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0], [0, 255, 255], [255, 0, 255], [255, 255, 255]];
+        let img = image::RgbImage::from_fn(64, 32, |x, _| image::Rgb(colors[(x / 8) as usize]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let header = |opts: &CompressionOptions| {
+            let out = compress_png_bytes(&png, opts).unwrap();
+            let reader = png::Decoder::new(Cursor::new(out)).read_info().unwrap();
+            let info = reader.info();
+            (info.bit_depth as u8, info.palette.as_ref().map_or(0, |p| p.len() / 3))
+        };
+        let auto = CompressionOptions { png_quality: "0-100".to_string(), oxipng: false, ..Default::default() };
+        let (bits, entries) = header(&auto);
+        assert!(bits <= 4 && entries <= 16, "{} bits, {} colors", bits, entries);
+
+        let capped = CompressionOptions { png_bit_depth: Some(2), ..auto };
+        let (bits, entries) = header(&capped);
+        assert!(bits <= 2 && entries <= 4, "{} bits, {} colors", bits, entries);
+        assert!(parse_png_bit_depth("3").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cors_origin_allow_list() {
+        use tower::ServiceExt;
+
+        let preflight = |origin: &str| {
+            Request::builder()
+                .method("OPTIONS")
+                .uri("/api/compress")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let allowed = parse_cors_origin("https://app.example.com/").unwrap();
+        let server_opts = ServerOptions { cors_origins: vec![allowed.clone()], ..Default::default() };
+
+        let res = build_router(&server_opts).oneshot(preflight("https://app.example.com")).await.unwrap();
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN), Some(&allowed));
+        let res = build_router(&server_opts).oneshot(preflight("https://evil.example")).await.unwrap();
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        // No origins configured: anything goes
+        let res = build_router(&ServerOptions::default()).oneshot(preflight("https://evil.example")).await.unwrap();
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(parse_cors_origin("app.example.com").is_err());
+    }
+
+    #[test]
+    fn test_jpeg_arithmetic_coding_is_smaller() {
+        let photo = image::RgbImage::from_fn(160, 120, |x, y| {
+            let v = ((x as f32 / 7.0).sin() * 50.0 + (y as f32 / 5.0).cos() * 50.0 + 128.0) as u8;
+            image::Rgb([v, v.wrapping_add((x * y % 37) as u8), 255 - v])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(photo).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let huffman = compress_jpeg_bytes(&png, 75, 0, 0, JpegScanMode::AllComponentsTogether, false, JpegEffort::Balanced).unwrap();
+        let arithmetic = compress_jpeg_bytes(&png, 75, 0, 0, JpegScanMode::AllComponentsTogether, true, JpegEffort::Balanced).unwrap();
+        assert_ne!(huffman, arithmetic);
+        assert!(arithmetic.len() < huffman.len(), "{} !< {}", arithmetic.len(), huffman.len());
+        // SOF10: progressive, arithmetic-coded
+        assert!(jpeg_segments(&arithmetic).iter().any(|(marker, _)| *marker == 0xCA));
+
+        let mut dec = mozjpeg::Decompress::new_mem(&arithmetic).unwrap().rgb().unwrap();
+        assert_eq!((dec.width(), dec.height()), (160, 120));
+        let pixels: Vec<[u8; 3]> = dec.read_scanlines().unwrap();
+        assert_eq!(pixels.len(), 160 * 120);
+    }
+
+    #[test]
+    fn test_scan_archives_repackages_compressed_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let entry = |name: &str, data: Vec<u8>| ZipEntry { name: name.to_string(), modified: (0, 0x21), data };
+        let photo = create_gradient_png();
+        let bundle = write_zip(&[
+            entry("textures/", Vec::new()),
+            entry("textures/a.png", photo.clone()),
+            entry("README.txt", b"not an image".to_vec()),
+        ])
+        .unwrap();
+        fs::write(dir.path().join("bundle.zip"), &bundle).unwrap();
+        fs::write(dir.path().join("evil.zip"), write_zip(&[entry("../escape.png", photo.clone())]).unwrap()).unwrap();
+
+        assert!(discover_files(dir.path(), &[]).0.is_empty());
+        let (files, _) = discover_files(dir.path(), &["zip"]);
+        assert_eq!(files.len(), 2);
+
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--scan-archives"]);
+        let res = process_file(&dir.path().join("bundle.zip"), &args, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+        let repacked = read_zip(&fs::read(dir.path().join("out/c_bundle.zip")).unwrap()).unwrap();
+        let names: Vec<&str> = repacked.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["textures/", "textures/a.png", "README.txt"]);
+        assert!(repacked[1].data.len() < photo.len());
+        assert_eq!(output_dimensions(&repacked[1].data), Some((128, 128)));
+        assert_eq!(repacked[2].data, b"not an image");
+        assert!(repacked.iter().all(|e| e.modified == (0, 0x21)));
+        assert!(read_zip(&bundle[..bundle.len() - 10]).is_err());
+
+        // Zip-slip: nothing is written for an archive with an escaping member
+        let res = process_file(&dir.path().join("evil.zip"), &args, &ctx);
+        assert!(matches!(res.status, FileStatus::Failed(ref msg) if msg.contains("unsafe path")), "{:?}", res.status);
+        assert!(!dir.path().join("out/c_evil.zip").exists());
+        assert!(!safe_zip_name("/etc/passwd") && !safe_zip_name("C:\\x.png") && safe_zip_name("a/b.png"));
+    }
+
+    #[test]
+    fn test_output_bit_depth_check() {
+        let img = image::ImageBuffer::from_fn(32, 32, |x, y| image::Rgb([(x * 2048) as u16, (y * 2048) as u16, 1234]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb16(img).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let mut opts = CompressionOptions { to_jpeg: true, bit_depth_check: true, ..Default::default() };
+        let (jpeg, mime) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(
+            depth_reduction(&png, &jpeg, &mime, &opts).as_deref(),
+            Some("16-bit source written as 8-bit image/jpeg: precision is lost")
+        );
+
+        opts.strict = true;
+        let err = compress_image_inproc(&png, "png", &opts).unwrap_err();
+        assert!(err.to_string().contains("precision is lost"), "{}", err);
+
+        // A 16-bit PNG kept at 16 bits, or an 8-bit source, loses nothing
+        let kept = CompressionOptions { preserve_bit_depth: true, to_jpeg: false, ..opts.clone() };
+        assert!(compress_image_inproc(&png, "png", &kept).is_ok());
+        assert!(compress_image_inproc(&create_gradient_png(), "png", &opts).is_ok());
+    }
+
+    #[test]
+    fn test_auto_dither_level() {
+        let photo = image::load_from_memory(&create_gradient_png()).unwrap().to_rgba8();
+        assert_eq!(auto_dither_level(&photo), 1.0);
+
+        // Flat logo: two solid colors, hard edges
+        let logo = image::RgbaImage::from_fn(128, 128, |x, y| {
+            let inside = (x as i32 - 64).pow(2) + (y as i32 - 64).pow(2) < 40 * 40;
+            if inside { image::Rgba([220, 30, 40, 255]) } else { image::Rgba([255, 255, 255, 0]) }
+        });
+        assert_eq!(auto_dither_level(&logo), 0.0);
+
+        // Over 256 colors, but each in a flat band: still not photographic
+        let bands = image::RgbaImage::from_fn(512, 64, |x, y| image::Rgba([(x / 8 * 4) as u8, (y / 8 * 32) as u8, 0, 255]));
+        assert_eq!(auto_dither_level(&bands), 0.0);
+
+        let opts = CompressionOptions { auto_dither: true, ..Default::default() };
+        assert!(compress_png_bytes(&create_gradient_png(), &opts).is_ok());
+    }
+
+    #[test]
+    fn test_fail_fast_skips_remaining_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let broken = dir.path().join("a_broken.png");
+        fs::write(&broken, b"\x89PNG\r\n\x1a\n not really").unwrap();
+        let good = dir.path().join("b_good.png");
+        fs::write(&good, create_gradient_png()).unwrap();
+
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--fail-fast"]);
+        let results = run_bounded(&[broken, good.clone()], 1, &MemoryBudget::new(None), |f| process_file(f, &args, &ctx));
+        assert!(matches!(results[0].status, FileStatus::Failed(_)));
+        assert_eq!(results[1].status, FileStatus::Skipped("not started (--fail-fast)".to_string()));
+        assert!(!dir.path().join("out/c_b_good.png").exists());
+
+        // Without the flag a failure doesn't stop the batch
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone"]);
+        assert_eq!(process_file(&good, &args, &ctx).status, FileStatus::Compressed);
+    }
+
+    #[test]
+    fn test_quality_sweep_variants() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("image.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+
+        let ctx = CliContext::new(dir.path().into(), Some(dir.path().join("out")), "50-80");
+        let args = Args::parse_from(["rust_tinypng_clone", "--to-webp", "--quality-sweep", "40,60,80"]);
+        let results = process_file_formats(&src, &args, &ctx);
+        assert!(results.iter().all(|r| r.status == FileStatus::Compressed));
+
+        let sizes: Vec<u64> = ["image-q40.webp", "image-q60.webp", "image-q80.webp"]
+            .iter()
+            .map(|name| fs::metadata(dir.path().join("out").join(name)).unwrap().len())
+            .collect();
+        assert!(sizes.windows(2).all(|w| w[0] < w[1]), "{:?}", sizes);
+        assert_eq!(results.iter().map(|r| r.after).collect::<Vec<_>>(), sizes);
+    }
+
+    #[tokio::test]
+    async fn test_compress_url_api() {
+        use tower::ServiceExt;
+
+        // Mock origin serving a small PNG and an oversized body
+        let png = create_gradient_png();
+        let origin = Router::new()
+            .route("/img/photo.png", get(move || async move { png }))
+            .route("/big.png", get(|| async { vec![0u8; MAX_UPLOAD_BYTES + 1] }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+
+        let call = |server_opts: ServerOptions, body: serde_json::Value| async move {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/compress-url")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let res = build_router(&server_opts).oneshot(req).await.unwrap();
+            let status = res.status();
+            let headers = res.headers().clone();
+            (status, headers, axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap())
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts_bmp);
-        assert!(result.is_ok());
-        let (_, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/bmp");
-        
-        // Test PNG to ICO conversion
-        let opts_ico = CompressionOptions {
-            png_lossy: false,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: true,
+
+        // The origin is on loopback, which only an explicit opt-in may reach
+        let local = || ServerOptions { fetch_private: true, ..Default::default() };
+        let url = format!("{}/img/photo.png", base);
+        let (status, headers, body) = call(local(), serde_json::json!({ "url": url })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/webp");
+        assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment; filename=\"photo.webp\"");
+        assert!(body.starts_with(b"RIFF"));
+
+        let json = serde_json::json!({ "url": url, "format": "png", "quality": "max", "base64": true });
+        let (status, _, body) = call(local(), json).await;
+        assert_eq!(status, StatusCode::OK);
+        let entry: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(entry["mime_type"], "image/png");
+        assert!(entry["data"].as_str().is_some_and(|d| !d.is_empty()));
+
+        let rejected = [
+            (ServerOptions::default(), serde_json::json!({ "url": "file:///etc/passwd" }), "only http and https"),
+            (local(), serde_json::json!({ "url": format!("{}/big.png", base) }), "upload limit"),
+            (ServerOptions::default(), serde_json::json!({ "url": url }), "loopback, private or link-local"),
+            (ServerOptions::default(), serde_json::json!({ "url": "http://169.254.169.254/latest/meta-data/" }), "link-local"),
+            (ServerOptions::default(), serde_json::json!({ "url": "http://[::ffff:10.0.0.1]/a.png" }), "private"),
+            (
+                ServerOptions::default(),
+                serde_json::json!({ "url": url.replace("127.0.0.1", "localhost") }),
+                "only resolves to loopback, private or link-local",
+            ),
+            (
+                ServerOptions { fetch_hosts: vec!["cdn.example.com".to_string()], fetch_private: true, ..Default::default() },
+                serde_json::json!({ "url": url }),
+                "not an allowed fetch host",
+            ),
+        ];
+        for (server_opts, json, expected) in rejected {
+            let (status, _, body) = call(server_opts, json).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(err["error"].as_str().unwrap().contains(expected), "{}", err);
+        }
+
+        // The multipart form's media_url goes through the same guard
+        let form = |server_opts: ServerOptions, media_url: String| async move {
+            let (boundary, body) = multipart_body(&[("media_url", None, media_url.as_bytes())]);
+            let req = Request::builder()
+                .method("POST")
+                .uri("/api/compress")
+                .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+                .body(Body::from(body))
+                .unwrap();
+            let res = build_router(&server_opts).oneshot(req).await.unwrap();
+            let status = res.status();
+            (status, axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap())
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts_ico);
-        assert!(result.is_ok());
-        let (_, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/x-icon");
+        let (status, body) = form(local(), url.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.starts_with(b"RIFF"));
+        let refused = [
+            (ServerOptions::default(), "http://169.254.169.254/latest/meta-data/".to_string(), "link-local"),
+            (ServerOptions::default(), url.clone(), "loopback, private or link-local"),
+            (local(), format!("{}/big.png", base), "upload limit"),
+        ];
+        for (server_opts, media_url, expected) in refused {
+            let (status, body) = form(server_opts, media_url).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(err["error"].as_str().unwrap().contains(expected), "{}", err);
+        }
     }
 
     #[test]
-    fn test_heic_conversion() {
-        // For this test, we'll use a JPEG as a stand-in for HEIC
-        // since actual HEIC files require special libraries
-        let jpeg_data = create_test_jpeg();
-        let opts = CompressionOptions {
-            png_lossy: false,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
-        };
-        
-        // Test HEIC extension triggers JPEG conversion
-        let result = compress_image_inproc(&jpeg_data, "heic", &opts);
-        assert!(result.is_ok());
-        let (_, mime_type) = result.unwrap();
-        assert_eq!(mime_type, "image/jpeg");
+    fn test_bilevel_scan_png() {
+        // Off-white page with noisy dark "text" lines, stored as 8-bit grayscale
+        let scan = image::GrayImage::from_fn(400, 300, |x, y| {
+            let noise = ((x * 7919 + y * 104_729) % 23) as u8;
+            let ink = y % 24 < 6 && x % 40 < 34;
+            image::Luma([if ink { 30 + noise } else { 220 + noise }])
+        });
+        let mut png = Vec::new();
+        DynamicImage::ImageLuma8(scan).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let opts = CompressionOptions { bilevel: Some((128, false)), ..Default::default() };
+        let (out, mime) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(mime, "image/png");
+        assert_eq!(png_bit_depth(&out), Some(1));
+        assert!(out.len() * 10 < png.len(), "{} vs {}", out.len(), png.len());
+        let decoded = image::load_from_memory(&out).unwrap().to_luma8();
+        assert_eq!((decoded.get_pixel(0, 0)[0], decoded.get_pixel(0, 12)[0]), (0, 255));
+
+        let dithered = CompressionOptions { bilevel: Some((128, true)), ..Default::default() };
+        let (out, _) = compress_image_inproc(&create_gradient_png(), "png", &dithered).unwrap();
+        let decoded = image::load_from_memory(&out).unwrap().to_luma8();
+        assert!(decoded.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        assert!(decoded.pixels().any(|p| p[0] == 0) && decoded.pixels().any(|p| p[0] == 255));
     }
 
     #[test]
-    fn test_quality_parsing() {
-        assert_eq!(parse_quality_range("50-80"), (50, 80));
-        assert_eq!(parse_quality_range("40-70"), (40, 70));
-        assert_eq!(parse_quality_range("20-60"), (20, 60)); // Max compression
-        assert_eq!(parse_quality_range("70-90"), (70, 90)); // Low compression
-        assert_eq!(parse_quality_range("60"), (60, 80)); // Default max
-        assert_eq!(parse_quality_range("invalid"), (50, 80)); // Default values
+    fn test_quality_presets() {
+        let cases = [
+            (QualityPreset::Web, (50, 80), 75, 75.0, 60.0),
+            (QualityPreset::Archive, (80, 95), 90, 90.0, 85.0),
+            (QualityPreset::Max, (20, 60), 50, 40.0, 35.0),
+            (QualityPreset::Lossless, (100, 100), 100, 100.0, 100.0),
+        ];
+        for (preset, png, jpeg, webp, avif) in cases {
+            let name = preset.to_possible_value().unwrap().get_name().to_string();
+            let args = Args::parse_from(["rust_tinypng_clone", "--preset", &name]);
+            let opts = CompressionOptions::from_args(&args, &base_quality(&args));
+            let q = opts.qualities();
+            assert_eq!((q.png, q.jpeg, q.jpeg_reencode, q.webp, q.avif), (png, jpeg, jpeg, webp, avif), "{}", name);
+            assert_eq!(opts.lossless, preset == QualityPreset::Lossless);
+        }
+
+        // Compression levels are the presets' PNG ranges, so "max" means smallest files everywhere
+        for (level, preset) in [("low", QualityPreset::Archive), ("mid", QualityPreset::Web), ("max", QualityPreset::Max)] {
+            assert_eq!(compression_level_to_range(level), preset.qualities().png_range());
+        }
+
+        // No preset: everything follows the --compression-lvl range, as before
+        let args = Args::parse_from(["rust_tinypng_clone", "--compression-lvl", "max"]);
+        let q = CompressionOptions::from_args(&args, &base_quality(&args)).qualities();
+        assert_eq!((q.png, q.jpeg, q.jpeg_reencode, q.webp, q.heic), ((20, 60), 40, 75, 40.0, 85));
+
+        // A range overridden after the preset (e.g. by .compressrc) wins
+        let args = Args::parse_from(["rust_tinypng_clone", "--preset", "archive"]);
+        assert_eq!(CompressionOptions::from_args(&args, "60-70").qualities().webp, 65.0);
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--preset", "web", "--compression-lvl", "low"]).is_err());
     }
-    
-    #[test] 
-    fn test_max_compression_levels() {
-        let png_data = create_test_png();
-        
-        // Test max compression PNG (20-60 range)
-        let opts_max = CompressionOptions {
-            png_lossy: true,
-            png_quality: "20-60".to_string(),
-            oxipng: true,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
+
+    #[test]
+    fn test_webp_input_recompression() {
+        let webp = to_webp_bytes(&create_gradient_png(), 80.0, 4, false, false, false, false).unwrap();
+        let img = load_image(&webp).unwrap();
+        assert_eq!((img.width(), img.height()), (128, 128));
+
+        let opts = CompressionOptions { to_png: true, png_lossy: false, ..Default::default() };
+        let (png, mime) = compress_image_inproc(&webp, "webp", &opts).unwrap();
+        assert_eq!(mime, "image/png");
+        assert_eq!(output_dimensions(&png), Some((128, 128)));
+
+        // Animated WebP is refused instead of silently keeping one frame
+        let frames: Vec<_> = (0..3).map(|i| image::RgbaImage::from_pixel(16, 16, image::Rgba([i * 100, 0, 0, 255]))).collect();
+        let animated = encode_animated_webp(&frames, 10.0, 75.0).unwrap();
+        let err = compress_image_inproc(&animated, "webp", &opts).unwrap_err();
+        assert!(err.to_string().contains("animation (3 frames)"), "{}", err);
+    }
+
+    #[test]
+    fn test_on_conflict_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("image.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        let out = dir.path().join("out");
+        fs::create_dir_all(&out).unwrap();
+        let existing = out.join("c_image.png");
+
+        let run = |policy: &str| {
+            fs::write(&existing, b"earlier run").unwrap();
+            let ctx = CliContext::new(dir.path().into(), Some(out.clone()), "50-80");
+            let args = Args::parse_from(["rust_tinypng_clone", "--on-conflict", policy]);
+            process_file(&src, &args, &ctx).status
         };
-        
-        let result_max = compress_image_inproc(&png_data, "png", &opts_max);
-        assert!(result_max.is_ok());
-        
-        // Test low compression PNG (70-90 range)
-        let opts_low = CompressionOptions {
-            png_lossy: true,
-            png_quality: "70-90".to_string(),
-            oxipng: true,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
+
+        assert!(matches!(run("skip"), FileStatus::Skipped(ref msg) if msg.ends_with("already exists")));
+        assert_eq!(fs::read(&existing).unwrap(), b"earlier run");
+
+        assert_eq!(run("rename"), FileStatus::Compressed);
+        assert_eq!(fs::read(&existing).unwrap(), b"earlier run");
+        assert!(output_dimensions(&fs::read(out.join("c_image-1.png")).unwrap()).is_some());
+        assert_eq!(run("rename"), FileStatus::Compressed);
+        assert!(out.join("c_image-2.png").exists());
+
+        assert_eq!(run("overwrite"), FileStatus::Compressed);
+        assert_ne!(fs::read(&existing).unwrap(), b"earlier run");
+    }
+
+    #[test]
+    fn test_comment_in_output_metadata() {
+        let comment = "(c) 2026 Example Studio";
+        let args = Args::parse_from(["rust_tinypng_clone", "--comment", comment]);
+        let opts = CompressionOptions::from_args(&args, "50-80");
+        assert_eq!(opts.strip, vec![MetadataKind::All]);
+
+        let (png, _) = compress_image_inproc(&create_gradient_png(), "png", &opts).unwrap();
+        let expected = [b"Comment\0".as_slice(), comment.as_bytes()].concat();
+        assert!(png_chunks(&png).iter().any(|(kind, data)| kind == b"tEXt" && *data == expected.as_slice()));
+        let decoder = png::Decoder::new(Cursor::new(&png)).read_info().unwrap();
+        let text = &decoder.info().uncompressed_latin1_text;
+        assert!(text.iter().any(|t| t.keyword == "Comment" && t.text == comment));
+
+        let (jpeg, _) = compress_image_inproc(&create_test_jpeg(), "jpg", &opts).unwrap();
+        assert!(jpeg_segments(&jpeg).iter().any(|&(marker, payload)| marker == 0xFE && payload == comment.as_bytes()));
+
+        let webp_opts = CompressionOptions { to_webp: true, ..opts.clone() };
+        let (webp, _) = compress_image_inproc(&create_gradient_png(), "png", &webp_opts).unwrap();
+        let xmp = read_metadata(&webp).xmp.unwrap();
+        assert!(String::from_utf8(xmp).unwrap().contains(comment));
+        assert!(webp::Decoder::new(&webp).decode().is_some());
+        assert!(parse_comment("").is_err());
+    }
+
+    #[test]
+    fn test_fit_pads_to_exact_dimensions() {
+        // 128x128 gradient into a wide 200x100 slot: scaled to 100x100, 50px bars left and right
+        let args = Args::parse_from(["rust_tinypng_clone", "--fit", "200x100", "--fit-background", "#ff0000"]);
+        let opts = CompressionOptions { png_lossy: false, ..CompressionOptions::from_args(&args, "50-80") };
+        let (png, _) = compress_image_inproc(&create_gradient_png(), "png", &opts).unwrap();
+        let out = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(out.dimensions(), (200, 100));
+        for (x, y) in [(0, 0), (49, 99), (150, 50), (199, 0)] {
+            assert_eq!(out.get_pixel(x, y).0, [255, 0, 0, 255], "({}, {})", x, y);
+        }
+        assert_ne!(out.get_pixel(100, 50).0, [255, 0, 0, 255]);
+
+        let canvas = fit_to_canvas(&load_image(&create_gradient_png()).unwrap(), 64, 96, [0, 0, 0, 0]);
+        assert_eq!(canvas.dimensions(), (64, 96));
+        assert_eq!((canvas.get_pixel(32, 0)[3], canvas.get_pixel(32, 48)[3]), (0, 255));
+        assert!(parse_fit("512").is_err() && parse_fit("0x10").is_err());
+    }
+
+    #[test]
+    fn test_transparent_index_first() {
+        // Opaque gradient with a translucent band and a fully transparent corner
+        let img = image::RgbaImage::from_fn(64, 64, |x, y| match (x, y) {
+            (0..=15, 0..=15) => image::Rgba([0, 0, 0, 0]),
+            (_, 0..=15) => image::Rgba([200, 40, 40, 128]),
+            _ => image::Rgba([(x * 4) as u8, (y * 4) as u8, 90, 255]),
+        });
+        let mut png = Vec::new();
+        img.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+
+        let first_palette_alpha = |opts: &CompressionOptions| {
+            let out = compress_png_bytes(&png, opts).unwrap();
+            let decoder = png::Decoder::new(Cursor::new(out)).read_info().unwrap();
+            let info = decoder.info();
+            assert_eq!(info.color_type, png::ColorType::Indexed);
+            info.trns.as_ref().map(|t| t[0]).unwrap_or(255)
         };
-        
-        let result_low = compress_image_inproc(&png_data, "png", &opts_low);
-        assert!(result_low.is_ok());
-        
-        // Max compression should typically produce smaller files
-        let (max_bytes, _) = result_max.unwrap();
-        let (low_bytes, _) = result_low.unwrap();
-        
-        // For very small test images this might not always hold, but ensure both work
-        assert!(max_bytes.len() > 0);
-        assert!(low_bytes.len() > 0);
+        let opts = CompressionOptions { transparent_index_first: true, ..Default::default() };
+        assert_eq!(first_palette_alpha(&opts), 0);
+        assert_eq!(first_palette_alpha(&CompressionOptions { oxipng: false, ..opts }), 0);
+
+        // Direct: the 0-alpha entry moves ahead of the other translucent one
+        let palette = [rgb::RGBA::new(9, 9, 9, 255), rgb::RGBA::new(1, 1, 1, 128), rgb::RGBA::new(0, 0, 0, 0)];
+        let out = encode_indexed_png(3, 1, &palette, &[0, 1, 2], true).unwrap();
+        let decoder = png::Decoder::new(Cursor::new(out)).read_info().unwrap();
+        assert_eq!(decoder.info().trns.as_deref(), Some([0, 128].as_slice()));
     }
 
     #[tokio::test]
-    async fn test_web_api_compression() {
-        // This would require more complex setup to test the actual multipart handling
-        // For now, we'll just test that the core compression functions work
-        let png_data = create_test_png();
-        
-        // Test that our compression function can handle the data
-        let opts = CompressionOptions {
-            png_lossy: true,
-            png_quality: "50-80".to_string(),
-            oxipng: false,
-            to_webp: false,
-            to_avif: false,
-            to_jpeg: false,
-            to_png: false,
-            to_tiff: false,
-            to_bmp: false,
-            to_ico: false,
+    async fn test_progress_stream_reports_batch() {
+        use futures_util::StreamExt;
+        use tower::ServiceExt;
+
+        let router = build_router(&ServerOptions::default());
+        let req = Request::builder().uri("/api/progress/batch-1").body(Body::empty()).unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        let png = create_test_png();
+        let (boundary, body) = multipart_body(&[
+            ("session", None, b"batch-1"),
+            ("file", Some("a.png"), &png),
+            ("file", Some("b.png"), b"not an image"),
+        ]);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/compress")
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", boundary))
+            .body(Body::from(body))
+            .unwrap();
+        let upload = tokio::spawn(router.oneshot(req));
+
+        // The stream closes itself after the done event
+        let mut stream = res.into_body().into_data_stream();
+        let mut text = String::new();
+        while let Some(chunk) = tokio::time::timeout(std::time::Duration::from_secs(30), stream.next()).await.unwrap() {
+            text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+        }
+        assert_eq!(upload.await.unwrap().unwrap().status(), StatusCode::MULTI_STATUS);
+
+        let events: Vec<serde_json::Value> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let kinds: Vec<&str> = events.iter().map(|e| e["event"].as_str().unwrap()).collect();
+        assert_eq!(kinds, ["started", "completed", "started", "failed", "done"]);
+        assert_eq!(events[0]["filename"], "a.png");
+        assert_eq!(events[1]["original_size"], png.len());
+        assert!(events[1]["compressed_size"].as_u64().unwrap() > 0);
+        assert_eq!(events[3]["filename"], "b.png");
+        assert_eq!(events[4]["files"], 2);
+    }
+
+    #[test]
+    fn test_dedup_compresses_identical_images_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let img = image::RgbaImage::from_fn(32, 32, |x, y| image::Rgba([(x * 8) as u8, (y * 8) as u8, 128, 255]));
+        // Same pixels, different encodings and names
+        img.save(dir.path().join("a.png")).unwrap();
+        let mut fast = Vec::new();
+        image::ImageEncoder::write_image(
+            image::codecs::png::PngEncoder::new_with_quality(&mut fast, image::codecs::png::CompressionType::Fast, Default::default()),
+            img.as_raw(),
+            32,
+            32,
+            image::ExtendedColorType::Rgba8,
+        )
+        .unwrap();
+        fs::write(dir.path().join("copy of a.png"), &fast).unwrap();
+        image::RgbaImage::from_pixel(32, 32, image::Rgba([1, 2, 3, 255])).save(dir.path().join("other.png")).unwrap();
+
+        let files = vec![dir.path().join("a.png"), dir.path().join("copy of a.png"), dir.path().join("other.png")];
+        let (unique, duplicates) = find_duplicates(&files);
+        assert_eq!(unique, [files[0].clone(), files[2].clone()]);
+        assert_eq!(duplicates, [(files[1].clone(), files[0].clone())]);
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--dedup"]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "65-80");
+        let original = process_file(&files[0], &args, &ctx);
+        assert_eq!(original.status, FileStatus::Compressed);
+        let (dup, hard_linked) = write_duplicate(&files[1], &original, &args, &ctx);
+        assert_eq!(dup.status, FileStatus::Compressed);
+        assert!(hard_linked);
+        assert_eq!(dup.after, original.after);
+        let (a_out, dup_out) = (dir.path().join("c_a.png"), dir.path().join("c_copy of a.png"));
+        assert_eq!(fs::read(&a_out).unwrap(), fs::read(&dup_out).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(fs::metadata(&a_out).unwrap().ino(), fs::metadata(&dup_out).unwrap().ino());
+        }
+    }
+
+    #[test]
+    fn test_jpeg_effort_best_beats_fast() {
+        let photo = create_gradient_png();
+        let encode = |effort| compress_jpeg_bytes(&photo, 85, 0, 0, JpegScanMode::AllComponentsTogether, false, effort).unwrap();
+        let (fast, balanced, best) = (encode(JpegEffort::Fast), encode(JpegEffort::Balanced), encode(JpegEffort::Best));
+        assert!(best.len() < fast.len(), "best {} vs fast {}", best.len(), fast.len());
+        assert!(balanced.len() < fast.len());
+        assert!(best.len() <= balanced.len());
+        // Fast is baseline (SOF0), the others progressive (SOF2)
+        assert!(jpeg_segments(&fast).iter().any(|(marker, _)| *marker == 0xC0));
+        assert!(jpeg_segments(&best).iter().any(|(marker, _)| *marker == 0xC2));
+        image::load_from_memory(&fast).unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--jpeg-effort", "best"]);
+        assert_eq!(CompressionOptions::from_args(&args, "50-80").jpeg_effort, JpegEffort::Best);
+    }
+
+    #[test]
+    fn test_clipboard_format_selection() {
+        let png = create_test_png();
+        let mime_for = |args: &[&str]| {
+            let args = Args::parse_from([&["rust_tinypng_clone", "--clipboard"], args].concat());
+            let (_, mime) = compress_image_inproc(&png, "png", &CompressionOptions::from_args(&args, "65-80")).unwrap();
+            clipboard_mime(&mime).map_err(|e| e.to_string())
         };
-        
-        let result = compress_image_inproc(&png_data, "png", &opts);
-        assert!(result.is_ok());
+        assert_eq!(mime_for(&[]).unwrap(), "image/png");
+        assert_eq!(mime_for(&["--to-webp"]).unwrap(), "image/webp");
+        assert_eq!(mime_for(&["--map", "png=jpeg"]).unwrap(), "image/jpeg");
+        assert!(mime_for(&["--to-avif"]).unwrap_err().contains("image/avif"));
+        assert!(clipboard_mime("image/tiff").is_err());
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--clipboard", "--stdout"]).is_err());
+        #[cfg(not(feature = "clipboard"))]
+        assert!(copy_to_clipboard(&png).unwrap_err().to_string().contains("--features clipboard"));
+        // Headless (or undecodable output) is an error, not a panic
+        #[cfg(feature = "clipboard")]
+        assert!(copy_to_clipboard(b"not an image").is_err());
+    }
+
+    #[test]
+    fn test_bandwidth_savings() {
+        let results = vec![
+            FileResult { path: PathBuf::from("a.png"), before: 300_000, after: 100_000, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("b.jpg"), before: 150_000, after: 100_000, status: FileStatus::Compressed },
+            // Grown and skipped files save nothing
+            FileResult { path: PathBuf::from("c.png"), before: 100, after: 120, status: FileStatus::Compressed },
+            FileResult { path: PathBuf::from("d.png"), before: 900, after: 900, status: FileStatus::Skipped("below --min-savings".into()) },
+        ];
+        let (bytes, dollars) = bandwidth_savings(&results, 40_000, Some(0.08));
+        assert_eq!(bytes, 250_000 * 40_000);
+        assert!((dollars.unwrap() - 0.8).abs() < 1e-9);
+        assert_eq!(bandwidth_savings(&results, 40_000, None), (10_000_000_000, None));
+        assert_eq!(bandwidth_savings(&results, u64::MAX, None).0, u64::MAX);
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--bandwidth-report", "--monthly-views", "40000", "--cost-per-gb", "0.08"]);
+        assert_eq!((args.monthly_views, args.cost_per_gb), (Some(40_000), Some(0.08)));
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--bandwidth-report"]).is_err());
+    }
+
+    #[test]
+    fn test_first_frame_of_animation() {
+        let colors = [[220, 30, 30, 255], [30, 220, 30, 255], [30, 30, 220, 255]];
+        let frames: Vec<image::RgbaImage> = colors.iter().map(|&c| image::RgbaImage::from_pixel(12, 8, image::Rgba(c))).collect();
+        let gif = encode_gif(&frames, 10.0, "50-80").unwrap();
+        assert_eq!(animation_frames(&gif), Some(("GIF", 3)));
+
+        let opts = CompressionOptions { first_frame: true, ..Default::default() };
+        let (out, mime) = compress_image_inproc(&gif, "gif", &opts).unwrap();
+        assert_eq!(mime, "image/png");
+        assert_eq!(animation_frames(&out), None);
+        let still = image::load_from_memory(&out).unwrap().to_rgba8();
+        assert_eq!(still.dimensions(), (12, 8));
+        let px = still.get_pixel(5, 5);
+        assert!(px[0] > 200 && px[1] < 60 && px[3] == 255, "{:?}", px);
+
+        // Animated WebP, converted on the way
+        let config = webp::WebPConfig::new().unwrap();
+        let mut encoder = webp::AnimEncoder::new(12, 8, &config);
+        for (n, frame) in frames.iter().enumerate() {
+            encoder.add_frame(webp::AnimFrame::from_rgba(frame.as_raw(), 12, 8, n as i32 * 100));
+        }
+        let webp = encoder.encode().to_vec();
+        assert_eq!(animation_frames(&webp), Some(("WebP", 3)));
+        let (out, mime) = compress_image_inproc(&webp, "webp", &CompressionOptions { to_jpeg: true, ..opts }).unwrap();
+        assert_eq!(mime, "image/jpeg");
+        let px = *image::load_from_memory(&out).unwrap().to_rgb8().get_pixel(5, 5);
+        assert!(px[0] > 180 && px[1] < 80, "{:?}", px);
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--first-frame", "--preserve-animation"]).is_err());
+
+        // GIFs are only discovered when they can be read
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("spinner.gif"), &gif).unwrap();
+        assert!(discover_files(dir.path(), &[]).0.is_empty());
+        assert_eq!(discover_files(dir.path(), &["gif"]).0, [dir.path().join("spinner.gif")]);
+
+        // A 1x1 screen whose frame descriptor claims 65535x65535 is refused before allocating
+        let mut huge = create_test_gif(1);
+        huge[32..36].copy_from_slice(&[0xFF; 4]);
+        let err = gif_first_frame(&huge, |w, h, _| {
+            if w as u64 * h as u64 > 1 << 20 {
+                return Err(anyhow!("{}x{} exceeds the limit", w, h));
+            }
+            Ok(())
+        })
+        .unwrap_err();
+        assert!(err.to_string().contains("65535x65535"), "{}", err);
+        assert_eq!(first_frame(&create_test_gif(1)).unwrap().dimensions(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_api_output_filename_multi_dot() {
+        assert_eq!(api_output_filename("my.photo.backup.png", "image/webp"), "my.photo.backup.webp");
+        // The old string replace rewrote every ".png" in the name
+        assert_eq!(api_output_filename("logo.png.final.png", "image/avif"), "logo.png.final.avif");
+        assert_eq!(api_output_filename("IMG_0001.HEIC", "image/jpeg"), "IMG_0001.jpg");
+        assert_eq!(api_output_filename("scan.v2.tif", "image/tiff"), "c_scan.v2.tif");
+        assert_eq!(api_output_filename("photo.jpeg", "image/jpeg"), "c_photo.jpeg");
+        assert_eq!(api_output_filename("report.2024.pdf", "application/pdf"), "c_report.2024.pdf");
+        assert_eq!(api_output_filename("image", "image/webp"), "image.webp");
+        // Formats converted by default (BMP -> PNG) are renamed too
+        assert_eq!(api_output_filename("old.icon.bmp", "image/png"), "old.icon.png");
+
+        let png = create_test_png();
+        let (status, headers, _) = post_multipart("/api/compress", &[("file", Some("my.photo.backup.png"), &png)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment; filename=\"my.photo.backup.webp\"");
+        let (_, headers, _) =
+            post_multipart("/api/compress", &[("output_format", None, b"png"), ("file", Some("a.png.b.png"), &png)]).await;
+        assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment; filename=\"c_a.png.b.png\"");
+    }
+
+    #[test]
+    fn test_trim_crops_uniform_border() {
+        // 20x15 of content on a white 60x40 page, with a speck of near-white noise in the margin
+        let mut page = image::RgbaImage::from_pixel(60, 40, image::Rgba([255, 255, 255, 255]));
+        for y in 5..20 {
+            for x in 10..30 {
+                page.put_pixel(x, y, image::Rgba([(x * 8) as u8, (y * 10) as u8, 40, 255]));
+            }
+        }
+        page.put_pixel(50, 35, image::Rgba([250, 252, 249, 255]));
+        assert_eq!(trim_bounds(&page, 0), Some((10, 5, 41, 31)));
+        assert_eq!(trim_bounds(&page, 8), Some((10, 5, 20, 15)));
+        assert_eq!(trim_bounds(&image::RgbaImage::from_pixel(8, 8, image::Rgba([9, 9, 9, 255])), 0), None);
+
+        let mut png = Vec::new();
+        page.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let opts = CompressionOptions { trim: Some(8), ..Default::default() };
+        let (out, _) = compress_image_inproc(&png, "png", &opts).unwrap();
+        assert_eq!(output_dimensions(&out), Some((20, 15)));
+
+        // Transparent borders trim whatever color their invisible pixels have
+        let mut sprite = image::RgbaImage::from_fn(30, 30, |x, _| image::Rgba([x as u8, 0, 0, 0]));
+        sprite.put_pixel(12, 14, image::Rgba([255, 0, 0, 255]));
+        assert_eq!(trim_bounds(&sprite, 0), Some((12, 14, 1, 1)));
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--trim", "--trim-tolerance", "12"]);
+        assert_eq!(CompressionOptions::from_args(&args, "50-80").trim, Some(12));
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--trim-tolerance", "12"]).is_err());
+    }
+
+    #[test]
+    fn test_temp_dir_cleaned_up_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let staging = tempfile::tempdir().unwrap();
+        let staged_files = || fs::read_dir(staging.path()).unwrap().count();
+        let png = create_gradient_png();
+        let src = dir.path().join("a.png");
+        fs::write(&src, &png).unwrap();
+
+        let args = Args::parse_from(["rust_tinypng_clone", "--overwrite", "--temp-dir", staging.path().to_str().unwrap()]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "65-80");
+        let res = process_file(&src, &args, &ctx);
+        assert_eq!(res.status, FileStatus::Compressed);
+        assert_ne!(fs::read(&src).unwrap(), png);
+        assert_eq!(staged_files(), 0);
+
+        // The backup rename fails (a directory holds its name) after the output was staged
+        fs::write(&src, &png).unwrap();
+        fs::create_dir(dir.path().join("a.png.bak")).unwrap();
+        fs::write(dir.path().join("a.png.bak").join("keep"), b"x").unwrap();
+        let res = process_file(&src, &args, &ctx);
+        assert!(matches!(&res.status, FileStatus::Failed(msg) if msg.starts_with("backup-failed")), "{:?}", res.status);
+        assert_eq!(staged_files(), 0);
+        assert_eq!(fs::read(&src).unwrap(), png);
+        // Nothing was staged next to the source either
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+
+        // Failed writes leave no temp file next to the destination
+        let dest = dir.path().join("out.bin");
+        assert!(write_atomic(&dest, |_| Err(std::io::Error::other("encoder gave up"))).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_perceptual_dedup_clusters_near_duplicates() {
+        let photo = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, ((x + y) * 2) as u8, (y * 5) as u8]));
+        // Slightly brighter, noisier, downscaled and with a small blemish: the same picture,
+        // but not the same hash
+        let mut edited = image::RgbImage::from_fn(64, 48, |x, y| {
+            let p = photo.get_pixel(x, y);
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+            let blemish = if (40..48).contains(&x) && (16..24).contains(&y) { 80 } else { 0 };
+            image::Rgb(p.0.map(|c| c.saturating_add(6 + noise).saturating_sub(blemish)))
+        });
+        edited = image::imageops::resize(&edited, 48, 36, image::imageops::FilterType::Triangle);
+        let other = image::RgbImage::from_fn(64, 48, |x, y| {
+            let v = if (x / 8 + y / 8) % 2 == 0 { 230 } else { 20 };
+            image::Rgb([v, v, v])
+        });
+        let hashes = vec![
+            (PathBuf::from("photo.png"), dhash(&DynamicImage::ImageRgb8(photo))),
+            (PathBuf::from("checker.png"), dhash(&DynamicImage::ImageRgb8(other))),
+            (PathBuf::from("photo-edit.jpg"), dhash(&DynamicImage::ImageRgb8(edited))),
+        ];
+        let near = (hashes[0].1 ^ hashes[2].1).count_ones();
+        assert!(near > 0 && near <= 5, "near-duplicates are {} bits apart", near);
+        assert!((hashes[0].1 ^ hashes[1].1).count_ones() > 10);
+
+        let clusters = similar_clusters(&hashes, 5);
+        assert_eq!(clusters, [vec![(PathBuf::from("photo.png"), 0), (PathBuf::from("photo-edit.jpg"), near)]]);
+        assert!(similar_clusters(&hashes, 0).is_empty());
+        assert!(Args::try_parse_from(["rust_tinypng_clone", "--perceptual-dedup", "65"]).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_outputs_keep_readable_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+
+        // New outputs get 0o666 less the umask, never tempfile's 0o600
+        let umask = unsafe {
+            let old = libc::umask(0o022);
+            libc::umask(old);
+            old
+        } as u32;
+        let dest = dir.path().join("c_new.png");
+        write_atomic(&dest, |w| w.write_all(b"x")).unwrap();
+        assert_eq!(mode(&dest), 0o666 & !umask);
+
+        // --overwrite carries the original's mode over to the replacement
+        let src = dir.path().join("a.png");
+        fs::write(&src, create_gradient_png()).unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o644)).unwrap();
+        let args = Args::parse_from(["rust_tinypng_clone", "--overwrite"]);
+        let ctx = CliContext::new(dir.path().to_path_buf(), None, "65-80");
+        assert_eq!(process_file(&src, &args, &ctx).status, FileStatus::Compressed);
+        assert_eq!(mode(&src), 0o644);
+    }
+
+    #[test]
+    fn test_tar_long_non_ascii_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut w = TarWriter::create(&dir.path().join("long.tar")).unwrap();
+        // Byte 156 lands in the middle of a 'ü'
+        let long_name = format!("{}/{}.png", "é".repeat(60), "ü".repeat(20));
+        assert!(!long_name.is_char_boundary(156));
+        w.append(&long_name, b"x").unwrap();
+        w.finish().unwrap();
+        let raw = fs::read(dir.path().join("long.tar")).unwrap();
+        assert_eq!(read_tar_entries(&raw), vec![(long_name, 1)]);
+    }
+
+    #[test]
+    fn test_avif_input_to_png() {
+        let avif = to_avif_bytes(&create_gradient_png(), 70.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        assert!(is_avif(&avif));
+        assert!(!is_avif(&create_test_png()));
+        let opts = CompressionOptions { to_png: true, ..Default::default() };
+
+        #[cfg(feature = "avif-decode")]
+        {
+            assert!(SUPPORTED_EXTS.contains(&"avif"));
+            let (png, mime) = compress_image_inproc(&avif, "avif", &opts).unwrap();
+            assert_eq!(mime, "image/png");
+            let img = image::load_from_memory(&png).unwrap().to_rgba8();
+            assert_eq!(img.dimensions(), image::load_from_memory(&create_gradient_png()).unwrap().to_rgba8().dimensions());
+        }
+        #[cfg(not(feature = "avif-decode"))]
+        {
+            assert!(!SUPPORTED_EXTS.contains(&"avif"));
+            let err = compress_image_inproc(&avif, "avif", &opts).unwrap_err();
+            assert!(err.to_string().contains("--features avif-decode"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_decode_cost_reads_heif_and_pdf_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let full_box = |kind: &[u8; 4], payload: &[u8]| [&((payload.len() + 8) as u32).to_be_bytes()[..], kind, payload].concat();
+        // ftyp + meta/iprp/ipco with a 512x512 tile extent and the 4000x3000 canvas
+        let ispe = |w: u32, h: u32| full_box(b"ispe", &[&[0; 4][..], &w.to_be_bytes(), &h.to_be_bytes()].concat());
+        let ipco = full_box(b"ipco", &[ispe(512, 512), ispe(4000, 3000)].concat());
+        let meta = full_box(b"meta", &[&[0; 4][..], &full_box(b"iprp", &ipco)].concat());
+        let heic = [full_box(b"ftyp", b"heic\0\0\0\0mif1heic"), meta, full_box(b"mdat", &[0; 64])].concat();
+        assert_eq!(heif_dimensions(&heic), Some((4000, 3000)));
+        let path = dir.path().join("photo.heic");
+        fs::write(&path, &heic).unwrap();
+        assert_eq!(decode_cost(&path), 4000 * 3000 * 4);
+
+        // Real encoder output carries ispe too
+        let avif = to_avif_bytes(&create_gradient_png(), 70.0, 8, AvifSubsampling::S444, None, false).unwrap();
+        let (w, h) = image::load_from_memory(&create_gradient_png()).unwrap().to_rgba8().dimensions();
+        assert_eq!(heif_dimensions(&avif), Some((w, h)));
+        assert_eq!(heif_dimensions(&create_test_png()), None);
+
+        // WebP and GIF sizes come from their headers, not the compressed file size
+        let rgba = image::RgbaImage::from_fn(300, 200, |x, _| image::Rgba([x as u8, 90, 40, if x < 10 { 128 } else { 255 }]));
+        let rgb = DynamicImage::ImageRgba8(rgba.clone()).to_rgb8();
+        let lossy = WebpEncoder::from_rgb(rgb.as_raw(), 300, 200).encode(75.0).to_vec();
+        let lossless = WebpEncoder::from_rgb(rgb.as_raw(), 300, 200).encode_lossless().to_vec();
+        let extended = WebpEncoder::from_rgba(rgba.as_raw(), 300, 200).encode(75.0).to_vec();
+        assert_eq!([&lossy[12..16], &lossless[12..16], &extended[12..16]], [b"VP8 ", b"VP8L", b"VP8X"]);
+        for (i, webp) in [lossy, lossless, extended].iter().enumerate() {
+            assert_eq!(webp_dimensions(&webp[..30]), Some((300, 200)));
+            let path = dir.path().join(format!("{}.webp", i));
+            fs::write(&path, webp).unwrap();
+            assert_eq!(decode_cost(&path), 300 * 200 * 4);
+        }
+        let gif = [&b"GIF89a"[..], &640u16.to_le_bytes(), &480u16.to_le_bytes(), &[0; 64]].concat();
+        assert_eq!(gif_dimensions(&gif), Some((640, 480)));
+        let path = dir.path().join("anim.gif");
+        fs::write(&path, &gif).unwrap();
+        assert_eq!(decode_cost(&path), 640 * 480 * 4);
+
+        #[cfg(feature = "pdf")]
+        {
+            let path = dir.path().join("scan.pdf");
+            fs::write(&path, create_test_pdf(300, 200)).unwrap();
+            assert_eq!(decode_cost(&path), 300 * 200 * 4);
+        }
     }
 }
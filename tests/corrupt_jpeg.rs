@@ -0,0 +1,35 @@
+//! Runs the real binary, so `cargo test --release` also covers the release profile's panic strategy
+
+use std::io::Cursor;
+use std::process::Command;
+
+/// A JPEG whose first Huffman table claims far more codes than it has. It still ends in EOI,
+/// so it gets past the truncated-input check and reaches libjpeg, which fails on it.
+fn jpeg_with_bogus_huffman_table() -> Vec<u8> {
+    let img = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg).unwrap();
+    let dht = jpeg.windows(2).position(|m| m == [0xFF, 0xC4]).expect("JPEG has a DHT segment");
+    // Marker, length, then the table class/id byte; the 16 code-length counts follow
+    jpeg[dht + 5..dht + 21].fill(0xFF);
+    jpeg
+}
+
+#[test]
+fn corrupt_jpeg_fails_the_file_not_the_process() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("bad.jpg");
+    std::fs::write(&input, jpeg_with_bogus_huffman_table()).unwrap();
+
+    // --lossless sends JPEGs straight to the libjpeg coefficient transcoder
+    let out = Command::new(env!("CARGO_BIN_EXE_rust_tinypng_clone"))
+        .arg(&input)
+        .arg("--lossless")
+        .arg("-o")
+        .arg(dir.path().join("out"))
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(out.status.code().is_some(), "killed by a signal: {:?}\n{}", out.status, stderr);
+    assert!(stderr.contains("bad.jpg: failed (compress-failed: lossless JPEG optimization failed"), "{}", stderr);
+}